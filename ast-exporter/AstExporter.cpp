@@ -1,6 +1,7 @@
 #include <fstream>
 #include <iterator>
 #include <iostream>
+#include <regex>
 #include <set>
 #include <unordered_map>
 #include <unordered_set>
@@ -19,6 +20,9 @@
 #include "clang/Basic/Builtins.h"
 #include "clang/Basic/Diagnostic.h"
 #include "clang/Frontend/CompilerInstance.h"
+#include "clang/Lex/Lexer.h"
+#include "clang/Lex/PPCallbacks.h"
+#include "clang/Lex/Preprocessor.h"
 #include "clang/Tooling/Tooling.h"
 
 #include <tinycbor/cbor.h>
@@ -1457,16 +1461,112 @@ void TypeEncoder::VisitVariableArrayType(const VariableArrayType *T) {
     VisitQualType(t);
 }
 
+// A part of a file which is only compiled when a macro is (or is not) defined: a branch of an
+// `#ifdef NAME`, `#ifndef NAME`, or `#if defined(NAME)`, running from the directive opening the
+// branch to the one closing it
+struct ConditionalRegion {
+    string macro;
+    bool when_defined;
+    SourceLocation begin;
+    SourceLocation end;
+};
+
+// Whether the condition of an `#if` is `defined(NAME)` or `!defined(NAME)`, with or without the
+// parentheses, and if so for which macro
+static bool simple_condition(const string &condition, string &macro, bool &when_defined) {
+    static const std::regex pattern(
+        R"(^\s*(!?)\s*defined\s*(?:\(\s*([A-Za-z_]\w*)\s*\)|\s([A-Za-z_]\w*))\s*$)");
+    std::smatch match;
+    if (!std::regex_match(condition, match, pattern)) {
+        return false;
+    }
+    when_defined = match[1].length() == 0;
+    macro = match[2].matched ? match[2].str() : match[3].str();
+    return true;
+}
+
+// Records the regions of simple conditionals while the file is preprocessed
+class ConditionalRecorder : public PPCallbacks {
+    const SourceManager &manager;
+    const LangOptions &options;
+    std::vector<ConditionalRegion> &regions;
+    
+    // The current branch of each conditional being preprocessed. Branches whose condition is
+    // not a single macro being defined or not have an empty macro name.
+    std::vector<ConditionalRegion> open;
+    
+    void open_branch(SourceLocation loc, const string &macro, bool when_defined) {
+        open.push_back(ConditionalRegion { macro, when_defined, loc, loc });
+    }
+    
+    void close_branch(SourceLocation loc) {
+        if (open.empty()) {
+            return;
+        }
+        auto region = open.back();
+        open.pop_back();
+        if (!region.macro.empty()) {
+            region.end = loc;
+            regions.push_back(region);
+        }
+    }
+    
+public:
+    ConditionalRecorder(const SourceManager &manager, const LangOptions &options,
+                        std::vector<ConditionalRegion> &regions)
+        : manager(manager), options(options), regions(regions) { }
+    
+    void Ifdef(SourceLocation Loc, const Token &MacroNameTok, const MacroDefinition &) override {
+        open_branch(Loc, MacroNameTok.getIdentifierInfo()->getName().str(), true);
+    }
+    
+    void Ifndef(SourceLocation Loc, const Token &MacroNameTok, const MacroDefinition &) override {
+        open_branch(Loc, MacroNameTok.getIdentifierInfo()->getName().str(), false);
+    }
+    
+    void If(SourceLocation Loc, SourceRange ConditionRange, ConditionValueKind) override {
+        auto condition = Lexer::getSourceText(CharSourceRange::getTokenRange(ConditionRange),
+                                              manager, options).str();
+        string macro;
+        bool when_defined = true;
+        if (!simple_condition(condition, macro, when_defined)) {
+            macro.clear();
+        }
+        open_branch(Loc, macro, when_defined);
+    }
+    
+    // The branches following an `#elif` depend on several conditions
+    void Elif(SourceLocation Loc, SourceRange, ConditionValueKind, SourceLocation) override {
+        close_branch(Loc);
+        open_branch(Loc, "", true);
+    }
+    
+    void Else(SourceLocation Loc, SourceLocation) override {
+        if (open.empty()) {
+            return;
+        }
+        auto branch = open.back();
+        close_branch(Loc);
+        open_branch(Loc, branch.macro, !branch.when_defined);
+    }
+    
+    void Endif(SourceLocation Loc, SourceLocation) override {
+        close_branch(Loc);
+    }
+};
+
 class TranslateConsumer : public clang::ASTConsumer {
     const std::string outfile;
+    const std::vector<ConditionalRegion> &regions;
 
 public:
-    explicit TranslateConsumer(llvm::StringRef InFile) 
-        : outfile(InFile.str().append(".cbor")) { }
+    explicit TranslateConsumer(llvm::StringRef InFile, const std::vector<ConditionalRegion> &regions)
+        : outfile(InFile.str().append(".cbor")), regions(regions) { }
     
     virtual void HandleTranslationUnit(clang::ASTContext &Context) {
   
         CborEncoder encoder;
+        auto &regions = this->regions;
 
         // There are some type nodes (see `TypedefType` and `RecordType`) which
         // can be "sugared". That means we should not follow the declarations we
@@ -1474,15 +1574,18 @@ public:
         // type instead.
         std::unordered_map<void*, QualType> sugared;
         
-        auto process = [&encoder, &Context, &sugared](uint8_t *buffer, size_t len)
+        auto process = [&encoder, &Context, &sugared, &regions](uint8_t *buffer, size_t len)
         {
             cbor_encoder_init(&encoder, buffer, len, 0);
             
             CborEncoder outer;
-            cbor_encoder_create_array(&encoder, &outer, 4);
+            cbor_encoder_create_array(&encoder, &outer, 7);
             
             CborEncoder array;
             
+            // 0. Identify the layout of what follows
+            cbor_encode_uint(&outer, AST_SCHEMA_VERSION);
+            
             // 1. Encode all of the reachable AST nodes and types
             cbor_encoder_create_array(&outer, &array, CborIndefiniteLength);
             TranslateASTVisitor visitor(&Context, &array, &sugared);
@@ -1522,6 +1625,34 @@ public:
             }
             cbor_encoder_close_container(&outer, &array);
             
+            // 5. Describe the data model of the target: whether `char` is signed, followed by
+            // the widths in bits of `short`, `int`, `long`, `long long`, and pointers
+            auto &target = Context.getTargetInfo();
+            cbor_encoder_create_array(&outer, &array, 6);
+            cbor_encode_boolean(&array, Context.CharTy->isSignedIntegerType());
+            cbor_encode_uint(&array, target.getShortWidth());
+            cbor_encode_uint(&array, target.getIntWidth());
+            cbor_encode_uint(&array, target.getLongWidth());
+            cbor_encode_uint(&array, target.getLongLongWidth());
+            cbor_encode_uint(&array, target.getPointerWidth(0));
+            cbor_encoder_close_container(&outer, &array);
+            
+            // 6. Emit the regions of simple conditionals as array of arrays. Each region is
+            // represented as the source position of its opening directive, followed by the line
+            // of its closing directive, the macro name, and whether the macro must be defined.
+            auto &manager = Context.getSourceManager();
+            cbor_encoder_create_array(&outer, &array, regions.size());
+            for (auto &region : regions) {
+                CborEncoder entry;
+                cbor_encoder_create_array(&array, &entry, 6);
+                visitor.encodeSourcePos(&entry, region.begin); // emits 3 values
+                cbor_encode_uint(&entry, manager.getPresumedLineNumber(region.end));
+                cbor_encode_string(&entry, region.macro);
+                cbor_encode_boolean(&entry, region.when_defined);
+                cbor_encoder_close_container(&array, &entry);
+            }
+            cbor_encoder_close_container(&outer, &array);
+            
             cbor_encoder_close_container(&encoder, &outer);
         };
         
@@ -1543,11 +1674,19 @@ public:
 };
 
 class TranslateAction : public clang::ASTFrontendAction {
+  std::vector<ConditionalRegion> regions;
     
 public:
+  virtual bool BeginSourceFileAction(clang::CompilerInstance &Compiler) {
+    regions.clear();
+    Compiler.getPreprocessor().addPPCallbacks(llvm::make_unique<ConditionalRecorder>(
+      Compiler.getSourceManager(), Compiler.getLangOpts(), regions));
+    return true;
+  }
+    
   virtual std::unique_ptr<clang::ASTConsumer> CreateASTConsumer(
     clang::CompilerInstance &Compiler, llvm::StringRef InFile) {
-    return std::unique_ptr<clang::ASTConsumer>(new TranslateConsumer(InFile));
+    return std::unique_ptr<clang::ASTConsumer>(new TranslateConsumer(InFile, regions));
   }
 };
 
@@ -9,6 +9,10 @@
 #ifndef ast_tags_h
 #define ast_tags_h
 
+// Version of the layout of the CBOR output, including the tags below. The exporter writes it
+// first and the importer refuses other versions, so bump it whenever the layout or a tag changes.
+#define AST_SCHEMA_VERSION 3
+
 enum ASTEntryTag {
     TagFunctionDecl = 0,
     TagParmVarDecl,
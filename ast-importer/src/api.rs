@@ -0,0 +1,350 @@
+//! A library interface to the translator, for use from build scripts and other tools.
+//!
+//! A `build.rs` can translate vendored C at build time, given the CBOR file the AST exporter
+//! produced for it:
+//!
+//! ```rust,ignore
+//! let tcfg = TranslationConfig::builder()
+//!     .emit_module(true)
+//!     .reloop_cfgs(true)
+//!     .build();
+//! let translated = api::translate_cbor_file("vendor/buffer.c.cbor", tcfg)?;
+//! for diagnostic in &translated.diagnostics {
+//!     println!("cargo:warning={}", diagnostic);
+//! }
+//! fs::write(out_dir.join("buffer.rs"), translated.rust)?;
+//! ```
+//!
+//! The features in `translated.features` have to be enabled by the crate including the output.
+//!
+//! Nothing is printed: declarations that fail to translate are reported in the diagnostics, and a
+//! translation that fails as a whole (for instance with `fail_on_error`) is returned as an error.
+//! Panics in the translator are returned as errors as well. The API leaves the panic hook of the
+//! process alone, so they are still reported through it.
+
+use std::any::Any;
+use std::fs::File;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use serde_cbor::{self, Value};
+use clang_ast::{process, AstContext};
+use c_ast::{ConversionContext, TypedAstContext};
+use safe_wrappers::SafeWrapperConfig;
+use call_rewrites::CallRewrites;
+use translator::{self, OverflowMode, Translated, TranslatedItems, TranslationConfig};
+use merge;
+
+/// Builds a `TranslationConfig`, starting from the defaults of the command line translator
+#[derive(Debug, Clone)]
+pub struct TranslationConfigBuilder {
+    tcfg: TranslationConfig,
+}
+
+impl TranslationConfig {
+    pub fn builder() -> TranslationConfigBuilder {
+        TranslationConfigBuilder { tcfg: TranslationConfig::default() }
+    }
+}
+
+impl TranslationConfigBuilder {
+    /// Translate all function bodies using the CFG/Relooper approach
+    pub fn reloop_cfgs(mut self, reloop_cfgs: bool) -> Self {
+        self.tcfg.reloop_cfgs = reloop_cfgs;
+        self
+    }
+
+    /// Split irreducible control flow by duplicating blocks of at most this many statements
+    pub fn split_irreducible(mut self, max_statements: usize) -> Self {
+        self.tcfg.split_irreducible = Some(max_statements);
+        self
+    }
+
+    /// Use the loop information in the C source to guide the relooper
+    pub fn use_c_loop_info(mut self, use_c_loop_info: bool) -> Self {
+        self.tcfg.use_c_loop_info = use_c_loop_info;
+        self
+    }
+
+    /// Use the branch information in the C source to guide the relooper
+    pub fn use_c_multiple_info(mut self, use_c_multiple_info: bool) -> Self {
+        self.tcfg.use_c_multiple_info = use_c_multiple_info;
+        self
+    }
+
+    /// Simplify the structures the relooper produces
+    pub fn simplify_structures(mut self, simplify_structures: bool) -> Self {
+        self.tcfg.simplify_structures = simplify_structures;
+        self
+    }
+
+    /// Translate inline assembly
+    pub fn translate_asm(mut self, translate_asm: bool) -> Self {
+        self.tcfg.translate_asm = translate_asm;
+        self
+    }
+
+    /// Translate uses of `va_list`
+    pub fn translate_valist(mut self, translate_valist: bool) -> Self {
+        self.tcfg.translate_valist = translate_valist;
+        self
+    }
+
+    /// Create a Rust entry point calling the C `main` function
+    pub fn translate_entry(mut self, translate_entry: bool) -> Self {
+        self.tcfg.translate_entry = translate_entry;
+        self
+    }
+
+    /// Leave out the crate preamble, so the output can be included as a module
+    pub fn emit_module(mut self, emit_module: bool) -> Self {
+        self.tcfg.emit_module = emit_module;
+        self
+    }
+
+    /// Fail the whole translation when a declaration cannot be translated, instead of leaving it
+    /// out
+    pub fn fail_on_error(mut self, fail_on_error: bool) -> Self {
+        self.tcfg.fail_on_error = fail_on_error;
+        self
+    }
+
+    /// Panic on violated invariants or invalid code, instead of emitting `compile_error!`
+    pub fn panic_on_translator_failure(mut self, panic_on_translator_failure: bool) -> Self {
+        self.tcfg.panic_on_translator_failure = panic_on_translator_failure;
+        self
+    }
+
+    /// Leave out type annotations where it should be safe to do so
+    pub fn reduce_type_annotations(mut self, reduce_type_annotations: bool) -> Self {
+        self.tcfg.reduce_type_annotations = reduce_type_annotations;
+        self
+    }
+
+    /// What integer arithmetic does on overflow
+    pub fn overflow_mode(mut self, overflow_mode: OverflowMode) -> Self {
+        self.tcfg.overflow_mode = overflow_mode;
+        self
+    }
+
+    /// Only emit code that builds on stable Rust, failing declarations that need nightly features
+    pub fn target_stable(mut self, target_stable: bool) -> Self {
+        self.tcfg.target_stable = target_stable;
+        self
+    }
+
+    /// Emit `#![no_std]` code, failing declarations that need `std`
+    pub fn no_std(mut self, no_std: bool) -> Self {
+        self.tcfg.no_std = no_std;
+        self
+    }
+
+    /// Use fixed width integer types like `i32` following the target's data model, keeping the
+    /// `libc` aliases only in `extern "C"` declarations
+    pub fn fixed_width_ints(mut self, fixed_width_ints: bool) -> Self {
+        self.tcfg.fixed_width_ints = fixed_width_ints;
+        self
+    }
+
+    /// Name types in UpperCamelCase and functions, parameters and locals in snake_case
+    pub fn normalize_names(mut self, normalize_names: bool) -> Self {
+        self.tcfg.normalize_names = normalize_names;
+        self
+    }
+
+    /// Reuse the names of top-level declarations recorded in this JSON file, then record the
+    /// names picked in it
+    pub fn name_map<S: Into<String>>(mut self, path: S) -> Self {
+        self.tcfg.name_map = Some(path.into());
+        self
+    }
+
+    /// Read the members of unions checked against a tag field through safe accessors, and give
+    /// each such union an enum of its members
+    pub fn tagged_unions(mut self, tagged_unions: bool) -> Self {
+        self.tcfg.tagged_unions = tagged_unions;
+        self
+    }
+
+    /// Turn counted `while` loops over local variables back into `for` loops over ranges
+    pub fn for_ranges(mut self, for_ranges: bool) -> Self {
+        self.tcfg.for_ranges = for_ranges;
+        self
+    }
+
+    /// Let static initializers take the address of a field or element of another static
+    pub fn static_interior_refs(mut self, static_interior_refs: bool) -> Self {
+        self.tcfg.static_interior_refs = static_interior_refs;
+        self
+    }
+
+    /// Index local and static arrays whose pointer never escapes as Rust arrays
+    pub fn index_arrays(mut self, index_arrays: bool) -> Self {
+        self.tcfg.index_arrays = index_arrays;
+        self
+    }
+
+    /// Number of threads to translate function bodies with
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.tcfg.jobs = jobs;
+        self
+    }
+
+    /// Write a JSON source map linking the output back to the C source
+    pub fn source_map<S: Into<String>>(mut self, path: S) -> Self {
+        self.tcfg.source_map = Some(path.into());
+        self
+    }
+
+    /// Lay out the output so its line numbers match the C source
+    pub fn debug_line_layout(mut self, debug_line_layout: bool) -> Self {
+        self.tcfg.debug_line_layout = debug_line_layout;
+        self
+    }
+
+    /// Also generate a module of safe wrappers around the functions of a header
+    pub fn safe_wrappers(mut self, config: SafeWrapperConfig) -> Self {
+        self.tcfg.safe_wrappers = Some(config);
+        self
+    }
+
+    /// Keep the conditionals of the C source on these macros as `#[cfg(feature = "macro")]`. Only
+    /// merged translations (see `translate_cbor_files_merged`) can take this, since each input only
+    /// has the branches compiled for it.
+    pub fn cfg_features(mut self, macros: Vec<String>) -> Self {
+        self.tcfg.cfg_features = macros;
+        self
+    }
+
+    /// Replace calls to C library functions like `memcpy` with equivalent Rust
+    pub fn rewrite_libc_calls(mut self, rewrite_libc_calls: bool) -> Self {
+        self.tcfg.call_rewrites = if rewrite_libc_calls { Some(CallRewrites::libc()) } else { None };
+        self
+    }
+
+    /// Rewrite calls to C functions as described by `rewrites`, in addition to the C library
+    /// functions of `rewrite_libc_calls`
+    pub fn call_rewrites(mut self, rewrites: CallRewrites) -> Self {
+        let mut table = self.tcfg.call_rewrites.take().unwrap_or_else(CallRewrites::libc);
+        table.extend(rewrites);
+        self.tcfg.call_rewrites = Some(table);
+        self
+    }
+
+    /// Emit cross-checks, configured by the given files
+    pub fn cross_checks(mut self, configs: Vec<String>) -> Self {
+        self.tcfg.cross_checks = true;
+        self.tcfg.cross_check_configs = configs;
+        self
+    }
+
+    /// Add a prefix to all function names
+    pub fn prefix_function_names<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.tcfg.prefix_function_names = Some(prefix.into());
+        self
+    }
+
+    pub fn build(self) -> TranslationConfig {
+        self.tcfg
+    }
+}
+
+/// Read the file the AST exporter wrote
+pub fn read_cbor_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, String> {
+    let path = path.as_ref();
+    let mut buffer = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buffer))
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(buffer)
+}
+
+/// Decode the Clang AST from the output of the AST exporter
+pub fn untyped_context_from_cbor(bytes: &[u8]) -> Result<AstContext, String> {
+    let items: Value = serde_cbor::from_slice(bytes)
+        .map_err(|e| format!("Failed to decode CBOR: {}", e))?;
+    process(items).map_err(|e| format!("{}", e))
+}
+
+/// Give types to the nodes of a Clang AST
+pub fn typed_context(untyped_context: &AstContext) -> Result<TypedAstContext, String> {
+    let mut conv = ConversionContext::new(untyped_context);
+    conv.convert(untyped_context).map_err(|e| format!("{}", e))?;
+    Ok(conv.typed_context)
+}
+
+/// Run `f`, returning a panic as an error rather than unwinding out of the API. Panics are bugs in
+/// the translator; the panic hook of the host still reports them, since it is left as it is.
+fn catch_panics<R, F: FnOnce() -> R>(f: F) -> Result<R, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| panic_message(&*payload))
+}
+
+fn panic_message(payload: &(Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else {
+        format!("Translation failed")
+    }
+}
+
+/// A translation of a single input would only have the branches of the conditionals kept by
+/// `cfg_features` compiled for it, guarded as if the others were there as well
+fn check_unmerged(tcfg: &TranslationConfig) -> Result<(), String> {
+    if tcfg.cfg_features.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Keeping conditionals on {:?} needs a merged translation", tcfg.cfg_features))
+    }
+}
+
+/// Translate the output of the AST exporter
+pub fn translate_cbor(bytes: &[u8], tcfg: TranslationConfig) -> Result<Translated, String> {
+    check_unmerged(&tcfg)?;
+    let typed_context = typed_context(&untyped_context_from_cbor(bytes)?)?;
+    catch_panics(|| translator::translate_to_text(typed_context, tcfg))?
+}
+
+/// Translate a file written by the AST exporter
+pub fn translate_cbor_file<P: AsRef<Path>>(path: P, tcfg: TranslationConfig) -> Result<Translated, String> {
+    translate_cbor(&read_cbor_file(path)?, tcfg)
+}
+
+/// Translate the output of the AST exporter into `libsyntax` items, which are passed to `f`. See
+/// `translator::translate_to_items`. A panic in `f` is returned as an error as well.
+pub fn translate_cbor_to_items<R, F>(bytes: &[u8], tcfg: TranslationConfig, f: F) -> Result<R, String>
+    where F: FnOnce(TranslatedItems) -> R {
+    check_unmerged(&tcfg)?;
+    let typed_context = typed_context(&untyped_context_from_cbor(bytes)?)?;
+    catch_panics(|| translator::translate_to_items(typed_context, tcfg, f))?
+}
+
+/// Translate the files the AST exporter wrote for one source built under several configurations
+/// into a single module, guarding the items that differ with `#[cfg]`. Each file comes with the
+/// `cfg` predicate of its configuration. See `merge::translate_merged`.
+pub fn translate_cbor_files_merged<P: AsRef<Path>>(configurations: Vec<(String, P)>, tcfg: TranslationConfig) -> Result<Translated, String> {
+    let mut contexts = vec![];
+    for (predicate, path) in configurations {
+        let untyped_context = untyped_context_from_cbor(&read_cbor_file(path)?)?;
+        contexts.push((predicate, typed_context(&untyped_context)?));
+    }
+    catch_panics(|| merge::translate_merged(contexts, tcfg))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn panics_are_returned() {
+        assert_eq!(catch_panics(|| 1), Ok(1));
+        assert_eq!(catch_panics(|| -> u32 { panic!("Failed translating {}", "f") }), Err("Failed translating f".to_owned()));
+    }
+
+    #[test]
+    fn cfg_features_need_merging() {
+        let tcfg = TranslationConfig::builder().cfg_features(vec!["USE_X".to_string()]).build();
+        assert!(translate_cbor(&[], tcfg).err().unwrap().contains("needs a merged translation"));
+    }
+}
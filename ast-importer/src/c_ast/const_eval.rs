@@ -0,0 +1,508 @@
+//! Evaluation of C constant expressions (6.6), such as the initializers of statics.
+//!
+//! Integer widths and sizes come from the data model of the target recorded in the AST. Evaluation
+//! gives up (returning `None`) on anything it does not understand, on undefined behaviour, and on
+//! values that only exist at runtime, like the integer value of an address.
+
+use c_ast::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// The value of a constant expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstValue {
+    /// An integer, already converted to the width and signedness of its type
+    Int(i128),
+    Float(f64),
+    /// A null pointer
+    Null,
+    /// A pointer to an object with static storage duration
+    Address(ConstAddress),
+}
+
+/// The location of an object with static storage duration, or of part of one
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConstAddress {
+    pub base: AddressBase,
+    /// Fields and array elements leading from the base to the object
+    pub path: Vec<Projection>,
+    /// Type of the object
+    pub ty: CTypeId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressBase {
+    /// A variable with static storage duration
+    Variable(CDeclId),
+    /// The array of a (narrow) string literal
+    StringLiteral(CExprId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// A field of a struct or union
+    Field(CRecordId, CFieldId),
+    /// An element of an array, along with the length of the array if it is known
+    Index(i128, Option<usize>),
+}
+
+impl ConstValue {
+    /// Whether the value compares unequal to 0
+    pub fn is_true(&self) -> bool {
+        match *self {
+            ConstValue::Int(i) => i != 0,
+            ConstValue::Float(f) => f != 0.0,
+            ConstValue::Null => false,
+            ConstValue::Address(_) => true,
+        }
+    }
+}
+
+/// Width in bits and signedness of an integral type, for those evaluation supports
+fn int_format(context: &TypedAstContext, ty: CTypeId) -> Option<(u32, bool)> {
+    context.int_format(ty).filter(|&(width, _)| width <= 64)
+}
+
+/// Convert an integer to an integral type, wrapping as two's complement
+fn wrap(value: i128, (bits, signed): (u32, bool)) -> i128 {
+    if bits == 1 {
+        return (value != 0) as i128
+    }
+    let modulus = 1i128 << bits;
+    let value = ((value % modulus) + modulus) % modulus;
+    if signed && value >= modulus / 2 { value - modulus } else { value }
+}
+
+/// Whether an integer is a value of an integral type
+fn fits(value: i128, (bits, signed): (u32, bool)) -> bool {
+    wrap(value, (bits, signed)) == value
+}
+
+fn round_up(offset: u64, align: u64) -> u64 {
+    (offset + align - 1) / align * align
+}
+
+pub struct ConstEvaluator<'a> {
+    context: &'a TypedAstContext,
+    /// The values of the expressions evaluated so far
+    values: RefCell<HashMap<CExprId, Option<ConstValue>>>,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    pub fn new(context: &'a TypedAstContext) -> Self {
+        ConstEvaluator::with_values(context, HashMap::new())
+    }
+
+    /// An evaluator reusing the values computed by an earlier one (see `into_values`), so that
+    /// evaluating the subexpressions of expressions already evaluated is free
+    pub fn with_values(context: &'a TypedAstContext, values: HashMap<CExprId, Option<ConstValue>>) -> Self {
+        ConstEvaluator { context, values: RefCell::new(values) }
+    }
+
+    /// The values of the expressions evaluated so far
+    pub fn into_values(self) -> HashMap<CExprId, Option<ConstValue>> {
+        self.values.into_inner()
+    }
+
+    /// Size and alignment of a type, in bytes
+    pub fn layout(&self, ty: CTypeId) -> Option<(u64, u64)> {
+        let target = &self.context.target;
+
+        // Scalars are aligned to their size, except that the alignment of those wider than
+        // pointers differs between the ABIs of 32-bit targets
+        let scalar = |bits: u64| {
+            if bits > target.pointer_width && target.pointer_width < 64 { None } else { Some((bits / 8, bits / 8)) }
+        };
+        match self.context.resolve_type(ty).kind {
+            CTypeKind::Bool | CTypeKind::Char | CTypeKind::SChar | CTypeKind::UChar => scalar(8),
+            CTypeKind::Short | CTypeKind::UShort => scalar(target.short_width),
+            CTypeKind::Int | CTypeKind::UInt => scalar(target.int_width),
+            CTypeKind::Long | CTypeKind::ULong => scalar(target.long_width),
+            CTypeKind::LongLong | CTypeKind::ULongLong => scalar(target.long_long_width),
+            CTypeKind::Pointer(_) => scalar(target.pointer_width),
+            CTypeKind::Float => scalar(32),
+            CTypeKind::Double => scalar(64),
+            CTypeKind::Int128 | CTypeKind::UInt128 => scalar(128),
+
+            CTypeKind::ConstantArray(elt, len) => {
+                let (size, align) = self.layout(elt)?;
+                Some((size.checked_mul(len as u64)?, align))
+            }
+            // Only found at the end of structs, where they take up no space
+            CTypeKind::IncompleteArray(elt) => self.layout(elt).map(|(_, align)| (0, align)),
+
+            CTypeKind::Struct(record_id) => match self.context[record_id].kind {
+                CDeclKind::Struct { fields: Some(ref fields), is_packed, manual_alignment, .. } => {
+                    let mut offset = 0;
+                    let mut max_align = manual_alignment.unwrap_or(1);
+                    for &field_id in fields {
+                        let (size, align) = self.field_layout(field_id)?;
+                        let align = if is_packed { 1 } else { align };
+                        offset = round_up(offset, align) + size;
+                        max_align = max_align.max(align);
+                    }
+                    Some((round_up(offset, max_align), max_align))
+                }
+                _ => None,
+            },
+
+            CTypeKind::Union(record_id) => match self.context[record_id].kind {
+                CDeclKind::Union { fields: Some(ref fields), .. } => {
+                    let mut max_size = 0;
+                    let mut max_align = 1;
+                    for &field_id in fields {
+                        let (size, align) = self.field_layout(field_id)?;
+                        max_size = max_size.max(size);
+                        max_align = max_align.max(align);
+                    }
+                    Some((round_up(max_size, max_align), max_align))
+                }
+                _ => None,
+            },
+
+            CTypeKind::Enum(enum_id) => match self.context[enum_id].kind {
+                CDeclKind::Enum { integral_type: Some(ty), .. } => self.layout(ty.ctype),
+                _ => None,
+            },
+
+            // `long double` is translated to `f64`, so its C size is not the size of its
+            // translation
+            _ => None,
+        }
+    }
+
+    fn field_layout(&self, field_id: CFieldId) -> Option<(u64, u64)> {
+        match self.context[field_id].kind {
+            CDeclKind::Field { typ, .. } => self.layout(typ.ctype),
+            _ => None,
+        }
+    }
+
+    /// The value of an expression
+    pub fn eval(&self, expr_id: CExprId) -> Option<ConstValue> {
+        if let Some(value) = self.values.borrow().get(&expr_id) {
+            return value.clone()
+        }
+        let value = self.eval_uncached(expr_id);
+        self.values.borrow_mut().insert(expr_id, value.clone());
+        value
+    }
+
+    fn eval_uncached(&self, expr_id: CExprId) -> Option<ConstValue> {
+        use self::ConstValue::*;
+
+        let expr_ty = self.context[expr_id].kind.get_type()?;
+        let int_result = |value: i128| -> Option<ConstValue> {
+            Some(Int(wrap(value, int_format(self.context, expr_ty)?)))
+        };
+
+        match self.context[expr_id].kind {
+            CExprKind::Literal(_, CLiteral::Integer(value, _)) |
+            CExprKind::Literal(_, CLiteral::Character(value)) => int_result(value as i128),
+            CExprKind::Literal(_, CLiteral::Floating(value)) => self.float_result(expr_ty, value),
+
+            CExprKind::DeclRef(_, decl_id) => match self.context[decl_id].kind {
+                CDeclKind::EnumConstant { value: ConstIntExpr::I(value), .. } => int_result(value as i128),
+                CDeclKind::EnumConstant { value: ConstIntExpr::U(value), .. } => int_result(value as i128),
+                _ => None,
+            },
+
+            CExprKind::OffsetOf(_, offset) => int_result(offset as i128),
+
+            CExprKind::UnaryType(_, op, _, arg_ty) => {
+                let (size, align) = self.layout(arg_ty.ctype)?;
+                match op {
+                    UnTypeOp::SizeOf => int_result(size as i128),
+                    UnTypeOp::AlignOf => int_result(align as i128),
+                }
+            }
+
+            CExprKind::Unary(_, op, arg) => match op {
+                UnOp::AddressOf => self.eval_lvalue(arg).map(Address),
+                UnOp::Plus | UnOp::Extension => self.eval(arg),
+                UnOp::Not => int_result(!self.eval(arg)?.is_true() as i128),
+                UnOp::Negate => match self.eval(arg)? {
+                    Int(i) => self.signed_result(expr_ty, -i),
+                    Float(f) => self.float_result(expr_ty, -f),
+                    _ => None,
+                },
+                UnOp::Complement => match self.eval(arg)? {
+                    Int(i) => int_result(!i),
+                    _ => None,
+                },
+                _ => None,
+            },
+
+            CExprKind::Binary(_, op, lhs, rhs, _, _) => self.eval_binary(expr_ty, op, lhs, rhs),
+
+            CExprKind::ImplicitCast(_, arg, kind, _) |
+            CExprKind::ExplicitCast(_, arg, kind, _) => self.eval_cast(expr_ty, arg, kind),
+
+            CExprKind::Conditional(_, cond, then, els) => {
+                if self.eval(cond)?.is_true() { self.eval(then) } else { self.eval(els) }
+            }
+            CExprKind::BinaryConditional(_, cond, els) => {
+                let cond = self.eval(cond)?;
+                if cond.is_true() { Some(cond) } else { self.eval(els) }
+            }
+
+            _ => None,
+        }
+    }
+
+    /// The result of signed arithmetic, unless it overflows
+    fn signed_result(&self, ty: CTypeId, value: i128) -> Option<ConstValue> {
+        let format = int_format(self.context, ty)?;
+        if format.1 && !fits(value, format) {
+            None
+        } else {
+            Some(ConstValue::Int(wrap(value, format)))
+        }
+    }
+
+    fn float_result(&self, ty: CTypeId, value: f64) -> Option<ConstValue> {
+        match self.context.resolve_type(ty).kind {
+            CTypeKind::Float => Some(ConstValue::Float(value as f32 as f64)),
+            CTypeKind::Double => Some(ConstValue::Float(value)),
+            _ => None,
+        }
+    }
+
+    fn eval_binary(&self, ty: CTypeId, op: BinOp, lhs: CExprId, rhs: CExprId) -> Option<ConstValue> {
+        use self::ConstValue::*;
+
+        let bool_result = |b: bool| Some(Int(b as i128));
+
+        // These don't evaluate their right operand when the left one decides the result
+        match op {
+            BinOp::And => return bool_result(self.eval(lhs)?.is_true() && self.eval(rhs)?.is_true()),
+            BinOp::Or => return bool_result(self.eval(lhs)?.is_true() || self.eval(rhs)?.is_true()),
+            _ => {}
+        }
+
+        match (op, self.eval(lhs)?, self.eval(rhs)?) {
+            (BinOp::Add, Address(a), Int(i)) => self.offset_address(a, lhs, i).map(Address),
+            (BinOp::Add, Int(i), Address(a)) => self.offset_address(a, rhs, i).map(Address),
+            (BinOp::Subtract, Address(a), Int(i)) => self.offset_address(a, lhs, -i).map(Address),
+            (BinOp::Subtract, Address(a), Address(b)) => {
+                // Pointers into the same array
+                let (&a_last, a_path) = a.path.split_last()?;
+                let (&b_last, b_path) = b.path.split_last()?;
+                match (a_last, b_last) {
+                    (Projection::Index(i, _), Projection::Index(j, _))
+                    if a.base == b.base && a_path == b_path => self.signed_result(ty, i - j),
+                    _ => None,
+                }
+            }
+
+            (op, Int(a), Int(b)) => {
+                let signed = int_format(self.context, ty)?.1;
+                let bits = int_format(self.context, self.context[lhs].kind.get_type()?)?.0;
+                match op {
+                    BinOp::Add => self.signed_result(ty, a + b),
+                    BinOp::Subtract => self.signed_result(ty, a - b),
+                    BinOp::Multiply => self.signed_result(ty, a.checked_mul(b)?),
+                    BinOp::Divide if b != 0 => self.signed_result(ty, a / b),
+                    BinOp::Modulus if b != 0 => self.signed_result(ty, a % b),
+                    BinOp::ShiftLeft if b >= 0 && b < bits as i128 && (a >= 0 || !signed) =>
+                        self.signed_result(ty, a << b),
+                    BinOp::ShiftRight if b >= 0 && b < bits as i128 => self.signed_result(ty, a >> b),
+                    BinOp::BitAnd => self.signed_result(ty, a & b),
+                    BinOp::BitOr => self.signed_result(ty, a | b),
+                    BinOp::BitXor => self.signed_result(ty, a ^ b),
+                    BinOp::Less => bool_result(a < b),
+                    BinOp::Greater => bool_result(a > b),
+                    BinOp::LessEqual => bool_result(a <= b),
+                    BinOp::GreaterEqual => bool_result(a >= b),
+                    BinOp::EqualEqual => bool_result(a == b),
+                    BinOp::NotEqual => bool_result(a != b),
+                    _ => None,
+                }
+            }
+
+            (op, Float(a), Float(b)) => match op {
+                BinOp::Add => self.float_result(ty, a + b),
+                BinOp::Subtract => self.float_result(ty, a - b),
+                BinOp::Multiply => self.float_result(ty, a * b),
+                BinOp::Divide => self.float_result(ty, a / b),
+                BinOp::Less => bool_result(a < b),
+                BinOp::Greater => bool_result(a > b),
+                BinOp::LessEqual => bool_result(a <= b),
+                BinOp::GreaterEqual => bool_result(a >= b),
+                BinOp::EqualEqual => bool_result(a == b),
+                BinOp::NotEqual => bool_result(a != b),
+                _ => None,
+            },
+
+            (BinOp::EqualEqual, Null, Null) => bool_result(true),
+            (BinOp::NotEqual, Null, Null) => bool_result(false),
+
+            _ => None,
+        }
+    }
+
+    fn eval_cast(&self, ty: CTypeId, arg: CExprId, kind: CastKind) -> Option<ConstValue> {
+        use self::ConstValue::*;
+
+        match (kind, self.eval_cast_operand(arg, kind)?) {
+            (CastKind::NoOp, v) | (CastKind::ConstCast, v) |
+            (CastKind::ArrayToPointerDecay, v) => Some(v),
+
+            // Pointers keep pointing at the same object, whatever its type
+            (CastKind::BitCast, v @ Address(_)) | (CastKind::BitCast, v @ Null) => Some(v),
+
+            (CastKind::NullToPointer, _) => Some(Null),
+            (CastKind::IntegralToPointer, Int(0)) => Some(Null),
+            (CastKind::PointerToIntegral, Null) => Some(Int(0)),
+
+            (CastKind::IntegralCast, Int(i)) => Some(Int(wrap(i, int_format(self.context, ty)?))),
+            (CastKind::IntegralToBoolean, v) |
+            (CastKind::FloatingToBoolean, v) |
+            (CastKind::PointerToBoolean, v) => Some(Int(v.is_true() as i128)),
+            (CastKind::BooleanToSignedIntegral, Int(i)) => Some(Int(-i)),
+
+            (CastKind::IntegralToFloating, Int(i)) => self.float_result(ty, i as f64),
+            (CastKind::FloatingCast, Float(f)) => self.float_result(ty, f),
+            (CastKind::FloatingToIntegral, Float(f)) => {
+                let format = int_format(self.context, ty)?;
+                let truncated = f.trunc();
+                if truncated.is_finite() && truncated.abs() < 2f64.powi(64) && fits(truncated as i128, format) {
+                    Some(Int(truncated as i128))
+                } else {
+                    None
+                }
+            }
+
+            _ => None,
+        }
+    }
+
+    /// The operand of a cast, which is an lvalue for array decay
+    fn eval_cast_operand(&self, arg: CExprId, kind: CastKind) -> Option<ConstValue> {
+        if let CastKind::ArrayToPointerDecay = kind {
+            let mut array = self.eval_lvalue(arg)?;
+            let (elt, len) = match self.context.resolve_type(array.ty).kind {
+                CTypeKind::ConstantArray(elt, len) => (elt, Some(len)),
+                CTypeKind::IncompleteArray(elt) => (elt, None),
+                _ => return None,
+            };
+            array.path.push(Projection::Index(0, len));
+            array.ty = elt;
+            return Some(ConstValue::Address(array))
+        }
+        self.eval(arg)
+    }
+
+    /// Move a pointer `offset` elements along the array it points into. `pointer` is the
+    /// expression it came from, giving the type of the elements.
+    fn offset_address(&self, mut address: ConstAddress, pointer: CExprId, offset: i128) -> Option<ConstAddress> {
+        if offset == 0 {
+            return Some(address)
+        }
+        let pointee = match self.context.resolve_type(self.context[pointer].kind.get_type()?).kind {
+            CTypeKind::Pointer(pointee) => pointee.ctype,
+            _ => return None,
+        };
+        if self.context.resolve_type_id(pointee) != self.context.resolve_type_id(address.ty) {
+            return None
+        }
+        match address.path.last_mut() {
+            Some(&mut Projection::Index(ref mut index, len)) => {
+                let moved = *index + offset;
+                // Pointing one past the end of the array is fine
+                if moved < 0 || len.map_or(false, |len| moved > len as i128) {
+                    return None
+                }
+                *index = moved;
+            }
+            _ => return None,
+        }
+        Some(address)
+    }
+
+    /// The location of the object an lvalue expression designates
+    pub fn eval_lvalue(&self, expr_id: CExprId) -> Option<ConstAddress> {
+        let expr_ty = self.context[expr_id].kind.get_type()?;
+        match self.context[expr_id].kind {
+            CExprKind::DeclRef(_, decl_id) => match self.context[decl_id].kind {
+                CDeclKind::Variable { is_static, is_extern, typ, .. } if is_static || is_extern =>
+                    Some(ConstAddress { base: AddressBase::Variable(decl_id), path: vec![], ty: typ.ctype }),
+                _ => None,
+            },
+
+            CExprKind::Literal(_, CLiteral::String(_, 1)) =>
+                Some(ConstAddress { base: AddressBase::StringLiteral(expr_id), path: vec![], ty: expr_ty }),
+
+            CExprKind::Member(_, base, field_id, kind) => {
+                let mut record = match kind {
+                    MemberKind::Dot => self.eval_lvalue(base)?,
+                    MemberKind::Arrow => match self.eval(base)? {
+                        ConstValue::Address(address) => address,
+                        _ => return None,
+                    },
+                };
+                let record_id = self.context.resolve_type(record.ty).kind.as_underlying_decl()?;
+                if self.context.parents.get(&field_id) != Some(&record_id) {
+                    return None
+                }
+                record.path.push(Projection::Field(record_id, field_id));
+                record.ty = expr_ty;
+                Some(record)
+            }
+
+            CExprKind::ArraySubscript(_, lhs, rhs) => {
+                match (self.eval(lhs)?, self.eval(rhs)?) {
+                    (ConstValue::Address(a), ConstValue::Int(i)) => self.offset_address(a, lhs, i),
+                    (ConstValue::Int(i), ConstValue::Address(a)) => self.offset_address(a, rhs, i),
+                    _ => None,
+                }
+            }
+
+            CExprKind::Unary(_, UnOp::Deref, pointer) => match self.eval(pointer)? {
+                ConstValue::Address(address) => Some(address),
+                _ => None,
+            },
+
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap(-1, (32, false)), 0xffff_ffff);
+        assert_eq!(wrap(0x1_0000_0001, (32, false)), 1);
+        assert_eq!(wrap(0x80, (8, true)), -0x80);
+        assert_eq!(wrap(5, (1, false)), 1);
+        assert!(fits(-0x80, (8, true)));
+        assert!(!fits(0x80, (8, true)));
+        assert!(!fits(-1, (16, false)));
+    }
+
+    #[test]
+    fn memoized() {
+        let mut context = TypedAstContext::new();
+        context.c_types.insert(CTypeId(1), Located { loc: None, kind: CTypeKind::Int });
+        let int = CQualTypeId { qualifiers: Qualifiers::default(), ctype: CTypeId(1) };
+        let one = CExprKind::Literal(int, CLiteral::Integer(1, IntBase::Dec));
+        context.c_exprs.insert(CExprId(0), Located { loc: None, kind: one });
+
+        // 1 + 1 + ... + 1, nested deeply enough that evaluating each node from scratch shows
+        for i in 1..2000 {
+            let sum = CExprKind::Binary(int, BinOp::Add, CExprId(i - 1), CExprId(0), None, None);
+            context.c_exprs.insert(CExprId(i), Located { loc: None, kind: sum });
+        }
+
+        let mut values = HashMap::new();
+        for i in 0..2000 {
+            let evaluator = ConstEvaluator::with_values(&context, values);
+            assert_eq!(evaluator.eval(CExprId(i)), Some(ConstValue::Int(i as i128 + 1)));
+            values = evaluator.into_values();
+        }
+        assert_eq!(values.len(), 2000);
+    }
+}
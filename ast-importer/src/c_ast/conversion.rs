@@ -111,8 +111,18 @@ fn not_located<T>(t: T) -> Located<T> {
     }
 }
 
-fn parse_cast_kind(kind: &str) -> CastKind {
-    match kind {
+/// The `index`th extra field of a node
+fn extra(extras: &[Value], index: usize) -> Result<&Value, String> {
+    extras.get(index).ok_or_else(|| format!("Expected at least {} extra fields", index + 1))
+}
+
+/// The `index`th child of a node, which is `None` for an absent optional child
+fn child(children: &[Option<u64>], index: usize) -> Result<Option<u64>, String> {
+    children.get(index).cloned().ok_or_else(|| format!("Expected at least {} children", index + 1))
+}
+
+fn parse_cast_kind(kind: &str) -> Result<CastKind, String> {
+    let kind = match kind {
         "BitCast" => CastKind::BitCast,
         "LValueToRValue" => CastKind::LValueToRValue,
         "NoOp" => CastKind::NoOp,
@@ -142,8 +152,9 @@ fn parse_cast_kind(kind: &str) -> CastKind {
         "IntegralComplexToFloatingComplex" => CastKind::IntegralComplexToFloatingComplex,
         "BuiltinFnToFnPtr" => CastKind::BuiltinFnToFnPtr,
         "ConstCast" => CastKind::ConstCast,
-        k => panic!("Unsupported implicit cast: {}", k),
-    }
+        k => return Err(format!("Unsupported implicit cast: {}", k)),
+    };
+    Ok(kind)
 }
 
 /// This stores the information needed to convert an `AstContext` into a `TypedAstContext`.
@@ -285,8 +296,12 @@ impl ConversionContext {
     /// Convert the contents of an `AstContext`, starting from the top-level declarations passed
     /// into the `ConversionContext` on creation.
     ///
-    /// This populates the `typed_context` of the `ConversionContext` it is called on.
-    pub fn convert(&mut self, untyped_context: &AstContext) -> () {
+    /// This populates the `typed_context` of the `ConversionContext` it is called on. Nodes which
+    /// are malformed (or not of the kind expected where they are used) make this fail.
+    pub fn convert(&mut self, untyped_context: &AstContext) -> Result<(), ImportError> {
+
+        self.typed_context.target = untyped_context.target;
+        self.typed_context.conditionals = untyped_context.conditionals.clone();
 
         for raw_comment in &untyped_context.comments {
             let comment = Located {
@@ -309,7 +324,8 @@ impl ConversionContext {
                 if ty & expected_ty != 0 {
                     continue;
                 }
-                panic!("Expected {} to be a node of type {}, not {}", &node_id, expected_ty, ty);
+                let problem = format!("Expected a node of type {}, not {}", expected_ty, ty);
+                return Err(node_error(untyped_context, node_id, expected_ty, problem));
             }
 
             // Create a `NewId` for this node
@@ -321,7 +337,10 @@ impl ConversionContext {
             }
 
             self.visit_node(untyped_context, node_id, new_id, expected_ty)
+                .map_err(|problem| node_error(untyped_context, node_id, expected_ty, problem))?
         }
+
+        Ok(())
     }
 
 
@@ -332,7 +351,7 @@ impl ConversionContext {
         node_id: ClangId,                 // Clang ID of node to visit
         new_id: ImporterId,                    // New ID of node to visit
         expected_ty: NodeType             // Expected type of node to visit
-    ) -> () {
+    ) -> Result<(), String> {
         use self::node_types::*;
 
         if expected_ty & TYPE != 0 {
@@ -340,7 +359,7 @@ impl ConversionContext {
             // Convert the node
             let ty_node: &TypeNode = match untyped_context.type_nodes.get(&node_id) {
                 Some(x) => x,
-                None => return,
+                None => return Ok(()),
             };
 
             match ty_node.tag {
@@ -440,8 +459,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagPointer if expected_ty & OTHER_TYPE != 0 => {
-                    let pointed = ty_node.extras[0].as_u64()
-                        .expect("Pointer child not found");
+                    let pointed = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Pointer child not found")?;
                     let pointed_new = self.visit_qualified_type( pointed);
 
                     let pointer_ty = CTypeKind::Pointer(pointed_new);
@@ -450,8 +469,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagBlockPointer if expected_ty & OTHER_TYPE != 0 => {
-                    let pointed = ty_node.extras[0].as_u64()
-                        .expect("Block pointer child not found");
+                    let pointed = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Block pointer child not found")?;
                     let pointed_new = self.visit_qualified_type( pointed);
 
                     let pointer_ty = CTypeKind::BlockPointer(pointed_new);
@@ -460,8 +479,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagComplexType if expected_ty & OTHER_TYPE != 0 => {
-                    let subelt = ty_node.extras[0].as_u64()
-                        .expect("Complex child not found");
+                    let subelt = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Complex child not found")?;
                     let subelt_new = self.visit_type(subelt);
 
                     let complex_ty = CTypeKind::Complex(subelt_new);
@@ -470,8 +489,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagStructType if expected_ty & OTHER_TYPE != 0 => {
-                    let decl = ty_node.extras[0].as_u64()
-                        .expect("Struct decl not found");
+                    let decl = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Struct decl not found")?;
                     let decl_new = CDeclId(self.visit_node_type(decl, RECORD_DECL));
 
                     let record_ty = CTypeKind::Struct(decl_new);
@@ -480,8 +499,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagUnionType if expected_ty & OTHER_TYPE != 0 => {
-                    let decl = ty_node.extras[0].as_u64()
-                        .expect("Union decl not found");
+                    let decl = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Union decl not found")?;
                     let decl_new = CDeclId(self.visit_node_type(decl, RECORD_DECL));
 
                     let record_ty = CTypeKind::Union(decl_new);
@@ -490,26 +509,29 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagFunctionType if expected_ty & FUNC_TYPE != 0 => {
-                    let mut arguments: Vec<CQualTypeId> = ty_node.extras[0].as_array()
-                        .expect("Function type expects array argument")
+                    let mut arguments: Vec<CQualTypeId> = extra(&ty_node.extras, 0)?.as_array()
+                        .ok_or("Function type expects array argument")?
                         .iter()
                         .map(|cbor| {
-                            let arg = cbor.as_u64().expect("Bad function type child id");
+                            let arg = cbor.as_u64().ok_or("Bad function type child id")?;
                             let arg_new = self.visit_qualified_type(arg);
 
-                            arg_new
+                            Ok(arg_new)
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
+                    if arguments.is_empty() {
+                        return Err(format!("Function type has no return type"));
+                    }
                     let ret = arguments.remove(0);
-                    let is_variadic = ty_node.extras[1].as_boolean().expect("Variadicity of function type not found");
-                    let is_noreturn = ty_node.extras[2].as_boolean().expect("NoReturn of function type not found");
+                    let is_variadic = extra(&ty_node.extras, 1)?.as_boolean().ok_or("Variadicity of function type not found")?;
+                    let is_noreturn = extra(&ty_node.extras, 2)?.as_boolean().ok_or("NoReturn of function type not found")?;
                     let function_ty = CTypeKind::Function(ret, arguments, is_variadic, is_noreturn);
                     self.add_type(new_id, not_located(function_ty));
                     self.processed_nodes.insert(new_id, FUNC_TYPE);
                 }
 
                 TypeTag::TagTypeOfType if expected_ty & TYPE != 0 => {
-                    let type_of_old = ty_node.extras[0].as_u64().expect("Type of (type) child not found");
+                    let type_of_old = extra(&ty_node.extras, 0)?.as_u64().ok_or("Type of (type) child not found")?;
                     let type_of = self.visit_type(type_of_old);
 
                     let type_of_ty = CTypeKind::TypeOf(type_of);
@@ -518,8 +540,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagTypedefType => {
-                    let decl = ty_node.extras[0].as_u64()
-                        .expect("Typedef decl not found");
+                    let decl = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Typedef decl not found")?;
                     let decl_new = CDeclId(self.visit_node_type(decl, TYPDEF_DECL));
 
                     let typedef_ty = CTypeKind::Typedef(decl_new);
@@ -528,8 +550,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagEnumType if expected_ty & OTHER_TYPE != 0 => {
-                    let decl = ty_node.extras[0].as_u64()
-                        .expect("Enum decl not found");
+                    let decl = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Enum decl not found")?;
                     let decl_new = CDeclId(self.visit_node_type(decl, ENUM_DECL));
 
                     let enum_ty = CTypeKind::Enum(decl_new);
@@ -538,7 +560,7 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagDecayedType if expected_ty & OTHER_TYPE != 0 => {
-                    let decayed_id = ty_node.extras[0].as_u64().expect("Decayed type child not found");
+                    let decayed_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("Decayed type child not found")?;
                     let decayed = self.visit_type(decayed_id);
 
                     let decayed_ty = CTypeKind::Decayed(decayed);
@@ -547,7 +569,7 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagElaboratedType if expected_ty & OTHER_TYPE != 0 => {
-                    let elaborated_id = ty_node.extras[0].as_u64().expect("Elaborated type child not found");
+                    let elaborated_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("Elaborated type child not found")?;
                     let elaborated = self.visit_type(elaborated_id);
 
                     let elaborated_ty = CTypeKind::Elaborated(elaborated);
@@ -556,7 +578,7 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagParenType => {
-                    let paren_id = ty_node.extras[0].as_u64().expect("Paren type child not found");
+                    let paren_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("Paren type child not found")?;
                     let paren = self.visit_type(paren_id);
 
                     let paren_ty = CTypeKind::Paren(paren);
@@ -565,16 +587,16 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagAttributedType => {
-                    let ty_id = ty_node.extras[0].as_u64().expect("Attributed type child not found");
+                    let ty_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("Attributed type child not found")?;
                     let ty = self.visit_qualified_type(ty_id);
 
-                    let kind = match expect_opt_str(&ty_node.extras[1]).expect("Attributed type kind not found")
+                    let kind = match expect_opt_str(extra(&ty_node.extras, 1)?).ok_or("Attributed type kind not found")?
                         {
                             None => None,
                             Some("noreturn") => Some(Attribute::NoReturn),
                             Some("nullable") => Some(Attribute::Nullable),
                             Some("notnull") => Some(Attribute::NotNull),
-                            Some(other) => panic!("Unknown type attribute: {}", other),
+                            Some(other) => return Err(format!("Unknown type attribute: {}", other)),
                         };
 
                     let ty = CTypeKind::Attributed(ty, kind);
@@ -583,10 +605,10 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagConstantArrayType => {
-                    let element_id = ty_node.extras[0].as_u64().expect("element id");
+                    let element_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("element id")?;
                     let element = self.visit_type(element_id);
 
-                    let count = ty_node.extras[1].as_u64().expect("count");
+                    let count = extra(&ty_node.extras, 1)?.as_u64().ok_or("count")?;
 
                     let element_ty = CTypeKind::ConstantArray(element, count as usize);
                     self.add_type(new_id, not_located(element_ty));
@@ -594,7 +616,7 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagIncompleteArrayType => {
-                    let element_id = ty_node.extras[0].as_u64().expect("element id");
+                    let element_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("element id")?;
                     let element = self.visit_type(element_id);
 
                     let element_ty = CTypeKind::IncompleteArray(element);
@@ -603,10 +625,10 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagVariableArrayType => {
-                    let element_id = ty_node.extras[0].as_u64().expect("element id");
+                    let element_id = extra(&ty_node.extras, 0)?.as_u64().ok_or("element id")?;
                     let element = self.visit_type(element_id);
 
-                    let count_id = expect_opt_u64(&ty_node.extras[1]).expect("count id");
+                    let count_id = expect_opt_u64(extra(&ty_node.extras, 1)?).ok_or("count id")?;
                     let count = count_id.map(|x| self.visit_expr(x));
 
                     let element_ty = CTypeKind::VariableArray(element, count);
@@ -621,8 +643,8 @@ impl ConversionContext {
                 }
 
                 TypeTag::TagVectorType => {
-                    let elt = ty_node.extras[0].as_u64()
-                        .expect("Vector child not found");
+                    let elt = extra(&ty_node.extras, 0)?.as_u64()
+                        .ok_or("Vector child not found")?;
                     let elt_new = self.visit_qualified_type(elt);
 
                     let vector_ty = CTypeKind::Vector(elt_new);
@@ -630,14 +652,14 @@ impl ConversionContext {
                     self.processed_nodes.insert(new_id, OTHER_TYPE);
                 }
 
-                t => panic!("Type conversion not implemented for {:?} expecting {:?}", t, expected_ty),
+                t => return Err(format!("Type conversion not implemented for {:?} expecting {:?}", t, expected_ty)),
             }
 
         } else {
             // Convert the node
             let node: &AstNode = match untyped_context.ast_nodes.get(&node_id) {
                 Some(x) => x,
-                None => return,
+                None => return Ok(()),
             };
 
 
@@ -658,10 +680,10 @@ impl ConversionContext {
                     let constituent_stmts: Vec<CStmtId> = node.children
                         .iter()
                         .map(|id| {
-                            let arg_id = id.expect("Compound stmt child not found");
-                            self.visit_stmt(arg_id)
+                            let arg_id = id.ok_or("Compound stmt child not found")?;
+                            Ok(self.visit_stmt(arg_id))
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
                     let compound_stmt = CStmtKind::Compound(constituent_stmts);
 
@@ -673,10 +695,10 @@ impl ConversionContext {
                     let decls = node.children
                         .iter()
                         .map(|decl| {
-                            let decl_id = decl.expect("Decl not found in decl-statement");
-                            self.visit_decl(decl_id)
+                            let decl_id = decl.ok_or("Decl not found in decl-statement")?;
+                            Ok(self.visit_decl(decl_id))
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
                     let decls_stmt = CStmtKind::Decls(decls);
 
@@ -685,7 +707,7 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagReturnStmt if expected_ty & OTHER_STMT != 0 => {
-                    let return_expr_opt = node.children[0]
+                    let return_expr_opt = child(&node.children, 0)?
                         .map(|id| self.visit_expr(id));
 
                     let return_stmt = CStmtKind::Return(return_expr_opt);
@@ -695,13 +717,13 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagIfStmt if expected_ty & OTHER_STMT != 0 => {
-                    let scrutinee_old = node.children[0].expect("If condition expression not found");
+                    let scrutinee_old = child(&node.children, 0)?.ok_or("If condition expression not found")?;
                     let scrutinee = self.visit_expr(scrutinee_old);
 
-                    let true_variant_old = node.children[1].expect("If then body statement not found");
+                    let true_variant_old = child(&node.children, 1)?.ok_or("If then body statement not found")?;
                     let true_variant = self.visit_stmt(true_variant_old);
 
-                    let false_variant = node.children[2]
+                    let false_variant = child(&node.children, 2)?
                         .map(|id| self.visit_stmt(id));
 
                     let if_stmt = CStmtKind::If { scrutinee, true_variant, false_variant };
@@ -711,7 +733,7 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagGotoStmt if expected_ty & OTHER_STMT != 0 => {
-                    let target_label_old = node.children[0].expect("Goto target label not found");
+                    let target_label_old = child(&node.children, 0)?.ok_or("Goto target label not found")?;
                     let target_label = CStmtId(self.visit_node_type(target_label_old, LABEL_STMT));
 
                     let goto_stmt = CStmtKind::Goto(target_label);
@@ -728,13 +750,13 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagForStmt if expected_ty & OTHER_STMT != 0 => {
-                    let init = node.children[0].map(|id| self.visit_stmt(id));
+                    let init = child(&node.children, 0)?.map(|id| self.visit_stmt(id));
 
-                    let condition = node.children[1].map(|id| self.visit_expr(id));
+                    let condition = child(&node.children, 1)?.map(|id| self.visit_expr(id));
 
-                    let increment = node.children[2].map(|id| self.visit_expr(id));
+                    let increment = child(&node.children, 2)?.map(|id| self.visit_expr(id));
 
-                    let body_old = node.children[3].expect("For loop body not found");
+                    let body_old = child(&node.children, 3)?.ok_or("For loop body not found")?;
                     let body = self.visit_stmt(body_old);
 
                     let for_stmt = CStmtKind::ForLoop { init, condition, increment, body };
@@ -744,10 +766,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagWhileStmt if expected_ty & OTHER_STMT != 0 => {
-                    let condition_old = node.children[0].expect("While loop condition not found");
+                    let condition_old = child(&node.children, 0)?.ok_or("While loop condition not found")?;
                     let condition = self.visit_expr(condition_old);
 
-                    let body_old = node.children[1].expect("While loop body not found");
+                    let body_old = child(&node.children, 1)?.ok_or("While loop body not found")?;
                     let body = self.visit_stmt(body_old);
 
                     let while_stmt = CStmtKind::While { condition, body };
@@ -758,10 +780,10 @@ impl ConversionContext {
 
                 ASTEntryTag::TagDoStmt if expected_ty & OTHER_STMT != 0 => {
 
-                    let body_old = node.children[0].expect("Do loop body not found");
+                    let body_old = child(&node.children, 0)?.ok_or("Do loop body not found")?;
                     let body = self.visit_stmt(body_old);
 
-                    let condition_old = node.children[1].expect("Do loop condition not found");
+                    let condition_old = child(&node.children, 1)?.ok_or("Do loop condition not found")?;
                     let condition = self.visit_expr(condition_old);
 
                     let do_stmt = CStmtKind::DoWhile { body, condition };
@@ -771,7 +793,7 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagLabelStmt if expected_ty & LABEL_STMT != 0 => {
-                    let substmt_old = node.children[0].expect("Label sub-statement not found");
+                    let substmt_old = child(&node.children, 0)?.ok_or("Label sub-statement not found")?;
                     let substmt = self.visit_stmt(substmt_old);
 
                     let label_stmt = CStmtKind::Label(substmt);
@@ -781,10 +803,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagSwitchStmt if expected_ty & OTHER_STMT != 0 => {
-                    let scrutinee_old = node.children[0].expect("Switch expression not found");
+                    let scrutinee_old = child(&node.children, 0)?.ok_or("Switch expression not found")?;
                     let scrutinee = self.visit_expr(scrutinee_old);
 
-                    let body_old = node.children[1].expect("Switch body not found");
+                    let body_old = child(&node.children, 1)?.ok_or("Switch body not found")?;
                     let body = self.visit_stmt(body_old);
 
                     let switch_stmt = CStmtKind::Switch { scrutinee, body };
@@ -794,16 +816,16 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagCaseStmt if expected_ty & OTHER_STMT != 0 => {
-                    let expr_old = node.children[0].expect("Case expression not found");
+                    let expr_old = child(&node.children, 0)?.ok_or("Case expression not found")?;
                     let expr = self.visit_expr(expr_old);
 
-                    let substmt_old = node.children[1].expect("Case sub-statement not found");
+                    let substmt_old = child(&node.children, 1)?.ok_or("Case sub-statement not found")?;
                     let substmt = self.visit_stmt(substmt_old);
 
-                    let cie = match node.extras[0] {
-                        Value::U64(n) => ConstIntExpr::U(n),
-                        Value::I64(n) => ConstIntExpr::I(n),
-                        _ => panic!("Expected constant int expr"),
+                    let cie = match extra(&node.extras, 0)? {
+                        &Value::U64(n) => ConstIntExpr::U(n),
+                        &Value::I64(n) => ConstIntExpr::I(n),
+                        _ => return Err(format!("Expected constant int expr")),
                     };
 
                     let case_stmt = CStmtKind::Case(expr, substmt, cie);
@@ -813,7 +835,7 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagDefaultStmt if expected_ty & OTHER_STMT != 0 => {
-                    let substmt_old = node.children[0].expect("Default sub-statement not found");
+                    let substmt_old = child(&node.children, 0)?.ok_or("Default sub-statement not found")?;
                     let substmt = self.visit_stmt(substmt_old);
 
                     let default_stmt = CStmtKind::Default(substmt);
@@ -824,28 +846,33 @@ impl ConversionContext {
 
                 ASTEntryTag::TagAsmStmt if expected_ty & OTHER_STMT != 0 => {
 
-                    let is_volatile = node.extras[0].as_boolean().expect("volatile flag");
-                    let asm = node.extras[1].as_string().expect("assembly string").to_owned();
-                    let raw_inputs = node.extras[2].as_array().expect("input constraints array");
-                    let raw_outputs = node.extras[3].as_array().expect("output constraints array");
-                    let raw_clobbers = node.extras[4].as_array().expect("clobber array");
+                    let is_volatile = extra(&node.extras, 0)?.as_boolean().ok_or("volatile flag")?;
+                    let asm = extra(&node.extras, 1)?.as_string().ok_or("assembly string")?.to_owned();
+                    let raw_inputs = extra(&node.extras, 2)?.as_array().ok_or("input constraints array")?;
+                    let raw_outputs = extra(&node.extras, 3)?.as_array().ok_or("output constraints array")?;
+                    let raw_clobbers = extra(&node.extras, 4)?.as_array().ok_or("clobber array")?;
 
+                    if raw_inputs.len() + raw_outputs.len() != node.children.len() {
+                        return Err(format!("Expected an operand for each constraint"));
+                    }
                     let (input_children, output_children) = node.children.split_at(raw_inputs.len());
 
                     let inputs: Vec<AsmOperand> = raw_inputs.iter().zip(input_children).map(|(c,e)| {
-                        let constraints = c.as_string().expect("constraint string").to_owned();
-                        let expression = self.visit_expr(e.expect("expression"));
-                        AsmOperand { constraints, expression }
-                    }).collect();
+                        let constraints = c.as_string().ok_or("constraint string")?.to_owned();
+                        let expression = self.visit_expr(e.ok_or("expression")?);
+                        Ok(AsmOperand { constraints, expression })
+                    }).collect::<Result<_, String>>()?;
 
                     let outputs: Vec<AsmOperand> = raw_outputs.iter().zip(output_children).map(|(c,e)| {
-                        let constraints = c.as_string().expect("constraint string").to_owned();
-                        let expression = self.visit_expr(e.expect("expression"));
-                        AsmOperand { constraints, expression }
-                    }).collect();
+                        let constraints = c.as_string().ok_or("constraint string")?.to_owned();
+                        let expression = self.visit_expr(e.ok_or("expression")?);
+                        Ok(AsmOperand { constraints, expression })
+                    }).collect::<Result<_, String>>()?;
 
-                    let clobbers: Vec<String> =
-                         raw_clobbers.iter().map(|c| c.as_string().expect("clobber string").to_owned()).collect();
+                    let clobbers: Vec<String> = raw_clobbers
+                        .iter()
+                        .map(|c| c.as_string().map(String::to_owned).ok_or("clobber string"))
+                        .collect::<Result<_, _>>()?;
 
                     let stmt = CStmtKind::Asm { is_volatile, asm, inputs, outputs, clobbers };
                     self.add_stmt(new_id, located(node, stmt));
@@ -855,16 +882,16 @@ impl ConversionContext {
                 // Expressions
 
                 ASTEntryTag::TagParenExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let wrapped = node.children[0].expect("Expected wrapped paren expression");
+                    let wrapped = child(&node.children, 0)?.ok_or("Expected wrapped paren expression")?;
 
                     self.id_mapper.merge_old(node_id, wrapped);
                     self.visit_node_type(wrapped, expected_ty);
                 }
 
                 ASTEntryTag::TagOffsetOfExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let value = node.extras[0].as_u64().expect("Expected offset value");
+                    let value = extra(&node.extras, 0)?.as_u64().ok_or("Expected offset value")?;
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let offset_of = CExprKind::OffsetOf(ty, value);
@@ -873,17 +900,17 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagIntegerLiteral if expected_ty & (EXPR | STMT) != 0 => {
-                    let value = node.extras[0].as_u64().expect("Expected integer literal value");
-                    let base = node.extras[1].as_u64().expect("Expected integer base value");
+                    let value = extra(&node.extras, 0)?.as_u64().ok_or("Expected integer literal value")?;
+                    let base = extra(&node.extras, 1)?.as_u64().ok_or("Expected integer base value")?;
 
                     let base = match base {
                         8  => IntBase::Oct,
                         10 => IntBase::Dec,
                         16 => IntBase::Hex,
-                        _  => panic!("Invalid base: {}", base),
+                        _  => return Err(format!("Invalid base: {}", base)),
                     };
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let integer_literal = CExprKind::Literal(ty, CLiteral::Integer(value, base));
@@ -892,18 +919,18 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagStringLiteral if expected_ty & (EXPR | STMT) != 0 => {
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
-                    let width = node.extras[1].as_u64().expect("string literal char width") as u8;
-                    let bytes = node.extras[2].as_bytes().expect("string literal bytes").to_owned();
+                    let width = extra(&node.extras, 1)?.as_u64().ok_or("string literal char width")? as u8;
+                    let bytes = extra(&node.extras, 2)?.as_bytes().ok_or("string literal bytes")?.to_owned();
                     let string_literal = CExprKind::Literal(ty, CLiteral::String(bytes, width));
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, string_literal);
                 }
 
                 ASTEntryTag::TagCharacterLiteral if expected_ty & (EXPR | STMT) != 0 => {
-                    let value = node.extras[0].as_u64().expect("Expected character literal value");
+                    let value = extra(&node.extras, 0)?.as_u64().ok_or("Expected character literal value")?;
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let character_literal = CExprKind::Literal(ty, CLiteral::Character(value));
@@ -912,9 +939,9 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagFloatingLiteral if expected_ty & (EXPR | STMT) != 0 => {
-                    let value = node.extras[0].as_f64().expect("Expected float literal value");
+                    let value = extra(&node.extras, 0)?.as_f64().ok_or("Expected float literal value")?;
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let floating_literal = CExprKind::Literal(ty, CLiteral::Floating(value));
@@ -924,9 +951,9 @@ impl ConversionContext {
 
                 ASTEntryTag::TagUnaryOperator if expected_ty & (EXPR | STMT) != 0 => {
 
-                    let prefix = node.extras[1].as_boolean().expect("Expected prefix information");
+                    let prefix = extra(&node.extras, 1)?.as_boolean().ok_or("Expected prefix information")?;
 
-                    let operator = match node.extras[0].as_string().expect("Expected operator").as_str() {
+                    let operator = match extra(&node.extras, 0)?.as_string().ok_or("Expected operator")?.as_str() {
                         "&" => UnOp::AddressOf,
                         "*" => UnOp::Deref,
                         "+" => UnOp::Plus,
@@ -939,13 +966,13 @@ impl ConversionContext {
                         "__imag" => UnOp::Imag,
                         "__extension__" => UnOp::Extension,
                         "co_await" => UnOp::Coawait,
-                        o => panic!("Unexpected operator: {}", o),
+                        o => return Err(format!("Unexpected operator: {}", o)),
                     };
 
-                    let operand_old = node.children[0].expect("Expected operand");
+                    let operand_old = child(&node.children, 0)?.ok_or("Expected operand")?;
                     let operand = self.visit_expr(operand_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
 
@@ -955,32 +982,32 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagImplicitCastExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let expression_old = node.children[0].expect("Expected expression for implicit cast");
+                    let expression_old = child(&node.children, 0)?.ok_or("Expected expression for implicit cast")?;
                     let expression = self.visit_expr(expression_old);
 
-                    let typ_old = node.type_id.expect("Expected type for implicit cast");
+                    let typ_old = node.type_id.ok_or("Expected type for implicit cast")?;
                     let typ = self.visit_qualified_type(typ_old);
 
 
-                    let kind = parse_cast_kind(node.extras[0].as_string().expect("Expected cast kind"));
+                    let kind = parse_cast_kind(extra(&node.extras, 0)?.as_string().ok_or("Expected cast kind")?)?;
                     let implicit = CExprKind::ImplicitCast(typ, expression, kind, None);
 
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, implicit);
                 }
 
                 ASTEntryTag::TagCStyleCastExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let expression_old = node.children[0].expect("Expected expression for explicit cast");
+                    let expression_old = child(&node.children, 0)?.ok_or("Expected expression for explicit cast")?;
                     let expression = self.visit_expr(expression_old);
 
-                    let typ_old = node.type_id.expect("Expected type for explicit cast");
+                    let typ_old = node.type_id.ok_or("Expected type for explicit cast")?;
                     let typ = self.visit_qualified_type(typ_old);
 
 
-                    let kind = parse_cast_kind(node.extras[0].as_string().expect("Expected cast kind"));
+                    let kind = parse_cast_kind(extra(&node.extras, 0)?.as_string().ok_or("Expected cast kind")?)?;
 
                     let opt_field_id = match kind {
                         CastKind::ToUnion => {
-                            let id = node.children[1].expect("Expected field for union cast");
+                            let id = child(&node.children, 1)?.ok_or("Expected field for union cast")?;
                             Some(self.visit_decl(id))
                         }
                         _ => None,
@@ -992,19 +1019,19 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagCallExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let func_old = node.children[0].expect("Expected function for function call");
+                    let func_old = child(&node.children, 0)?.ok_or("Expected function for function call")?;
                     let func = self.visit_expr(func_old);
 
                     let args: Vec<CExprId> = node.children
                         .iter()
                         .skip(1)
                         .map(|id| {
-                            let arg_id = id.expect("Expected call expression argument");
-                            self.visit_expr(arg_id)
+                            let arg_id = id.ok_or("Expected call expression argument")?;
+                            Ok(self.visit_expr(arg_id))
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let call = CExprKind::Call(ty, func, args);
@@ -1013,17 +1040,17 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagMemberExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let base_old = node.children[0].expect("Expected base for member expression");
+                    let base_old = child(&node.children, 0)?.ok_or("Expected base for member expression")?;
                     let base = self.visit_expr(base_old);
 
-                    let field_old = node.children[1].expect("Expected field for member expression");
+                    let field_old = child(&node.children, 1)?.ok_or("Expected field for member expression")?;
                     let field = self.visit_decl(field_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let member_kind =
-                        if node.extras[0].as_boolean().expect("is arrow")
+                        if extra(&node.extras, 0)?.as_boolean().ok_or("is arrow")?
                             { MemberKind::Arrow } else { MemberKind::Dot };
 
                     let member = CExprKind::Member(ty, base, field, member_kind);
@@ -1032,7 +1059,7 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagBinaryOperator if expected_ty & (EXPR | STMT) != 0 => {
-                    let operator = match node.extras[0].as_string().expect("Expected operator").as_str() {
+                    let operator = match extra(&node.extras, 0)?.as_string().ok_or("Expected operator")?.as_str() {
                         "*" => BinOp::Multiply,
                         "/" => BinOp::Divide,
                         "%" => BinOp::Modulus,
@@ -1066,19 +1093,19 @@ impl ConversionContext {
                         _ => unimplemented!(),
                     };
 
-                    let left_operand_old = node.children[0].expect("Expected left operand");
+                    let left_operand_old = child(&node.children, 0)?.ok_or("Expected left operand")?;
                     let left_operand = self.visit_expr(left_operand_old);
 
-                    let right_operand_old = node.children[1].expect("Expected right operand");
+                    let right_operand_old = child(&node.children, 1)?.ok_or("Expected right operand")?;
                     let right_operand = self.visit_expr(right_operand_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
-                    let opt_lhs_type_id = expect_opt_u64(&node.extras[1]).expect("Expected compute lhs type");
+                    let opt_lhs_type_id = expect_opt_u64(extra(&node.extras, 1)?).ok_or("Expected compute lhs type")?;
                     let opt_lhs_type = opt_lhs_type_id.map(|x| self.visit_qualified_type(x));
 
-                    let opt_res_type_id = expect_opt_u64(&node.extras[2]).expect("Expected compute lhs type");
+                    let opt_res_type_id = expect_opt_u64(extra(&node.extras, 2)?).ok_or("Expected compute lhs type")?;
                     let opt_res_type = opt_res_type_id.map(|x| self.visit_qualified_type(x));
 
                     let binary = CExprKind::Binary(ty, operator, left_operand, right_operand, opt_lhs_type, opt_res_type);
@@ -1087,10 +1114,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagDeclRefExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let declaration_old = node.children[0].expect("Expected declaration on expression tag decl");
+                    let declaration_old = child(&node.children, 0)?.ok_or("Expected declaration on expression tag decl")?;
                     let declaration = self.visit_decl(declaration_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let decl = CExprKind::DeclRef(ty, declaration);
@@ -1099,13 +1126,13 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagArraySubscriptExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let lhs_old = node.children[0].expect("Expected LHS on array subscript expression");
+                    let lhs_old = child(&node.children, 0)?.ok_or("Expected LHS on array subscript expression")?;
                     let lhs = self.visit_expr(lhs_old);
 
-                    let rhs_old = node.children[1].expect("Expected RHS on array subscript expression");
+                    let rhs_old = child(&node.children, 1)?.ok_or("Expected RHS on array subscript expression")?;
                     let rhs = self.visit_expr(rhs_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let subscript = CExprKind::ArraySubscript(ty, lhs, rhs);
@@ -1114,16 +1141,16 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagConditionalOperator if expected_ty & (EXPR | STMT) != 0 => {
-                    let cond_old = node.children[0].expect("Expected condition on if expression");
+                    let cond_old = child(&node.children, 0)?.ok_or("Expected condition on if expression")?;
                     let cond = self.visit_expr(cond_old);
 
-                    let lhs_old = node.children[1].expect("Expected 'then' on if expression");
+                    let lhs_old = child(&node.children, 1)?.ok_or("Expected 'then' on if expression")?;
                     let lhs = self.visit_expr(lhs_old);
 
-                    let rhs_old = node.children[2].expect("Expected 'else' on if expression");
+                    let rhs_old = child(&node.children, 2)?.ok_or("Expected 'else' on if expression")?;
                     let rhs = self.visit_expr(rhs_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let conditional = CExprKind::Conditional(ty, cond, lhs, rhs);
@@ -1132,13 +1159,13 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagBinaryConditionalOperator if expected_ty & (EXPR | STMT) != 0 => {
-                    let lhs_old = node.children[0].expect("Expected condition on if expression");
+                    let lhs_old = child(&node.children, 0)?.ok_or("Expected condition on if expression")?;
                     let lhs = self.visit_expr(lhs_old);
 
-                    let rhs_old = node.children[1].expect("Expected 'else' on if expression");
+                    let rhs_old = child(&node.children, 1)?.ok_or("Expected 'else' on if expression")?;
                     let rhs = self.visit_expr(rhs_old);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let conditional = CExprKind::BinaryConditional(ty, lhs, rhs);
@@ -1147,19 +1174,19 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagUnaryExprOrTypeTraitExpr if expected_ty & (EXPR | STMT) != 0 => {
-                    let ty = node.type_id.expect("Expected expression to have type");
+                    let ty = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty);
 
-                    let expr = node.children[0].map(|x| self.visit_expr(x));
+                    let expr = child(&node.children, 0)?.map(|x| self.visit_expr(x));
 
-                    let kind_name = node.extras[0].as_string().expect("expected kind").as_str();
+                    let kind_name = extra(&node.extras, 0)?.as_string().ok_or("expected kind")?.as_str();
                     let kind = match kind_name {
                         "sizeof" => UnTypeOp::SizeOf,
                         "alignof" => UnTypeOp::AlignOf,
-                        str => panic!("Unsupported operation: {}", str),
+                        str => return Err(format!("Unsupported operation: {}", str)),
                     };
 
-                    let arg_ty = node.extras[1].as_u64().expect("expected type id");
+                    let arg_ty = extra(&node.extras, 1)?.as_u64().ok_or("expected type id")?;
                     let arg_ty = self.visit_qualified_type(arg_ty);
 
                     let operator = CExprKind::UnaryType(ty, kind, expr, arg_ty);
@@ -1168,27 +1195,27 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagCompoundLiteralExpr => {
-                    let ty_old = node.type_id.expect("Expected compound literal to have type");
+                    let ty_old = node.type_id.ok_or("Expected compound literal to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
-                    let val_old = node.children[0].expect("Expected child on compound literal");
+                    let val_old = child(&node.children, 0)?.ok_or("Expected child on compound literal")?;
                     let val = self.visit_expr(val_old);
 
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, CExprKind::CompoundLiteral(ty, val))
                 }
 
                 ASTEntryTag::TagPredefinedExpr => {
-                    let ty_old = node.type_id.expect("Expected predefined expr to have type");
+                    let ty_old = node.type_id.ok_or("Expected predefined expr to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
-                    let val_old = node.children[0].expect("Expected child on predefined expr");
+                    let val_old = child(&node.children, 0)?.ok_or("Expected child on predefined expr")?;
                     let val = self.visit_expr(val_old);
 
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, CExprKind::Predefined(ty, val))
                 }
 
                 ASTEntryTag::TagImplicitValueInitExpr => {
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, CExprKind::ImplicitValueInit(ty))
@@ -1199,38 +1226,39 @@ impl ConversionContext {
                     let exprs: Vec<CExprId> = node.children
                         .iter()
                         .map(|id| {
-                            let expr_id = id.expect("init expression id");
-                            self.visit_expr(expr_id)
+                            let expr_id = id.ok_or("init expression id")?;
+                            Ok(self.visit_expr(expr_id))
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
-                    let union_field_id = expect_opt_u64(&node.extras[0]).expect("Bad union field ID entry").map(|x| self.visit_decl(x));
-                    let syntax_id = expect_opt_u64(&node.extras[1]).expect("Bad syntax ID entry").map(|x| self.visit_expr(x));
+                    let union_field_id = expect_opt_u64(extra(&node.extras, 0)?).ok_or("Bad union field ID entry")?.map(|x| self.visit_decl(x));
+                    let syntax_id = expect_opt_u64(extra(&node.extras, 1)?).ok_or("Bad syntax ID entry")?.map(|x| self.visit_expr(x));
 
                     let kind = CExprKind::InitList(ty, exprs, union_field_id, syntax_id);
                     self.expr_possibly_as_stmt(expected_ty, new_id, node, kind)
                 }
 
                 ASTEntryTag::TagDesignatedInitExpr => {
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
-                    let designator_cbors = node.extras[0].as_array().expect("Expected designators array");
+                    let designator_cbors = extra(&node.extras, 0)?.as_array().ok_or("Expected designators array")?;
                     let designators = designator_cbors.into_iter().map(|x| {
-                        let entry = x.as_array().expect("expected designator array");
-                        match entry[0].as_u64().expect("expected designator tag") {
-                            1 => Designator::Index(entry[1].as_u64().expect("expected array index")),
-                            2 => Designator::Field(CDeclId(entry[1].as_u64().expect("expected field id"))),
-                            3 => Designator::Range(entry[1].as_u64().expect("expected array start"),
-                                                   entry[2].as_u64().expect("expected array end")),
-                            n => panic!("invalid designator tag: {}", n),
-                        }
-                    } ).collect();
+                        let entry = x.as_array().ok_or("expected designator array")?;
+                        let designator = match extra(entry, 0)?.as_u64().ok_or("expected designator tag")? {
+                            1 => Designator::Index(extra(entry, 1)?.as_u64().ok_or("expected array index")?),
+                            2 => Designator::Field(CDeclId(extra(entry, 1)?.as_u64().ok_or("expected field id")?)),
+                            3 => Designator::Range(extra(entry, 1)?.as_u64().ok_or("expected array start")?,
+                                                   extra(entry, 2)?.as_u64().ok_or("expected array end")?),
+                            n => return Err(format!("invalid designator tag: {}", n)),
+                        };
+                        Ok(designator)
+                    } ).collect::<Result<_, String>>()?;
 
-                    let init_id = node.children[0].expect("Expected initializer expression on designated init expr");
+                    let init_id = child(&node.children, 0)?.ok_or("Expected initializer expression on designated init expr")?;
                     let init_expr = self.visit_expr(init_id);
 
                     let kind = CExprKind::DesignatedInitExpr(ty, designators, init_expr);
@@ -1239,10 +1267,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagStmtExpr => {
-                    let child_id = node.children[0].expect("Expected compound statement ID");
+                    let child_id = child(&node.children, 0)?.ok_or("Expected compound statement ID")?;
                     let child = self.visit_stmt(child_id);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let stmt_expr = CExprKind::Statements(ty, child);
@@ -1251,10 +1279,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagVAArgExpr => {
-                    let child_id = node.children[0].expect("Expected subexpression");
+                    let child_id = child(&node.children, 0)?.ok_or("Expected subexpression")?;
                     let child = self.visit_expr(child_id);
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let vaarg_expr = CExprKind::VAArg(ty, child);
@@ -1264,7 +1292,7 @@ impl ConversionContext {
 
                 ASTEntryTag::TagShuffleVectorExpr => {
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let e = CExprKind::ShuffleVector(ty);
@@ -1274,7 +1302,7 @@ impl ConversionContext {
 
                 ASTEntryTag::TagConvertVectorExpr => {
 
-                    let ty_old = node.type_id.expect("Expected expression to have type");
+                    let ty_old = node.type_id.ok_or("Expected expression to have type")?;
                     let ty = self.visit_qualified_type(ty_old);
 
                     let e = CExprKind::ConvertVector(ty);
@@ -1285,34 +1313,34 @@ impl ConversionContext {
                 // Declarations
 
                 ASTEntryTag::TagFunctionDecl if expected_ty & OTHER_DECL != 0 => {
-                    let name = node.extras[0].as_string()
-                        .expect("Expected to find function name").to_owned();
+                    let name = extra(&node.extras, 0)?.as_string()
+                        .ok_or("Expected to find function name")?.to_owned();
 
-                    let is_extern = node.extras[1].as_boolean().expect("Expected to find visibility");
-                    let is_inline = node.extras[2].as_boolean().expect("Expected to find inline");
+                    let is_extern = extra(&node.extras, 1)?.as_boolean().ok_or("Expected to find visibility")?;
+                    let is_inline = extra(&node.extras, 2)?.as_boolean().ok_or("Expected to find inline")?;
 
-                    let is_main = node.extras[3].as_boolean().expect("Expected to find main");
+                    let is_main = extra(&node.extras, 3)?.as_boolean().ok_or("Expected to find main")?;
                     if is_main {
                         self.typed_context.c_main = Some(CDeclId(new_id));
                     }
 
-                    let is_implicit = node.extras[4].as_boolean().expect("Expected to find implicit");
+                    let is_implicit = extra(&node.extras, 4)?.as_boolean().ok_or("Expected to find implicit")?;
 
-                    let typ_old = node.type_id.expect("Expected to find a type on a function decl");
+                    let typ_old = node.type_id.ok_or("Expected to find a type on a function decl")?;
                     let typ = CTypeId(self.visit_node_type(typ_old, TYPE));
 
                     let (body_id, parameter_ids) = node.children.split_last()
-                        .expect("Expected to find a function body");
+                        .ok_or("Expected to find a function body")?;
 
                     let body = body_id.map(|b| self.visit_stmt(b));
 
                     let parameters = parameter_ids
                         .iter()
                         .map(|id| {
-                            let param = id.expect("Param field decl not found");
-                            CDeclId(self.visit_node_type(param, VAR_DECL))
+                            let param = id.ok_or("Param field decl not found")?;
+                            Ok(CDeclId(self.visit_node_type(param, VAR_DECL)))
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
                     let function_decl =
                         CDeclKind::Function { is_extern, is_inline, is_implicit, typ, name, parameters, body };
@@ -1322,10 +1350,10 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagTypedefDecl if expected_ty & TYPDEF_DECL != 0 => {
-                    let name = node.extras[0].as_string().expect("Expected to find typedef name").to_owned();
-                    let is_implicit = node.extras[1].as_boolean().expect("Expected to find implicit");
+                    let name = extra(&node.extras, 0)?.as_string().ok_or("Expected to find typedef name")?.to_owned();
+                    let is_implicit = extra(&node.extras, 1)?.as_boolean().ok_or("Expected to find implicit")?;
 
-                    let typ_old = node.type_id.expect("Expected to find type on typedef declaration");
+                    let typ_old = node.type_id.ok_or("Expected to find type on typedef declaration")?;
                     let typ = self.visit_qualified_type(typ_old);
 
                     let typdef_decl = CDeclKind::Typedef { name, typ, is_implicit };
@@ -1335,17 +1363,17 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagEnumDecl if expected_ty & ENUM_DECL != 0 => {
-                    let name = expect_opt_str(&node.extras[0]).unwrap().map(str::to_string);
+                    let name = expect_opt_str(extra(&node.extras, 0)?).ok_or("Expected enum name")?.map(str::to_string);
 
                     let variants = node.children
                         .iter()
                         .map(|id| {
-                            let con = id.expect("Enum constant not found");
+                            let con = id.ok_or("Enum constant not found")?;
                             let id = CDeclId(self.visit_node_type(con, ENUM_CON));
                             self.typed_context.parents.insert(id, CDeclId(new_id));
-                            id
+                            Ok(id)
                         })
-                        .collect();
+                        .collect::<Result<_, String>>()?;
 
                     let integral_type = node.type_id.map(|x| self.visit_qualified_type(x));
 
@@ -1356,11 +1384,11 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagEnumConstantDecl if expected_ty & ENUM_CON != 0 => {
-                    let name = node.extras[0].as_string().expect("Expected to find enum constant name").to_owned();
-                    let value = match node.extras[1] {
-                        Value::U64(n) => ConstIntExpr::U(n),
-                        Value::I64(n) => ConstIntExpr::I(n),
-                        _ => panic!("Expected constant int expr"),
+                    let name = extra(&node.extras, 0)?.as_string().ok_or("Expected to find enum constant name")?.to_owned();
+                    let value = match extra(&node.extras, 1)? {
+                        &Value::U64(n) => ConstIntExpr::U(n),
+                        &Value::I64(n) => ConstIntExpr::I(n),
+                        _ => return Err(format!("Expected constant int expr")),
                     };
                     let enum_constant_decl = CDeclKind::EnumConstant { name, value };
 
@@ -1369,17 +1397,19 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagVarDecl if expected_ty & VAR_DECL != 0 => {
-                    let ident = node.extras[0].as_string().expect("Expected to find variable name").to_owned();
+                    let ident = extra(&node.extras, 0)?.as_string().ok_or("Expected to find variable name")?.to_owned();
 
-                    let is_static = node.extras[1].as_boolean().expect("Expected to find duration");
-                    let is_extern = node.extras[2].as_boolean().expect("Expected to find visibility");
-                    let is_defn   = node.extras[3].as_boolean().expect("Expected to find whether decl is definition");
-                    assert!(if is_extern { is_static } else { true }, "Something cannot be extern without also being static");
+                    let is_static = extra(&node.extras, 1)?.as_boolean().ok_or("Expected to find duration")?;
+                    let is_extern = extra(&node.extras, 2)?.as_boolean().ok_or("Expected to find visibility")?;
+                    let is_defn   = extra(&node.extras, 3)?.as_boolean().ok_or("Expected to find whether decl is definition")?;
+                    if is_extern && !is_static {
+                        return Err(format!("Something cannot be extern without also being static"));
+                    }
 
-                    let initializer = node.children[0]
+                    let initializer = child(&node.children, 0)?
                         .map(|id| self.visit_expr(id));
 
-                    let typ_id = node.type_id.expect("Expected to find type on variable declaration");
+                    let typ_id = node.type_id.ok_or("Expected to find type on variable declaration")?;
                     let typ = self.visit_qualified_type(typ_id);
 
                     let variable_decl = CDeclKind::Variable { is_static, is_extern, is_defn, ident, initializer, typ };
@@ -1389,29 +1419,29 @@ impl ConversionContext {
                 }
 
                 ASTEntryTag::TagStructDecl if expected_ty & RECORD_DECL != 0 => {
-                    let name = expect_opt_str(&node.extras[0]).unwrap().map(str::to_string);
-                    let has_def = node.extras[1].as_boolean().expect("Expected has_def flag on struct");
-                    let attrs = node.extras[2].as_array().expect("Expected attribute array on record");
-                    let manual_alignment = expect_opt_u64(&node.extras[3]).expect("Expected struct alignment");
+                    let name = expect_opt_str(extra(&node.extras, 0)?).ok_or("Expected struct name")?.map(str::to_string);
+                    let has_def = extra(&node.extras, 1)?.as_boolean().ok_or("Expected has_def flag on struct")?;
+                    let attrs = extra(&node.extras, 2)?.as_array().ok_or("Expected attribute array on record")?;
+                    let manual_alignment = expect_opt_u64(extra(&node.extras, 3)?).ok_or("Expected struct alignment")?;
 
                     let fields: Option<Vec<CDeclId>> =
                     if has_def {
                         Some(node.children
                             .iter()
                             .map(|id| {
-                                let field = id.expect("Record field decl not found");
+                                let field = id.ok_or("Record field decl not found")?;
                                 let id = CDeclId(self.visit_node_type(field, FIELD_DECL));
                                 self.typed_context.parents.insert(id, CDeclId(new_id));
-                                id
+                                Ok(id)
                             })
-                            .collect())
+                            .collect::<Result<_, String>>()?)
                     } else {
                         None
                     };
 
                     let mut is_packed = false;
                     for attr in attrs {
-                        match attr.as_string().expect("Records attributes should be strings").as_str() {
+                        match attr.as_string().ok_or("Records attributes should be strings")?.as_str() {
                             "packed" => is_packed = true,
                             _ => {}
                         }
@@ -1424,19 +1454,19 @@ impl ConversionContext {
                 },
 
                 ASTEntryTag::TagUnionDecl if expected_ty & RECORD_DECL != 0 => {
-                    let name = expect_opt_str(&node.extras[0]).unwrap().map(str::to_string);
-                    let has_def = node.extras[1].as_boolean().expect("Expected has_def flag on struct");
+                    let name = expect_opt_str(extra(&node.extras, 0)?).ok_or("Expected union name")?.map(str::to_string);
+                    let has_def = extra(&node.extras, 1)?.as_boolean().ok_or("Expected has_def flag on struct")?;
                     let fields: Option<Vec<CDeclId>> =
                         if has_def {
                             Some(node.children
                                 .iter()
                                 .map(|id| {
-                                    let field = id.expect("Record field decl not found");
+                                    let field = id.ok_or("Record field decl not found")?;
                                     let id = CDeclId(self.visit_node_type(field, FIELD_DECL));
                                     self.typed_context.parents.insert(id, CDeclId(new_id));
-                                    id
+                                    Ok(id)
                                 })
-                                .collect())
+                                .collect::<Result<_, String>>()?)
                         } else {
                             None
                         };
@@ -1448,18 +1478,67 @@ impl ConversionContext {
                 },
 
                 ASTEntryTag::TagFieldDecl if expected_ty & FIELD_DECL != 0 => {
-                    let name = node.extras[0].as_string().expect("A field needs a name").to_owned();
-                    let typ_id = node.type_id.expect("Expected to find type on field declaration");
+                    let name = extra(&node.extras, 0)?.as_string().ok_or("A field needs a name")?.to_owned();
+                    let typ_id = node.type_id.ok_or("Expected to find type on field declaration")?;
                     let typ = self.visit_qualified_type(typ_id);
                     let field = CDeclKind::Field { name, typ };
                     self.add_decl(new_id, located(node, field));
                     self.processed_nodes.insert(new_id, FIELD_DECL);
                 }
 
-                t => panic!("Could not translate node {:?} as type {}", t, expected_ty),
+                t => return Err(format!("Could not translate node {:?} as type {}", t, expected_ty)),
             }
         }
+
+        Ok(())
     }
 }
 
+/// Describe what is wrong with the node `node_id`, which was visited expecting `expected_ty`
+fn node_error(untyped_context: &AstContext, node_id: ClangId, expected_ty: NodeType, problem: String) -> ImportError {
+    let tag = if expected_ty & node_types::TYPE != 0 {
+        untyped_context.type_nodes.get(&node_id).map(|n| n.tag as u64)
+    } else {
+        untyped_context.ast_nodes.get(&node_id).map(|n| n.tag as u64)
+    };
+    ImportError::Node { id: Some(node_id), tag, problem }
+}
+
+
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context_with(node: AstNode) -> AstContext {
+        let mut ast_nodes = HashMap::new();
+        ast_nodes.insert(1, node);
+        AstContext {
+            ast_nodes,
+            type_nodes: HashMap::new(),
+            top_nodes: vec![1],
+            comments: vec![],
+            target: TargetInfo::default(),
+            conditionals: vec![],
+        }
+    }
+
+    #[test]
+    fn malformed_node() {
+        // A variable declaration without its name and flags
+        let untyped_context = context_with(AstNode {
+            tag: ASTEntryTag::TagVarDecl,
+            children: vec![],
+            fileid: 0,
+            line: 1,
+            column: 1,
+            type_id: None,
+            extras: vec![],
+        });
+
+        let mut conv = ConversionContext::new(&untyped_context);
+        let problem = format!("Expected at least 1 extra fields");
+        let expected = ImportError::Node { id: Some(1), tag: Some(ASTEntryTag::TagVarDecl as u64), problem };
+        assert_eq!(conv.convert(&untyped_context), Err(expected));
+    }
+}
@@ -0,0 +1,239 @@
+//! Finding arrays that can be indexed in Rust: local or static variables of constant size whose
+//! pointer never escapes, because every use of the array is one of
+//!
+//!   * a subscript `buf[i]`,
+//!   * a dereference `*(buf + i)`, `*(i + buf)` or `*buf`,
+//!   * `sizeof buf` or `_Alignof buf`.
+//!
+//! Elements which are themselves arrays (rows of `int m[4][4]`) have to be used the same way, as do
+//! array fields of elements (`buf[i].arr`). Taking the address of an element or of one of its
+//! fields (`&buf[i]`, `&buf[i].f`) lets a pointer into the array escape as well.
+//!
+//! Dereferences of such arrays can be translated to `buf[i as usize]` rather than to pointer
+//! offsets, since no raw pointer into the array is ever made.
+
+use std::collections::{HashMap, HashSet};
+use c_ast::*;
+use c_ast::iterators::{SomeId, immediate_children};
+
+/// Find the array variables whose pointer never escapes
+pub fn find_indexed_arrays(context: &TypedAstContext) -> HashSet<CDeclId> {
+    let mut parents: HashMap<CExprId, CExprId> = HashMap::new();
+    for &expr_id in context.c_exprs.keys() {
+        for child in immediate_children(context, SomeId::Expr(expr_id)) {
+            if let SomeId::Expr(child) = child {
+                parents.insert(child, expr_id);
+            }
+        }
+    }
+
+    let finder = Finder { context, parents };
+    let mut candidates = HashSet::new();
+    let mut escaping = HashSet::new();
+
+    for (&expr_id, expr) in &context.c_exprs {
+        if let CExprKind::DeclRef(_, decl_id) = expr.kind {
+            if !finder.is_local_or_static_array(decl_id) {
+                continue
+            }
+            candidates.insert(decl_id);
+            if finder.array_escapes(expr_id) {
+                escaping.insert(decl_id);
+            }
+        }
+    }
+
+    candidates.difference(&escaping).cloned().collect()
+}
+
+struct Finder<'a> {
+    context: &'a TypedAstContext,
+    /// The expression each expression is an operand of
+    parents: HashMap<CExprId, CExprId>,
+}
+
+impl<'a> Finder<'a> {
+    fn is_local_or_static_array(&self, decl_id: CDeclId) -> bool {
+        match self.context[decl_id].kind {
+            // Variables with external linkage could be used through a pointer in another file
+            CDeclKind::Variable { is_extern: false, typ, .. } => self.is_constant_array(typ.ctype),
+            _ => false,
+        }
+    }
+
+    fn is_constant_array(&self, type_id: CTypeId) -> bool {
+        match self.context.resolve_type(type_id).kind {
+            CTypeKind::ConstantArray(..) => true,
+            _ => false,
+        }
+    }
+
+    fn parent(&self, expr_id: CExprId) -> Option<(CExprId, &'a CExprKind)> {
+        let context = self.context;
+        self.parents.get(&expr_id).map(|&parent| (parent, &context[parent].kind))
+    }
+
+    /// Whether an expression of array type is used other than through subscripts and `sizeof`
+    fn array_escapes(&self, array: CExprId) -> bool {
+        match self.parent(array) {
+            Some((_, &CExprKind::UnaryType(..))) => false,
+            Some((decayed, &CExprKind::ImplicitCast(_, _, CastKind::ArrayToPointerDecay, _))) =>
+                self.decayed_array_escapes(decayed),
+            _ => true,
+        }
+    }
+
+    /// Whether the pointer an array decays to is used other than to access an element
+    fn decayed_array_escapes(&self, decayed: CExprId) -> bool {
+        match self.parent(decayed) {
+            Some((element, &CExprKind::ArraySubscript(..))) |
+            Some((element, &CExprKind::Unary(_, UnOp::Deref, _))) => self.element_escapes(element),
+
+            Some((sum, &CExprKind::Binary(_, BinOp::Add, lhs, rhs, _, _))) => {
+                let offset = if lhs == decayed { rhs } else { lhs };
+                let offset_is_integral = self.context[offset].kind.get_type()
+                    .map_or(false, |ty| self.context.resolve_type(ty).kind.is_integral_type());
+
+                match self.parent(sum) {
+                    Some((element, &CExprKind::Unary(_, UnOp::Deref, _))) if offset_is_integral =>
+                        self.element_escapes(element),
+                    _ => true,
+                }
+            }
+
+            _ => true,
+        }
+    }
+
+    /// Whether an element of an array is used in a way that lets a pointer into the array escape
+    fn element_escapes(&self, element: CExprId) -> bool {
+        let is_array = self.context[element].kind.get_type()
+            .map_or(false, |ty| self.is_constant_array(ty));
+        if is_array {
+            return self.array_escapes(element)
+        }
+
+        match self.parent(element) {
+            Some((_, &CExprKind::Unary(_, UnOp::AddressOf, _))) => true,
+            // Fields of an element are in the array too
+            Some((field, &CExprKind::Member(_, _, _, MemberKind::Dot))) => self.element_escapes(field),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INT: CTypeId = CTypeId(1);
+    const FIELD_ARRAY: CTypeId = CTypeId(2);
+    const STRUCT: CTypeId = CTypeId(3);
+    const ARRAY: CTypeId = CTypeId(4);
+    const INT_POINTER: CTypeId = CTypeId(5);
+    const STRUCT_POINTER: CTypeId = CTypeId(6);
+
+    const F: CFieldId = CDeclId(11);
+    const ARR: CFieldId = CDeclId(12);
+    const BUF: CDeclId = CDeclId(20);
+
+    fn qual(ctype: CTypeId) -> CQualTypeId {
+        CQualTypeId { qualifiers: Qualifiers::default(), ctype }
+    }
+
+    /// `static struct { int f; int arr[2]; } buf[4];`, used by the expressions made with `expr`
+    struct Ast {
+        context: TypedAstContext,
+        next_id: u64,
+    }
+
+    impl Ast {
+        fn new() -> Ast {
+            let mut context = TypedAstContext::new();
+            let types = vec![
+                (INT, CTypeKind::Int),
+                (FIELD_ARRAY, CTypeKind::ConstantArray(INT, 2)),
+                (STRUCT, CTypeKind::Struct(CDeclId(10))),
+                (ARRAY, CTypeKind::ConstantArray(STRUCT, 4)),
+                (INT_POINTER, CTypeKind::Pointer(qual(INT))),
+                (STRUCT_POINTER, CTypeKind::Pointer(qual(STRUCT))),
+            ];
+            for (id, kind) in types {
+                context.c_types.insert(id, Located { loc: None, kind });
+            }
+            let decls = vec![
+                (CDeclId(10), CDeclKind::Struct { name: None, fields: Some(vec![F, ARR]), is_packed: false, manual_alignment: None }),
+                (F, CDeclKind::Field { name: "f".to_string(), typ: qual(INT) }),
+                (ARR, CDeclKind::Field { name: "arr".to_string(), typ: qual(FIELD_ARRAY) }),
+                (BUF, CDeclKind::Variable {
+                    is_static: true, is_extern: false, is_defn: true, ident: "buf".to_string(),
+                    initializer: None, typ: qual(ARRAY),
+                }),
+            ];
+            for (id, kind) in decls {
+                context.c_decls.insert(id, Located { loc: None, kind });
+            }
+            Ast { context, next_id: 100 }
+        }
+
+        fn expr(&mut self, kind: CExprKind) -> CExprId {
+            self.next_id += 1;
+            self.context.c_exprs.insert(CExprId(self.next_id), Located { loc: None, kind });
+            CExprId(self.next_id)
+        }
+
+        /// `buf[1]`
+        fn element(&mut self) -> CExprId {
+            let buf = self.expr(CExprKind::DeclRef(qual(ARRAY), BUF));
+            let decayed = self.expr(CExprKind::ImplicitCast(qual(STRUCT_POINTER), buf, CastKind::ArrayToPointerDecay, None));
+            let index = self.expr(CExprKind::Literal(qual(INT), CLiteral::Integer(1, IntBase::Dec)));
+            self.expr(CExprKind::ArraySubscript(qual(STRUCT), decayed, index))
+        }
+
+        /// `buf[1].arr`, decayed to a pointer
+        fn decayed_field_array(&mut self) -> CExprId {
+            let element = self.element();
+            let arr = self.expr(CExprKind::Member(qual(FIELD_ARRAY), element, ARR, MemberKind::Dot));
+            self.expr(CExprKind::ImplicitCast(qual(INT_POINTER), arr, CastKind::ArrayToPointerDecay, None))
+        }
+
+        fn is_indexed(&self) -> bool {
+            find_indexed_arrays(&self.context).contains(&BUF)
+        }
+    }
+
+    #[test]
+    fn fields_of_elements() {
+        // buf[1].f
+        let mut ast = Ast::new();
+        let element = ast.element();
+        let f = ast.expr(CExprKind::Member(qual(INT), element, F, MemberKind::Dot));
+        ast.expr(CExprKind::ImplicitCast(qual(INT), f, CastKind::LValueToRValue, None));
+        assert!(ast.is_indexed());
+
+        // &buf[1].f
+        let element = ast.element();
+        let f = ast.expr(CExprKind::Member(qual(INT), element, F, MemberKind::Dot));
+        ast.expr(CExprKind::Unary(qual(INT_POINTER), UnOp::AddressOf, f));
+        assert!(!ast.is_indexed());
+    }
+
+    #[test]
+    fn array_fields_of_elements() {
+        // buf[1].arr[0]
+        let mut ast = Ast::new();
+        let arr = ast.decayed_field_array();
+        let index = ast.expr(CExprKind::Literal(qual(INT), CLiteral::Integer(0, IntBase::Dec)));
+        let item = ast.expr(CExprKind::ArraySubscript(qual(INT), arr, index));
+        ast.expr(CExprKind::ImplicitCast(qual(INT), item, CastKind::LValueToRValue, None));
+        assert!(ast.is_indexed());
+
+        // int *p = buf[1].arr;
+        let arr = ast.decayed_field_array();
+        ast.context.c_decls.insert(CDeclId(30), Located { loc: None, kind: CDeclKind::Variable {
+            is_static: false, is_extern: false, is_defn: true, ident: "p".to_string(),
+            initializer: Some(arr), typ: qual(INT_POINTER),
+        }});
+        assert!(!ast.is_indexed());
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::{HashMap,HashSet,BTreeMap};
 use indexmap::IndexMap;
 use std::ops::Index;
+use clang_ast::{TargetInfo, ConditionalRegion};
+use rust_ast::comment_store::TRAILING_COMMENT;
 
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Copy, Clone)]
 pub struct CTypeId(pub u64);
@@ -30,6 +32,9 @@ pub use self::print::Printer;
 mod conversion;
 mod print;
 pub mod iterators;
+pub mod const_eval;
+pub mod tagged_unions;
+pub mod indexed_arrays;
 
 /// AST context containing all of the nodes in the Clang AST
 #[derive(Debug, Clone)]
@@ -48,6 +53,9 @@ pub struct TypedAstContext {
     pub parents: HashMap<CDeclId, CDeclId>, // record fields and enum constants
 
     pub comments: Vec<Located<String>>,
+
+    pub target: TargetInfo,
+    pub conditionals: Vec<ConditionalRegion>,
 }
 
 /// Comments associated with a typed AST context
@@ -55,6 +63,18 @@ pub struct TypedAstContext {
 pub struct CommentContext {
     decl_comments: HashMap<CDeclId, Vec<String>>,
     stmt_comments: HashMap<CStmtId, Vec<String>>,
+    file_comments: HashMap<u64, Vec<String>>,
+
+    /// Comments taken for statements which did not produce anything to attach them to. These go
+    /// with the next statement translated instead.
+    deferred_comments: Vec<String>,
+}
+
+/// What a comment can be attached to
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum CommentTarget {
+    Decl(CDeclId),
+    Stmt(CStmtId),
 }
 
 impl TypedAstContext {
@@ -71,9 +91,25 @@ impl TypedAstContext {
             parents: HashMap::new(),
 
             comments: vec![],
+
+            target: TargetInfo::default(),
+            conditionals: vec![],
         }
     }
 
+    /// The C file defining the most top-level functions, which is taken to be the file being
+    /// translated (as opposed to the headers it includes).
+    pub fn main_file_id(&self) -> Option<u64> {
+        let mut definitions: HashMap<u64, usize> = HashMap::new();
+        for top_id in &self.c_decls_top {
+            let decl = &self.c_decls[top_id];
+            if let (&CDeclKind::Function { body: Some(_), .. }, Some(loc)) = (&decl.kind, decl.loc) {
+                *definitions.entry(loc.fileid).or_insert(0) += 1;
+            }
+        }
+        definitions.into_iter().max_by_key(|&(fileid, count)| (count, fileid)).map(|(fileid, _)| fileid)
+    }
+
     pub fn is_null_expr(&self, expr_id: CExprId) -> bool {
         match self[expr_id].kind {
             CExprKind::ExplicitCast(_, _, CastKind::NullToPointer, _) |
@@ -136,6 +172,43 @@ impl TypedAstContext {
         self.index(resolved_typ_id)
     }
 
+    /// Width in bits and signedness of an integral type on the target. `_Bool` counts as a 1-bit
+    /// unsigned type, and enums as their underlying type.
+    pub fn int_format(&self, typ: CTypeId) -> Option<(u32, bool)> {
+        let target = &self.target;
+        let (width, signed) = match self.resolve_type(typ).kind {
+            CTypeKind::Bool => (1, false),
+            CTypeKind::Char => (8, target.char_is_signed),
+            CTypeKind::SChar => (8, true),
+            CTypeKind::UChar => (8, false),
+            CTypeKind::Short => (target.short_width, true),
+            CTypeKind::UShort => (target.short_width, false),
+            CTypeKind::Int => (target.int_width, true),
+            CTypeKind::UInt => (target.int_width, false),
+            CTypeKind::Long => (target.long_width, true),
+            CTypeKind::ULong => (target.long_width, false),
+            CTypeKind::LongLong => (target.long_long_width, true),
+            CTypeKind::ULongLong => (target.long_long_width, false),
+            CTypeKind::Int128 => (128, true),
+            CTypeKind::UInt128 => (128, false),
+            CTypeKind::Enum(enum_id) => match self[enum_id].kind {
+                CDeclKind::Enum { integral_type: Some(ty), .. } => return self.int_format(ty.ctype),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some((width as u32, signed))
+    }
+
+    /// The regions of conditionals enclosing a location, outermost first
+    pub fn conditionals_at(&self, loc: &SrcLoc) -> Vec<&ConditionalRegion> {
+        let mut regions: Vec<&ConditionalRegion> = self.conditionals.iter()
+            .filter(|r| r.fileid == loc.fileid && r.begin_line < loc.line && loc.line < r.end_line)
+            .collect();
+        regions.sort_by_key(|r| r.begin_line);
+        regions
+    }
+
     /// Pessimistically try to check if an expression has side effects. If it does, or we can't tell
     /// that it doesn't, return `false`.
     pub fn is_expr_pure(&self, expr: CExprId) -> bool {
@@ -394,36 +467,65 @@ impl CommentContext {
         CommentContext {
             decl_comments: HashMap::new(),
             stmt_comments: HashMap::new(),
+            file_comments: HashMap::new(),
+            deferred_comments: vec![],
         }
     }
 
 
-    // Try to match up every comment with a declaration or a statement
+    /// Try to match up every comment with a declaration or a statement. Only those declarations
+    /// and statements whose translation takes their comments are considered. A comment goes to:
+    ///
+    ///   - the file header, if it is in the main file and comes before (and not right above)
+    ///     everything else there
+    ///   - the node it follows on the same line (a trailing comment)
+    ///   - the node it follows, if it is inside an expression (an expression starts after it,
+    ///     but before the next node)
+    ///   - the next node, otherwise
+    ///
+    /// When there is no next node, a comment goes to the closest node before it instead, and
+    /// to the file header if there is none of those either.
     pub fn new(
         ast_context: &mut TypedAstContext
     ) -> CommentContext {
 
-        // Group and sort declarations by file and by position
-        let mut decls: HashMap<u64, Vec<(SrcLoc, CDeclId)>> = HashMap::new();
+        // Group and sort declarations and statements by file and by position. Local variables
+        // and parameters are left out since they are translated as part of their statement or
+        // function, as are compound statements.
+        let mut targets: HashMap<u64, Vec<(SrcLoc, CommentTarget)>> = HashMap::new();
         for (decl_id, ref loc_decl) in &ast_context.c_decls {
-            if let Some(loc) = loc_decl.loc {
-                decls.entry(loc.fileid).or_insert(vec![]).push((loc, *decl_id));
+            let local = match loc_decl.kind {
+                CDeclKind::Variable { .. } => !ast_context.c_decls_top.contains(decl_id),
+                _ => false,
+            };
+            if let (Some(loc), false) = (loc_decl.loc, local) {
+                targets.entry(loc.fileid).or_insert(vec![]).push((loc, CommentTarget::Decl(*decl_id)));
             }
         }
-        decls.iter_mut().for_each(|(_, v)| v.sort());
-
-        // Group and sort statements by file and by position
-        let mut stmts: HashMap<u64, Vec<(SrcLoc, CStmtId)>> = HashMap::new();
         for (stmt_id, ref loc_stmt) in &ast_context.c_stmts {
-            if let Some(loc) = loc_stmt.loc {
-                stmts.entry(loc.fileid).or_insert(vec![]).push((loc, *stmt_id));
+            let compound = match loc_stmt.kind {
+                CStmtKind::Compound(_) => true,
+                _ => false,
+            };
+            if let (Some(loc), false) = (loc_stmt.loc, compound) {
+                targets.entry(loc.fileid).or_insert(vec![]).push((loc, CommentTarget::Stmt(*stmt_id)));
+            }
+        }
+        targets.iter_mut().for_each(|(_, v)| v.sort());
+
+        // Group and sort expressions by file and by position
+        let mut exprs: HashMap<u64, Vec<SrcLoc>> = HashMap::new();
+        for loc_expr in ast_context.c_exprs.values() {
+            if let Some(loc) = loc_expr.loc {
+                exprs.entry(loc.fileid).or_insert(vec![]).push(loc);
             }
         }
-        stmts.iter_mut().for_each(|(_, v)| v.sort());
+        exprs.iter_mut().for_each(|(_, v)| v.sort());
 
+        let main_file = ast_context.main_file_id();
 
-        let mut decl_comments_map: HashMap<CDeclId, BTreeMap<SrcLoc, String>> = HashMap::new();
-        let mut stmt_comments_map: HashMap<CStmtId, BTreeMap<SrcLoc, String>> = HashMap::new();
+        let mut target_comments: HashMap<CommentTarget, BTreeMap<SrcLoc, String>> = HashMap::new();
+        let mut file_comments: HashMap<u64, BTreeMap<SrcLoc, String>> = HashMap::new();
 
         let empty_vec1 = &vec![];
         let empty_vec2 = &vec![];
@@ -432,50 +534,61 @@ impl CommentContext {
         // Match comments to declarations and statements
         while let Some(Located { loc, kind: str }) = ast_context.comments.pop() {
             if let Some(loc) = loc {
-                let this_file_decls = decls.get(&loc.fileid).unwrap_or(empty_vec1);
-                let this_file_stmts = stmts.get(&loc.fileid).unwrap_or(empty_vec2);
+                let this_file_targets = targets.get(&loc.fileid).unwrap_or(empty_vec1);
+                let this_file_exprs = exprs.get(&loc.fileid).unwrap_or(empty_vec2);
 
-                // Find the closest declaration and statement
-                let decl_ix = this_file_decls
-                    .binary_search_by_key(&loc.line, |&(l,_)| l.line)
-                    .unwrap_or_else(|x| x);
-                let stmt_ix = this_file_stmts
-                    .binary_search_by_key(&loc.line, |&(l,_)| l.line)
+                // The first node after the comment, and the last one before it
+                let next_ix = this_file_targets
+                    .binary_search_by_key(&loc, |&(l, _)| l)
                     .unwrap_or_else(|x| x);
+                let next = this_file_targets.get(next_ix);
+                let prev = if next_ix > 0 { this_file_targets.get(next_ix - 1) } else { None };
 
-                // Prefer the one that is higher up (biasing towards declarations if there is a tie)
-                match (this_file_decls.get(decl_ix), this_file_stmts.get(stmt_ix)) {
-                    (Some(&(l1, d)), Some(&(l2, s))) => {
-                        if l1 > l2 {
-                            stmt_comments_map.entry(s).or_insert(BTreeMap::new()).insert(loc, str);
-                        } else {
-                            decl_comments_map.entry(d).or_insert(BTreeMap::new()).insert(loc, str);
-                        }
-                    }
-                    (Some(&(_, d)), None) => {
-                        decl_comments_map.entry(d).or_insert(BTreeMap::new()).insert(loc, str);
-                    }
-                    (None, Some(&(_, s))) => {
-                        stmt_comments_map.entry(s).or_insert(BTreeMap::new()).insert(loc, str);
-                    }
-                    (None, None) => {
-                        eprintln!("Didn't find a target node for the comment '{}'", str);
-                    },
+                let end_line = loc.line + str.lines().count().max(1) as u64 - 1;
+                let is_header = Some(loc.fileid) == main_file && prev.is_none() &&
+                    next.map_or(true, |&(l, _)| end_line + 1 < l.line);
+
+                let inside_expr = |&(next_loc, _): &(SrcLoc, CommentTarget)| {
+                    let expr_ix = this_file_exprs.binary_search(&loc).unwrap_or_else(|x| x);
+                    this_file_exprs.get(expr_ix).map_or(false, |&l| l < next_loc)
                 };
+
+                // A comment on one line following a statement or declaration goes at its end
+                let trailing = prev.map_or(false, |&(l, _)| l.line == loc.line) && !str.contains('\n');
+                let str = if trailing && !is_header { format!("{}{}", TRAILING_COMMENT, str) } else { str };
+
+                let target = match (prev, next) {
+                    _ if is_header => None,
+                    (Some(&(l, t)), _) if l.line == loc.line => Some(t),
+                    (Some(&(_, t)), Some(next)) if inside_expr(next) => Some(t),
+                    (_, Some(&(_, t))) => Some(t),
+                    (Some(&(_, t)), None) => Some(t),
+                    (None, None) => None,
+                };
+
+                match target {
+                    Some(t) => { target_comments.entry(t).or_insert(BTreeMap::new()).insert(loc, str); }
+                    None => { file_comments.entry(loc.fileid).or_insert(BTreeMap::new()).insert(loc, str); }
+                }
             }
         }
 
         // Flatten out the nested comment maps
-        let decl_comments = decl_comments_map
-          .into_iter()
-          .map(|(decl_id, map)| (decl_id, map.into_iter().map(|(_, v)| v).collect()))
-          .collect();
-        let stmt_comments = stmt_comments_map
+        let mut decl_comments = HashMap::new();
+        let mut stmt_comments = HashMap::new();
+        for (target, map) in target_comments {
+            let comments = map.into_iter().map(|(_, v)| v).collect();
+            match target {
+                CommentTarget::Decl(decl_id) => decl_comments.insert(decl_id, comments),
+                CommentTarget::Stmt(stmt_id) => stmt_comments.insert(stmt_id, comments),
+            };
+        }
+        let file_comments = file_comments
           .into_iter()
-          .map(|(decl_id, map)| (decl_id, map.into_iter().map(|(_, v)| v).collect()))
+          .map(|(file_id, map)| (file_id, map.into_iter().map(|(_, v)| v).collect()))
           .collect();
 
-        CommentContext { decl_comments, stmt_comments }
+        CommentContext { decl_comments, stmt_comments, file_comments, deferred_comments: vec![] }
     }
 
     // Extract the comment for a given declaration
@@ -483,9 +596,29 @@ impl CommentContext {
         self.decl_comments.remove(&decl_id).unwrap_or(vec![])
     }
 
-    // Extract the comment for a given statement
+    // Extract the comment for a given statement, preceded by any deferred comments
     pub fn remove_stmt_comment(&mut self, stmt_id: CStmtId) -> Vec<String> {
-        self.stmt_comments.remove(&stmt_id).unwrap_or(vec![])
+        let mut comments = self.take_deferred_comments();
+        comments.extend(self.stmt_comments.remove(&stmt_id).unwrap_or(vec![]));
+        comments
+    }
+
+    // Put off comments until the next statement
+    pub fn defer_comments(&mut self, comments: Vec<String>) {
+        // These no longer follow the statement they were on, so they go in front of the next one
+        self.deferred_comments.extend(comments.into_iter().map(|c| {
+            if c.starts_with(TRAILING_COMMENT) { c[TRAILING_COMMENT.len_utf8()..].to_string() } else { c }
+        }));
+    }
+
+    // Extract the comments put off until the next statement
+    pub fn take_deferred_comments(&mut self) -> Vec<String> {
+        self.deferred_comments.drain(..).collect()
+    }
+
+    // Extract the comments heading a given file
+    pub fn remove_file_comments(&mut self, file_id: u64) -> Vec<String> {
+        self.file_comments.remove(&file_id).unwrap_or(vec![])
     }
 }
 
@@ -832,7 +965,7 @@ impl UnOp {
 }
 
 /// Represents a binary operator in C (6.5.5 Multiplicative operators - 6.5.14 Logical OR operator)
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinOp {
     Multiply,         // *
     Divide,           // /
@@ -0,0 +1,740 @@
+//! Finding tagged unions: union fields of structs whose members are only read once another field
+//! of the struct, the tag, has been checked.
+//!
+//! A read of a member like `x.u.m` or `p->u.m` is checked when it is dominated by a comparison of
+//! the tag of the same variable (`x.tag` or `p->tag`) with a constant: the read happens in the
+//! branch of an `if`, `&&`, `||` or `?:` that the comparison selects, or under the `case`s of a
+//! `switch` on the tag. A comparison stops counting for a statement or expression that assigns to
+//! the variable or to its tag, takes the address of anything in the struct or passes a pointer to
+//! it to a function, and past labels. A `switch` with `case`s nested in its statements tells
+//! nothing. The values the tag is known to have at the reads of a member tell which tag values
+//! select that member.
+//!
+//! Writes to members are left alone: whoever writes a member is expected to set the tag as well.
+
+use std::collections::{HashMap, HashSet};
+use c_ast::*;
+use c_ast::iterators::{DFExpr, SomeId, immediate_children};
+use c_ast::const_eval::{ConstEvaluator, ConstValue};
+
+/// A value of a tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagValue {
+    pub value: i128,
+    /// The enum constant the value was written as, if any
+    pub constant: Option<CEnumConstantId>,
+}
+
+/// A union field of a struct whose members are all read under a check of a tag field
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaggedUnion {
+    pub record: CRecordId,
+    pub tag: CFieldId,
+    pub union_field: CFieldId,
+    /// The members of the union which are read, in declaration order, along with the tag values
+    /// selecting them
+    pub members: Vec<(CFieldId, Vec<TagValue>)>,
+}
+
+/// The variable a struct is accessed through: either it holds the struct, or points to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base {
+    Variable(CDeclId),
+    Pointee(CDeclId),
+}
+
+/// The tag of the struct at `base` has one of `values`
+#[derive(Debug, Clone)]
+struct Fact {
+    base: Base,
+    tag: CFieldId,
+    values: Vec<TagValue>,
+}
+
+/// A read of `member` of the union in `union_field`, with the values its tag is known to have
+struct Read {
+    union_field: CFieldId,
+    member: CFieldId,
+    checked: Option<(CFieldId, Vec<TagValue>)>,
+}
+
+struct Finder<'a> {
+    context: &'a TypedAstContext,
+    evaluator: ConstEvaluator<'a>,
+    reads: Vec<Read>,
+    /// Union fields used in a way that could read a member unchecked
+    unsupported_fields: HashSet<CFieldId>,
+    /// Unions whose members are accessed other than through a union field of a struct
+    unsupported_unions: HashSet<CRecordId>,
+}
+
+/// Find the tagged unions among the union fields of structs, by the union field
+pub fn find_tagged_unions(context: &TypedAstContext) -> HashMap<CFieldId, TaggedUnion> {
+    let mut finder = Finder {
+        context,
+        evaluator: ConstEvaluator::new(context),
+        reads: vec![],
+        unsupported_fields: HashSet::new(),
+        unsupported_unions: HashSet::new(),
+    };
+
+    for decl in context.c_decls.values() {
+        match decl.kind {
+            CDeclKind::Function { body: Some(body), .. } => finder.walk_stmt(body, &[]),
+            CDeclKind::Variable { initializer: Some(init), .. } => finder.walk_expr(init, &[]),
+            _ => (),
+        }
+    }
+
+    let mut fields: HashMap<CFieldId, Vec<&Read>> = HashMap::new();
+    for read in &finder.reads {
+        fields.entry(read.union_field).or_insert(vec![]).push(read);
+    }
+
+    let mut tagged_unions = HashMap::new();
+    for (union_field, reads) in fields {
+        if let Some(tagged) = finder.tagged_union(union_field, &reads) {
+            tagged_unions.insert(union_field, tagged);
+        }
+    }
+    tagged_unions
+}
+
+impl<'a> Finder<'a> {
+    fn tagged_union(&self, union_field: CFieldId, reads: &[&Read]) -> Option<TaggedUnion> {
+        let union_id = self.union_of_field(union_field)?;
+        if self.unsupported_fields.contains(&union_field) || self.unsupported_unions.contains(&union_id) {
+            return None
+        }
+
+        // Every read has to check the same tag
+        let tag = reads.first()?.checked.as_ref()?.0;
+        let mut values: HashMap<CFieldId, Vec<TagValue>> = HashMap::new();
+        for read in reads {
+            match read.checked {
+                Some((read_tag, ref read_values)) if read_tag == tag => {
+                    let member_values = values.entry(read.member).or_insert(vec![]);
+                    for value in read_values {
+                        if !member_values.iter().any(|v| v.value == value.value) {
+                            member_values.push(*value);
+                        }
+                    }
+                }
+                _ => return None,
+            }
+        }
+
+        // A tag value selecting several members means the union is used for type punning
+        let mut seen = HashSet::new();
+        for value in values.values().flat_map(|v| v) {
+            if !seen.insert(value.value) {
+                return None
+            }
+        }
+
+        let union_fields = match self.context[union_id].kind {
+            CDeclKind::Union { fields: Some(ref fields), .. } => fields,
+            _ => return None,
+        };
+        let members = union_fields.iter()
+            .filter_map(|member| values.remove(member).map(|values| (*member, values)))
+            .collect();
+
+        Some(TaggedUnion {
+            record: *self.context.parents.get(&union_field)?,
+            tag,
+            union_field,
+            members,
+        })
+    }
+
+    /// The union declaration a field of a struct has the type of
+    fn union_of_field(&self, field: CFieldId) -> Option<CRecordId> {
+        let record = *self.context.parents.get(&field)?;
+        match self.context[record].kind {
+            CDeclKind::Struct { .. } => (),
+            _ => return None,
+        }
+        match self.context[field].kind {
+            CDeclKind::Field { typ, .. } => match self.context.resolve_type(typ.ctype).kind {
+                CTypeKind::Union(union_id) => Some(union_id),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn is_union_member(&self, field: CFieldId) -> bool {
+        match self.context.parents.get(&field).map(|&record| &self.context[record].kind) {
+            Some(&CDeclKind::Union { .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn strip_casts(&self, mut expr: CExprId) -> CExprId {
+        while let CExprKind::ImplicitCast(_, inner, _, _) = self.context[expr].kind {
+            expr = inner;
+        }
+        expr
+    }
+
+    /// The variable accessed by a member access with this base expression and kind
+    fn base(&self, expr: CExprId, kind: MemberKind) -> Option<Base> {
+        match (kind, &self.context[expr].kind) {
+            (MemberKind::Dot, &CExprKind::DeclRef(_, var)) => Some(Base::Variable(var)),
+            (MemberKind::Arrow, &CExprKind::ImplicitCast(_, inner, CastKind::LValueToRValue, _)) =>
+                match self.context[inner].kind {
+                    CExprKind::DeclRef(_, var) => Some(Base::Pointee(var)),
+                    _ => None,
+                },
+            _ => None,
+        }
+    }
+
+    /// An access to a member of a union in a union field of a struct: the union field, the
+    /// member, and the variable accessed
+    fn union_member_access(&self, expr: CExprId) -> Option<(CFieldId, CFieldId, Option<Base>)> {
+        if let CExprKind::Member(_, union_expr, member, MemberKind::Dot) = self.context[expr].kind {
+            if let CExprKind::Member(_, struct_expr, union_field, kind) = self.context[union_expr].kind {
+                if self.is_union_member(member) && self.union_of_field(union_field).is_some() {
+                    return Some((union_field, member, self.base(struct_expr, kind)))
+                }
+            }
+        }
+        None
+    }
+
+    /// A read of an integral field which could be a tag
+    fn tag_read(&self, expr: CExprId) -> Option<(Base, CFieldId)> {
+        match self.context[self.strip_casts(expr)].kind {
+            CExprKind::Member(ty, struct_expr, field, kind) => {
+                let is_integral = match self.context.resolve_type(ty.ctype).kind {
+                    CTypeKind::Enum(_) => true,
+                    ref k => k.is_integral_type(),
+                };
+                let in_struct = match self.context.parents.get(&field).map(|&r| &self.context[r].kind) {
+                    Some(&CDeclKind::Struct { .. }) => true,
+                    _ => false,
+                };
+                if is_integral && in_struct {
+                    self.base(struct_expr, kind).map(|base| (base, field))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn tag_value(&self, expr: CExprId) -> Option<TagValue> {
+        let value = match self.evaluator.eval(expr)? {
+            ConstValue::Int(value) => value,
+            _ => return None,
+        };
+        let constant = match self.context[self.strip_casts(expr)].kind {
+            CExprKind::DeclRef(_, decl) => match self.context[decl].kind {
+                CDeclKind::EnumConstant { .. } => Some(decl),
+                _ => None,
+            },
+            _ => None,
+        };
+        Some(TagValue { value, constant })
+    }
+
+    /// What is known about tags when `cond` evaluates to `truth`
+    fn condition_facts(&self, cond: CExprId, truth: bool) -> Vec<Fact> {
+        match self.context[self.strip_casts(cond)].kind {
+            CExprKind::Unary(_, UnOp::Not, arg) => self.condition_facts(arg, !truth),
+            CExprKind::Binary(_, BinOp::And, lhs, rhs, _, _) if truth => {
+                let mut facts = self.condition_facts(lhs, true);
+                facts.extend(self.condition_facts(rhs, true));
+                facts
+            }
+            CExprKind::Binary(_, BinOp::Or, lhs, rhs, _, _) if !truth => {
+                let mut facts = self.condition_facts(lhs, false);
+                facts.extend(self.condition_facts(rhs, false));
+                facts
+            }
+            // Either side may hold, so only tags both sides know about are known
+            CExprKind::Binary(_, BinOp::Or, lhs, rhs, _, _) => {
+                let rhs_facts = self.condition_facts(rhs, true);
+                self.condition_facts(lhs, true).into_iter()
+                    .filter_map(|mut fact| {
+                        let other = rhs_facts.iter().find(|f| f.base == fact.base && f.tag == fact.tag)?;
+                        fact.values.extend(other.values.iter().cloned());
+                        Some(fact)
+                    })
+                    .collect()
+            }
+            CExprKind::Binary(_, op, lhs, rhs, _, _) if op == BinOp::EqualEqual || op == BinOp::NotEqual => {
+                if (op == BinOp::EqualEqual) != truth {
+                    return vec![]
+                }
+                let comparison = self.tag_read(lhs).and_then(|tag| self.tag_value(rhs).map(|v| (tag, v)))
+                    .or_else(|| self.tag_read(rhs).and_then(|tag| self.tag_value(lhs).map(|v| (tag, v))));
+                match comparison {
+                    Some(((base, tag), value)) => vec![Fact { base, tag, values: vec![value] }],
+                    None => vec![],
+                }
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Whether the code at `node` assigns to the variable of `fact` or to its tag, takes the address
+    /// of anything accessed through the variable, or passes a pointer involving the variable to a
+    /// function, which could then change the tag
+    fn invalidates(&self, node: SomeId, fact: &Fact) -> bool {
+        let base_var = match fact.base {
+            Base::Variable(v) | Base::Pointee(v) => v,
+        };
+        let uses_base = |expr: CExprId| DFExpr::new(self.context, expr.into())
+            .filter_map(SomeId::expr)
+            .any(|e| match self.context[e].kind {
+                CExprKind::DeclRef(_, var) => var == base_var,
+                _ => false,
+            });
+        let is_pointer = |expr: CExprId| match self.context[expr].kind.get_type() {
+            Some(ty) => match self.context.resolve_type(ty).kind {
+                CTypeKind::Pointer(_) => true,
+                _ => false,
+            },
+            None => false,
+        };
+
+        let targets = |expr: CExprId| match self.context[self.strip_casts(expr)].kind {
+            CExprKind::DeclRef(_, var) => var == base_var,
+            CExprKind::Member(_, struct_expr, field, kind) =>
+                field == fact.tag && self.base(struct_expr, kind) == Some(fact.base),
+            CExprKind::Unary(_, UnOp::Deref, ptr) => match self.context[self.strip_casts(ptr)].kind {
+                CExprKind::DeclRef(_, var) => fact.base == Base::Pointee(var),
+                _ => false,
+            },
+            _ => false,
+        };
+
+        DFExpr::new(self.context, node).filter_map(SomeId::expr).any(|expr| {
+            match self.context[expr].kind {
+                CExprKind::Binary(_, op, lhs, _, _, _)
+                if op == BinOp::Assign || op.underlying_assignment().is_some() => targets(lhs),
+                CExprKind::Unary(_, op, arg) => match op {
+                    UnOp::AddressOf => uses_base(arg),
+                    UnOp::PreIncrement | UnOp::PostIncrement |
+                    UnOp::PreDecrement | UnOp::PostDecrement => targets(arg),
+                    _ => false,
+                },
+                CExprKind::Call(_, _, ref args) =>
+                    args.iter().any(|&arg| is_pointer(arg) && uses_base(arg)),
+                _ => false,
+            }
+        })
+    }
+
+    /// Whether a statement has `case` or `default` labels of the enclosing `switch` nested in it,
+    /// past the labels it starts with
+    fn has_nested_cases(&self, mut stmt: CStmtId) -> bool {
+        loop {
+            match self.context[stmt].kind {
+                CStmtKind::Case(_, sub, _) | CStmtKind::Default(sub) | CStmtKind::Label(sub) => stmt = sub,
+                _ => break,
+            }
+        }
+        immediate_children(self.context, stmt.into()).into_iter().any(|child| self.has_case_labels(child))
+    }
+
+    fn has_case_labels(&self, node: SomeId) -> bool {
+        let stmt = match node {
+            SomeId::Stmt(stmt) => stmt,
+            _ => return false,
+        };
+        match self.context[stmt].kind {
+            CStmtKind::Case(..) | CStmtKind::Default(_) => true,
+            // Its labels belong to it
+            CStmtKind::Switch { .. } => false,
+            _ => immediate_children(self.context, stmt.into()).into_iter().any(|child| self.has_case_labels(child)),
+        }
+    }
+
+    /// `facts` extended with the facts of `new_facts` which hold throughout `node`
+    fn with_facts(&self, facts: &[Fact], new_facts: Vec<Fact>, node: SomeId) -> Vec<Fact> {
+        let mut facts = facts.to_vec();
+        facts.extend(new_facts.into_iter().filter(|fact| !self.invalidates(node, fact)));
+        facts
+    }
+
+    fn walk_stmt(&mut self, stmt: CStmtId, facts: &[Fact]) {
+        let context = self.context;
+        match context[stmt].kind {
+            CStmtKind::If { scrutinee, true_variant, false_variant } => {
+                self.walk_expr(scrutinee, facts);
+                let true_facts = self.condition_facts(scrutinee, true);
+                let true_facts = self.with_facts(facts, true_facts, true_variant.into());
+                self.walk_stmt(true_variant, &true_facts);
+                if let Some(false_variant) = false_variant {
+                    let false_facts = self.condition_facts(scrutinee, false);
+                    let false_facts = self.with_facts(facts, false_facts, false_variant.into());
+                    self.walk_stmt(false_variant, &false_facts);
+                }
+            }
+            CStmtKind::Switch { scrutinee, body } => {
+                self.walk_expr(scrutinee, facts);
+                match (self.tag_read(scrutinee), &context[body].kind) {
+                    (Some((base, tag)), &CStmtKind::Compound(ref stmts)) => {
+                        let fact = Fact { base, tag, values: vec![] };
+                        if self.invalidates(body.into(), &fact) || stmts.iter().any(|&s| self.has_nested_cases(s)) {
+                            self.walk_stmts(stmts, facts)
+                        } else {
+                            self.walk_switch_cases(stmts, fact, facts)
+                        }
+                    }
+                    _ => self.walk_stmt(body, facts),
+                }
+            }
+            // A label can be jumped to from anywhere
+            CStmtKind::Label(sub) => self.walk_stmt(sub, &[]),
+            CStmtKind::Compound(ref stmts) => self.walk_stmts(stmts, facts),
+            CStmtKind::Decls(ref decls) => {
+                for &decl in decls {
+                    if let CDeclKind::Variable { initializer: Some(init), .. } = context[decl].kind {
+                        self.walk_expr(init, facts)
+                    }
+                }
+            }
+            _ => {
+                for child in immediate_children(context, stmt.into()) {
+                    self.walk_child(child, facts)
+                }
+            }
+        }
+    }
+
+    fn walk_stmts(&mut self, stmts: &[CStmtId], facts: &[Fact]) {
+        for &stmt in stmts {
+            self.walk_stmt(stmt, facts)
+        }
+    }
+
+    /// Walk the body of a switch on a tag, knowing which values the tag has under each `case`.
+    /// Falling through from one case to the next keeps the values of both.
+    fn walk_switch_cases(&mut self, stmts: &[CStmtId], fact: Fact, facts: &[Fact]) {
+        let context = self.context;
+        let mut values: Option<Vec<TagValue>> = None;
+        let mut falls_through = false;
+        for &stmt in stmts {
+            let mut stmt = stmt;
+            loop {
+                match context[stmt].kind {
+                    CStmtKind::Case(expr, sub, ref value) => {
+                        let value = match *value {
+                            ConstIntExpr::I(i) => i as i128,
+                            ConstIntExpr::U(u) => u as i128,
+                        };
+                        let value = TagValue { value, constant: self.tag_value(expr).and_then(|v| v.constant) };
+                        values = match (values.take(), falls_through) {
+                            (Some(mut values), true) => { values.push(value); Some(values) }
+                            (None, true) => None,
+                            (_, false) => Some(vec![value]),
+                        };
+                        falls_through = true;
+                        stmt = sub;
+                    }
+                    CStmtKind::Default(sub) | CStmtKind::Label(sub) => {
+                        values = None;
+                        stmt = sub;
+                    }
+                    _ => break,
+                }
+            }
+
+            match values {
+                Some(ref values) => {
+                    let mut facts = facts.to_vec();
+                    facts.push(Fact { values: values.clone(), ..fact.clone() });
+                    self.walk_stmt(stmt, &facts)
+                }
+                None => self.walk_stmt(stmt, facts),
+            }
+
+            falls_through = self.can_fall_through(stmt);
+        }
+    }
+
+    /// Whether control can leave a statement by reaching its end
+    fn can_fall_through(&self, stmt: CStmtId) -> bool {
+        match self.context[stmt].kind {
+            CStmtKind::Break | CStmtKind::Continue | CStmtKind::Return(_) | CStmtKind::Goto(_) => false,
+            CStmtKind::Compound(ref stmts) => stmts.last().map_or(true, |&last| self.can_fall_through(last)),
+            _ => true,
+        }
+    }
+
+    fn walk_child(&mut self, child: SomeId, facts: &[Fact]) {
+        match child {
+            SomeId::Stmt(stmt) => self.walk_stmt(stmt, facts),
+            SomeId::Expr(expr) => self.walk_expr(expr, facts),
+            SomeId::Decl(_) | SomeId::Type(_) => (),
+        }
+    }
+
+    fn walk_expr(&mut self, expr: CExprId, facts: &[Fact]) {
+        let context = self.context;
+        match context[expr].kind {
+            CExprKind::ImplicitCast(_, inner, CastKind::LValueToRValue, _) => {
+                if let Some((union_field, member, base)) = self.union_member_access(inner) {
+                    let checked = base.and_then(|base| facts.iter().rev()
+                        .find(|fact| fact.base == base &&
+                            self.context.parents.get(&fact.tag) == self.context.parents.get(&union_field))
+                        .map(|fact| (fact.tag, fact.values.clone())));
+                    self.reads.push(Read { union_field, member, checked });
+                    return
+                }
+            }
+            CExprKind::Binary(_, BinOp::Assign, lhs, rhs, _, _) => {
+                if self.union_member_access(lhs).is_some() {
+                    return self.walk_expr(rhs, facts)
+                }
+            }
+            CExprKind::Binary(_, op, lhs, rhs, _, _) if op == BinOp::And || op == BinOp::Or => {
+                self.walk_expr(lhs, facts);
+                let rhs_facts = self.condition_facts(lhs, op == BinOp::And);
+                let rhs_facts = self.with_facts(facts, rhs_facts, rhs.into());
+                return self.walk_expr(rhs, &rhs_facts)
+            }
+            CExprKind::Conditional(_, cond, lhs, rhs) => {
+                self.walk_expr(cond, facts);
+                let lhs_facts = self.condition_facts(cond, true);
+                let lhs_facts = self.with_facts(facts, lhs_facts, lhs.into());
+                self.walk_expr(lhs, &lhs_facts);
+                let rhs_facts = self.condition_facts(cond, false);
+                let rhs_facts = self.with_facts(facts, rhs_facts, rhs.into());
+                return self.walk_expr(rhs, &rhs_facts)
+            }
+            CExprKind::Member(_, union_expr, member, _) if self.is_union_member(member) => {
+                // Any other use of a member could read it, through a pointer for instance
+                match self.union_member_access(expr) {
+                    Some((union_field, _, _)) => { self.unsupported_fields.insert(union_field); }
+                    None => { self.unsupported_unions.insert(self.context.parents[&member]); }
+                }
+                return self.walk_expr(union_expr, facts)
+            }
+            _ => (),
+        }
+
+        for child in immediate_children(context, expr.into()) {
+            self.walk_child(child, facts)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INT: CTypeId = CTypeId(1);
+    const UNION: CTypeId = CTypeId(2);
+    const POINTER: CTypeId = CTypeId(4);
+    const VOID_POINTER: CTypeId = CTypeId(5);
+
+    const UNION_DECL: CRecordId = CDeclId(100);
+    const I: CFieldId = CDeclId(101);
+    const F: CFieldId = CDeclId(102);
+    const TAG: CFieldId = CDeclId(111);
+    const U: CFieldId = CDeclId(112);
+    const OTHER: CFieldId = CDeclId(113);
+    const P: CDeclId = CDeclId(120);
+
+    fn qual(ctype: CTypeId) -> CQualTypeId {
+        CQualTypeId { qualifiers: Qualifiers::default(), ctype }
+    }
+
+    fn located<T>(kind: T) -> Located<T> {
+        Located { loc: None, kind }
+    }
+
+    /// The declarations of `struct S { int tag; union { int i; int f; } u; int other; } *p;`,
+    /// along with the functions added by `function`
+    struct Ast {
+        context: TypedAstContext,
+        next_id: u64,
+    }
+
+    impl Ast {
+        fn new() -> Ast {
+            let mut context = TypedAstContext::new();
+            context.c_types.insert(INT, located(CTypeKind::Int));
+            context.c_types.insert(UNION, located(CTypeKind::Union(UNION_DECL)));
+            context.c_types.insert(CTypeId(3), located(CTypeKind::Struct(CDeclId(110))));
+            context.c_types.insert(POINTER, located(CTypeKind::Pointer(qual(CTypeId(3)))));
+            context.c_types.insert(CTypeId(6), located(CTypeKind::Void));
+            context.c_types.insert(VOID_POINTER, located(CTypeKind::Pointer(qual(CTypeId(6)))));
+
+            let fields = vec![(I, "i", INT, UNION_DECL), (F, "f", INT, UNION_DECL), (TAG, "tag", INT, CDeclId(110)),
+                              (U, "u", UNION, CDeclId(110)), (OTHER, "other", INT, CDeclId(110))];
+            for (field, name, typ, record) in fields {
+                context.c_decls.insert(field, located(CDeclKind::Field { name: name.to_string(), typ: qual(typ) }));
+                context.parents.insert(field, record);
+            }
+            context.c_decls.insert(UNION_DECL, located(CDeclKind::Union { name: None, fields: Some(vec![I, F]) }));
+            context.c_decls.insert(CDeclId(110), located(CDeclKind::Struct {
+                name: Some("S".to_string()), fields: Some(vec![TAG, U, OTHER]), is_packed: false, manual_alignment: None,
+            }));
+            context.c_decls.insert(P, located(CDeclKind::Variable {
+                is_static: false, is_extern: false, is_defn: true, ident: "p".to_string(), initializer: None, typ: qual(POINTER),
+            }));
+            Ast { context, next_id: 1000 }
+        }
+
+        fn expr(&mut self, kind: CExprKind) -> CExprId {
+            self.next_id += 1;
+            self.context.c_exprs.insert(CExprId(self.next_id), located(kind));
+            CExprId(self.next_id)
+        }
+
+        fn stmt(&mut self, kind: CStmtKind) -> CStmtId {
+            self.next_id += 1;
+            self.context.c_stmts.insert(CStmtId(self.next_id), located(kind));
+            CStmtId(self.next_id)
+        }
+
+        fn p(&mut self) -> CExprId {
+            let p = self.expr(CExprKind::DeclRef(qual(POINTER), P));
+            self.expr(CExprKind::ImplicitCast(qual(POINTER), p, CastKind::LValueToRValue, None))
+        }
+
+        /// `p->field`, as an lvalue
+        fn field(&mut self, field: CFieldId, ty: CTypeId) -> CExprId {
+            let p = self.p();
+            self.expr(CExprKind::Member(qual(ty), p, field, MemberKind::Arrow))
+        }
+
+        fn rvalue(&mut self, expr: CExprId) -> CExprId {
+            self.expr(CExprKind::ImplicitCast(qual(INT), expr, CastKind::LValueToRValue, None))
+        }
+
+        /// `p->u.member;`
+        fn read(&mut self, member: CFieldId) -> CStmtId {
+            let union = self.field(U, UNION);
+            let member = self.expr(CExprKind::Member(qual(INT), union, member, MemberKind::Dot));
+            let read = self.rvalue(member);
+            self.stmt(CStmtKind::Expr(read))
+        }
+
+        fn int(&mut self, value: u64) -> CExprId {
+            self.expr(CExprKind::Literal(qual(INT), CLiteral::Integer(value, IntBase::Dec)))
+        }
+
+        fn tag(&mut self) -> CExprId {
+            let tag = self.field(TAG, INT);
+            self.rvalue(tag)
+        }
+
+        /// `if (p->tag == value) { stmts }`
+        fn if_tag(&mut self, value: u64, stmts: Vec<CStmtId>) -> CStmtId {
+            let tag = self.tag();
+            let value = self.int(value);
+            let scrutinee = self.expr(CExprKind::Binary(qual(INT), BinOp::EqualEqual, tag, value, None, None));
+            let true_variant = self.stmt(CStmtKind::Compound(stmts));
+            self.stmt(CStmtKind::If { scrutinee, true_variant, false_variant: None })
+        }
+
+        fn case(&mut self, value: u64, sub: CStmtId) -> CStmtId {
+            let expr = self.int(value);
+            self.stmt(CStmtKind::Case(expr, sub, ConstIntExpr::U(value)))
+        }
+
+        /// `switch (p->tag) { stmts }`
+        fn switch_tag(&mut self, stmts: Vec<CStmtId>) -> CStmtId {
+            let scrutinee = self.tag();
+            let body = self.stmt(CStmtKind::Compound(stmts));
+            self.stmt(CStmtKind::Switch { scrutinee, body })
+        }
+
+        fn function(&mut self, stmts: Vec<CStmtId>) {
+            let body = self.stmt(CStmtKind::Compound(stmts));
+            self.next_id += 1;
+            self.context.c_decls.insert(CDeclId(self.next_id), located(CDeclKind::Function {
+                is_extern: true, is_inline: false, is_implicit: false, typ: CTypeId(7),
+                name: format!("f{}", self.next_id), parameters: vec![P], body: Some(body),
+            }));
+        }
+
+        fn tagged_union(&self) -> Option<Vec<(CFieldId, Vec<i128>)>> {
+            let tagged = find_tagged_unions(&self.context).remove(&U)?;
+            assert_eq!(tagged.tag, TAG);
+            Some(tagged.members.into_iter()
+                .map(|(member, values)| (member, values.into_iter().map(|v| v.value).collect()))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn checked_reads() {
+        let mut ast = Ast::new();
+        let read_i = ast.read(I);
+        let if_i = ast.if_tag(0, vec![read_i]);
+        let read_f = ast.read(F);
+        let brk = ast.stmt(CStmtKind::Break);
+        let case_f = ast.case(1, read_f);
+        let case_f2 = ast.case(2, case_f);
+        let switch = ast.switch_tag(vec![case_f2, brk]);
+        ast.function(vec![if_i, switch]);
+        assert_eq!(ast.tagged_union(), Some(vec![(I, vec![0]), (F, vec![2, 1])]));
+
+        // An unchecked read
+        let read = ast.read(F);
+        ast.function(vec![read]);
+        assert_eq!(ast.tagged_union(), None);
+    }
+
+    #[test]
+    fn calls_and_addresses_invalidate() {
+        // if (p->tag == 0) { g(p); p->u.i; }
+        let mut ast = Ast::new();
+        let g = ast.expr(CExprKind::DeclRef(qual(CTypeId(7)), CDeclId(130)));
+        let p = ast.p();
+        let arg = ast.expr(CExprKind::ImplicitCast(qual(VOID_POINTER), p, CastKind::BitCast, None));
+        let call = ast.expr(CExprKind::Call(qual(INT), g, vec![arg]));
+        let call = ast.stmt(CStmtKind::Expr(call));
+        let read = ast.read(I);
+        let checked = ast.if_tag(0, vec![call, read]);
+        ast.function(vec![checked]);
+        assert_eq!(ast.tagged_union(), None);
+
+        // if (p->tag == 0) { &p->other; p->u.i; }
+        let mut ast = Ast::new();
+        let other = ast.field(OTHER, INT);
+        let address = ast.expr(CExprKind::Unary(qual(VOID_POINTER), UnOp::AddressOf, other));
+        let address = ast.stmt(CStmtKind::Expr(address));
+        let read = ast.read(I);
+        let checked = ast.if_tag(0, vec![address, read]);
+        ast.function(vec![checked]);
+        assert_eq!(ast.tagged_union(), None);
+
+        // Passing a value read through the pointer is fine: if (p->tag == 0) { g(p->other); p->u.i; }
+        let mut ast = Ast::new();
+        let g = ast.expr(CExprKind::DeclRef(qual(CTypeId(7)), CDeclId(130)));
+        let other = ast.field(OTHER, INT);
+        let other = ast.rvalue(other);
+        let call = ast.expr(CExprKind::Call(qual(INT), g, vec![other]));
+        let call = ast.stmt(CStmtKind::Expr(call));
+        let read = ast.read(I);
+        let checked = ast.if_tag(0, vec![call, read]);
+        ast.function(vec![checked]);
+        assert_eq!(ast.tagged_union(), Some(vec![(I, vec![0])]));
+    }
+
+    #[test]
+    fn nested_cases() {
+        // switch (p->tag) { case 0: { p->u.i; case 1: p->u.i; } break; }
+        let mut ast = Ast::new();
+        let read = ast.read(I);
+        let nested = ast.case(1, read);
+        let read = ast.read(I);
+        let block = ast.stmt(CStmtKind::Compound(vec![read, nested]));
+        let case = ast.case(0, block);
+        let brk = ast.stmt(CStmtKind::Break);
+        let switch = ast.switch_tag(vec![case, brk]);
+        ast.function(vec![switch]);
+        assert_eq!(ast.tagged_union(), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Rewriting calls to well-known C library functions into equivalent Rust.
+//!
+//! With a call rewriting table, a call like `memcpy(dst, src, n)` to a function the translated
+//! code declares but does not define becomes `::std::ptr::copy_nonoverlapping(src, dst, n)`
+//! instead of going through an `extern "C"` declaration. The table starts out with functions of
+//! the C standard library (see `CallRewrites::libc`) and can be extended by a JSON file of the form
+//!
+//! ```json
+//! {
+//!   "functions": {
+//!     "memcpy": { "path": "std::ptr::copy_nonoverlapping", "args": [1, 0, "2 as usize"], "returns": 0 },
+//!     "memset": { "path": "std::ptr::write_bytes", "args": [0, "1 as u8", "2 as usize"], "returns": 0 },
+//!     "abs":    { "method": "wrapping_abs" },
+//!     "strlen": { "path": "std::ffi::CStr::from_ptr", "then": ["to_bytes", "len"], "cast_result": true }
+//!   }
+//! }
+//! ```
+//!
+//! A rewritten call either calls the function at `path` (which uses `core` rather than `std` with
+//! `--no-std`, and is left alone then when `core` lacks the module) or calls `method` on its first
+//! argument. `args` lists the C arguments passed to it by position, each of them optionally cast,
+//! and defaults to all of them in order. The methods in `then` are then called on the result,
+//! which `cast_result` casts to the return type of the C function. Finally, `returns` names the
+//! argument the C function returns when the Rust one does not.
+
+use std::collections::HashMap;
+use std::fs::File;
+use serde_json::{self, Value};
+
+/// The rewrites of the C standard library functions with an equivalent in Rust's
+const LIBC_REWRITES: &str = r#"{
+    "functions": {
+        "memcpy":  { "path": "std::ptr::copy_nonoverlapping", "args": [1, 0, "2 as usize"], "returns": 0 },
+        "memmove": { "path": "std::ptr::copy", "args": [1, 0, "2 as usize"], "returns": 0 },
+        "memset":  { "path": "std::ptr::write_bytes", "args": [0, "1 as u8", "2 as usize"], "returns": 0 },
+        "abs":     { "method": "wrapping_abs" },
+        "labs":    { "method": "wrapping_abs" },
+        "llabs":   { "method": "wrapping_abs" },
+        "strlen":  { "path": "std::ffi::CStr::from_ptr", "then": ["to_bytes", "len"], "cast_result": true }
+    }
+}"#;
+
+/// What a rewritten call calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Callee {
+    /// The function at a path, given by its segments
+    Function(Vec<String>),
+    /// A method of the first argument
+    Method(String),
+}
+
+/// An argument of a rewritten call
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallArg {
+    /// Position of the C argument
+    pub index: usize,
+    /// Type to cast the argument to, given as a path
+    pub cast: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallRewrite {
+    pub callee: Callee,
+    /// The arguments to pass, or `None` for all of the C arguments in order
+    pub args: Option<Vec<CallArg>>,
+    /// Methods without arguments called on the result
+    pub then: Vec<String>,
+    /// Whether to cast the result to the return type of the C function
+    pub cast_result: bool,
+    /// The argument the C function returns
+    pub returns: Option<usize>,
+}
+
+/// The modules of `std` that `core` has no counterpart of
+const STD_ONLY_MODULES: &[&str] = &["ffi", "io", "os", "fs", "env", "process"];
+
+impl CallRewrite {
+    /// Whether the function called is in a module of `std` that `core` lacks, so that the call has
+    /// to be left alone with `--no-std`
+    pub fn needs_std(&self) -> bool {
+        match self.callee {
+            Callee::Function(ref path) =>
+                path.len() > 1 && path[0] == "std" && STD_ONLY_MODULES.contains(&path[1].as_str()),
+            Callee::Method(_) => false,
+        }
+    }
+
+    /// The arguments of the rewritten call, if a call with `arg_count` C arguments has all the
+    /// arguments it needs
+    pub fn arguments(&self, arg_count: usize) -> Option<Vec<CallArg>> {
+        let args = match self.args {
+            Some(ref args) => args.clone(),
+            None => (0..arg_count).map(|index| CallArg { index, cast: None }).collect(),
+        };
+        let has_receiver = match self.callee {
+            Callee::Method(_) => !args.is_empty(),
+            Callee::Function(_) => true,
+        };
+        let in_range = args.iter().map(|arg| arg.index).chain(self.returns).all(|i| i < arg_count);
+        if has_receiver && in_range { Some(args) } else { None }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CallRewrites {
+    pub functions: HashMap<String, CallRewrite>,
+}
+
+impl CallRewrites {
+    /// The rewrites of C standard library functions
+    pub fn libc() -> CallRewrites {
+        let value = serde_json::from_str(LIBC_REWRITES).expect("Invalid libc call rewrites");
+        CallRewrites::from_json(&value).expect("Invalid libc call rewrites")
+    }
+
+    pub fn from_file(path: &str) -> Result<CallRewrites, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open call rewrites {}: {}", path, e))?;
+        let value: Value = serde_json::from_reader(file)
+            .map_err(|e| format!("Failed to parse call rewrites {}: {}", path, e))?;
+        CallRewrites::from_json(&value)
+    }
+
+    pub fn from_json(value: &Value) -> Result<CallRewrites, String> {
+        let entries = value.get("functions").and_then(Value::as_object)
+            .ok_or_else(|| format!("Call rewrites are missing a \"functions\" object"))?;
+
+        let mut functions = HashMap::new();
+        for (name, entry) in entries {
+            functions.insert(name.clone(), parse_rewrite(name, entry)?);
+        }
+        Ok(CallRewrites { functions })
+    }
+
+    /// Add the rewrites of `other`, replacing ours for the same functions
+    pub fn extend(&mut self, other: CallRewrites) {
+        self.functions.extend(other.functions);
+    }
+
+    pub fn get(&self, function: &str) -> Option<&CallRewrite> {
+        self.functions.get(function)
+    }
+}
+
+fn parse_rewrite(name: &str, entry: &Value) -> Result<CallRewrite, String> {
+    let callee = match (entry.get("path"), entry.get("method")) {
+        (Some(path), None) => {
+            let path = path.as_str()
+                .ok_or_else(|| format!("Path of rewrite for {} should be a string", name))?;
+            Callee::Function(path.split("::").map(str::to_owned).collect())
+        }
+        (None, Some(method)) => {
+            let method = method.as_str()
+                .ok_or_else(|| format!("Method of rewrite for {} should be a string", name))?;
+            Callee::Method(method.to_owned())
+        }
+        _ => return Err(format!("Rewrite for {} needs one of \"path\" or \"method\"", name)),
+    };
+
+    let args = match entry.get("args") {
+        Some(args) => {
+            let args = args.as_array()
+                .ok_or_else(|| format!("Arguments of rewrite for {} should be an array", name))?;
+            let args = args.iter()
+                .map(|arg| parse_arg(arg)
+                    .ok_or_else(|| format!("Invalid argument in rewrite for {}: {}", name, arg)))
+                .collect::<Result<Vec<CallArg>, String>>()?;
+            Some(args)
+        }
+        None => None,
+    };
+
+    let then: Vec<String> = match entry.get("then") {
+        Some(then) => then.as_array()
+            .and_then(|methods| methods.iter().map(|m| m.as_str().map(str::to_owned)).collect())
+            .ok_or_else(|| format!("\"then\" of rewrite for {} should be an array of strings", name))?,
+        None => vec![],
+    };
+
+    let cast_result = match entry.get("cast_result") {
+        Some(cast_result) => cast_result.as_bool()
+            .ok_or_else(|| format!("\"cast_result\" of rewrite for {} should be a boolean", name))?,
+        None => false,
+    };
+
+    let returns = match entry.get("returns") {
+        Some(returns) => Some(returns.as_u64()
+            .ok_or_else(|| format!("\"returns\" of rewrite for {} should be an argument position", name))? as usize),
+        None => None,
+    };
+
+    Ok(CallRewrite { callee, args, then, cast_result, returns })
+}
+
+/// An argument is either its position or a string like `1 as u8`
+fn parse_arg(arg: &Value) -> Option<CallArg> {
+    if let Some(index) = arg.as_u64() {
+        return Some(CallArg { index: index as usize, cast: None })
+    }
+    let mut words = arg.as_str()?.split_whitespace();
+    let index = words.next()?.parse().ok()?;
+    match (words.next(), words.next(), words.next()) {
+        (None, _, _) => Some(CallArg { index, cast: None }),
+        (Some("as"), Some(ty), None) => Some(CallArg { index, cast: Some(ty.to_owned()) }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn libc_rewrites() {
+        let rewrites = CallRewrites::libc();
+
+        let memset = rewrites.get("memset").unwrap();
+        assert_eq!(memset.callee, Callee::Function(vec!["std".to_owned(), "ptr".to_owned(), "write_bytes".to_owned()]));
+        assert_eq!(memset.arguments(3).unwrap()[1], CallArg { index: 1, cast: Some("u8".to_owned()) });
+        assert_eq!(memset.arguments(2), None);
+
+        let abs = rewrites.get("abs").unwrap();
+        assert_eq!(abs.arguments(1), Some(vec![CallArg { index: 0, cast: None }]));
+        assert_eq!(abs.arguments(0), None);
+
+        assert!(!memset.needs_std());
+        assert!(!abs.needs_std());
+        assert!(rewrites.get("strlen").unwrap().needs_std());
+    }
+
+    #[test]
+    fn invalid_rewrites() {
+        let invalid = [
+            r#"{ "functions": { "f": { "args": [0] } } }"#,
+            r#"{ "functions": { "f": { "method": "g", "args": ["0 as"] } } }"#,
+            r#"{ "functions": { "f": { "method": "g", "then": [1] } } }"#,
+        ];
+        for rewrites in &invalid {
+            assert!(CallRewrites::from_json(&serde_json::from_str(rewrites).unwrap()).is_err());
+        }
+    }
+}
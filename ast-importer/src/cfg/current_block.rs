@@ -0,0 +1,350 @@
+//! This module removes uses of `current_block` that can be resolved statically. Even when Relooper
+//! manages to produce structured code, the output often contains sequences like
+//!
+//! ```norun
+//! current_block = 2;
+//! ...
+//! match current_block { 2 => { .. } _ => { .. } }
+//! ```
+//!
+//! where only one arm of the `match` can ever be taken. By tracking which values `current_block`
+//! can have at every point of a `StructuredAST`, we drop `match` arms that can't be taken and
+//! assignments of a value `current_block` already has. When no `match` on `current_block` is left,
+//! all the assignments go too, and so can the variable itself.
+
+use super::*;
+use cfg::structures::StructuredAST;
+use cfg::structures::StructuredAST::*;
+
+/// Values `current_block` can have at some point, where `None` means any value. No values at all
+/// means the point can't be reached.
+type Values<L> = Option<HashSet<L>>;
+
+fn unreachable<L: Eq + Hash>() -> Values<L> {
+    Some(HashSet::new())
+}
+
+fn join<L: Eq + Hash>(a: Values<L>, b: Values<L>) -> Values<L> {
+    match (a, b) {
+        (Some(mut a), Some(b)) => {
+            a.extend(b);
+            Some(a)
+        }
+        _ => None,
+    }
+}
+
+/// Values going out of a loop through `break` and back to its start through `continue`
+struct LoopExits<L> {
+    label: Option<L>,
+    breaks: Values<L>,
+    continues: Values<L>,
+}
+
+/// Where the `break`s and `continue`s in the structure being simplified go
+struct Exits<L> {
+    loops: Vec<LoopExits<L>>,
+    blocks: Vec<(String, Values<L>)>,
+}
+
+impl<L: Copy + Eq + Hash> Exits<L> {
+    fn exit(&mut self, exit_style: ExitStyle, label: Option<L>, values: Values<L>) {
+        let target = match label {
+            None => self.loops.last_mut(),
+            Some(label) => self.loops.iter_mut().rev().find(|l| l.label == Some(label)),
+        };
+        if let Some(target) = target {
+            match exit_style {
+                ExitStyle::Break => target.breaks = join(target.breaks.take(), values),
+                ExitStyle::Continue => target.continues = join(target.continues.take(), values),
+            }
+        }
+    }
+
+    fn block_exit(&mut self, label: &str, values: Values<L>) {
+        if let Some(&mut (_, ref mut breaks)) = self.blocks.iter_mut().rev().find(|&&mut (ref l, _)| l == label) {
+            *breaks = join(breaks.take(), values);
+        }
+    }
+}
+
+/// Number of assignments to and `match`es on `current_block`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CurrentBlockUses {
+    pub assignments: usize,
+    pub dispatches: usize,
+}
+
+impl<E, P, L: Copy + Eq + Hash, S> StructuredAST<E, P, L, S> {
+
+    /// Simplify uses of `current_block`, removing them altogether if it is never matched on
+    pub fn propagate_current_block(self) -> Self {
+        let mut exits = Exits { loops: vec![], blocks: vec![] };
+        let (ast, _) = self.simplify(None, &mut exits);
+
+        if ast.current_block_uses().dispatches == 0 {
+            ast.remove_gotos()
+        } else {
+            ast
+        }
+    }
+
+    pub fn current_block_uses(&self) -> CurrentBlockUses {
+        let mut uses = CurrentBlockUses::default();
+        self.count_uses(&mut uses);
+        uses
+    }
+
+    fn count_uses(&self, uses: &mut CurrentBlockUses) {
+        match *self {
+            Empty | Singleton(_) | Exit(..) | BlockExit(_) => { }
+            Goto(_) => uses.assignments += 1,
+            Append(ref a, ref b) | If(_, ref a, ref b) => {
+                a.count_uses(uses);
+                b.count_uses(uses);
+            }
+            Match(_, ref cases) => for &(_, ref body) in cases {
+                body.count_uses(uses);
+            },
+            GotoTable(ref cases, ref then) => {
+                uses.dispatches += 1;
+                for &(_, ref body) in cases {
+                    body.count_uses(uses);
+                }
+                then.count_uses(uses);
+            }
+            Loop(_, ref body) | LabelledBlock(_, ref body) => body.count_uses(uses),
+        }
+    }
+
+    fn remove_gotos(self) -> Self {
+        match self {
+            Goto(_) => Empty,
+            Append(a, b) => Append(Box::new(a.remove_gotos()), Box::new(b.remove_gotos())),
+            If(c, a, b) => If(c, Box::new(a.remove_gotos()), Box::new(b.remove_gotos())),
+            Match(e, cases) => Match(e, cases.into_iter().map(|(p, b)| (p, b.remove_gotos())).collect()),
+            GotoTable(cases, then) => GotoTable(
+                cases.into_iter().map(|(l, b)| (l, b.remove_gotos())).collect(),
+                Box::new(then.remove_gotos()),
+            ),
+            Loop(l, body) => Loop(l, Box::new(body.remove_gotos())),
+            LabelledBlock(l, body) => LabelledBlock(l, Box::new(body.remove_gotos())),
+            other => other,
+        }
+    }
+
+    /// Values `current_block` can have after this structure, given those it can have before it
+    fn flow(&self, values: Values<L>, exits: &mut Exits<L>) -> Values<L> {
+        match *self {
+            Empty | Singleton(_) => values,
+            Goto(to) => if values == unreachable() { values } else { Some(vec![to].into_iter().collect()) },
+            Append(ref a, ref b) => {
+                let values = a.flow(values, exits);
+                b.flow(values, exits)
+            }
+            If(_, ref a, ref b) => {
+                let after_a = a.flow(values.clone(), exits);
+                join(after_a, b.flow(values, exits))
+            }
+            Match(_, ref cases) => {
+                if cases.is_empty() {
+                    return values
+                }
+                let mut after = unreachable();
+                for &(_, ref body) in cases {
+                    after = join(after, body.flow(values.clone(), exits));
+                }
+                after
+            }
+            GotoTable(ref cases, ref then) => {
+                let mut after = unreachable();
+                for &(lbl, ref body) in cases {
+                    after = join(after, body.flow(restrict(&values, lbl), exits));
+                }
+                let labels: Vec<L> = cases.iter().map(|&(lbl, _)| lbl).collect();
+                join(after, then.flow(exclude(&values, &labels), exits))
+            }
+            Loop(label, ref body) => {
+                let entry = loop_entry(label, body, values, exits);
+                exits.loops.push(LoopExits { label, breaks: unreachable(), continues: unreachable() });
+                body.flow(entry, exits);
+                exits.loops.pop().expect("loop exits were pushed").breaks
+            }
+            Exit(exit_style, label) => {
+                exits.exit(exit_style, label, values);
+                unreachable()
+            }
+            LabelledBlock(ref label, ref body) => {
+                exits.blocks.push((label.clone(), unreachable()));
+                let after = body.flow(values, exits);
+                let (_, breaks) = exits.blocks.pop().expect("block exits were pushed");
+                join(after, breaks)
+            }
+            BlockExit(ref label) => {
+                exits.block_exit(label, values);
+                unreachable()
+            }
+        }
+    }
+
+    /// Like `flow`, but also remove the `current_block` dispatches and assignments that aren't
+    /// needed given the values `current_block` can have.
+    fn simplify(self, values: Values<L>, exits: &mut Exits<L>) -> (Self, Values<L>) {
+        match self {
+            Goto(to) => {
+                let known: Values<L> = Some(vec![to].into_iter().collect());
+                if values == known || values == unreachable() {
+                    (Empty, values)
+                } else {
+                    (Goto(to), known)
+                }
+            }
+            Append(a, b) => {
+                let (a, values) = a.simplify(values, exits);
+                let (b, values) = b.simplify(values, exits);
+                (Append(Box::new(a), Box::new(b)), values)
+            }
+            If(c, a, b) => {
+                let (a, after_a) = a.simplify(values.clone(), exits);
+                let (b, after_b) = b.simplify(values, exits);
+                (If(c, Box::new(a), Box::new(b)), join(after_a, after_b))
+            }
+            Match(e, cases) => {
+                if cases.is_empty() {
+                    return (Match(e, cases), values)
+                }
+                let mut after = unreachable();
+                let mut simplified = vec![];
+                for (pats, body) in cases {
+                    let (body, after_body) = body.simplify(values.clone(), exits);
+                    after = join(after, after_body);
+                    simplified.push((pats, body));
+                }
+                (Match(e, simplified), after)
+            }
+            GotoTable(cases, then) => {
+                let mut after = unreachable();
+                let labels: Vec<L> = cases.iter().map(|&(lbl, _)| lbl).collect();
+
+                let mut live_cases = vec![];
+                for (lbl, body) in cases {
+                    let case_values = restrict(&values, lbl);
+                    if case_values != unreachable() {
+                        let (body, after_body) = body.simplify(case_values, exits);
+                        after = join(after, after_body);
+                        live_cases.push((lbl, body));
+                    }
+                }
+
+                let then_values = exclude(&values, &labels);
+                let then_live = then_values != unreachable();
+                let (then, after_then) = then.simplify(then_values, exits);
+                after = join(after, after_then);
+
+                let ast = match (live_cases.len(), then_live) {
+                    (0, _) => then,
+                    (1, false) => live_cases.pop().expect("one case is live").1,
+                    (_, true) => GotoTable(live_cases, Box::new(then)),
+                    (_, false) => GotoTable(live_cases, Box::new(Empty)),
+                };
+                (ast, after)
+            }
+            Loop(label, body) => {
+                let entry = loop_entry(label, &body, values, exits);
+                exits.loops.push(LoopExits { label, breaks: unreachable(), continues: unreachable() });
+                let (body, _) = body.simplify(entry, exits);
+                let breaks = exits.loops.pop().expect("loop exits were pushed").breaks;
+                (Loop(label, Box::new(body)), breaks)
+            }
+            LabelledBlock(label, body) => {
+                exits.blocks.push((label.clone(), unreachable()));
+                let (body, after) = body.simplify(values, exits);
+                let (_, breaks) = exits.blocks.pop().expect("block exits were pushed");
+                (LabelledBlock(label, Box::new(body)), join(after, breaks))
+            }
+            other => {
+                let after = other.flow(values, exits);
+                (other, after)
+            }
+        }
+    }
+}
+
+/// Values `current_block` can have at the start of a loop body: those it has when entering the
+/// loop, and those it has when getting back to the start through `continue` or the end of the body.
+fn loop_entry<E, P, L: Copy + Eq + Hash, S>(
+    label: Option<L>,
+    body: &StructuredAST<E, P, L, S>,
+    values: Values<L>,
+    exits: &mut Exits<L>,
+) -> Values<L> {
+    let mut entry = values.clone();
+    loop {
+        exits.loops.push(LoopExits { label, breaks: unreachable(), continues: unreachable() });
+        let end = body.flow(entry.clone(), exits);
+        let continues = exits.loops.pop().expect("loop exits were pushed").continues;
+
+        let next_entry = join(join(values.clone(), continues), end);
+        if next_entry == entry {
+            return entry
+        }
+        entry = next_entry;
+    }
+}
+
+/// Values `current_block` can have in the arm for `lbl` of a dispatch
+fn restrict<L: Copy + Eq + Hash>(values: &Values<L>, lbl: L) -> Values<L> {
+    match *values {
+        Some(ref values) if !values.contains(&lbl) => unreachable(),
+        _ => Some(vec![lbl].into_iter().collect()),
+    }
+}
+
+/// Values `current_block` can have in the default arm of a dispatch on `labels`
+fn exclude<L: Copy + Eq + Hash>(values: &Values<L>, labels: &[L]) -> Values<L> {
+    values.as_ref().map(|values| values.iter().filter(|lbl| !labels.contains(lbl)).cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type AST = StructuredAST<(), (), u64, &'static str>;
+
+    fn append(a: AST, b: AST) -> AST {
+        Append(Box::new(a), Box::new(b))
+    }
+
+    #[test]
+    fn known_value_removes_dispatch() {
+        let ast: AST = append(
+            append(Singleton("a"), Goto(1)),
+            GotoTable(vec![(1, Singleton("b")), (2, Singleton("c"))], Box::new(Empty)),
+        );
+        assert_eq!(ast.current_block_uses(), CurrentBlockUses { assignments: 1, dispatches: 1 });
+
+        let ast = ast.propagate_current_block();
+        assert_eq!(ast.current_block_uses(), CurrentBlockUses::default());
+        match ast {
+            Append(_, ref b) => match **b {
+                Singleton("b") => { }
+                _ => panic!("dispatch should have been replaced by its only live arm"),
+            },
+            _ => panic!("expected an append"),
+        }
+    }
+
+    #[test]
+    fn loop_values_keep_dispatch() {
+        // The dispatch can see `1` on entry and `2` from the previous iteration
+        let ast: AST = append(
+            Goto(1),
+            Loop(None, Box::new(append(
+                GotoTable(vec![(1, Singleton("b")), (2, Singleton("c"))], Box::new(Empty)),
+                Goto(2),
+            ))),
+        );
+        let ast = ast.propagate_current_block();
+        assert_eq!(ast.current_block_uses(), CurrentBlockUses { assignments: 2, dispatches: 1 });
+    }
+}
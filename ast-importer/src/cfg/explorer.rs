@@ -0,0 +1,468 @@
+//! This module writes the self-contained HTML reports requested with `--html-function-cfgs`. A
+//! report shows four views of one function:
+//!
+//!   - the C source of the function body
+//!   - its CFG, with the liveness information of every block
+//!   - the `Structure`s the relooper chose
+//!   - the Rust statements finally produced
+//!
+//! Hovering over a block (or any mention of its label) highlights it in every view. Rust statements
+//! don't record which block they came from, so they are matched back to blocks by their
+//! pretty-printed text. This is good enough to follow the relooper, but a statement which appears
+//! in several blocks gets highlighted along with each of them.
+
+use super::*;
+
+/// What a report shows of a basic block
+struct BlockView {
+    label: String,
+    body: Vec<String>,
+    terminator: String,
+    edges: Vec<(String, String)>,
+    live: Vec<String>,
+    defined: Vec<String>,
+}
+
+/// Report on how the body of one function was relooped. The views are filled in as translation
+/// progresses, since the CFG itself is consumed by the relooper.
+pub struct Explorer {
+    name: String,
+    c_source: Vec<(u64, String)>,
+    entries: Vec<String>,
+    blocks: Vec<BlockView>,
+    cfg_json: String,
+    structures: String,
+    rust: Vec<String>,
+}
+
+impl Explorer {
+
+    /// Start a report from the C body of a function and the CFG built from it
+    pub fn new(
+        name: &str,
+        ctx: &TypedAstContext,
+        body_ids: &[CStmtId],
+        graph: &Cfg<Label, StmtOrDecl>,
+        store: &DeclStmtStore,
+    ) -> Explorer {
+        let names = |decls: &HashSet<CDeclId>| -> Vec<String> {
+            let mut names: Vec<String> = decls
+                .iter()
+                .filter_map(|decl| ctx.index(*decl).kind.get_name())
+                .cloned()
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut labels: Vec<&Label> = graph.nodes.keys().collect();
+        labels.sort();
+
+        let blocks = labels
+            .into_iter()
+            .map(|lbl| {
+                let bb = &graph.nodes[lbl];
+                let (terminator, edges) = match bb.terminator {
+                    End => (String::new(), vec![]),
+                    Jump(tgt) => (String::new(), vec![(String::new(), tgt.debug_print())]),
+                    Branch(ref cond, tru, fal) => (
+                        format!("if {}", pprust::expr_to_string(cond)),
+                        vec![
+                            (String::from("true"), tru.debug_print()),
+                            (String::from("false"), fal.debug_print()),
+                        ],
+                    ),
+                    Switch { ref expr, ref cases } => (
+                        format!("match {}", pprust::expr_to_string(expr)),
+                        cases
+                            .iter()
+                            .map(|&(ref pats, tgt)| {
+                                let pats: Vec<String> = pats.iter().map(|p| pprust::pat_to_string(p)).collect();
+                                (pats.join(" | "), tgt.debug_print())
+                            })
+                            .collect(),
+                    ),
+                };
+
+                BlockView {
+                    label: lbl.debug_print(),
+                    body: bb.body.iter().flat_map(|sd| sd.to_string(store)).collect(),
+                    terminator,
+                    edges,
+                    live: names(&bb.live),
+                    defined: names(&bb.defined),
+                }
+            })
+            .collect();
+
+        let mut entries: Vec<&Label> = graph.entries.iter().collect();
+        entries.sort();
+
+        let cfg_mapped = graph.map_stmts(|sd: &StmtOrDecl| -> Vec<String> { sd.to_string(store) });
+
+        Explorer {
+            name: String::from(name),
+            c_source: c_source(ctx, body_ids),
+            entries: entries.into_iter().map(Label::debug_print).collect(),
+            blocks,
+            cfg_json: serde_json::to_string(&cfg_mapped).unwrap_or_default(),
+            structures: String::new(),
+            rust: vec![],
+        }
+    }
+
+    /// Record the structures produced by the relooper
+    pub fn set_structures(&mut self, structures: &[Structure<StmtOrComment>]) {
+        let mut html = String::new();
+        structures_html(structures, &mut html);
+        self.structures = html;
+    }
+
+    /// Record the Rust statements finally produced for the function body
+    pub fn set_rust(&mut self, stmts: &[Stmt]) {
+        self.rust = stmts
+            .iter()
+            .flat_map(|s| pprust::stmt_to_string(s).lines().map(String::from).collect::<Vec<_>>())
+            .collect();
+    }
+
+    pub fn write(&self, file_path: String) -> io::Result<()> {
+        let mut file = File::create(file_path)?;
+        file.write_all(self.to_html().as_bytes())
+    }
+
+    fn to_html(&self) -> String {
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str(&format!("<title>CFG of {}</title>\n", escape(&self.name)));
+        html.push_str(STYLE);
+        html.push_str("</head>\n<body>\n");
+        html.push_str(&format!("<h1>{}</h1>\n<div class=\"views\">\n", escape(&self.name)));
+
+        // C source
+        html.push_str("<section><h2>C source</h2>\n<pre>");
+        if self.c_source.is_empty() {
+            html.push_str("(source unavailable)");
+        }
+        for &(line, ref text) in &self.c_source {
+            html.push_str(&format!("<span class=\"lineno\">{:5}</span> {}\n", line, escape(text)));
+        }
+        html.push_str("</pre></section>\n");
+
+        // CFG
+        html.push_str("<section><h2>CFG</h2>\n<p>entries: ");
+        let entries: Vec<String> = self.entries.iter().map(|l| label_html(l)).collect();
+        html.push_str(&entries.join(", "));
+        html.push_str("</p>\n");
+        for block in &self.blocks {
+            html.push_str(&format!("<div class=\"block\" data-blocks=\"{}\">\n", block.label));
+            html.push_str(&format!("<div class=\"label\">{}:</div>\n", block.label));
+            if !block.live.is_empty() {
+                html.push_str(&format!("<div class=\"live\">live in: {{{}}}</div>\n", escape(&block.live.join(", "))));
+            }
+            if !block.defined.is_empty() {
+                html.push_str(&format!("<div class=\"live\">defined: {{{}}}</div>\n", escape(&block.defined.join(", "))));
+            }
+            html.push_str(&format!("<pre>{}</pre>\n", escape(&block.body.join("\n"))));
+            if !block.terminator.is_empty() {
+                html.push_str(&format!("<pre class=\"terminator\">{}</pre>\n", escape(&block.terminator)));
+            }
+            for &(ref desc, ref tgt) in &block.edges {
+                let desc = if desc.is_empty() { String::new() } else { format!("{} ", escape(desc)) };
+                html.push_str(&format!("<div class=\"edge\">{}&rarr; {}</div>\n", desc, label_html(tgt)));
+            }
+            html.push_str("</div>\n");
+        }
+        html.push_str("<details><summary>JSON</summary>\n<pre>");
+        html.push_str(&escape(&self.cfg_json));
+        html.push_str("</pre></details></section>\n");
+
+        // Structures
+        html.push_str("<section><h2>Structures</h2>\n");
+        html.push_str(&self.structures);
+        html.push_str("</section>\n");
+
+        // Rust
+        let lines = self.rust_line_blocks();
+        html.push_str("<section><h2>Rust</h2>\n<pre>");
+        for (line, blocks) in self.rust.iter().zip(lines) {
+            html.push_str(&format!("<span data-blocks=\"{}\">{}</span>\n", blocks.join(" "), escape(line)));
+        }
+        html.push_str("</pre></section>\n");
+
+        html.push_str("</div>\n");
+        html.push_str(&format!(
+            "<script type=\"application/json\" id=\"cfg\">{}</script>\n",
+            self.cfg_json.replace("</", "<\\/"),
+        ));
+        html.push_str(SCRIPT);
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    /// For every line of Rust output, the blocks with a statement that line contains. Comparing
+    /// lines rather than whole statements lets us match statements that ended up printed on one
+    /// line together with others, or spread over several lines.
+    fn rust_line_blocks(&self) -> Vec<Vec<String>> {
+        let mut stmt_lines: Vec<(&str, &str)> = vec![];
+        for block in &self.blocks {
+            for line in block.body.iter().flat_map(|s| s.lines()) {
+                let key = line_key(line);
+                if key.chars().any(char::is_alphanumeric) {
+                    stmt_lines.push((key, &block.label));
+                }
+            }
+        }
+
+        self.rust
+            .iter()
+            .map(|line| {
+                let mut blocks: Vec<String> = vec![];
+                for &(key, label) in &stmt_lines {
+                    if line.contains(key) && !blocks.iter().any(|b| b == label) {
+                        blocks.push(String::from(label));
+                    }
+                }
+                blocks
+            })
+            .collect()
+    }
+}
+
+/// The part of a line of code compared when matching Rust output to blocks
+fn line_key(line: &str) -> &str {
+    line.trim().trim_right_matches(';')
+}
+
+/// The lines spanned by the given C statements, read from their source file
+fn c_source(ctx: &TypedAstContext, stmt_ids: &[CStmtId]) -> Vec<(u64, String)> {
+    let locs: Vec<SrcLoc> = stmt_ids.iter().filter_map(|&s| ctx.index(s).loc).collect();
+    let fileid = match locs.first() {
+        Some(loc) => loc.fileid,
+        None => return vec![],
+    };
+    let lines = locs.iter().filter(|loc| loc.fileid == fileid).map(|loc| loc.line);
+    let first = lines.clone().min().unwrap_or(1).saturating_sub(1).max(1);
+    let last = lines.max().unwrap_or(first) + 1;
+
+    let mut text = String::new();
+    let read = ctx.c_files
+        .get(&fileid)
+        .and_then(|path| File::open(path).ok())
+        .map_or(false, |mut file| io::Read::read_to_string(&mut file, &mut text).is_ok());
+    if !read {
+        return vec![];
+    }
+
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i as u64 + 1, String::from(line)))
+        .filter(|&(i, _)| first <= i && i <= last)
+        .collect()
+}
+
+fn structures_html(structures: &[Structure<StmtOrComment>], html: &mut String) {
+    html.push_str("<ul>\n");
+    for structure in structures {
+        let mut entries: Vec<&Label> = structure.get_entries().iter().collect();
+        entries.sort();
+        let entries: Vec<String> = entries.into_iter().map(Label::debug_print).collect();
+        let entries_html: Vec<String> = entries.iter().map(|l| label_html(l)).collect();
+        let entries_html = entries_html.join(", ");
+
+        match *structure {
+            Structure::Simple { ref body, ref terminator, .. } => {
+                html.push_str(&format!(
+                    "<li><span data-blocks=\"{}\">Simple</span> {}\n",
+                    entries.join(" "),
+                    entries_html,
+                ));
+                let body: Vec<String> = body
+                    .iter()
+                    .map(|s| match *s {
+                        StmtOrComment::Stmt(ref s) => pprust::stmt_to_string(s),
+                        StmtOrComment::Comment(ref c) => c.clone(),
+                    })
+                    .collect();
+                if !body.is_empty() {
+                    html.push_str(&format!("<pre>{}</pre>\n", escape(&body.join("\n"))));
+                }
+                terminator_html(terminator, html);
+                html.push_str("</li>\n");
+            }
+            Structure::Loop { ref body, .. } => {
+                html.push_str(&format!("<li>Loop {}\n", entries_html));
+                structures_html(body, html);
+                html.push_str("</li>\n");
+            }
+            Structure::Multiple { ref branches, ref then, .. } => {
+                html.push_str(&format!("<li>Multiple {}\n<ul>\n", entries_html));
+                for (lbl, branch) in branches {
+                    html.push_str(&format!("<li>branch {}\n", label_html(&lbl.debug_print())));
+                    structures_html(branch, html);
+                    html.push_str("</li>\n");
+                }
+                html.push_str("<li>then\n");
+                structures_html(then, html);
+                html.push_str("</li>\n</ul></li>\n");
+            }
+        }
+    }
+    html.push_str("</ul>\n");
+}
+
+fn terminator_html(terminator: &GenTerminator<StructureLabel<StmtOrComment>>, html: &mut String) {
+    let target_html = |tgt: &StructureLabel<StmtOrComment>, html: &mut String| match *tgt {
+        StructureLabel::GoTo(l) => html.push_str(&format!("goto {}\n", label_html(&l.debug_print()))),
+        StructureLabel::ExitTo(l) => html.push_str(&format!("exit to {}\n", label_html(&l.debug_print()))),
+        StructureLabel::Nested(ref nested) => structures_html(nested, html),
+    };
+
+    html.push_str("<div class=\"edge\">");
+    match *terminator {
+        End => html.push_str("end\n"),
+        Jump(ref tgt) => target_html(tgt, html),
+        Branch(ref cond, ref tru, ref fal) => {
+            html.push_str(&format!("if {}\n<div class=\"edge\">true: ", escape(&pprust::expr_to_string(cond))));
+            target_html(tru, html);
+            html.push_str("</div><div class=\"edge\">false: ");
+            target_html(fal, html);
+            html.push_str("</div>");
+        }
+        Switch { ref expr, ref cases } => {
+            html.push_str(&format!("match {}\n", escape(&pprust::expr_to_string(expr))));
+            for &(ref pats, ref tgt) in cases {
+                let pats: Vec<String> = pats.iter().map(|p| pprust::pat_to_string(p)).collect();
+                html.push_str(&format!("<div class=\"edge\">{}: ", escape(&pats.join(" | "))));
+                target_html(tgt, html);
+                html.push_str("</div>");
+            }
+        }
+    }
+    html.push_str("</div>\n");
+}
+
+fn label_html(label: &str) -> String {
+    format!("<span class=\"ref\" data-blocks=\"{0}\">{0}</span>", label)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+const STYLE: &str = "<style>
+body { font-family: sans-serif; margin: 1em; }
+.views { display: grid; grid-template-columns: 1fr 1fr; grid-gap: 1em; }
+section { border: 1px solid #ccc; padding: 0 0.5em; overflow: auto; max-height: 45em; }
+pre { margin: 0.2em 0; }
+.block { border: 1px solid #999; margin: 0.5em 0; padding: 0.3em; }
+.label { font-weight: bold; font-family: monospace; }
+.live, .edge { font-family: monospace; font-size: 90%; color: #555; }
+.edge .edge { margin-left: 1em; }
+.lineno { color: #999; }
+.ref { font-family: monospace; text-decoration: underline dotted; }
+.hl { background: #ffe08a; }
+</style>
+";
+
+const SCRIPT: &str = "<script>
+var marked = document.querySelectorAll('[data-blocks]');
+function labels(el) { return el.getAttribute('data-blocks').split(' ').filter(function (l) { return l; }); }
+function highlight(active, on) {
+  marked.forEach(function (el) {
+    if (labels(el).some(function (l) { return active.indexOf(l) >= 0; })) el.classList.toggle('hl', on);
+  });
+}
+marked.forEach(function (el) {
+  el.addEventListener('mouseenter', function () { highlight(labels(el), true); });
+  el.addEventListener('mouseleave', function () { highlight(labels(el), false); });
+});
+</script>
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(label: &str, body: &[&str]) -> BlockView {
+        BlockView {
+            label: String::from(label),
+            body: body.iter().map(|s| String::from(*s)).collect(),
+            terminator: String::new(),
+            edges: vec![],
+            live: vec![],
+            defined: vec![],
+        }
+    }
+
+    fn explorer(blocks: Vec<BlockView>, rust: &[&str], cfg_json: &str) -> Explorer {
+        Explorer {
+            name: String::from("f"),
+            c_source: vec![(1, String::from("if (a < b && s[0] == '\"') return \"<b>\";"))],
+            entries: vec![],
+            blocks,
+            cfg_json: String::from(cfg_json),
+            structures: String::new(),
+            rust: rust.iter().map(|s| String::from(*s)).collect(),
+        }
+    }
+
+    #[test]
+    fn escaped_source() {
+        assert_eq!(escape("if (a < b && s[0] == '\"') return \"<b>\";"),
+                   "if (a &lt; b &amp;&amp; s[0] == '&quot;') return &quot;&lt;b&gt;&quot;;");
+        assert_eq!(escape("let r: &Vec<u8> = &v;"), "let r: &amp;Vec&lt;u8&gt; = &amp;v;");
+
+        let html = explorer(vec![], &["let s: &str = \"</pre>\";"], "").to_html();
+        assert!(html.contains("if (a &lt; b &amp;&amp; s[0] == '&quot;') return &quot;&lt;b&gt;&quot;;"), "{}", html);
+        assert!(html.contains("let s: &amp;str = &quot;&lt;/pre&gt;&quot;;"), "{}", html);
+    }
+
+    #[test]
+    fn embedded_json_stays_in_its_script() {
+        let json = r#"{"stmts":["let s = \"</script><script>alert(1)</script>\";"]}"#;
+        let html = explorer(vec![], &[], json).to_html();
+
+        let start = html.find("<script type=\"application/json\" id=\"cfg\">").unwrap();
+        let embedded = &html[start..];
+        let end = embedded.find("</script>").unwrap();
+        let embedded = &embedded[embedded.find('>').unwrap() + 1..end];
+        assert!(!embedded.contains("</"), "{}", embedded);
+        assert_eq!(embedded.replace("<\\/", "</"), json);
+    }
+
+    #[test]
+    fn rust_lines_map_to_blocks() {
+        let blocks = vec![
+            block("bb1", &["let mut i: libc::c_int = 0;", "f(i);"]),
+            block("bb2", &["i += 1;", "f(i);"]),
+            block("bb3", &["g(\n    i,\n);", "{ }"]),
+        ];
+        let rust = [
+            "let mut i: libc::c_int = 0;",
+            "loop {",
+            "    i += 1;",
+            "    f(i);",
+            "    g(",
+            "        i,",
+            "    );",
+            "}",
+        ];
+        let lines = explorer(blocks, &rust, "").rust_line_blocks();
+
+        let expected: Vec<Vec<&str>> = vec![
+            vec!["bb1"],
+            vec![],
+            vec!["bb2"],
+            vec!["bb1", "bb2"],
+            vec!["bb3"],
+            vec!["bb3"],
+            vec![],
+            vec![],
+        ];
+        assert_eq!(lines, expected);
+    }
+}
@@ -163,6 +163,20 @@ impl<Lbl: Hash + Eq + Clone> LoopInfo<Lbl> {
         }
     }
 
+    /// Put copies of blocks (keyed by the block they are a copy of) in the same loops as the
+    /// originals.
+    pub fn copy_blocks(&mut self, copies: &HashMap<Lbl, Lbl>) -> () {
+        for (original, copy) in copies {
+            if let Some(&id) = self.node_loops.get(original) {
+                self.node_loops.insert(copy.clone(), id);
+            }
+        }
+        for (_, &mut (ref mut set, _)) in self.loops.iter_mut() {
+            let copied: Vec<Lbl> = set.iter().filter_map(|lbl| copies.get(lbl)).cloned().collect();
+            set.extend(copied);
+        }
+    }
+
     /// Add in information about a new loop
     pub fn add_loop(&mut self, id: LoopId, contents: HashSet<Lbl>, outer_id: Option<LoopId>) -> () {
         for elem in &contents {
@@ -12,7 +12,8 @@
 //!   - use the _Relooper algorithm_ to convert this CFG into a sequence of `Structure<StmtOrDecl>`s
 //!   - place the declarations in the right place and produce a sequence of `Structure<Stmt>`s
 //!   - simplify that sequence of `Structure<Stmt>`s into another such sequence
-//!   - convert the `Vec<Structure<Stmt>>` back into a `Vec<Stmt>`
+//!   - convert the `Vec<Structure<Stmt>>` back into a `Vec<Stmt>`, resolving what uses of
+//!     `current_block` we can statically
 //!
 
 use syntax;
@@ -40,12 +41,16 @@ use serde_json;
 use translator::*;
 use with_stmts::WithStmts;
 use c_ast::*;
+use c_ast::iterators::{immediate_children, SomeId};
 use rust_ast::mk;
 
 pub mod relooper;
 pub mod structures;
 pub mod loops;
 pub mod multiples;
+pub mod splitting;
+pub mod current_block;
+pub mod explorer;
 
 use cfg::loops::*;
 use cfg::multiples::*;
@@ -452,6 +457,15 @@ pub struct Cfg<Lbl: Ord + Hash, Stmt> {
 
     /// Branching in the graph
     multiples: MultipleInfo<Lbl>,
+
+    /// Declarations in blocks which were copied to make the graph reducible (see `splitting`).
+    /// These have to be hoisted to the top of the function.
+    split_decls: HashSet<CDeclId>,
+
+    /// Declarations of the variables holding the values of statement expressions lowered into the
+    /// graph (see `lower_stmt_exprs`). Blocks between the assignment and use of such a variable
+    /// can put them in different scopes, so these go at the top of the function as well.
+    stmt_expr_vars: Vec<syntax::ast::Stmt>,
 }
 
 impl<L: Clone + Ord + Hash, S1> Cfg<L, S1> {
@@ -462,8 +476,10 @@ impl<L: Clone + Ord + Hash, S1> Cfg<L, S1> {
         let nodes = self.nodes.iter().map(|(l,bb)| (l.clone(), bb.map_stmts(&f))).collect();
         let loops = self.loops.clone();
         let multiples = self.multiples.clone();
+        let split_decls = self.split_decls.clone();
+        let stmt_expr_vars = self.stmt_expr_vars.clone();
 
-        Cfg { entries, nodes, loops, multiples }
+        Cfg { entries, nodes, loops, multiples, split_decls, stmt_expr_vars }
     }
 }
 
@@ -476,6 +492,20 @@ impl<L: Serialize + Ord + Hash, St: Serialize> Serialize for Cfg<L, St> {
     }
 }
 
+/// Counts of relooped bodies, and of those among them that did not need a `current_block`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StructuringStats {
+    pub relooped: usize,
+    pub structured: usize,
+}
+
+impl StructuringStats {
+    pub fn add(&mut self, other: StructuringStats) {
+        self.relooped += other.relooped;
+        self.structured += other.structured;
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum ExitStyle {
     Continue,
@@ -554,20 +584,28 @@ impl Cfg<Label, StmtOrDecl> {
         })?;
 
         // Check the graph doesn't reference any labels it doesn't contain
-        let bad_labels: Vec<&CLabelId> = cfg_builder.c_labels_used
-            .difference(&cfg_builder.c_labels_defined)
-            .collect();
-        if !bad_labels.is_empty() {
-            Err(format!(
-                "Control flow graph for statements {:?} references undefined label(s): {:?}",
-                stmt_ids,
-                bad_labels,
-            ))?
+        {
+            let bad_labels: Vec<&CLabelId> = cfg_builder.c_labels_used
+                .difference(&cfg_builder.c_labels_defined)
+                .collect();
+            if !bad_labels.is_empty() {
+                Err(format!(
+                    "Control flow graph for statements {:?} references undefined label(s): {:?}",
+                    stmt_ids,
+                    bad_labels,
+                ))?
+            }
         }
 
         cfg_builder.graph.prune_empty_blocks_mut();
         cfg_builder.graph.prune_unreachable_blocks_mut();
 
+        // Make irreducible control flow reducible, allowing the code to grow by some percentage
+        if let Some(max_growth) = translator.tcfg.split_irreducible {
+            let size: usize = cfg_builder.graph.nodes.values().map(|bb| bb.body.len().max(1)).sum();
+            cfg_builder.split_irreducible(size * max_growth / 100);
+        }
+
         Ok((cfg_builder.graph, cfg_builder.decls_seen))
     }
 }
@@ -744,7 +782,10 @@ struct CfgBuilder {
 /// choosing what to do until later.
 #[derive(Clone, Debug)]
 pub struct DeclStmtStore {
-    store: HashMap<CDeclId, DeclStmtInfo>
+    store: HashMap<CDeclId, DeclStmtInfo>,
+
+    /// Declarations in blocks copied by node splitting, whose initializer is needed once per copy
+    copied: HashSet<CDeclId>,
 }
 
 /// This contains the information one needs to convert a C declaration in all the possible ways:
@@ -782,7 +823,7 @@ impl DeclStmtInfo {
 impl DeclStmtStore {
 
     pub fn new() -> Self {
-        DeclStmtStore { store: HashMap::new() }
+        DeclStmtStore { store: HashMap::new(), copied: HashSet::new() }
     }
 
     /// Extract _just_ the Rust statements for a declaration (without initialization). Used when you
@@ -802,7 +843,8 @@ impl DeclStmtStore {
 
    /// Extract _just_ the Rust statements for an initializer (without the declaration it was
    /// initially attached to). Used when you've moved a declaration but now you need to also run the
-   /// initializer.
+   /// initializer. The initializers of declarations copied by node splitting can be extracted once
+   /// per copy.
     pub fn extract_assign(&mut self, decl_id: CDeclId) -> Result<Vec<Stmt>, String> {
         let DeclStmtInfo { decl, assign, .. } = self.store
             .remove(&decl_id)
@@ -810,7 +852,8 @@ impl DeclStmtStore {
 
         let assign: Vec<Stmt> = assign.ok_or(format!("Assignment for {:?} has already been extracted", decl_id))?;
 
-        let pruned = DeclStmtInfo { decl, assign: None, decl_and_assign: None };
+        let assign_again = if self.copied.contains(&decl_id) { Some(assign.clone()) } else { None };
+        let pruned = DeclStmtInfo { decl, assign: assign_again, decl_and_assign: None };
         self.store.insert(decl_id, pruned);
 
         Ok(assign)
@@ -1009,6 +1052,8 @@ impl CfgBuilder {
                 nodes: HashMap::new(),
                 loops: LoopInfo::new(),
                 multiples: MultipleInfo::new(),
+                split_decls: HashSet::new(),
+                stmt_expr_vars: vec![],
             },
 
             prev_label: 0,
@@ -1064,6 +1109,96 @@ impl CfgBuilder {
     }
 
 
+    /// Lower into the graph the statement expressions of `expr` which have to be part of the CFG of
+    /// the enclosing function (see `stmt_exprs_to_lower`). Their bodies become ordinary blocks, and
+    /// their values are assigned to fresh variables which the translation of `expr` then uses.
+    ///
+    /// The statement expressions get evaluated before the rest of `expr`. Since they are never
+    /// under `&&`, `||`, `?:`, or on the right of `,`, this is an order C allows, as long as no
+    /// other part of `expr` which C may evaluate first has side effects.
+    fn lower_stmt_exprs(
+        &mut self,
+        translator: &Translation,
+        use_: ExprUse,            // How the value of `expr` is used
+        expr: CExprId,            // Expression whose statement expressions to lower
+        mut wip: WipBlock,        // Current WIP block
+    ) -> Result<WipBlock, String> {
+
+        let stmt_exprs = stmt_exprs_to_lower(&translator.ast_context, expr);
+        if !stmt_exprs.is_empty() && reorders_side_effects(&translator.ast_context, expr, &stmt_exprs) {
+            Err(format!(
+                "Cannot evaluate the statement expressions jumping out of {:?} ahead of the side effects around them",
+                expr,
+            ))?
+        }
+
+        for stmt_expr in stmt_exprs {
+            let stmt_ids: Vec<CStmtId> = match translator.ast_context[stmt_expr].kind {
+                CExprKind::Statements(_, compound) => match translator.ast_context[compound].kind {
+                    CStmtKind::Compound(ref stmt_ids) => stmt_ids.clone(),
+                    _ => Err(format!("Statement expression {:?} has no compound body", stmt_expr))?,
+                },
+                _ => Err(format!("{:?} is not a statement expression", stmt_expr))?,
+            };
+
+            // The last statement of the body is the value of the whole expression, if it is an
+            // expression. We keep it in the same block as whatever uses the value, so that the
+            // variable it is bound to stays in scope.
+            let (body, result) = match stmt_ids.split_last() {
+                Some((&last, body)) => match translator.ast_context[last].kind {
+                    CStmtKind::Expr(result) => (body, Some(result)),
+                    _ => (&stmt_ids[..], None),
+                },
+                None => (&stmt_ids[..], None),
+            };
+
+            let mut body_wip = Some(wip);
+            for &stmt in body {
+                let new_label = self.fresh_label();
+                let new_wip = body_wip.unwrap_or(self.new_wip_block(new_label));
+                body_wip = self.convert_stmt_help(translator, stmt, new_wip)?;
+            }
+
+            wip = match body_wip {
+                Some(mut body_wip) => {
+                    let used = stmt_expr != expr || use_ != ExprUse::Unused;
+                    match result {
+                        Some(result) if used => {
+                            body_wip = self.lower_stmt_exprs(translator, ExprUse::RValue, result, body_wip)?;
+                            let WithStmts { stmts, val } =
+                                translator.convert_expr(ExprUse::RValue, result, false, DecayRef::Default)?;
+                            body_wip.extend(stmts);
+                            let (decl, assign) = translator.bind_lowered_stmt_expr(stmt_expr, val)?;
+                            self.graph.stmt_expr_vars.push(decl);
+                            body_wip.push_stmt(assign);
+                        }
+                        Some(result) => {
+                            body_wip = self.lower_stmt_exprs(translator, ExprUse::Unused, result, body_wip)?;
+                            let WithStmts { stmts, .. } =
+                                translator.convert_expr(ExprUse::Unused, result, false, DecayRef::Default)?;
+                            body_wip.extend(stmts);
+                            translator.set_lowered_stmt_expr(stmt_expr, mk().tuple_expr(vec![] as Vec<P<Expr>>));
+                        }
+                        None => translator.set_lowered_stmt_expr(stmt_expr, mk().tuple_expr(vec![] as Vec<P<Expr>>)),
+                    }
+                    body_wip
+                }
+
+                // The body never finishes, so what follows is unreachable and will get pruned
+                None => {
+                    translator.set_lowered_stmt_expr(
+                        stmt_expr,
+                        translator.panic("Reached the end of a statement expression which cannot finish"),
+                    );
+                    let new_label = self.fresh_label();
+                    self.new_wip_block(new_label)
+                }
+            };
+        }
+
+        Ok(wip)
+    }
+
     /// Translate a C statement and tack it onto the end of the `WipBlock` passed in. If necessary,
     /// intermediate basic blocks can be outputted to the control flow graph.
     ///
@@ -1083,9 +1218,15 @@ impl CfgBuilder {
         mut wip: WipBlock,        // Current WIP block
     ) -> Result<Option<WipBlock>, String> {
 
-        // Add statement comment into current block right before the current statement
-        for cmmt in translator.comment_context.borrow_mut().remove_stmt_comment(stmt_id) {
-            wip.push_comment(cmmt);
+        // Add statement comment into current block right before the current statement. Labelled
+        // statements start a new block, so their comments go with the sub-statement instead.
+        let stmt_cmt = translator.comment_context.borrow_mut().remove_stmt_comment(stmt_id);
+        match translator.ast_context.index(stmt_id).kind {
+            CStmtKind::Label(_) | CStmtKind::Case(..) | CStmtKind::Default(_) =>
+                translator.comment_context.borrow_mut().defer_comments(stmt_cmt),
+            _ => for cmmt in stmt_cmt {
+                wip.push_comment(cmmt);
+            },
         }
 
         match translator.ast_context.index(stmt_id).kind {
@@ -1093,6 +1234,9 @@ impl CfgBuilder {
 
             CStmtKind::Decls(ref decls) => {
                 for decl in decls {
+                    if let CDeclKind::Variable { initializer: Some(init), .. } = translator.ast_context.index(*decl).kind {
+                        wip = self.lower_stmt_exprs(translator, ExprUse::RValue, init, wip)?;
+                    }
                     let info = translator.convert_decl_stmt_info(*decl)?;
                     self.decls_seen.store.insert(*decl, info);
 
@@ -1112,6 +1256,9 @@ impl CfgBuilder {
                 stmt_id,
             )),
             CStmtKind::Return(expr) => {
+                if let Some(expr) = expr {
+                    wip = self.lower_stmt_exprs(translator, ExprUse::RValue, expr, wip)?;
+                }
                 let val = match expr.map(|i| translator.convert_expr(ExprUse::RValue, i, false, DecayRef::Default)) {
                     Some(r) => Some(r?),
                     None => None,
@@ -1132,6 +1279,7 @@ impl CfgBuilder {
                 let else_entry = if false_variant.is_none() { next_entry } else { self.fresh_label() };
 
                 // Condition
+                let mut wip = self.lower_stmt_exprs(translator, ExprUse::RValue, scrutinee, wip)?;
                 let WithStmts { stmts, val } = translator.convert_condition(true, scrutinee, false)?;
                 let cond_val = translator.ast_context[scrutinee].kind.get_bool();
                 wip.extend(stmts);
@@ -1180,9 +1328,10 @@ impl CfgBuilder {
                 self.open_loop();
 
                 // Condition
+                let cond_wip = self.new_wip_block(cond_entry);
+                let mut cond_wip = self.lower_stmt_exprs(translator, ExprUse::RValue, condition, cond_wip)?;
                 let WithStmts { stmts, val } = translator.convert_condition(true, condition, false)?;
                 let cond_val = translator.ast_context[condition].kind.get_bool();
-                let mut cond_wip = self.new_wip_block(cond_entry);
                 cond_wip.extend(stmts);
                 self.add_wip_block(
                     cond_wip,
@@ -1233,9 +1382,10 @@ impl CfgBuilder {
                 self.continue_labels.pop();
 
                 // Condition
+                let cond_wip = self.new_wip_block(cond_entry);
+                let mut cond_wip = self.lower_stmt_exprs(translator, ExprUse::RValue, condition, cond_wip)?;
                 let WithStmts { stmts, val } = translator.convert_condition(true, condition, false)?;
                 let cond_val = translator.ast_context[condition].kind.get_bool();
-                let mut cond_wip = self.new_wip_block(cond_entry);
                 cond_wip.extend(stmts);
                 self.add_wip_block(
                     cond_wip,
@@ -1277,9 +1427,10 @@ impl CfgBuilder {
 
                     // Condition
                     if let Some(cond) = condition {
+                        let cond_wip = slf.new_wip_block(cond_entry);
+                        let mut cond_wip = slf.lower_stmt_exprs(translator, ExprUse::RValue, cond, cond_wip)?;
                         let WithStmts { stmts, val } = translator.convert_condition(true, cond, false)?;
                         let cond_val = translator.ast_context[cond].kind.get_bool();
-                        let mut cond_wip = slf.new_wip_block(cond_entry);
                         cond_wip.extend(stmts);
                         slf.add_wip_block(
                             cond_wip,
@@ -1311,10 +1462,11 @@ impl CfgBuilder {
                     match increment {
                         None => slf.add_block(incr_entry, BasicBlock::new_jump(cond_entry)),
                         Some(incr) => {
+                          let incr_wip = slf.new_wip_block(incr_entry);
+                          let mut incr_wip = slf.lower_stmt_exprs(translator, ExprUse::Unused, incr, incr_wip)?;
                           let incr_stmts = translator
                                   .convert_expr(ExprUse::Unused, incr, false, DecayRef::Default)?
                                   .stmts;
-                          let mut incr_wip = slf.new_wip_block(incr_entry);
                           incr_wip.extend(incr_stmts);
                           slf.add_wip_block(incr_wip, Jump(cond_entry));
                         }
@@ -1354,11 +1506,22 @@ impl CfgBuilder {
             ),
 
             CStmtKind::Expr(expr) => {
-                wip.extend(translator.convert_expr(ExprUse::Unused, expr, false, DecayRef::Default)?.stmts);
+                let mut wip = self.lower_stmt_exprs(translator, ExprUse::Unused, expr, wip)?;
+                let stmts = translator.convert_expr(ExprUse::Unused, expr, false, DecayRef::Default)?.stmts;
+                let diverges = translator.ast_context.expr_diverges(expr);
+
+                // A statement the C source only compiles under some condition on features keeps
+                // that condition, as long as leaving it out does not change the control flow
+                let cfg_attrs = translator.feature_cfg_attrs(translator.ast_context[stmt_id].loc);
+                if cfg_attrs.is_empty() || diverges || !stmt_exprs_to_lower(&translator.ast_context, expr).is_empty() {
+                    wip.extend(stmts);
+                } else {
+                    wip.extend(stmts.into_iter().map(|stmt| stmt_with_attrs(stmt, &cfg_attrs)));
+                }
 
                 // If we can tell the expression is going to diverge, there is no falling through to
                 // the next block.
-                let next = if translator.ast_context.expr_diverges(expr) {
+                let next = if diverges {
                     self.add_wip_block(wip, End);
                     None
                 } else {
@@ -1441,6 +1604,7 @@ impl CfgBuilder {
                 let body_label = self.fresh_label();
 
                 // Convert the condition
+                let mut wip = self.lower_stmt_exprs(translator, ExprUse::RValue, scrutinee, wip)?;
                 let WithStmts { stmts, val } = translator.convert_expr(ExprUse::RValue, scrutinee, false, DecayRef::Default)?;
                 wip.extend(stmts);
 
@@ -1483,6 +1647,105 @@ impl CfgBuilder {
 }
 
 
+/// The statement expressions in `expr` which have to be lowered into the CFG of the enclosing
+/// function because their bodies jump somewhere other than to their own end, or contain labels,
+/// outermost first and in evaluation order.
+///
+/// Statement expressions under `&&`, `||`, `?:`, or on the right of `,` are left out since they
+/// can't be evaluated ahead of the rest of the expression. These still go through
+/// `convert_statement_expression`, which will reject their jumps.
+fn stmt_exprs_to_lower(ctx: &TypedAstContext, expr: CExprId) -> Vec<CExprId> {
+    fn collect(ctx: &TypedAstContext, expr: CExprId, found: &mut Vec<CExprId>) {
+        match ctx[expr].kind {
+            CExprKind::Statements(_, compound) => {
+                if jumps_out(ctx, compound.into(), false, false) {
+                    found.push(expr);
+                }
+                return
+            }
+            CExprKind::Binary(_, BinOp::And, lhs, _, _, _) |
+            CExprKind::Binary(_, BinOp::Or, lhs, _, _, _) |
+            CExprKind::Binary(_, BinOp::Comma, lhs, _, _, _) |
+            CExprKind::Conditional(_, lhs, _, _) |
+            CExprKind::BinaryConditional(_, lhs, _) => return collect(ctx, lhs, found),
+            _ => { }
+        }
+
+        for child in immediate_children(ctx, expr.into()) {
+            if let SomeId::Expr(child) = child {
+                collect(ctx, child, found);
+            }
+        }
+    }
+
+    let mut found = vec![];
+    collect(ctx, expr, &mut found);
+    found
+}
+
+/// Whether evaluating the `lowered` statement expressions of `expr` ahead of the rest of it could
+/// move them past side effects C may evaluate first. Operands evaluated after the statement
+/// expressions in C anyway (the right of `&&`, `||` and `,`, and the arms of `?:`) don't matter.
+fn reorders_side_effects(ctx: &TypedAstContext, expr: CExprId, lowered: &[CExprId]) -> bool {
+    fn contains(ctx: &TypedAstContext, expr: CExprId, lowered: &[CExprId]) -> bool {
+        lowered.contains(&expr) || immediate_children(ctx, expr.into())
+            .into_iter()
+            .any(|child| match child {
+                SomeId::Expr(child) => contains(ctx, child, lowered),
+                _ => false,
+            })
+    }
+
+    if lowered.contains(&expr) {
+        return false
+    }
+
+    match ctx[expr].kind {
+        CExprKind::Binary(_, BinOp::And, lhs, _, _, _) |
+        CExprKind::Binary(_, BinOp::Or, lhs, _, _, _) |
+        CExprKind::Binary(_, BinOp::Comma, lhs, _, _, _) |
+        CExprKind::Conditional(_, lhs, _, _) |
+        CExprKind::BinaryConditional(_, lhs, _) => return reorders_side_effects(ctx, lhs, lowered),
+        _ => { }
+    }
+
+    immediate_children(ctx, expr.into())
+        .into_iter()
+        .any(|child| match child {
+            SomeId::Expr(child) if contains(ctx, child, lowered) => reorders_side_effects(ctx, child, lowered),
+            SomeId::Expr(child) => !ctx.is_expr_pure(child),
+            _ => false,
+        })
+}
+
+/// Whether control can get into or out of a node other than by falling through it
+fn jumps_out(ctx: &TypedAstContext, node: SomeId, in_loop: bool, in_switch: bool) -> bool {
+    let (in_loop, in_switch) = match node {
+        SomeId::Stmt(stmt) => match ctx[stmt].kind {
+            CStmtKind::Goto(_) | CStmtKind::Label(_) | CStmtKind::Return(_) => return true,
+            CStmtKind::Break if !in_loop && !in_switch => return true,
+            CStmtKind::Continue if !in_loop => return true,
+            CStmtKind::Case(..) | CStmtKind::Default(_) if !in_switch => return true,
+            CStmtKind::While { .. } | CStmtKind::DoWhile { .. } | CStmtKind::ForLoop { .. } =>
+                (true, in_switch),
+            CStmtKind::Switch { .. } => (in_loop, true),
+            _ => (in_loop, in_switch),
+        },
+        SomeId::Expr(_) => (in_loop, in_switch),
+        SomeId::Decl(decl) => match ctx[decl].kind {
+            CDeclKind::Variable { initializer: Some(init), .. } =>
+                return jumps_out(ctx, init.into(), in_loop, in_switch),
+            _ => return false,
+        },
+        SomeId::Type(_) => return false,
+    };
+
+    immediate_children(ctx, node)
+        .into_iter()
+        .any(|child| jumps_out(ctx, child, in_loop, in_switch))
+}
+
+
 /// This impl block deals with pretty-printing control flow graphs into a format that `dot` can
 /// consume. Compiling these files into images means running something like:
 ///
@@ -98,6 +98,17 @@ impl<Lbl: Hash + Ord + Clone> MultipleInfo<Lbl> {
             .collect();
     }
 
+    /// Put copies of blocks (keyed by the block they are a copy of) in the same arms as the
+    /// originals.
+    pub fn copy_blocks(&mut self, copies: &HashMap<Lbl, Lbl>) -> () {
+        for (_, &mut (_, ref mut arms)) in self.multiples.iter_mut() {
+            for (_, arm_body) in arms.iter_mut() {
+                let copied: Vec<Lbl> = arm_body.iter().filter_map(|lbl| copies.get(lbl)).cloned().collect();
+                arm_body.extend(copied);
+            }
+        }
+    }
+
     /// Add in information about a new multiple
     pub fn add_multiple(&mut self, join: Lbl, arms: Vec<(Lbl, HashSet<Lbl>)>) -> () {
         let entry_set: BTreeSet<Lbl> = arms.iter().map(|&(ref l,_)| l.clone()).collect();
@@ -25,16 +25,21 @@ pub fn reloop(
     let loop_info = if use_c_loop_info { Some(cfg.loops) } else { None };
     let multiple_info = if use_c_multiple_info { Some(cfg.multiples) } else { None };
     let mut state = RelooperState::new(loop_info, multiple_info);
+    for decl in cfg.split_decls {
+        state.add_to_top_scope(decl);
+        state.lifted.insert(decl);
+    }
     state.relooper(entries, blocks, &mut relooped_with_decls);
 
     // These are declarations we need to lift
     let lift_me: HashSet<CDeclId> = state.lifted;
 
     // These are the statements that emerge from these lifts
-    let lifted_stmts: Vec<Stmt> = lift_me
+    let mut lifted_stmts: Vec<Stmt> = lift_me
         .iter()
         .flat_map(|&decl: &CDeclId| store.extract_decl(decl).unwrap())
         .collect();
+    lifted_stmts.extend(cfg.stmt_expr_vars);
 
     // We map over the existing structure and flatten everything to `Stmt`
     let mut relooped: Vec<Structure<StmtOrComment>> = relooped_with_decls
@@ -0,0 +1,349 @@
+//! This module makes irreducible control flow reducible before relooping, by splitting nodes.
+//!
+//! A region of a CFG is irreducible when it is a cycle that can be entered at more than one block,
+//! for instance because of a `goto` into the body of a loop. Relooper can only express such a
+//! region with a `current_block` state machine. Instead, we pick one entry of the region to be the
+//! loop header and give the edges coming into every other entry their own copy of the blocks they
+//! go through before getting back to the header:
+//!
+//! ```text
+//!   A --> B <--> C <-- D      becomes      A --> B <--> C
+//!                                                ^
+//!                                                +---- C' <-- D
+//! ```
+//!
+//! Copying code makes the output bigger, so splitting stops once it would copy more statements
+//! than a budget allows. Whatever irreducible control flow is left is handled with `current_block`.
+
+use super::*;
+
+impl CfgBuilder {
+
+    /// Split nodes until the graph is reducible, copying at most `budget` statements. Returns
+    /// whether the graph ended up reducible.
+    pub fn split_irreducible(&mut self, mut budget: usize) -> bool {
+        loop {
+            let successors: HashMap<Label, BTreeSet<Label>> = self.graph.nodes
+                .iter()
+                .map(|(lbl, bb)| (*lbl, bb.terminator.get_labels().into_iter().cloned().collect()))
+                .collect();
+            let mut predecessors: HashMap<Label, BTreeSet<Label>> = HashMap::new();
+            for (&lbl, succs) in &successors {
+                for &succ in succs {
+                    predecessors.entry(succ).or_insert(BTreeSet::new()).insert(lbl);
+                }
+            }
+            let all_labels: BTreeSet<Label> = self.graph.nodes.keys().cloned().collect();
+
+            let (region, entries) = match self.irreducible_region(&all_labels, &successors, &predecessors) {
+                None => return true,
+                Some(found) => found,
+            };
+
+            // Prefer the header that requires copying the least code
+            let header = match entries.iter().find(|entry| self.graph.entries.contains(entry)) {
+                Some(&entry) => entry,
+                None => *entries
+                    .iter()
+                    .min_by_key(|&&header| {
+                        let cost: usize = entries
+                            .iter()
+                            .filter(|&&entry| entry != header)
+                            .map(|&entry| self.copy_cost(&reached_avoiding(&region, entry, header, &successors)))
+                            .sum();
+                        (cost, header)
+                    })
+                    .expect("irreducible regions have several entries"),
+            };
+
+            // Split one entry at a time, since the region has to be recomputed afterwards
+            let entry = *entries.iter().find(|&&entry| entry != header).expect("irreducible regions have several entries");
+            let copied = reached_avoiding(&region, entry, header, &successors);
+            let cost = self.copy_cost(&copied);
+            if cost > budget {
+                return false
+            }
+            budget -= cost;
+
+            self.split_entry(&region, entry, &copied);
+        }
+    }
+
+    /// Find a strongly connected set of `nodes` which can be entered at several blocks, along with
+    /// those entry blocks. Single-entry regions are searched for irreducible regions nested inside
+    /// them, ignoring their header.
+    fn irreducible_region(
+        &self,
+        nodes: &BTreeSet<Label>,
+        successors: &HashMap<Label, BTreeSet<Label>>,
+        predecessors: &HashMap<Label, BTreeSet<Label>>,
+    ) -> Option<(BTreeSet<Label>, Vec<Label>)> {
+        for region in strongly_connected_components(nodes, successors) {
+            let first = *region.iter().next().expect("components are non-empty");
+            let is_cycle = region.len() > 1 || successors[&first].contains(&first);
+            if !is_cycle {
+                continue
+            }
+
+            let entries: Vec<Label> = region
+                .iter()
+                .filter(|&lbl| {
+                    self.graph.entries.contains(lbl) || predecessors
+                        .get(lbl)
+                        .map_or(false, |preds| preds.iter().any(|pred| !region.contains(pred)))
+                })
+                .cloned()
+                .collect();
+
+            if entries.len() > 1 {
+                return Some((region, entries))
+            } else if let Some(header) = entries.first() {
+                let mut body = region.clone();
+                body.remove(header);
+                if let Some(nested) = self.irreducible_region(&body, successors, predecessors) {
+                    return Some(nested)
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Number of statements that copying these blocks would add (empty blocks count as one)
+    fn copy_cost(&self, labels: &BTreeSet<Label>) -> usize {
+        labels
+            .iter()
+            .map(|lbl| self.graph.nodes[lbl].body.len().max(1))
+            .sum()
+    }
+
+    /// Copy the `copied` blocks and redirect the edges entering `region` at `entry` into the copy.
+    fn split_entry(&mut self, region: &BTreeSet<Label>, entry: Label, copied: &BTreeSet<Label>) {
+        let renaming: HashMap<Label, Label> = copied
+            .iter()
+            .map(|&lbl| (lbl, self.fresh_label()))
+            .collect();
+
+        for lbl in copied {
+            let mut bb = self.graph.nodes[lbl].clone();
+            for target in bb.terminator.get_labels_mut() {
+                if let Some(&copy) = renaming.get(target) {
+                    *target = copy;
+                }
+            }
+
+            // A declaration can only be emitted once, so copied ones get hoisted
+            for stmt in &bb.body {
+                if let StmtOrDecl::Decl(decl) = *stmt {
+                    self.graph.split_decls.insert(decl);
+                    self.decls_seen.copied.insert(decl);
+                }
+            }
+
+            self.graph.nodes.insert(renaming[lbl], bb);
+        }
+
+        // Copies belong to the same loops and arms of branches as the blocks they were copied from
+        self.graph.loops.copy_blocks(&renaming);
+        self.graph.multiples.copy_blocks(&renaming);
+
+        let entry_copy = renaming[&entry];
+        for (lbl, bb) in self.graph.nodes.iter_mut() {
+            if region.contains(lbl) {
+                continue
+            }
+            for target in bb.terminator.get_labels_mut() {
+                if *target == entry {
+                    *target = entry_copy;
+                }
+            }
+        }
+    }
+}
+
+/// Blocks of `region` reachable from `entry` without going through `header`
+fn reached_avoiding(
+    region: &BTreeSet<Label>,
+    entry: Label,
+    header: Label,
+    successors: &HashMap<Label, BTreeSet<Label>>,
+) -> BTreeSet<Label> {
+    let mut reached: BTreeSet<Label> = BTreeSet::new();
+    let mut to_visit: Vec<Label> = vec![entry];
+
+    while let Some(lbl) = to_visit.pop() {
+        if lbl == header || !region.contains(&lbl) || !reached.insert(lbl) {
+            continue
+        }
+        to_visit.extend(successors[&lbl].iter().cloned());
+    }
+
+    reached
+}
+
+/// Tarjan's algorithm on the subgraph induced by `nodes`, without recursion since CFGs can be
+/// large. The order of the components only depends on the graph.
+fn strongly_connected_components(
+    nodes: &BTreeSet<Label>,
+    successors: &HashMap<Label, BTreeSet<Label>>,
+) -> Vec<BTreeSet<Label>> {
+    let mut tarjan = Tarjan {
+        nodes,
+        successors,
+        index: HashMap::new(),
+        lowlink: HashMap::new(),
+        stack: vec![],
+        on_stack: HashSet::new(),
+        frames: vec![],
+    };
+    let mut components: Vec<BTreeSet<Label>> = vec![];
+
+    for &root in nodes {
+        if tarjan.index.contains_key(&root) {
+            continue
+        }
+        tarjan.visit(root);
+
+        while let Some((lbl, next)) = tarjan.frames.last_mut().map(|&mut (lbl, ref mut todo)| (lbl, todo.pop())) {
+            match next {
+                Some(succ) if !tarjan.index.contains_key(&succ) => tarjan.visit(succ),
+                Some(succ) => if tarjan.on_stack.contains(&succ) {
+                    let low = tarjan.lowlink[&lbl].min(tarjan.index[&succ]);
+                    tarjan.lowlink.insert(lbl, low);
+                },
+                None => {
+                    tarjan.frames.pop();
+                    if let Some(&(parent, _)) = tarjan.frames.last() {
+                        let low = tarjan.lowlink[&parent].min(tarjan.lowlink[&lbl]);
+                        tarjan.lowlink.insert(parent, low);
+                    }
+
+                    if tarjan.lowlink[&lbl] == tarjan.index[&lbl] {
+                        let mut component = BTreeSet::new();
+                        loop {
+                            let member = tarjan.stack.pop().expect("Tarjan stack should hold the component");
+                            tarjan.on_stack.remove(&member);
+                            component.insert(member);
+                            if member == lbl {
+                                break
+                            }
+                        }
+                        components.push(component);
+                    }
+                }
+            }
+        }
+    }
+
+    components
+}
+
+/// State of `strongly_connected_components`
+struct Tarjan<'a> {
+    nodes: &'a BTreeSet<Label>,
+    successors: &'a HashMap<Label, BTreeSet<Label>>,
+    index: HashMap<Label, usize>,
+    lowlink: HashMap<Label, usize>,
+    stack: Vec<Label>,
+    on_stack: HashSet<Label>,
+    /// Nodes being visited, along with the successors they have yet to visit
+    frames: Vec<(Label, Vec<Label>)>,
+}
+
+impl<'a> Tarjan<'a> {
+    fn visit(&mut self, lbl: Label) {
+        let i = self.index.len();
+        self.index.insert(lbl, i);
+        self.lowlink.insert(lbl, i);
+        self.stack.push(lbl);
+        self.on_stack.insert(lbl);
+
+        let nodes = self.nodes;
+        let todo = self.successors[&lbl].iter().filter(|succ| nodes.contains(succ)).cloned().collect();
+        self.frames.push((lbl, todo));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntax::with_globals;
+
+    fn lbl(n: u64) -> Label {
+        Label::Synthetic(n)
+    }
+
+    fn block(terminator: GenTerminator<Label>) -> BasicBlock<Label, StmtOrDecl> {
+        BasicBlock { body: vec![], terminator, live: HashSet::new(), defined: HashSet::new() }
+    }
+
+    fn branch(then: u64, els: u64) -> GenTerminator<Label> {
+        Branch(mk().ident_expr("c"), lbl(then), lbl(els))
+    }
+
+    /// `0` branches into both `1` and `2`, which form a loop exiting to `3`
+    fn irreducible() -> CfgBuilder {
+        let mut builder = CfgBuilder::new(false);
+        builder.prev_label = 3;
+        builder.add_block(lbl(0), block(branch(1, 2)));
+        builder.add_block(lbl(1), block(Jump(lbl(2))));
+        builder.add_block(lbl(2), block(branch(1, 3)));
+        builder.add_block(lbl(3), block(End));
+        builder
+    }
+
+    fn successors(builder: &CfgBuilder) -> HashMap<Label, BTreeSet<Label>> {
+        builder.graph.nodes
+            .iter()
+            .map(|(lbl, bb)| (*lbl, bb.terminator.get_labels().into_iter().cloned().collect()))
+            .collect()
+    }
+
+    #[test]
+    fn components() {
+        with_globals(|| {
+            let builder = irreducible();
+            let nodes: BTreeSet<Label> = builder.graph.nodes.keys().cloned().collect();
+            let mut components = strongly_connected_components(&nodes, &successors(&builder));
+            components.sort();
+
+            let expected: Vec<BTreeSet<Label>> = vec![
+                vec![lbl(0)].into_iter().collect(),
+                vec![lbl(1), lbl(2)].into_iter().collect(),
+                vec![lbl(3)].into_iter().collect(),
+            ];
+            assert_eq!(components, expected);
+        })
+    }
+
+    #[test]
+    fn split_makes_reducible() {
+        with_globals(|| {
+            let mut builder = irreducible();
+            let id = builder.fresh_loop_id();
+            builder.graph.loops.add_loop(id, vec![lbl(1), lbl(2)].into_iter().collect(), None);
+
+            assert!(builder.split_irreducible(1));
+            assert_eq!(builder.graph.nodes.len(), 5);
+
+            // `0` enters the loop at one block, whose copy is in the loop as well
+            let succs = successors(&builder);
+            let header = if succs[&lbl(0)].contains(&lbl(1)) { lbl(1) } else { lbl(2) };
+            let copy = *succs[&lbl(0)].iter().find(|&&succ| succ != header).unwrap();
+            assert!(copy != lbl(1) && copy != lbl(2));
+            assert_eq!(builder.graph.loops.enclosing_loops(&copy), vec![id]);
+
+            // Nothing is left to split
+            assert!(builder.split_irreducible(0));
+        })
+    }
+
+    #[test]
+    fn split_respects_budget() {
+        with_globals(|| {
+            let mut builder = irreducible();
+            assert!(!builder.split_irreducible(0));
+            assert_eq!(builder.graph.nodes.len(), 4);
+        })
+    }
+}
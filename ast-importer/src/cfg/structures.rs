@@ -3,14 +3,39 @@
 use super::*;
 
 use rust_ast::comment_store;
+use cfg::current_block::CurrentBlockUses;
+use std::cell::Cell;
+use rust_ast::traverse::{self, Traversal};
+use syntax::ast::{BinOpKind, BindingMode, Block, BlockCheckMode, LitKind, Local, Mutability, PatKind, RangeLimits, Ty, UnOp};
+
+/// What the caller of `structured_cfg` needs to know about the statements it produced
+#[derive(Clone, Debug)]
+pub struct StructuredInfo {
+    /// Labelled blocks need `label_break_value`
+    pub uses_labelled_blocks: bool,
+    /// Uses of `current_block` before and after resolving those we can statically
+    pub current_block_before: CurrentBlockUses,
+    pub current_block_after: CurrentBlockUses,
+    /// Comments that came after every statement, when there was no statement to carry them
+    pub unplaced_comments: Vec<String>,
+}
+
+impl StructuredInfo {
+    /// Whether the statements need a `current_block` variable
+    pub fn uses_current_block(&self) -> bool {
+        self.current_block_after != CurrentBlockUses::default()
+    }
+}
 
-/// Convert a sequence of structures produced by Relooper back into Rust statements
+/// Convert a sequence of structures produced by Relooper back into Rust statements. Labelled blocks
+/// are only used when `labelled_blocks` is set.
 pub fn structured_cfg(
     root: &Vec<Structure<StmtOrComment>>,
     comment_store: &mut comment_store::CommentStore,
     current_block: P<Expr>,
-    debug_labels: bool
-) -> Result<Vec<Stmt>, String> {
+    debug_labels: bool,
+    labelled_blocks: bool,
+) -> Result<(Vec<Stmt>, StructuredInfo), String> {
 
 
     let ast: StructuredAST<P<Expr>, P<Pat>, Label, StmtOrComment> = structured_cfg_help(
@@ -18,19 +43,24 @@ pub fn structured_cfg(
         &HashSet::new(),
         root,
         &mut HashSet::new(),
+        &HashMap::new(),
+        labelled_blocks,
+        false,
     )?;
 
+    let current_block_before = ast.current_block_uses();
+    let ast = ast.propagate_current_block();
+    let current_block_after = ast.current_block_uses();
+
     let s = StructureState {
         enable_comments: true,
         debug_labels,
         current_block,
+        uses_labelled_blocks: Cell::new(false),
     };
     let mut queued = vec![];
     let mut stmts = vec![];
     s.into_stmt(ast, comment_store, &mut queued, &mut stmts);
-    if !queued.is_empty() {
-        eprintln!("Did not find a statement for comments {:?}", queued);
-    }
 
     // If the very last statement in the vector is a `return`, we can either cut it out or replace
     // it with the returned value.
@@ -39,7 +69,18 @@ pub fn structured_cfg(
         Some(Stmt { node: StmtKind::Semi(ref ret), .. }) => {
             match ret.node {
                 ExprKind::Ret(None) => {
-                    stmts.pop();
+                    if let Some(ret) = stmts.pop() {
+                        let mut lines = comment_store.take_comment_lines(ret.span);
+                        lines.extend(queued.drain(..));
+                        if stmts.is_empty() && !lines.is_empty() {
+                            // Keep the `return` around, since it is the only place for the comments
+                            let mut ret = ret;
+                            ret.span = comment_store.add_comment_lines(lines);
+                            stmts.push(ret);
+                        } else {
+                            queued = lines;
+                        }
+                    }
                 }
                 // TODO: why does libsyntax print a ';' after this even if it is 'Expr' and not 'Semi'
 //                ExprKind::Ret(Some(ref e)) => {
@@ -52,7 +93,18 @@ pub fn structured_cfg(
         _ => { }
     }
 
-    Ok(stmts)
+    // Comments after the last statement go with that statement
+    if let Some(last) = stmts.last_mut() {
+        last.span = comment_store.append_comment_lines(last.span, queued.drain(..).collect());
+    }
+
+    let info = StructuredInfo {
+        uses_labelled_blocks: s.uses_labelled_blocks.get(),
+        current_block_before,
+        current_block_after,
+        unplaced_comments: queued,
+    };
+    Ok((stmts, info))
 }
 
 
@@ -104,6 +156,15 @@ pub trait StructuredStatement: Sized {
         exit_style: ExitStyle,            // `break` or a `continue`
         label: Option<Self::L>,           // which loop are we breaking
     ) -> Self;
+
+    /// Make a labelled block
+    fn mk_labelled_block(
+        lbl: String,
+        body: Self
+    ) -> Self;
+
+    /// Make a `break` out of a labelled block
+    fn mk_block_exit(lbl: String) -> Self;
 }
 
 
@@ -118,6 +179,8 @@ pub enum StructuredAST<E, P, L, S> {
     GotoTable(Vec<(L, StructuredAST<E,P,L,S>)>, Box<StructuredAST<E,P,L,S>>),
     Loop(Option<L>, Box<StructuredAST<E,P,L,S>>),
     Exit(ExitStyle, Option<L>),
+    LabelledBlock(String, Box<StructuredAST<E,P,L,S>>),
+    BlockExit(String),
 }
 
 impl<E, P, L, S> StructuredStatement for StructuredAST<E, P, L, S> {
@@ -161,10 +224,71 @@ impl<E, P, L, S> StructuredStatement for StructuredAST<E, P, L, S> {
     fn mk_exit(exit_style: ExitStyle, label: Option<Self::L>) -> Self {
         StructuredAST::Exit(exit_style, label)
     }
+
+    fn mk_labelled_block(lbl: String, body: Self) -> Self {
+        StructuredAST::LabelledBlock(lbl, Box::new(body))
+    }
+
+    fn mk_block_exit(lbl: String) -> Self {
+        StructuredAST::BlockExit(lbl)
+    }
+}
+
+/// How an arm of a `switch` reaches one of the entries of the `Multiple` following it, when that
+/// `Multiple` is laid out after the `match` rather than dispatched on with `current_block`.
+#[derive(Clone, Debug)]
+struct SwitchExit {
+    /// Whether `current_block` still needs to be set, for a dispatch further down
+    keep_goto: bool,
+    /// Labelled block to break out of, unless falling out of the `match` is enough
+    block: Option<String>,
+}
+
+/// Bodies of a `Multiple` following a `switch`, in the order they are laid out after the `match`
+struct SwitchLayout<S> {
+    /// Each body, along with the labelled block that ends right before it
+    bodies: Vec<(Option<String>, S)>,
+    /// Labelled block wrapping the whole switch, if something breaks out of it
+    end: Option<String>,
+}
+
+impl<S: StructuredStatement> SwitchLayout<S> {
+    /// Wrap the `match` of the switch in labelled blocks and lay the bodies out after it
+    fn wrap(self, dispatch: S) -> S {
+        let mut wrapped = dispatch;
+        let last = self.bodies.len() - 1;
+
+        for (i, (block, body)) in self.bodies.into_iter().enumerate() {
+            if let Some(block) = block {
+                wrapped = S::mk_labelled_block(block, wrapped);
+            }
+            wrapped = S::mk_append(wrapped, body);
+            if i != last {
+                let end = self.end.clone().expect("several bodies need a block to skip the others");
+                wrapped = S::mk_append(wrapped, S::mk_block_exit(end));
+            }
+        }
+
+        match self.end {
+            Some(end) => S::mk_labelled_block(end, wrapped),
+            None => wrapped,
+        }
+    }
 }
 
+/// A `Multiple` directly following a `Simple` ending in a `switch` can only be entered from the
+/// arms of that `switch`, so it can be laid out after the `match` instead.
+fn follows_switch<S>(root: &[Structure<S>], i: usize) -> bool {
+    match (i.checked_sub(1).map(|j| &root[j]), &root[i]) {
+        (Some(&Structure::Simple { terminator: Switch { .. }, .. }), &Structure::Multiple { .. }) => true,
+        _ => false,
+    }
+}
 
-/// Recursive helper for `structured_cfg`
+
+/// Recursive helper for `structured_cfg`. Inside a labelled block (but outside any loop nested
+/// in it), exiting the innermost loop needs a label, since an unlabelled `break` or `continue`
+/// can't leave a labelled block.
 ///
 /// TODO: move this into `structured_cfg`?
 fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=StmtOrComment>>(
@@ -172,13 +296,24 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
     next: &HashSet<Label>,
     root: &Vec<Structure<StmtOrComment>>,
     used_loop_labels: &mut HashSet<Label>,
+    switch_exits: &HashMap<Label, SwitchExit>,
+    labelled_blocks: bool,
+    in_labelled_block: bool,
 ) -> Result<S, String> {
 
     let mut next: &HashSet<Label> = next;
     let mut rest: S = S::empty();
 
-    for structure in root.iter().rev() {
+    // Layout of the `Multiple` that was just converted, if it is waiting for its `switch`
+    let mut switch_layout: Option<(HashMap<Label, SwitchExit>, SwitchLayout<S>)> = None;
+
+    // Switch exits are only reached by falling out of the last structure. Others fall into the
+    // structure after them, which can have the same entries (see `simplify_structure`).
+    let no_switch_exits: HashMap<Label, SwitchExit> = HashMap::new();
+
+    for (i, structure) in root.iter().enumerate().rev() {
         let mut new_rest: S = S::empty();
+        let switch_exits = if i + 1 == root.len() { switch_exits } else { &no_switch_exits };
 
         match structure {
             &Structure::Simple { ref body, ref terminator, .. } => {
@@ -187,8 +322,24 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                     new_rest = S::mk_append(new_rest, S::mk_singleton(s));
                 }
 
+                let (arm_exits, layout) = match switch_layout.take() {
+                    Some((arm_exits, layout)) => (Some(arm_exits), Some(layout)),
+                    None => (None, None),
+                };
+                let switch_exits = arm_exits.as_ref().unwrap_or(switch_exits);
+                let in_labelled_block = in_labelled_block || layout.as_ref().map_or(false, |l| l.end.is_some());
+
                 let insert_goto = |to: Label, target: &HashSet<Label>| -> S {
-                    if target.len() == 1 { S::empty() } else { S::mk_goto(to) }
+                    match switch_exits.get(&to) {
+                        Some(exit) => {
+                            let goto = if exit.keep_goto { S::mk_goto(to) } else { S::empty() };
+                            match exit.block {
+                                Some(ref block) => S::mk_append(goto, S::mk_block_exit(block.clone())),
+                                None => goto,
+                            }
+                        }
+                        None => if target.len() == 1 { S::empty() } else { S::mk_goto(to) },
+                    }
                 };
 
                 let mut branch = |slbl: &StructureLabel<StmtOrComment>| -> Result<S, String> {
@@ -199,19 +350,26 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                                 next,
                                 nested,
                                 used_loop_labels,
+                                switch_exits,
+                                labelled_blocks,
+                                in_labelled_block,
                             ),
 
                         &StructureLabel::GoTo(to) |
                         &StructureLabel::ExitTo(to) if next.contains(&to) =>
                             Ok(insert_goto(to, &next)),
 
+                        // Breaking out of a labelled block also leaves the loops inside it
+                        &StructureLabel::ExitTo(to) if switch_exits.get(&to).map_or(false, |e| e.block.is_some()) =>
+                            Ok(insert_goto(to, &next)),
+
                         &StructureLabel::ExitTo(to) => {
 
                             let mut immediate = true;
                             for &(label, ref local) in &exits {
                                 if let Some(&(ref follow, exit_style)) = local.get(&to) {
 
-                                    let lbl = if immediate {
+                                    let lbl = if immediate && !in_labelled_block {
                                         None
                                     } else {
                                         used_loop_labels.insert(label);
@@ -236,7 +394,7 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                     }
                 };
 
-                new_rest = S::mk_append(new_rest, match terminator {
+                let dispatch = match terminator {
                     &End => S::empty(),
                     &Jump(ref to) => branch(to)?,
                     &Branch(ref c, ref t, ref f) => S::mk_if(c.clone(), branch(t)?, branch(f)?),
@@ -248,9 +406,84 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
 
                         S::mk_match(expr.clone(), branched_cases)
                     },
+                };
+
+                new_rest = S::mk_append(new_rest, match layout {
+                    Some(layout) => layout.wrap(dispatch),
+                    None => dispatch,
                 });
             }
 
+            // Instead of dispatching on `current_block`, the arms of the `switch` get to the
+            // bodies by falling out of the `match` or by breaking out of labelled blocks:
+            //
+            //     'switch_s_1: {
+            //         'case_c_3: {
+            //             match x { 1 => { .. } 2 => { ..; break 'case_c_3 } _ => break 'switch_s_1 }
+            //             <first body>
+            //             break 'switch_s_1;
+            //         }
+            //         <second body>
+            //     }
+            //
+            // Labelled blocks need `label_break_value`, so without them the bodies are dispatched
+            // on like those of any other `Multiple`.
+            //
+            &Structure::Multiple { ref entries, ref branches, ref then }
+                if labelled_blocks && follows_switch(root, i) => {
+                let switch_label = root[i - 1].get_entries().iter().min()
+                    .ok_or(format!("The switch before {:?} has no entry", structure))?;
+                let end = format!("'switch_{}", switch_label.debug_print());
+
+                let then_entries: HashSet<Label> = entries
+                    .iter()
+                    .filter(|&lbl| !branches.contains_key(lbl))
+                    .cloned()
+                    .collect();
+
+                // Unless there is a single body, they all end up inside the block for the end
+                let body_count = branches.len() + if then.is_empty() { 0 } else { 1 };
+                let wrapped = body_count > 1 || (then.is_empty() && !then_entries.is_empty());
+                let in_body_block = in_labelled_block || wrapped;
+
+                let mut arm_exits = HashMap::new();
+                let mut layout = SwitchLayout { bodies: vec![], end: None };
+
+                for (lbl, body) in branches {
+                    let block = if layout.bodies.is_empty() {
+                        None
+                    } else {
+                        Some(format!("'case_{}", lbl.debug_print()))
+                    };
+                    arm_exits.insert(*lbl, SwitchExit { keep_goto: false, block: block.clone() });
+
+                    let body = structured_cfg_help(exits.clone(), next, body, used_loop_labels, switch_exits, labelled_blocks, in_body_block)?;
+                    layout.bodies.push((block, body));
+                }
+
+                // The entries left are dispatched on again by `then`, or after the switch if
+                // `then` is empty (in which case there is no need for a body).
+                let then_targets = if then.is_empty() { next } else { &then_entries };
+                let then_block = if then.is_empty() {
+                    Some(end.clone())
+                } else {
+                    then_entries.iter().min().map(|lbl| format!("'case_{}", lbl.debug_print()))
+                };
+                for lbl in &then_entries {
+                    arm_exits.insert(*lbl, SwitchExit { keep_goto: then_targets.len() > 1, block: then_block.clone() });
+                }
+                if !then.is_empty() {
+                    let body = structured_cfg_help(exits.clone(), next, then, used_loop_labels, switch_exits, labelled_blocks, in_body_block)?;
+                    layout.bodies.push((then_block, body));
+                }
+
+                if wrapped {
+                    layout.end = Some(end);
+                }
+
+                switch_layout = Some((arm_exits, layout));
+            }
+
             &Structure::Multiple { ref branches, ref then, .. } => {
                 let cases: Vec<(Label, S)> = branches
                     .iter()
@@ -260,6 +493,9 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                             next,
                             body,
                             used_loop_labels,
+                            switch_exits,
+                            labelled_blocks,
+                            in_labelled_block,
                         )?;
                         Ok((*lbl, stmts))
                     })
@@ -270,6 +506,9 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                     next,
                     then,
                     used_loop_labels,
+                    switch_exits,
+                    labelled_blocks,
+                    in_labelled_block,
                 )?;
 
                 new_rest = S::mk_append(new_rest, S::mk_goto_table(cases, then));
@@ -297,6 +536,9 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
                     entries,
                     body,
                     used_loop_labels,
+                    switch_exits,
+                    labelled_blocks,
+                    false,
                 )?;
                 let loop_lbl = if used_loop_labels.contains(label) { Some(*label) } else { None };
                 new_rest = S::mk_append(new_rest, S::mk_loop(loop_lbl, body));
@@ -313,10 +555,13 @@ fn structured_cfg_help<S: StructuredStatement<E=P<Expr>, P=P<Pat>, L=Label, S=St
 }
 
 /// Checks if there are any `Multiple` structures anywhere. Only if so will there be any need for a
-/// `current_block` variable.
+/// `current_block` variable. Those following a `switch` are laid out after its `match`, so they
+/// only need it if the ones they contain do.
 pub fn has_multiple<Stmt>(root: &Vec<Structure<Stmt>>) -> bool {
-    root.iter().any(|structure| {
+    root.iter().enumerate().any(|(i, structure)| {
         match structure {
+            &Structure::Multiple { ref branches, ref then, .. } if follows_switch(root, i) =>
+                branches.values().any(has_multiple) || has_multiple(then),
             &Structure::Simple { ref terminator, .. } => terminator
                 .get_labels()
                 .into_iter()
@@ -337,6 +582,7 @@ struct StructureState {
     enable_comments: bool,
     debug_labels: bool,
     current_block: P<Expr>,
+    uses_labelled_blocks: Cell<bool>,
 }
 
 impl StructureState {
@@ -389,7 +635,7 @@ impl StructureState {
                         };
 
                         let body = mk().block_expr(mk().block(stmts));
-                        mk().arm(pats, None as Option<P<Expr>>, body)
+                        mk().arm(merge_case_ranges(pats), None as Option<P<Expr>>, body)
                     })
                     .collect();
 
@@ -502,6 +748,8 @@ impl StructureState {
                 // Make (possibly labelled) `loop`.
                 //
                 //   * Loops that start with an `if <cond-expr> { break; }` get converted into `while` loops
+                //   * So do loops that start with an `if <cond-expr> { break; } else { .. }`
+                //   * So do loops whose whole body is an `if <cond-expr> { .. } else { break; }`
                 //
 
                 let s = comment_store.add_comment_lines(queued_comments.drain(..).collect());
@@ -512,26 +760,10 @@ impl StructureState {
                     output
                 };
 
-
-                // TODO: this is ugly but it needn't be. We are just pattern matching on particular ASTs.
-                if let Some(&Stmt{ node: syntax::ast::StmtKind::Expr(ref expr), .. }) = body.iter().nth(0) {
-                    if let syntax::ast::ExprKind::If(ref cond, ref thn, None) = expr.node {
-                        if let &syntax::ast::Block { ref stmts, rules: syntax::ast::BlockCheckMode::Default, .. } = thn.deref() {
-                            if stmts.len() == 1 {
-                                if let Some(&Stmt{ node: syntax::ast::StmtKind::Semi(ref expr), .. }) = stmts.iter().nth(0) {
-                                    if let syntax::ast::ExprKind::Break(None, None) = expr.node {
-                                        let e = mk().while_expr(
-                                            not(cond),
-                                            mk().block(body.iter().skip(1).cloned().collect()),
-                                            lbl.map(|l| l.pretty_print()),
-                                        );
-                                        output.push(mk().span(s).expr_stmt(e));
-                                        return;
-                                    }
-                                }
-                            }
-                        }
-                    }
+                if let Some((cond, body)) = while_loop(&body) {
+                    let e = mk().while_expr(cond, mk().block(body), lbl.map(|l| l.pretty_print()));
+                    output.push(mk().span(s).expr_stmt(e));
+                    return;
                 }
 
                 let e = mk().loop_expr(mk().block(body), lbl.map(|l| l.pretty_print()));
@@ -552,7 +784,439 @@ impl StructureState {
 
                 output.push(mk().span(s).semi_stmt(e));
             },
+
+            LabelledBlock(lbl, body) => {
+                // Make a labelled block, only ever exited with a labelled `break`.
+
+                let s = comment_store.add_comment_lines(queued_comments.drain(..).collect());
+
+                let body = {
+                    let mut output = vec![];
+                    self.into_stmt(*body, comment_store, queued_comments, &mut output);
+                    output
+                };
+
+                self.uses_labelled_blocks.set(true);
+                let e = mk().labelled_block_expr(mk().block(body), Some(lbl));
+
+                output.push(mk().span(s).expr_stmt(e));
+            },
+
+            BlockExit(lbl) => {
+                // Make a labelled `break` out of a block.
+
+                let s = comment_store.add_comment_lines(queued_comments.drain(..).collect());
+
+                let e = mk().break_expr(Some(lbl));
+
+                output.push(mk().span(s).semi_stmt(e));
+            },
+        }
+    }
+}
+
+/// Value of a `case` pattern, if it is an integer literal
+fn case_value(pat: &Pat) -> Option<i128> {
+    fn int_lit(expr: &Expr) -> Option<i128> {
+        match expr.node {
+            ExprKind::Lit(ref lit) => match lit.node {
+                LitKind::Int(n, _) if n <= i128::max_value() as u128 => Some(n as i128),
+                _ => None,
+            },
+            ExprKind::Unary(UnOp::Neg, ref e) => int_lit(e).map(|n| -n),
+            _ => None,
+        }
+    }
+
+    match pat.node {
+        PatKind::Lit(ref e) => int_lit(e),
+        _ => None,
+    }
+}
+
+/// Merge runs of at least three consecutive `case` values into a range pattern.
+///
+///   * `1 | 2 | 3 | 5` turns into `1..=3 | 5`
+///
+fn merge_case_ranges(pats: Vec<P<Pat>>) -> Vec<P<Pat>> {
+    if !pats.iter().all(|pat| case_value(pat).is_some()) {
+        return pats
+    }
+    let mut values: Vec<(i128, P<Pat>)> = pats
+        .into_iter()
+        .map(|pat| (case_value(&pat).unwrap(), pat))
+        .collect();
+    values.sort_by_key(|&(n, _)| n);
+
+    let mut merged = vec![];
+    let mut i = 0;
+    while i < values.len() {
+        let mut j = i;
+        while j + 1 < values.len() && values[j + 1].0 == values[j].0 + 1 {
+            j += 1;
+        }
+
+        if j - i >= 2 {
+            let lo = match values[i].1.node { PatKind::Lit(ref e) => e.clone(), _ => unreachable!() };
+            let hi = match values[j].1.node { PatKind::Lit(ref e) => e.clone(), _ => unreachable!() };
+            merged.push(mk().range_pat(lo, hi));
+        } else {
+            merged.extend(values[i..j + 1].iter().map(|&(_, ref pat)| pat.clone()));
+        }
+        i = j + 1;
+    }
+    merged
+}
+
+/// Split the body of a `loop` into the condition and body of an equivalent `while` loop, if the
+/// body starts by testing whether to leave the loop.
+fn while_loop(body: &[Stmt]) -> Option<(P<Expr>, Vec<Stmt>)> {
+    let (cond, thn, els) = match body.first() {
+        Some(&Stmt { node: StmtKind::Expr(ref expr), .. }) => match expr.node {
+            ExprKind::If(ref cond, ref thn, ref els) => (cond, thn, els),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    let rest = &body[1..];
+
+    // `into_stmt` leaves out the block around an `else` branch made of a single expression
+    let els: Option<Vec<Stmt>> = match *els {
+        None => None,
+        Some(ref els) => match els.node {
+            ExprKind::Block(ref blk, None) if blk.rules == BlockCheckMode::Default => Some(blk.stmts.clone()),
+            ExprKind::Block(..) => return None,
+            _ => Some(vec![mk().expr_stmt(els.clone())]),
+        },
+    };
+
+    if is_break(&thn.stmts) && thn.rules == BlockCheckMode::Default {
+        // if <cond-expr> { break; } [else { .. }] ..
+        let mut stmts: Vec<Stmt> = els.unwrap_or(vec![]);
+        stmts.extend(rest.iter().cloned());
+        Some((not(cond), stmts))
+    } else if els.as_ref().map_or(false, |els| is_break(els)) && rest.is_empty() && thn.rules == BlockCheckMode::Default {
+        // if <cond-expr> { .. } else { break; }
+        Some((cond.clone(), thn.stmts.clone()))
+    } else {
+        None
+    }
+}
+
+/// Check if a block only consists of an unlabelled `break`
+fn is_break(stmts: &[Stmt]) -> bool {
+    match stmts {
+        &[Stmt { node: StmtKind::Semi(ref expr), .. }] => match expr.node {
+            ExprKind::Break(None, None) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Turn counted `while` loops back into `for` loops over a range.
+///
+///   * `i = <a>; while i < <b> { ..; i += 1 }` turns into `for i in <a>..<b> { .. }`
+///   * `i = <a>; while i <= <b> { ..; i += <k> }` turns into `for i in (<a>..=<b>).step_by(<k>) { .. }`
+///
+/// Both `i` and `<b>` have to be local variables declared in the function (`<b>` can also be a
+/// literal) whose address is never taken, so that nothing but the function itself can change them.
+/// The body of the loop must not change `i` or `<b>`, nor `continue` the loop (which would skip the
+/// increment). Since the `for` loop scopes `i`, `i` must not be used anywhere else in the function
+/// either, in which case its now unused declaration gets removed.
+pub fn reconstruct_for_ranges(stmts: Vec<Stmt>) -> Vec<Stmt> {
+    let mut all_uses = IdentUses::default();
+    let stmts: Vec<Stmt> = stmts.into_iter().map(|s| all_uses.traverse_stmt(s)).collect();
+    let locals = LocalVars {
+        declared: all_uses.declared.clone(),
+        address_taken: all_uses.address_taken.clone(),
+    };
+
+    let mut finder = ForRanges { locals: &locals, convert: None, candidate_uses: HashMap::new() };
+    let stmts = finder.for_ranges(stmts);
+
+    let convert: HashSet<String> = finder.candidate_uses
+        .into_iter()
+        .filter(|&(ref var, uses)| all_uses.uses.get(var) == Some(&uses))
+        .map(|(var, _)| var)
+        .collect();
+    if convert.is_empty() {
+        return stmts
+    }
+
+    let mut rewriter = ForRanges { locals: &locals, convert: Some(convert), candidate_uses: HashMap::new() };
+    rewriter.for_ranges(stmts)
+}
+
+/// Counts how often each variable is mentioned, and finds the local variables declared
+#[derive(Default)]
+struct IdentUses {
+    uses: HashMap<String, usize>,
+    declared: HashMap<String, Option<P<Ty>>>,
+    address_taken: HashSet<String>,
+}
+
+impl Traversal for IdentUses {
+    fn traverse_expr(&mut self, e: Expr) -> Expr {
+        if let Some(var) = local_var(&e) {
+            *self.uses.entry(var).or_insert(0) += 1;
+        }
+        if let ExprKind::AddrOf(_, ref e) = e.node {
+            self.address_taken.extend(local_var(e));
+        }
+        traverse::traverse_expr_def(self, e)
+    }
+
+    fn traverse_local(&mut self, l: Local) -> Local {
+        if let PatKind::Ident(_, ident, None) = l.pat.node {
+            self.declared.insert(ident.to_string(), l.ty.clone());
+        }
+        traverse::traverse_local_def(self, l)
+    }
+}
+
+/// Local variables of a function which can only change through assignments to them. Names are
+/// unique within a function, and don't clash with the globals it uses, so a variable named like
+/// one of these is that variable.
+struct LocalVars {
+    /// Variables declared with `let`, along with their type
+    declared: HashMap<String, Option<P<Ty>>>,
+    /// Variables borrowed somewhere in the function
+    address_taken: HashSet<String>,
+}
+
+impl LocalVars {
+    fn is_private(&self, var: &str) -> bool {
+        self.declared.contains_key(var) && !self.address_taken.contains(var)
+    }
+}
+
+/// Finds counted loops (when `convert` is `None`) or rewrites those of the given variables.
+struct ForRanges<'a> {
+    locals: &'a LocalVars,
+    convert: Option<HashSet<String>>,
+    /// How often each variable is mentioned by the counted loops over it
+    candidate_uses: HashMap<String, usize>,
+}
+
+/// A counted loop over `var`, along with the statement initializing `var`
+struct ForRange {
+    var: String,
+    range: P<Expr>,
+    body: Vec<Stmt>,
+    label: Option<syntax::ast::Label>,
+}
+
+impl<'a> ForRanges<'a> {
+    fn for_ranges(&mut self, stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let mut output: Vec<Stmt> = vec![];
+
+        for stmt in stmts {
+            let counted = match output.last() {
+                Some(init) => counted_loop(init, &stmt, self.locals),
+                None => None,
+            };
+
+            match counted {
+                Some(ForRange { var, range, body, label }) => match self.convert {
+                    None => {
+                        let mut uses = IdentUses::default();
+                        let init = output.last().cloned().expect("counted loops have an initializer");
+                        uses.traverse_stmt(init);
+                        uses.traverse_stmt(stmt.clone());
+                        *self.candidate_uses.entry(var.clone()).or_insert(0) += uses.uses.get(&var).cloned().unwrap_or(0);
+                        output.push(stmt);
+                    }
+                    Some(ref convert) if convert.contains(&var) => {
+                        let init = output.pop().expect("counted loops have an initializer");
+                        let body = mk().block(body);
+                        let e = mk().for_expr(mk().ident_pat(&var), range, body, label.map(|l| l.ident));
+                        output.push(mk().span(init.span).expr_stmt(e));
+                    }
+                    Some(_) => output.push(stmt),
+                },
+                None => output.push(stmt),
+            }
         }
+
+        // The declarations of the converted variables are now unused
+        if let Some(ref convert) = self.convert {
+            output.retain(|stmt| match stmt.node {
+                StmtKind::Local(ref local) => match (&local.pat.node, &local.init) {
+                    (&PatKind::Ident(_, ident, None), init) if convert.contains(&ident.to_string()) =>
+                        !init.as_ref().map_or(true, |init| is_lit(init)),
+                    _ => true,
+                },
+                _ => true,
+            });
+        }
+
+        output.into_iter().map(|s| self.traverse_stmt(s)).collect()
+    }
+}
+
+impl<'a> Traversal for ForRanges<'a> {
+    fn traverse_block(&mut self, mut b: Block) -> Block {
+        b.stmts = self.for_ranges(b.stmts);
+        b
+    }
+}
+
+/// Recognize `init` followed by `stmt` as a counted loop
+fn counted_loop(init: &Stmt, stmt: &Stmt, locals: &LocalVars) -> Option<ForRange> {
+    let (var, start) = match init.node {
+        StmtKind::Expr(ref e) | StmtKind::Semi(ref e) => match e.node {
+            ExprKind::Assign(ref lhs, ref rhs) => (local_var(lhs)?, rhs.clone()),
+            _ => return None,
+        },
+        StmtKind::Local(ref local) => match (&local.pat.node, &local.init) {
+            (&PatKind::Ident(BindingMode::ByValue(_), ident, None), &Some(ref init)) => (ident.to_string(), init.clone()),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    if !locals.is_private(&var) {
+        return None
+    }
+
+    let (cond, body, label) = match stmt.node {
+        StmtKind::Expr(ref e) | StmtKind::Semi(ref e) => match e.node {
+            ExprKind::While(ref cond, ref body, label) => (cond, body, label),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let (limits, end) = match cond.node {
+        ExprKind::Binary(op, ref lhs, ref rhs) if local_var(lhs).as_ref() == Some(&var) => match op.node {
+            BinOpKind::Lt => (RangeLimits::HalfOpen, rhs.clone()),
+            BinOpKind::Le => (RangeLimits::Closed, rhs.clone()),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let (increment, body) = body.stmts.split_last()?;
+    let step = increment_of(increment, &var)?;
+
+    // The range is only evaluated once
+    let end_var = match end.node {
+        ExprKind::Lit(_) => None,
+        _ => Some(local_var(&end).filter(|end| locals.is_private(end))?),
+    };
+    let mut mentions = IdentUses::default();
+    mentions.traverse_expr((*start).clone());
+    if mentions.uses.contains_key(&var) || end_var.as_ref() == Some(&var) {
+        return None
+    }
+
+    let mut changes = LoopChanges { vars: vec![var.clone()], label, depth: 0, found: false };
+    changes.vars.extend(end_var);
+    for s in body {
+        changes.traverse_stmt(s.clone());
+    }
+    if changes.found {
+        return None
+    }
+
+    // Without its declaration, `i` gets the type of the range
+    let start = match locals.declared[&var] {
+        Some(ref ty) if !is_lit(&start) && !is_cast(&start) => mk().cast_expr(start, ty.clone()),
+        _ => start,
+    };
+
+    let mut range = mk().range_expr(start, end, limits);
+    if step != 1 {
+        let step = mk().lit_expr(mk().int_lit(step, LitIntType::Unsuffixed));
+        range = mk().method_call_expr(mk().paren_expr(range), "step_by", vec![step]);
+    }
+
+    Some(ForRange { var, range, body: body.to_vec(), label })
+}
+
+/// The amount `stmt` increments `var` by, if it is a constant
+///
+///   * `<var> += <k>`
+///   * `<var> = <var>.wrapping_add(<k>)`
+///
+fn increment_of(stmt: &Stmt, var: &str) -> Option<u128> {
+    let e = match stmt.node {
+        StmtKind::Expr(ref e) | StmtKind::Semi(ref e) => e,
+        _ => return None,
+    };
+
+    let step = match e.node {
+        ExprKind::AssignOp(op, ref lhs, ref rhs) if op.node == BinOpKind::Add && local_var(lhs)? == var => rhs,
+        ExprKind::Assign(ref lhs, ref rhs) if local_var(lhs)? == var => match rhs.node {
+            ExprKind::MethodCall(ref seg, ref args) if seg.ident.name == "wrapping_add" && args.len() == 2 &&
+                local_var(&args[0]).as_ref().map(|s| s.as_str()) == Some(var) => &args[1],
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    match step.node {
+        ExprKind::Lit(ref lit) => match lit.node {
+            LitKind::Int(k, _) if k > 0 => Some(k),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Checks whether a loop body changes any of `vars`, or `continue`s the loop
+struct LoopChanges {
+    vars: Vec<String>,
+    label: Option<syntax::ast::Label>,
+    /// Number of loops nested in the body we are in
+    depth: usize,
+    found: bool,
+}
+
+impl Traversal for LoopChanges {
+    fn traverse_expr(&mut self, e: Expr) -> Expr {
+        let changed = match e.node {
+            ExprKind::Assign(ref lhs, _) |
+            ExprKind::AssignOp(_, ref lhs, _) |
+            ExprKind::AddrOf(Mutability::Mutable, ref lhs) =>
+                local_var(lhs).map_or(false, |var| self.vars.contains(&var)),
+            ExprKind::Continue(None) => self.depth == 0,
+            ExprKind::Continue(Some(lbl)) => self.label.map_or(false, |l| l.ident == lbl.ident),
+            _ => false,
+        };
+        self.found |= changed;
+
+        let is_loop = match e.node {
+            ExprKind::While(..) | ExprKind::WhileLet(..) | ExprKind::ForLoop(..) | ExprKind::Loop(..) => true,
+            _ => false,
+        };
+        if is_loop { self.depth += 1; }
+        let e = traverse::traverse_expr_def(self, e);
+        if is_loop { self.depth -= 1; }
+        e
+    }
+}
+
+/// Name of the local variable an expression consists of
+fn local_var(e: &Expr) -> Option<String> {
+    match e.node {
+        ExprKind::Path(None, ref path) if path.segments.len() == 1 =>
+            Some(path.segments[0].ident.to_string()),
+        _ => None,
+    }
+}
+
+fn is_lit(e: &Expr) -> bool {
+    match e.node {
+        ExprKind::Lit(_) => true,
+        _ => false,
+    }
+}
+
+fn is_cast(e: &Expr) -> bool {
+    match e.node {
+        ExprKind::Cast(..) => true,
+        _ => false,
     }
 }
 
@@ -566,3 +1230,74 @@ fn not(bool_expr: &P<Expr>) -> P<Expr> {
         _ => mk().unary_expr("!", bool_expr.clone()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntax::with_globals;
+
+    fn int(i: u128) -> P<Expr> {
+        mk().lit_expr(mk().int_lit(i, "i32"))
+    }
+
+    fn let_int(var: &str, init: P<Expr>) -> Stmt {
+        mk().local_stmt(P(mk().local(mk().mutbl().ident_pat(var), Some(mk().path_ty(vec!["i32"])), Some(init))))
+    }
+
+    /// `<init>; while i < <end> { x += i; <extra>; i += 1 }`
+    fn counted(init: Stmt, end: P<Expr>, extra: Vec<Stmt>) -> Vec<Stmt> {
+        let mut body = vec![mk().semi_stmt(mk().assign_op_expr(BinOpKind::Add, mk().ident_expr("x"), mk().ident_expr("i")))];
+        body.extend(extra);
+        body.push(mk().semi_stmt(mk().assign_op_expr(BinOpKind::Add, mk().ident_expr("i"), int(1))));
+        let cond = mk().binary_expr(BinOpKind::Lt, mk().ident_expr("i"), end);
+        let while_ = mk().while_expr(cond, mk().block(body), None as Option<syntax::ast::Ident>);
+        vec![let_int("x", int(0)), init, mk().expr_stmt(while_)]
+    }
+
+    fn print(stmts: &[Stmt]) -> String {
+        stmts.iter().map(pprust::stmt_to_string).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn local_counter() {
+        with_globals(|| {
+            let stmts = counted(let_int("i", int(0)), int(10), vec![]);
+            let stmts = reconstruct_for_ranges(stmts);
+            assert_eq!(print(&stmts), "let mut x: i32 = 0i32; for i in 0i32..10i32 { x += i; }");
+        })
+    }
+
+    #[test]
+    fn global_counter() {
+        with_globals(|| {
+            // `i` isn't declared in the function, so it may be a `static mut`
+            let init = mk().semi_stmt(mk().assign_expr(mk().ident_expr("i"), int(0)));
+            let stmts = reconstruct_for_ranges(counted(init, int(10), vec![]));
+            assert!(print(&stmts).contains("while"));
+        })
+    }
+
+    #[test]
+    fn borrowed_bound() {
+        with_globals(|| {
+            // A call in the body could change `n` through the pointer to it
+            let mut stmts = vec![let_int("n", int(10))];
+            let borrow = mk().addr_of_expr(mk().ident_expr("n"));
+            stmts.push(mk().local_stmt(P(mk().local(mk().ident_pat("p"), None as Option<P<Ty>>, Some(borrow)))));
+            let call = mk().semi_stmt(mk().call_expr(mk().ident_expr("f"), vec![mk().ident_expr("p")]));
+            stmts.extend(counted(let_int("i", int(0)), mk().ident_expr("n"), vec![call]));
+            let stmts = reconstruct_for_ranges(stmts);
+            assert!(print(&stmts).contains("while"));
+        })
+    }
+
+    #[test]
+    fn start_keeps_type() {
+        with_globals(|| {
+            let mut stmts = vec![let_int("a", int(3))];
+            stmts.extend(counted(let_int("i", mk().ident_expr("a")), int(10), vec![]));
+            let stmts = reconstruct_for_ranges(stmts);
+            assert!(print(&stmts).contains("for i in a as i32..10i32"), "{}", print(&stmts));
+        })
+    }
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use serde_cbor::{Value, from_value};
-use serde_cbor::error;
 use std;
+use std::fmt;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
@@ -38,12 +38,53 @@ impl TypeNode {
     pub const VOLATILE_MASK: u64 = 0b100;
 }
 
+/// The data model of the target the AST was built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TargetInfo {
+    pub char_is_signed: bool,
+    /// Widths of the integer types and of pointers, in bits
+    pub short_width: u64,
+    pub int_width: u64,
+    pub long_width: u64,
+    pub long_long_width: u64,
+    pub pointer_width: u64,
+}
+
+impl Default for TargetInfo {
+    /// The LP64 data model of x86-64 Linux and macOS
+    fn default() -> Self {
+        TargetInfo {
+            char_is_signed: true,
+            short_width: 16,
+            int_width: 32,
+            long_width: 64,
+            long_long_width: 64,
+            pointer_width: 64,
+        }
+    }
+}
+
+/// A region of a C file only compiled when a macro is (or is not) defined, from a branch of an
+/// `#ifdef`, `#ifndef`, or `#if defined(..)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConditionalRegion {
+    pub fileid: u64,
+    /// Lines of the directives opening and closing the region
+    pub begin_line: u64,
+    pub end_line: u64,
+    pub macro_name: String,
+    /// Whether the region is compiled when the macro is defined, rather than when it is not
+    pub when_defined: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct AstContext {
     pub ast_nodes: HashMap<u64, AstNode>,
     pub type_nodes: HashMap<u64, TypeNode>,
     pub top_nodes: Vec<u64>,
     pub comments: Vec<CommentNode>,
+    pub target: TargetInfo,
+    pub conditionals: Vec<ConditionalRegion>,
 }
 
 pub fn expect_opt_str(val: &Value) -> Option<Option<&str>> {
@@ -62,73 +103,231 @@ pub fn expect_opt_u64(val: &Value) -> Option<Option<u64>> {
     }
 }
 
-fn import_ast_tag(tag: u64) -> ASTEntryTag {
-    unsafe {
-        return std::mem::transmute::<u32, ASTEntryTag>(tag as u32);
+/// Why the output of the AST exporter could not be imported
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportError {
+    /// The input does not have the expected layout
+    Layout(String),
+    /// The input comes from an exporter which predates schema versions
+    MissingSchemaVersion,
+    /// The input comes from an exporter built against another version of `ast_tags.hpp`
+    SchemaVersion { found: u64, expected: u64 },
+    /// A node of the input is malformed
+    Node { id: Option<u64>, tag: Option<u64>, problem: String },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::Layout(ref problem) =>
+                write!(f, "Malformed AST file: {}", problem),
+            ImportError::MissingSchemaVersion =>
+                write!(f, "AST file has no schema version; it was written by an older AST exporter \
+                           (expected schema version {})", AST_SCHEMA_VERSION),
+            ImportError::SchemaVersion { found, expected } =>
+                write!(f, "AST file has schema version {}, but this importer expects version {}; \
+                           rebuild the AST exporter and importer from the same sources", found, expected),
+            ImportError::Node { id, tag, ref problem } => {
+                write!(f, "Malformed AST node")?;
+                if let Some(id) = id {
+                    write!(f, " {}", id)?;
+                }
+                if let Some(tag) = tag {
+                    write!(f, " (tag {})", tag)?;
+                }
+                write!(f, ": {}", problem)
+            }
+        }
+    }
+}
+
+/// Tags are numbered consecutively within each group of `ast_tags.hpp`. These are the first and
+/// last tags of each group, and need to be kept up to date when tags are added (the `tag_ranges`
+/// test checks them against the header).
+const AST_TAG_RANGES: &[(ASTEntryTag, ASTEntryTag)] = &[
+    (ASTEntryTag::TagFunctionDecl, ASTEntryTag::TagUnionDecl),
+    (ASTEntryTag::TagCompoundStmt, ASTEntryTag::TagAttributedStmt),
+    (ASTEntryTag::TagBinaryOperator, ASTEntryTag::TagDesignatedInitExpr),
+    (ASTEntryTag::TagIntegerLiteral, ASTEntryTag::TagFloatingLiteral),
+];
+
+const TYPE_TAG_RANGES: &[(TypeTag, TypeTag)] = &[
+    (TypeTag::TagTypeUnknown, TypeTag::TagTypeUnknown),
+    (TypeTag::TagInt, TypeTag::TagHalf),
+];
+
+fn import_ast_tag(tag: u64) -> Option<ASTEntryTag> {
+    let known = AST_TAG_RANGES.iter().any(|&(first, last)| first as u64 <= tag && tag <= last as u64);
+    if known {
+        Some(unsafe { std::mem::transmute::<u32, ASTEntryTag>(tag as u32) })
+    } else {
+        None
     }
 }
 
-fn import_type_tag(tag: u64) -> TypeTag {
-    unsafe {
-        return std::mem::transmute::<u32, TypeTag>(tag as u32);
+fn import_type_tag(tag: u64) -> Option<TypeTag> {
+    let known = TYPE_TAG_RANGES.iter().any(|&(first, last)| first as u64 <= tag && tag <= last as u64);
+    if known {
+        Some(unsafe { std::mem::transmute::<u32, TypeTag>(tag as u32) })
+    } else {
+        None
     }
 }
 
-pub fn process(items: Value) -> error::Result<AstContext> {
+/// Import the output of the AST exporter: a schema version followed by the nodes, the top-level
+/// declarations, the file names, the comments, the data model of the target, and the regions of
+/// simple conditionals.
+pub fn process(items: Value) -> Result<AstContext, ImportError> {
+
+    let mut items = match items {
+        Value::Array(items) => items,
+        _ => return Err(ImportError::Layout(format!("expected an array at the top level"))),
+    };
+
+    match items.first() {
+        Some(&Value::U64(version)) if version == AST_SCHEMA_VERSION as u64 => { }
+        Some(&Value::U64(found)) =>
+            return Err(ImportError::SchemaVersion { found, expected: AST_SCHEMA_VERSION as u64 }),
+        _ => return Err(ImportError::MissingSchemaVersion),
+    }
+    items.remove(0);
 
     let mut asts: HashMap<u64, AstNode> = HashMap::new();
     let mut types: HashMap<u64, TypeNode> = HashMap::new();
     let mut comments: Vec<CommentNode> = vec![];
 
-    let (all_nodes, top_nodes, _filenames, raw_comments):
+    let (all_nodes, top_nodes, _filenames, raw_comments, raw_target, raw_conditionals):
         (Vec<Vec<Value>>,
          Vec<u64>,
          Vec<String>,
          Vec<(u64, u64, u64, String)>,
-        ) = from_value(items)?;
+         (bool, u64, u64, u64, u64, u64),
+         Vec<(u64, u64, u64, u64, String, bool)>,
+        ) = from_value(Value::Array(items)).map_err(|e| ImportError::Layout(format!("{}", e)))?;
+
+    let (char_is_signed, short_width, int_width, long_width, long_long_width, pointer_width) = raw_target;
+    let target = TargetInfo { char_is_signed, short_width, int_width, long_width, long_long_width, pointer_width };
 
     for (fileid, line, column, string) in raw_comments {
         comments.push(CommentNode{fileid, line, column, string})
     }
 
+    let conditionals = raw_conditionals.into_iter()
+        .map(|(fileid, begin_line, _, end_line, macro_name, when_defined)|
+            ConditionalRegion { fileid, begin_line, end_line, macro_name, when_defined })
+        .collect();
+
     for entry in all_nodes {
-        let entry_id = entry[0].as_u64().unwrap();
-        let tag = entry[1].as_u64().unwrap();
+        let entry_id = entry.get(0).and_then(Value::as_u64);
+        let tag = entry.get(1).and_then(Value::as_u64);
+        let node_error = |problem: &str| ImportError::Node { id: entry_id, tag, problem: problem.to_string() };
+
+        let entry_id = entry_id.ok_or_else(|| node_error("missing node ID"))?;
+        let tag = tag.ok_or_else(|| node_error("missing tag"))?;
+        let field = |i: usize, what: &str| entry.get(i).ok_or_else(|| node_error(&format!("missing {}", what)));
+        let u64_field = |i: usize, what: &str| field(i, what)?.as_u64()
+            .ok_or_else(|| node_error(&format!("{} is not an unsigned integer", what)));
 
-        if tag < 400 {
+        if let Some(ast_tag) = import_ast_tag(tag) {
 
-            let children =
-                entry[2].as_array().unwrap()
-                    .iter()
-                    .map(|x| expect_opt_u64(x).unwrap())
-                    .collect::<Vec<Option<u64>>>();
+            let children = field(2, "children")?.as_array()
+                .ok_or_else(|| node_error("children are not an array"))?
+                .iter()
+                .map(|x| expect_opt_u64(x).ok_or_else(|| node_error("child is not a node ID or null")))
+                .collect::<Result<Vec<Option<u64>>, ImportError>>()?;
 
-            let type_id: Option<u64> = expect_opt_u64(&entry[6]).unwrap();
+            let type_id: Option<u64> = expect_opt_u64(field(6, "type")?)
+                .ok_or_else(|| node_error("type is not a type ID or null"))?;
 
             let node = AstNode {
-                tag: import_ast_tag(tag),
+                tag: ast_tag,
                 children,
-                fileid: entry[3].as_u64().unwrap(),
-                line: entry[4].as_u64().unwrap(),
-                column: entry[5].as_u64().unwrap(),
+                fileid: u64_field(3, "file ID")?,
+                line: u64_field(4, "line")?,
+                column: u64_field(5, "column")?,
                 type_id,
                 extras: entry[7..].to_vec(),
             };
 
             asts.insert(entry_id, node);
-        } else {
+        } else if let Some(type_tag) = import_type_tag(tag) {
             let node = TypeNode {
-                tag: import_type_tag(tag),
+                tag: type_tag,
                 extras: entry[2..].to_vec(),
             };
 
             types.insert(entry_id, node);
+        } else {
+            return Err(node_error("unknown tag"))
         }
     }
+
+    if let Some(missing) = top_nodes.iter().find(|id| !asts.contains_key(id)) {
+        return Err(ImportError::Node { id: Some(*missing), tag: None, problem: format!("top-level declaration is missing") })
+    }
+
     Ok(AstContext {
         top_nodes,
         ast_nodes: asts,
         type_nodes: types,
         comments,
+        target,
+        conditionals,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unversioned_input() {
+        let items = Value::Array(vec![Value::Array(vec![]), Value::Array(vec![])]);
+        assert_eq!(process(items).err(), Some(ImportError::MissingSchemaVersion));
+    }
+
+    /// The first and last value of each run of consecutively numbered tags in `enum name` of
+    /// `ast_tags.hpp`
+    fn header_tag_ranges(name: &str) -> Vec<(u64, u64)> {
+        let header = include_str!("../../ast-exporter/ast_tags.hpp");
+        let start = header.find(&format!("enum {} {{", name)).expect("enum missing from the header");
+        let body = &header[start..];
+        let body = &body[body.find('{').unwrap() + 1..body.find("};").unwrap()];
+
+        let mut ranges: Vec<(u64, u64)> = vec![];
+        let entries = body.lines().map(|l| l.split("//").next().unwrap()).collect::<Vec<_>>().join("");
+        for entry in entries.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            match entry.find('=') {
+                Some(eq) => {
+                    let value = entry[eq + 1..].trim().parse().expect("tag value is not a number");
+                    ranges.push((value, value));
+                }
+                None => ranges.last_mut().expect("first tag has no value").1 += 1,
+            }
+        }
+        ranges
+    }
+
+    #[test]
+    fn tag_ranges() {
+        let ast_ranges: Vec<(u64, u64)> = AST_TAG_RANGES
+            .iter()
+            .map(|&(first, last)| (first as u64, last as u64))
+            .collect();
+        assert_eq!(ast_ranges, header_tag_ranges("ASTEntryTag"));
+
+        let type_ranges: Vec<(u64, u64)> = TYPE_TAG_RANGES
+            .iter()
+            .map(|&(first, last)| (first as u64, last as u64))
+            .collect();
+        assert_eq!(type_ranges, header_tag_ranges("TypeTag"));
+    }
+
+    #[test]
+    fn mismatched_version() {
+        let found = AST_SCHEMA_VERSION as u64 + 1;
+        let items = Value::Array(vec![Value::U64(found), Value::Array(vec![])]);
+        let expected = AST_SCHEMA_VERSION as u64;
+        assert_eq!(process(items).err(), Some(ImportError::SchemaVersion { found, expected }));
+    }
+}
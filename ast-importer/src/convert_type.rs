@@ -7,8 +7,15 @@ use renamer::*;
 use std::collections::{HashSet,HashMap};
 use c_ast::CDeclId;
 
+#[derive(Clone)]
 pub struct TypeConverter {
     pub translate_valist: bool,
+    /// Fail instead of using nightly features
+    pub target_stable: bool,
+    pub no_std: bool,
+    pub fixed_width_ints: bool,
+    /// Keep the `libc` aliases of C's arithmetic types despite `fixed_width_ints`
+    pub c_type_aliases: bool,
     renamer: Renamer<CDeclId>,
     fields: HashMap<CDeclId, Renamer<CFieldId>>,
     features: HashSet<&'static str>,
@@ -51,6 +58,10 @@ impl TypeConverter {
     pub fn new() -> TypeConverter {
         TypeConverter {
             translate_valist: false,
+            target_stable: false,
+            no_std: false,
+            fixed_width_ints: false,
+            c_type_aliases: false,
             renamer: Renamer::new(&RESERVED_NAMES),
             fields: HashMap::new(),
             features: HashSet::new(),
@@ -61,6 +72,86 @@ impl TypeConverter {
         &self.features
     }
 
+    /// Record the use of a language feature, which fails when targeting stable Rust
+    fn require_feature(&mut self, feature: &'static str) -> Result<(), String> {
+        if self.target_stable {
+            Err(format!("Translating this requires the nightly feature `{}`", feature))
+        } else {
+            self.features.insert(feature);
+            Ok(())
+        }
+    }
+
+    /// The crate paths into the standard library start from: `core` when emitting `no_std` code
+    pub fn std_crate(&self) -> &'static str {
+        if self.no_std { "core" } else { "std" }
+    }
+
+    /// A C type like `c_int`, from `libc` or, without `std`, from `cty` (`core` has no C types)
+    pub fn c_type(&self, name: &str) -> P<Ty> {
+        if self.no_std {
+            mk().path_ty(vec!["cty", name])
+        } else {
+            mk().path_ty(vec!["libc", name])
+        }
+    }
+
+    /// A C arithmetic type: its `libc` alias, or with `fixed_width_ints` the Rust type with the
+    /// same representation on the target
+    pub fn arithmetic_type(&self, ctxt: &TypedAstContext, ctype: CTypeId, alias: &str) -> P<Ty> {
+        self.arithmetic_format_type(ctxt.int_format(ctype), alias)
+    }
+
+    /// C's `int`, which comparisons and logical operators evaluate to, see `arithmetic_type`
+    pub fn int_type(&self, ctxt: &TypedAstContext) -> P<Ty> {
+        self.arithmetic_format_type(Some((ctxt.target.int_width as u32, true)), "c_int")
+    }
+
+    fn arithmetic_format_type(&self, int_format: Option<(u32, bool)>, alias: &str) -> P<Ty> {
+        if !self.fixed_width_ints || self.c_type_aliases {
+            return self.c_type(alias)
+        }
+        let name = match int_format {
+            Some((width, true)) => format!("i{}", width),
+            Some((width, false)) => format!("u{}", width),
+            None if alias == "c_float" => "f32".to_string(),
+            None => "f64".to_string(),
+        };
+        mk().path_ty(vec![name])
+    }
+
+    /// With `fixed_width_ints`, the Rust integer type to use directly in place of a `<stdint.h>` or
+    /// `<stddef.h>` typedef like `uint32_t` or `size_t`. Typedefs whose underlying type does not
+    /// match their name are left alone.
+    pub fn fixed_width_typedef(&self, ctxt: &TypedAstContext, typedef_id: CTypedefId) -> Option<P<Ty>> {
+        if !self.fixed_width_ints {
+            return None
+        }
+        let (name, typ) = match ctxt[typedef_id].kind {
+            CDeclKind::Typedef { ref name, typ, .. } => (name.trim_left_matches('_'), typ.ctype),
+            _ => return None,
+        };
+        let pointer_width = ctxt.target.pointer_width as u32;
+        let (rust_name, width, signed) = match name {
+            "int8_t" => ("i8", 8, true),
+            "int16_t" => ("i16", 16, true),
+            "int32_t" => ("i32", 32, true),
+            "int64_t" => ("i64", 64, true),
+            "uint8_t" => ("u8", 8, false),
+            "uint16_t" => ("u16", 16, false),
+            "uint32_t" => ("u32", 32, false),
+            "uint64_t" => ("u64", 64, false),
+            "intptr_t" | "ssize_t" | "ptrdiff_t" => ("isize", pointer_width, true),
+            "uintptr_t" | "size_t" => ("usize", pointer_width, false),
+            _ => return None,
+        };
+        if ctxt.int_format(typ) == Some((width, signed)) {
+            Some(mk().path_ty(vec![rust_name]))
+        } else {
+            None
+        }
+    }
+
     pub fn declare_decl_name(&mut self, decl_id: CDeclId, name: &str) -> String {
         self.renamer.insert(decl_id, name).expect("Name already assigned")
     }
@@ -73,6 +164,11 @@ impl TypeConverter {
         self.renamer.get(&decl_id)
     }
 
+    /// Pick a name for a type with no declaration in the C source
+    pub fn declare_type_name(&mut self, name: &str) -> String {
+        self.renamer.pick_name(name)
+    }
+
     pub fn declare_field_name(&mut self, record_id: CRecordId, field_id: CFieldId, name: &str) -> String {
 
         let name = if name.is_empty() { "unnamed" } else { name };
@@ -127,7 +223,7 @@ impl TypeConverter {
             // in the case of pointers.
             CTypeKind::Void => {
                 let mutbl = if qtype.qualifiers.is_const { Mutability::Immutable } else { Mutability::Mutable };
-                return Ok(mk().set_mutbl(mutbl).ptr_ty(mk().path_ty(vec!["libc","c_void"])))
+                return Ok(mk().set_mutbl(mutbl).ptr_ty(self.c_type("c_void")))
             }
 
             CTypeKind::VariableArray(mut elt,_len) => {
@@ -154,8 +250,8 @@ impl TypeConverter {
                 if self.translate_valist {
                     if let CDeclKind::Struct { name: Some(ref struct_name), .. } = ctxt[struct_id].kind {
                         if struct_name == "__va_list_tag" {
-                            self.features.insert("c_variadic");
-                            let path = vec!["","std","ffi","VaList"];
+                            self.require_feature("c_variadic")?;
+                            let path = vec!["", self.std_crate(), "ffi", "VaList"];
                             let ty = mk().path_ty(path);
                             return Ok(ty)
                         }
@@ -178,20 +274,20 @@ impl TypeConverter {
         match ctxt.index(ctype).kind {
             CTypeKind::Void => Ok(mk().tuple_ty(vec![] as Vec<P<Ty>>)),
             CTypeKind::Bool => Ok(mk().path_ty(mk().path(vec!["bool"]))),
-            CTypeKind::Short => Ok(mk().path_ty(mk().path(vec!["libc","c_short"]))),
-            CTypeKind::Int => Ok(mk().path_ty(mk().path(vec!["libc","c_int"]))),
-            CTypeKind::Long => Ok(mk().path_ty(mk().path(vec!["libc","c_long"]))),
-            CTypeKind::LongLong => Ok(mk().path_ty(mk().path(vec!["libc","c_longlong"]))),
-            CTypeKind::UShort => Ok(mk().path_ty(mk().path(vec!["libc","c_ushort"]))),
-            CTypeKind::UInt => Ok(mk().path_ty(mk().path(vec!["libc","c_uint"]))),
-            CTypeKind::ULong => Ok(mk().path_ty(mk().path(vec!["libc","c_ulong"]))),
-            CTypeKind::ULongLong => Ok(mk().path_ty(mk().path(vec!["libc","c_ulonglong"]))),
-            CTypeKind::SChar => Ok(mk().path_ty(mk().path(vec!["libc","c_schar"]))),
-            CTypeKind::UChar => Ok(mk().path_ty(mk().path(vec!["libc","c_uchar"]))),
-            CTypeKind::Char => Ok(mk().path_ty(mk().path(vec!["libc","c_char"]))),
-            CTypeKind::Double => Ok(mk().path_ty(mk().path(vec!["libc","c_double"]))),
-            CTypeKind::LongDouble => Ok(mk().path_ty(mk().path(vec!["libc","c_double"]))),
-            CTypeKind::Float => Ok(mk().path_ty(mk().path(vec!["libc","c_float"]))),
+            CTypeKind::Short => Ok(self.arithmetic_type(ctxt, ctype, "c_short")),
+            CTypeKind::Int => Ok(self.arithmetic_type(ctxt, ctype, "c_int")),
+            CTypeKind::Long => Ok(self.arithmetic_type(ctxt, ctype, "c_long")),
+            CTypeKind::LongLong => Ok(self.arithmetic_type(ctxt, ctype, "c_longlong")),
+            CTypeKind::UShort => Ok(self.arithmetic_type(ctxt, ctype, "c_ushort")),
+            CTypeKind::UInt => Ok(self.arithmetic_type(ctxt, ctype, "c_uint")),
+            CTypeKind::ULong => Ok(self.arithmetic_type(ctxt, ctype, "c_ulong")),
+            CTypeKind::ULongLong => Ok(self.arithmetic_type(ctxt, ctype, "c_ulonglong")),
+            CTypeKind::SChar => Ok(self.arithmetic_type(ctxt, ctype, "c_schar")),
+            CTypeKind::UChar => Ok(self.arithmetic_type(ctxt, ctype, "c_uchar")),
+            CTypeKind::Char => Ok(self.arithmetic_type(ctxt, ctype, "c_char")),
+            CTypeKind::Double => Ok(self.arithmetic_type(ctxt, ctype, "c_double")),
+            CTypeKind::LongDouble => Ok(self.arithmetic_type(ctxt, ctype, "c_double")),
+            CTypeKind::Float => Ok(self.arithmetic_type(ctxt, ctype, "c_float")),
             CTypeKind::Int128 => Ok(mk().path_ty(mk().path(vec!["i128"]))),
             CTypeKind::UInt128 => Ok(mk().path_ty(mk().path(vec!["u128"]))),
 
@@ -217,6 +313,9 @@ impl TypeConverter {
             }
 
             CTypeKind::Typedef(decl_id) => {
+                if let Some(ty) = self.fixed_width_typedef(ctxt, decl_id) {
+                    return Ok(ty)
+                }
                 let new_name = self.resolve_decl_name(decl_id).unwrap();
                 Ok(mk().path_ty(mk().path(vec![new_name])))
             }
@@ -17,6 +17,12 @@ pub mod c_ast;
 pub mod rust_ast;
 pub mod cfg;
 pub mod with_stmts;
+pub mod source_map;
+pub mod safe_wrappers;
+pub mod call_rewrites;
+pub mod naming;
+pub mod api;
+pub mod merge;
 
 #[cfg(test)]
 mod tests {
@@ -1,18 +1,20 @@
 #[macro_use]
 extern crate clap;
-extern crate serde_cbor;
 extern crate ast_importer;
 
-use std::io::{Error, stdout};
+use std::io::stdout;
 use std::io::prelude::*;
 use std::fs::File;
-use ast_importer::clang_ast::process;
-use ast_importer::c_ast::*;
+use std::panic;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use ast_importer::c_ast::Printer;
-use ast_importer::clang_ast::AstContext;
-use ast_importer::translator::{ReplaceMode,TranslationConfig};
+use ast_importer::api::{read_cbor_file, untyped_context_from_cbor, typed_context};
+use ast_importer::merge::translate_merged;
+use ast_importer::translator::{OverflowMode,TranslationConfig};
+use ast_importer::safe_wrappers::SafeWrapperConfig;
+use ast_importer::call_rewrites::CallRewrites;
 use clap::{Arg, App};
-use serde_cbor::{Value, from_slice};
 
 fn main() {
 
@@ -62,6 +64,11 @@ fn main() {
             .long("reloop-cfgs")
             .help("Translate ALL function bodies using a CFG/Relooper approach")
             .takes_value(false))
+        .arg(Arg::with_name("split-irreducible")
+            .long("split-irreducible")
+            .value_name("MAX_GROWTH")
+            .help("Split nodes to make irreducible control flow reducible, letting each function grow by up to MAX_GROWTH percent")
+            .takes_value(true))
         .arg(Arg::with_name("no-simplify-structures")
             .long("no-simplify-structures")
             .help("Do not run a pass to simplify structures")
@@ -82,6 +89,11 @@ fn main() {
             .long("json-function-cfgs")
             .help("Dumps into files JSON visualizations of the CFGs of every function")
             .takes_value(false))
+        .arg(Arg::with_name("html-function-cfgs")
+            .long("html-function-cfgs")
+            .help("Writes for every relooped function an HTML report relating its C source, CFG, \
+                   relooper structures, and Rust output")
+            .takes_value(false))
         .arg(Arg::with_name("dump-cfgs-liveness")
             .requires("dump-function-cfgs")
             .long("ddump-cfgs-liveness")
@@ -110,14 +122,107 @@ fn main() {
 
         // End-user
         .arg(Arg::with_name("INPUT")
-            .help("Sets the input CBOR file to use")
+            .help("Sets the input CBOR file(s) to use. With several inputs, each `foo.c.cbor` is translated into `foo.rs`")
             .required(true)
+            .multiple(true)
             .index(1))
+        .arg(Arg::with_name("validate-only")
+             .long("validate-only")
+             .help("Check that the input CBOR file(s) can be read by this version of the importer, without translating them")
+             .takes_value(false))
+        .arg(Arg::with_name("merge-cfg")
+             .long("merge-cfg")
+             .value_name("PREDICATE")
+             .help("Treat the inputs as the same source built for different targets or configurations, and merge their translations into one module with the items that differ guarded by #[cfg(PREDICATE)]. Give one predicate per input, in order, e.g. 'target_arch = \"x86\"'")
+             .conflicts_with_all(&["validate-only", "emit-source-map", "debug-line-layout"])
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("cfg-feature")
+             .long("cfg-feature")
+             .value_name("MACRO")
+             .help("Keep the #ifdef MACRO, #ifndef MACRO, and #if defined(MACRO) conditionals of the C source as #[cfg(feature = \"macro\")] attributes. Only the branches compiled for an input are translated, so this needs --merge-cfg with inputs built with and without the macro defined")
+             .requires("merge-cfg")
+             .multiple(true)
+             .number_of_values(1)
+             .takes_value(true))
+        .arg(Arg::with_name("emit-source-map")
+             .long("emit-source-map")
+             .help("Write a JSON source map linking the output back to the C source (`foo.c.cbor` gets `foo.rs.map.json`)")
+             .takes_value(false))
+        .arg(Arg::with_name("debug-line-layout")
+             .long("debug-line-layout")
+             .help("Lay out the output so its line numbers match the C source, for debugging. Compile it with `-C debuginfo=2 --remap-path-prefix <output.rs>=<input.c>` (without reformatting it) to debug against the C source.")
+             .takes_value(false))
+        .arg(Arg::with_name("safe-wrappers")
+             .long("safe-wrappers")
+             .value_name("CONFIG")
+             .help("Also generate a module of safe wrappers around the functions of a header, as described by a JSON config file")
+             .takes_value(true))
+        .arg(Arg::with_name("rewrite-libc-calls")
+             .long("rewrite-libc-calls")
+             .help("Replace calls to C library functions like memcpy and strlen with equivalent Rust")
+             .takes_value(false))
+        .arg(Arg::with_name("call-rewrites")
+             .long("call-rewrites")
+             .value_name("CONFIG")
+             .help("Rewrite calls to C functions as described by a JSON config file, in addition to the C library functions of --rewrite-libc-calls")
+             .takes_value(true))
+        .arg(Arg::with_name("jobs")
+             .long("jobs")
+             .short("j")
+             .help("Number of threads to use. Several inputs are translated in parallel; a single input has its function bodies translated in parallel")
+             .takes_value(true)
+             .default_value("1"))
         .arg(Arg::with_name("invalid-code")
             .long("invalid-code")
             .help("How to handle violated invariants or invalid code")
             .possible_values(&["panic", "compile_error"])
             .default_value("compile_error"))
+        .arg(Arg::with_name("overflow")
+            .long("overflow")
+            .help("What integer arithmetic does on overflow: 'faithful' wraps unsigned and leaves signed undefined as in C, 'checked' panics on any overflow, and 'wrapping' wraps on any overflow")
+            .possible_values(&["faithful", "checked", "wrapping"])
+            .default_value("faithful"))
+        .arg(Arg::with_name("target-stable")
+             .long("target-stable")
+             .help("Emit code that builds on stable Rust, failing declarations that need nightly features")
+             .conflicts_with_all(&["cross-checks", "translate-asm", "translate-valist"])
+             .takes_value(false))
+        .arg(Arg::with_name("no-std")
+             .long("no-std")
+             .help("Emit #![no_std] code using core paths and the C types of the cty crate, failing declarations that need std")
+             .conflicts_with("cross-checks")
+             .takes_value(false))
+        .arg(Arg::with_name("fixed-width-ints")
+             .long("fixed-width-ints")
+             .help("Use Rust's fixed width integer types following the target's data model, keeping libc types only in extern declarations")
+             .takes_value(false))
+        .arg(Arg::with_name("normalize-names")
+             .long("normalize-names")
+             .help("Name types in UpperCamelCase and functions, parameters and locals in snake_case, keeping exported symbols' C names")
+             .takes_value(false))
+        .arg(Arg::with_name("name-map")
+             .long("name-map")
+             .help("Reuse the names recorded in this JSON file for top-level declarations, then record the names picked in it")
+             .value_name("FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("tagged-unions")
+             .long("tagged-unions")
+             .help("Read the members of unions checked against a tag field through safe accessors, and give them an enum")
+             .takes_value(false))
+        .arg(Arg::with_name("for-ranges")
+             .long("for-ranges")
+             .help("Turn counted loops over local variables back into `for` loops over ranges")
+             .takes_value(false))
+        .arg(Arg::with_name("static-interior-refs")
+             .long("static-interior-refs")
+             .help("Let static initializers take the address of a field or element of another static, which older compilers reject (E0494)")
+             .takes_value(false))
+        .arg(Arg::with_name("index-arrays")
+             .long("index-arrays")
+             .help("Index local and static arrays whose pointer never escapes as Rust arrays, rather than through pointer offsets")
+             .takes_value(false))
         .arg(Arg::with_name("emit-module")
              .long("emit-module")
              .help("Emit the .rs file as a module instead of a crate, excluding the crate preamble")
@@ -132,13 +237,14 @@ fn main() {
              .takes_value(false))
         .get_matches();
 
-    // Build a TranslationConfig from the command line
+    // Build a TranslationConfig from the command line, starting from the library's defaults
     let tcfg = TranslationConfig {
         fail_on_error:          matches.is_present("fail-on-error"),
         reloop_cfgs:            matches.is_present("reloop-cfgs"),
         fail_on_multiple:       matches.is_present("fail-on-multiple"),
         dump_function_cfgs:     matches.is_present("dump-function-cfgs"),
         json_function_cfgs:     matches.is_present("json-function-cfgs"),
+        html_function_cfgs:     matches.is_present("html-function-cfgs"),
         dump_cfg_liveness:      matches.is_present("dump-cfgs-liveness"),
         dump_structures:        matches.is_present("dump-structures"),
         debug_relooper_labels:  matches.is_present("debug-labels"),
@@ -163,37 +269,229 @@ fn main() {
                 _ => panic!("Invalid option"),
             }
         },
-        replace_unsupported_decls: ReplaceMode::Extern,
+        overflow_mode: {
+            match matches.value_of("overflow") {
+                Some("faithful") => OverflowMode::Faithful,
+                Some("checked") => OverflowMode::Checked,
+                Some("wrapping") => OverflowMode::Wrapping,
+                _ => panic!("Invalid option"),
+            }
+        },
+        target_stable:          matches.is_present("target-stable"),
+        no_std:                 matches.is_present("no-std"),
+        fixed_width_ints:       matches.is_present("fixed-width-ints"),
+        normalize_names:        matches.is_present("normalize-names"),
+        name_map:               matches.value_of("name-map").map(String::from),
+        tagged_unions:          matches.is_present("tagged-unions"),
+        for_ranges:             matches.is_present("for-ranges"),
+        static_interior_refs:   matches.is_present("static-interior-refs"),
+        index_arrays:           matches.is_present("index-arrays"),
+        jobs:                   value_t!(matches, "jobs", usize).unwrap_or_else(|e| e.exit()),
+        debug_line_layout:      matches.is_present("debug-line-layout"),
+        split_irreducible:      if matches.is_present("split-irreducible") {
+            Some(value_t!(matches, "split-irreducible", usize).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        },
+        safe_wrappers:          matches.value_of("safe-wrappers").map(|path| {
+            SafeWrapperConfig::from_file(path).unwrap_or_else(|e| panic!("{}", e))
+        }),
+        cfg_features:           matches.values_of("cfg-feature").map_or(vec![], |macros| macros.map(String::from).collect()),
+        call_rewrites:          if matches.is_present("rewrite-libc-calls") || matches.is_present("call-rewrites") {
+            let mut rewrites = CallRewrites::libc();
+            if let Some(path) = matches.value_of("call-rewrites") {
+                rewrites.extend(CallRewrites::from_file(path).unwrap_or_else(|e| panic!("{}", e)));
+            }
+            Some(rewrites)
+        } else {
+            None
+        },
+        ..TranslationConfig::default()
     };
-    let file = matches.value_of("INPUT").unwrap();
-    let dump_untyped_context = matches.is_present("dump-untyped-clang-ast");
-    let dump_typed_context = matches.is_present("dump-typed-clang-ast");
-    let pretty_typed_context = matches.is_present("pretty-typed-clang-ast");
+    let emit_source_map = matches.is_present("emit-source-map");
+    let dumps = Dumps {
+        untyped_context:        matches.is_present("dump-untyped-clang-ast"),
+        typed_context:          matches.is_present("dump-typed-clang-ast"),
+        pretty_typed_context:   matches.is_present("pretty-typed-clang-ast"),
+    };
+    let files: Vec<String> = matches.values_of("INPUT").unwrap().map(String::from).collect();
 
-    // Extract the untyped AST from the CBOR file
-    let untyped_context = match parse_untyped_ast(file) {
-        Err(e) => panic!("{:#?}", e),
-        Ok(cxt) => cxt,
+    if matches.is_present("validate-only") {
+        validate_files(&files);
+    } else if let Some(predicates) = matches.values_of("merge-cfg") {
+        let predicates: Vec<String> = predicates.map(String::from).collect();
+        if predicates.len() != files.len() {
+            eprintln!("Got {} inputs but {} --merge-cfg predicates", files.len(), predicates.len());
+            std::process::exit(1);
+        }
+        match translate_files_merged(predicates, &files, tcfg) {
+            Ok(rust) => println!("{}", rust),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    } else if files.len() > 1 && tcfg.name_map.is_some() {
+        eprintln!("--name-map takes a single input, or the inputs of --merge-cfg");
+        std::process::exit(1);
+    } else if files.len() == 1 {
+        let tcfg = with_source_map(&files[0], tcfg, emit_source_map);
+        match translate_file(&files[0], tcfg, dumps) {
+            Ok(rust) => println!("{}", rust),
+            Err(e) => {
+                eprintln!("{}: {}", files[0], e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        translate_files(files, tcfg, dumps, emit_source_map);
+    }
+}
+
+/// Which intermediate ASTs to print out
+#[derive(Copy, Clone)]
+struct Dumps {
+    untyped_context: bool,
+    typed_context: bool,
+    pretty_typed_context: bool,
+}
+
+/// Translate several translation units in parallel, writing each one next to its input. Function
+/// bodies within a unit are then translated sequentially, so `--jobs` bounds the total number of
+/// threads.
+fn translate_files(files: Vec<String>, tcfg: TranslationConfig, dumps: Dumps, emit_source_map: bool) {
+    // Dumps go to stderr, where those of different units would interleave
+    let dumping = dumps.untyped_context || dumps.typed_context || dumps.pretty_typed_context ||
+        tcfg.dump_structures;
+    let num_workers = if dumping { 1 } else { tcfg.jobs.max(1).min(files.len()) };
+    let unit_tcfg = TranslationConfig { jobs: 1, ..tcfg };
+
+    let queue = Arc::new(Mutex::new(files.into_iter()));
+    let workers: Vec<thread::JoinHandle<bool>> = (0..num_workers)
+        .map(|_| {
+            let queue = queue.clone();
+            let tcfg = unit_tcfg.clone();
+            thread::spawn(move || {
+                let mut all_succeeded = true;
+                loop {
+                    let file = match queue.lock().expect("input queue poisoned").next() {
+                        Some(file) => file,
+                        None => break,
+                    };
+
+                    let output_file = rust_output_path(&file);
+                    let file_tcfg = with_source_map(&file, tcfg.clone(), emit_source_map);
+                    let translated = panic::catch_unwind(|| translate_file(&file, file_tcfg, dumps));
+
+                    match translated {
+                        Ok(Ok(rust)) => {
+                            if let Err(e) = File::create(&output_file).and_then(|mut f| f.write_all(rust.as_bytes())) {
+                                eprintln!("Failed to write {}: {}", output_file, e);
+                                all_succeeded = false;
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            eprintln!("{}: {}", file, e);
+                            all_succeeded = false;
+                        }
+                        Err(_) => {
+                            eprintln!("Failed to translate {}", file);
+                            all_succeeded = false;
+                        }
+                    }
+                }
+                all_succeeded
+            })
+        })
+        .collect();
+
+    let mut all_succeeded = true;
+    for worker in workers {
+        all_succeeded &= worker.join().unwrap_or(false);
+    }
+
+    if !all_succeeded {
+        std::process::exit(1);
+    }
+}
+
+/// Translate the builds of one source for several configurations into a single module
+fn translate_files_merged(predicates: Vec<String>, files: &[String], tcfg: TranslationConfig) -> Result<String, String> {
+    let mut configurations = vec![];
+    for (predicate, file) in predicates.into_iter().zip(files) {
+        let typed_context = read_cbor_file(file)
+            .and_then(|bytes| untyped_context_from_cbor(&bytes))
+            .and_then(|untyped_context| typed_context(&untyped_context))
+            .map_err(|e| format!("{}: {}", file, e))?;
+        configurations.push((predicate, typed_context));
+    }
+    let translated = translate_merged(configurations, tcfg)?;
+    for diagnostic in &translated.diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    Ok(translated.rust)
+}
+
+/// Check that each input can be imported, reporting what is wrong with those that can't
+fn validate_files(files: &[String]) {
+    let mut all_valid = true;
+    for file in files {
+        let imported = read_cbor_file(file)
+            .and_then(|bytes| untyped_context_from_cbor(&bytes))
+            .and_then(|cxt| typed_context(&cxt).map(|_| cxt));
+        match imported {
+            Ok(cxt) => println!("{}: ok ({} AST nodes, {} type nodes)", file, cxt.ast_nodes.len(), cxt.type_nodes.len()),
+            Err(e) => {
+                println!("{}: {}", file, e);
+                all_valid = false;
+            }
+        }
+    }
+
+    if !all_valid {
+        std::process::exit(1);
+    }
+}
+
+/// `foo.c.cbor` gets translated into `foo.rs`
+fn rust_output_path(input: &str) -> String {
+    let stem = if input.ends_with(".c.cbor") {
+        &input[..input.len() - ".c.cbor".len()]
+    } else if input.ends_with(".cbor") {
+        &input[..input.len() - ".cbor".len()]
+    } else {
+        input
     };
+    format!("{}.rs", stem)
+}
+
+/// Set where the source map for the translation of `input` should go, if we want one
+fn with_source_map(input: &str, tcfg: TranslationConfig, emit_source_map: bool) -> TranslationConfig {
+    if emit_source_map {
+        TranslationConfig { source_map: Some(format!("{}.map.json", rust_output_path(input))), ..tcfg }
+    } else {
+        tcfg
+    }
+}
+
+fn translate_file(file: &str, tcfg: TranslationConfig, dumps: Dumps) -> Result<String, String> {
+    // Extract the untyped AST from the CBOR file
+    let untyped_context = read_cbor_file(file).and_then(|bytes| untyped_context_from_cbor(&bytes))?;
 
-    if dump_untyped_context {
+    if dumps.untyped_context {
         println!("CBOR Clang AST");
         println!("{:#?}", untyped_context);
     }
 
     // Convert this into a typed AST
-    let typed_context = {
-        let mut conv = ConversionContext::new(&untyped_context);
-        conv.convert(&untyped_context);
-        conv.typed_context
-    };
+    let typed_context = typed_context(&untyped_context)?;
 
-    if dump_typed_context {
+    if dumps.typed_context {
         println!("Clang AST");
         println!("{:#?}", typed_context);
     }
 
-    if pretty_typed_context {
+    if dumps.pretty_typed_context {
         println!("Pretty-printed Clang AST");
         println!("{:#?}", Printer::new(stdout()).print(&typed_context));
     }
@@ -210,20 +508,5 @@ fn main() {
 
     // Perform the translation
 
-    println!("{}", ast_importer::translator::translate(typed_context, tcfg));
-}
-
-fn parse_untyped_ast(filename: &str) -> Result<AstContext, Error> {
-    let mut f = File::open(filename)?;
-    let mut buffer = vec![];
-    f.read_to_end(&mut buffer)?;
-
-    let items: Value = from_slice(&buffer[..]).unwrap();
-
-    match process(items) {
-        Ok(cxt) => Ok(cxt),
-        Err(e) => panic!("{:#?}", e),
-    }
+    ast_importer::translator::translate(typed_context, tcfg)
 }
-
-
@@ -0,0 +1,208 @@
+//! Merging the translations of one C source made for several targets or configurations.
+//!
+//! Each configuration is named by a `cfg` predicate, like `target_arch = "x86"` or
+//! `feature = "ipv6"`, and comes with the AST Clang produced under it. The configurations are
+//! translated separately, and their items are then combined into a single module: an item which
+//! every configuration translates the same way appears once, and every other item is guarded by
+//! `#[cfg(...)]` so that it only exists in the configurations that produced it.
+//!
+//! Items are compared by their pretty-printed text, so the merged module only shares an item when
+//! the translations agree exactly.
+
+use std::collections::{HashMap, HashSet};
+use syntax::with_globals;
+use syntax::ast::ItemKind;
+use syntax::print::pprust::PrintState;
+use c_ast::TypedAstContext;
+use source_map::{SourceLocations, to_string_annotated};
+use translator::{self, print_preamble, Translated, TranslatedItems, TranslationConfig};
+
+/// Separates the items of a configuration when it is pretty-printed as a whole
+const ITEM_END: char = '\u{1e}';
+
+/// The translation of one configuration, with its items pretty-printed
+struct RenderedConfiguration {
+    items: Vec<String>,
+    /// Members of the `extern "C"` block
+    foreign_items: Vec<String>,
+    features: Vec<&'static str>,
+    diagnostics: Vec<String>,
+}
+
+/// Translate the configurations in `configurations`, given as pairs of `cfg` predicates and ASTs,
+/// into one module
+pub fn translate_merged(configurations: Vec<(String, TypedAstContext)>, tcfg: TranslationConfig) -> Result<Translated, String> {
+    if tcfg.source_map.is_some() || tcfg.debug_line_layout {
+        return Err(format!("Merged translations have no source map or line layout"))
+    }
+
+    let mut predicates = vec![];
+    let mut rendered = vec![];
+    for (predicate, ast_context) in configurations {
+        rendered.push(translator::translate_to_items(ast_context, tcfg.clone(), render_items)?);
+        predicates.push(predicate);
+    }
+
+    let mut features: Vec<&'static str> = rendered.iter().flat_map(|r| r.features.iter().cloned()).collect();
+    features.sort();
+    features.dedup();
+
+    let mut diagnostics = vec![];
+    for (predicate, r) in predicates.iter().zip(&rendered) {
+        diagnostics.extend(r.diagnostics.iter().map(|d| format!("cfg({}): {}", predicate, d)));
+    }
+
+    let items: Vec<&[String]> = rendered.iter().map(|r| &r.items[..]).collect();
+    let foreign_items: Vec<&[String]> = rendered.iter().map(|r| &r.foreign_items[..]).collect();
+
+    let mut rust = with_globals(|| {
+        to_string_annotated(&SourceLocations::new(), |s| print_preamble(s, &tcfg, features.clone()))
+    });
+    if !rust.is_empty() && !rust.ends_with('\n') {
+        rust.push('\n');
+    }
+    let foreign_items = merge_items(&foreign_items);
+    if !foreign_items.is_empty() {
+        rust.push_str("extern \"C\" {\n");
+        for (item, present) in foreign_items {
+            for line in guarded(&item, &predicates, &present).lines() {
+                rust.push_str("    ");
+                rust.push_str(line);
+                rust.push('\n');
+            }
+        }
+        rust.push_str("}\n");
+    }
+    for (item, present) in merge_items(&items) {
+        rust.push_str(&guarded(&item, &predicates, &present));
+    }
+
+    Ok(Translated { rust, features, diagnostics })
+}
+
+/// Pretty-print the items of a configuration one by one, pulling apart its `extern "C"` block
+fn render_items(t: TranslatedItems) -> RenderedConfiguration {
+    let TranslatedItems { items, comments, features, diagnostics } = t;
+
+    let mut is_foreign = vec![];
+    let text = to_string_annotated(&SourceLocations::new(), |s| {
+        s.comments().get_or_insert(vec![]).extend(comments);
+        for item in &items {
+            if let ItemKind::ForeignMod(ref foreign_mod) = item.node {
+                for foreign_item in &foreign_mod.items {
+                    s.print_foreign_item(foreign_item)?;
+                    s.writer().word(&ITEM_END.to_string())?;
+                    s.writer().hardbreak()?;
+                    is_foreign.push(true);
+                }
+            } else {
+                s.print_item(item)?;
+                s.writer().word(&ITEM_END.to_string())?;
+                s.writer().hardbreak()?;
+                is_foreign.push(false);
+            }
+        }
+        Ok(())
+    });
+
+    let mut rendered = RenderedConfiguration { items: vec![], foreign_items: vec![], features, diagnostics };
+    for (text, is_foreign) in text.split(ITEM_END).zip(is_foreign) {
+        let item = text.trim_matches('\n').to_string();
+        if is_foreign { rendered.foreign_items.push(item) } else { rendered.items.push(item) }
+    }
+    rendered
+}
+
+/// Combine the item lists of all configurations, keeping the order of each one. Every distinct
+/// item comes with which configurations contain it.
+fn merge_items(configurations: &[&[String]]) -> Vec<(String, Vec<bool>)> {
+    let mut merged: Vec<(String, Vec<bool>)> = vec![];
+    let mut positions: HashMap<String, usize> = HashMap::new();
+
+    for (index, items) in configurations.iter().enumerate() {
+        let own_items: HashSet<&String> = items.iter().collect();
+
+        // Items new to this configuration go after the item preceding them in it, and after the
+        // items only other configurations have
+        let mut insert_at = 0;
+        for item in items.iter() {
+            let position = match positions.get(item) {
+                Some(&position) => position,
+                None => {
+                    while insert_at < merged.len() && !own_items.contains(&merged[insert_at].0) {
+                        insert_at += 1;
+                    }
+                    for p in positions.values_mut() {
+                        if *p >= insert_at {
+                            *p += 1;
+                        }
+                    }
+                    positions.insert(item.clone(), insert_at);
+                    merged.insert(insert_at, (item.clone(), vec![false; configurations.len()]));
+                    insert_at
+                }
+            };
+            merged[position].1[index] = true;
+            insert_at = insert_at.max(position + 1);
+        }
+    }
+
+    merged
+}
+
+/// An item's text with a `#[cfg]` attribute limiting it to the configurations it is present in
+fn guarded(text: &str, predicates: &[String], present: &[bool]) -> String {
+    let predicates: Vec<&str> = predicates.iter()
+        .zip(present)
+        .filter(|&(_, &present)| present)
+        .map(|(predicate, _)| predicate.as_str())
+        .collect();
+
+    let guard = if predicates.len() == present.len() {
+        return format!("{}\n", text)
+    } else if predicates.len() == 1 {
+        format!("#[cfg({})]\n", predicates[0])
+    } else {
+        format!("#[cfg(any({}))]\n", predicates.join(", "))
+    };
+
+    // Items translated with `cfg_features` may already be guarded the same way
+    if text.starts_with(&guard) {
+        format!("{}\n", text)
+    } else {
+        format!("{}{}\n", guard, text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn merge_keeps_order() {
+        let a = items(&["a", "b1", "c"]);
+        let b = items(&["a", "b2", "d", "c"]);
+        let merged: Vec<(String, Vec<bool>)> = merge_items(&[&a, &b])
+            .into_iter()
+            .collect();
+        assert_eq!(merged, vec![
+            ("a".to_string(), vec![true, true]),
+            ("b1".to_string(), vec![true, false]),
+            ("b2".to_string(), vec![false, true]),
+            ("d".to_string(), vec![false, true]),
+            ("c".to_string(), vec![true, true]),
+        ]);
+    }
+
+    #[test]
+    fn guards() {
+        let predicates = vec!["unix".to_string(), "windows".to_string()];
+        assert_eq!(guarded("fn f() { }", &predicates, &[true, true]), "fn f() { }\n");
+        assert_eq!(guarded("fn f() { }", &predicates, &[false, true]), "#[cfg(windows)]\nfn f() { }\n");
+        assert_eq!(guarded("#[cfg(windows)]\nfn f() { }", &predicates, &[false, true]), "#[cfg(windows)]\nfn f() { }\n");
+    }
+}
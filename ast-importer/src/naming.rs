@@ -0,0 +1,184 @@
+//! Normalizing C names to Rust's naming conventions, and keeping the names picked for top-level
+//! declarations stable across translations.
+//!
+//! With normalization, type names (of structs, unions, enums and typedefs) become UpperCamelCase
+//! and the names of functions, parameters and local variables become snake_case, so
+//! `struct json_object` is translated to `JsonObject` and `JSONParse` to `json_parse`. Exported
+//! functions keep their C symbol through `#[export_name]`.
+//!
+//! The names picked for top-level declarations can be written to a name map of the form
+//!
+//! ```json
+//! {
+//!   "types":  { "struct json_object": "JsonObject", "json_object": "JsonObject_0" },
+//!   "values": { "JSONParse": "json_parse", "LH_EMPTY": "LH_EMPTY" }
+//! }
+//! ```
+//!
+//! where structs, unions and enums are keyed by their tag, apart from typedefs of the same name.
+//! When a later translation reads it back, declarations found in the map get their recorded name
+//! before any other declaration is named, so a declaration added to the C source cannot take the
+//! name of an existing one.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use serde_json::{self, Map, Value};
+
+/// Split a C identifier into its words, at underscores and at changes of case. The last capital
+/// of a run of capitals starts a new word when followed by a lowercase letter, so `HTTPServer`
+/// splits into `HTTP` and `Server`.
+fn words(name: &str) -> Vec<String> {
+    let chars: Vec<char> = name.chars().collect();
+    let mut words = vec![];
+    let mut word = String::new();
+
+    for (i, &c) in chars.iter().enumerate() {
+        if c == '_' {
+            if !word.is_empty() {
+                words.push(word);
+                word = String::new();
+            }
+            continue;
+        }
+
+        if c.is_uppercase() && !word.is_empty() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).map_or(false, |n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_is_lower) {
+                words.push(word);
+                word = String::new();
+            }
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// A normalized name, or the original one if normalizing would not leave a valid identifier
+fn valid_or(normalized: String, name: &str) -> String {
+    match normalized.chars().next() {
+        Some(c) if !c.is_numeric() => normalized,
+        _ => name.to_owned(),
+    }
+}
+
+/// `json_object` becomes `JsonObject`, `LH_EMPTY` becomes `LhEmpty`
+pub fn to_upper_camel_case(name: &str) -> String {
+    let normalized = words(name).iter()
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().into_iter().flat_map(char::to_uppercase);
+            first.chain(chars.flat_map(char::to_lowercase)).collect::<String>()
+        })
+        .collect::<String>();
+    valid_or(normalized, name)
+}
+
+/// `JSONParse` becomes `json_parse`. Leading underscores are kept.
+pub fn to_snake_case(name: &str) -> String {
+    let underscores = name.chars().take_while(|&c| c == '_').count();
+    let normalized = words(name).iter()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join("_");
+    valid_or(format!("{}{}", &name[..underscores], normalized), name)
+}
+
+/// The Rust names of top-level declarations, by their C name
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameMap {
+    pub types: BTreeMap<String, String>,
+    pub values: BTreeMap<String, String>,
+}
+
+impl NameMap {
+    /// Read a name map, which is empty if the file does not exist yet
+    pub fn from_file(path: &str) -> Result<NameMap, String> {
+        if !Path::new(path).exists() {
+            return Ok(NameMap::default())
+        }
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open name map {}: {}", path, e))?;
+        let value: Value = serde_json::from_reader(file)
+            .map_err(|e| format!("Failed to parse name map {}: {}", path, e))?;
+        NameMap::from_json(&value)
+    }
+
+    pub fn from_json(value: &Value) -> Result<NameMap, String> {
+        Ok(NameMap {
+            types: parse_names(value, "types")?,
+            values: parse_names(value, "values")?,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        fn names_json(names: &BTreeMap<String, String>) -> Value {
+            Value::Object(names.iter()
+                .map(|(c_name, name)| (c_name.clone(), Value::String(name.clone())))
+                .collect::<Map<String, Value>>())
+        }
+
+        let mut map = Map::new();
+        map.insert("types".to_owned(), names_json(&self.types));
+        map.insert("values".to_owned(), names_json(&self.values));
+        Value::Object(map)
+    }
+
+    pub fn dump_json(&self, file_path: &str) -> io::Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer_pretty(file, &self.to_json())?;
+        Ok(())
+    }
+}
+
+fn parse_names(value: &Value, key: &str) -> Result<BTreeMap<String, String>, String> {
+    let names = match value.get(key) {
+        Some(names) => names.as_object()
+            .ok_or_else(|| format!("\"{}\" of the name map should be an object", key))?,
+        None => return Ok(BTreeMap::new()),
+    };
+
+    let mut map = BTreeMap::new();
+    for (c_name, name) in names {
+        let name = name.as_str()
+            .ok_or_else(|| format!("Name of {} in the name map should be a string", c_name))?;
+        map.insert(c_name.clone(), name.to_owned());
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        assert_eq!(to_upper_camel_case("json_object"), "JsonObject");
+        assert_eq!(to_upper_camel_case("LH_EMPTY"), "LhEmpty");
+        assert_eq!(to_upper_camel_case("_IO_FILE"), "IoFile");
+        assert_eq!(to_upper_camel_case("HTTPServer"), "HttpServer");
+        assert_eq!(to_upper_camel_case("_"), "_");
+
+        assert_eq!(to_snake_case("JSONParse"), "json_parse");
+        assert_eq!(to_snake_case("lh_table_new"), "lh_table_new");
+        assert_eq!(to_snake_case("getX2Value"), "get_x2_value");
+        assert_eq!(to_snake_case("__bufSize"), "__buf_size");
+        assert_eq!(to_snake_case("x86_64"), "x86_64");
+    }
+
+    #[test]
+    fn name_map_round_trip() {
+        let mut names = NameMap::default();
+        names.types.insert("json_object".to_owned(), "JsonObject".to_owned());
+        names.values.insert("JSONParse".to_owned(), "json_parse".to_owned());
+        assert_eq!(NameMap::from_json(&names.to_json()), Ok(names));
+
+        let invalid: Value = serde_json::from_str(r#"{ "types": { "a": 1 } }"#).unwrap();
+        assert!(NameMap::from_json(&invalid).is_err());
+    }
+}
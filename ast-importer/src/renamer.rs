@@ -3,6 +3,7 @@ use std::collections::HashMap;
 use std::hash::Hash;
 use std::iter::FromIterator;
 
+#[derive(Clone)]
 struct Scope<T> {
     name_map: HashMap<T, String>,
     used: HashSet<String>,
@@ -35,6 +36,7 @@ impl<T: Clone + Eq + Hash> Scope<T> {
     }
 }
 
+#[derive(Clone)]
 pub struct Renamer<T> {
     scopes: Vec<Scope<T>>,
     next_fresh: u64,
@@ -22,12 +22,19 @@
 //!   let updated_module: Mod = trav.traverse_mod(module);
 //!   let updated_cmmt_store = trav.into_comment_store();
 //! ```
+//!
+//! Comments are always printed on the lines in front of the node they belong to. Those starting
+//! with `TRAILING_COMMENT` followed the node in C, and `source_map::place_trailing_comments` moves
+//! them to the end of its line once printing is over.
 
 use syntax_pos::BytePos;
 use syntax_pos::hygiene::SyntaxContext;
 use syntax::codemap::{DUMMY_SP, Span};
 use syntax::parse::lexer::comments;
 use std::collections::BTreeMap;
+
+/// Marks a comment which should go at the end of the line of the node it belongs to
+pub const TRAILING_COMMENT: char = '\u{4}';
 use syntax::ast::*;
 use rust_ast::traverse;
 
@@ -85,16 +92,7 @@ impl CommentStore {
     /// Add a comment at the current position, then return the `Span` that should be given to
     /// something we want associated with this comment.
     pub fn add_comment_lines(&mut self, lines: Vec<String>) -> Span {
-        let lines: Vec<String> = lines
-            .into_iter()
-            .map(|mut comment| {
-                if comment.starts_with("//!") || comment.starts_with("///") ||
-                    comment.starts_with("/**") || comment.starts_with("/*!") {
-                    comment.insert(2,' ');
-                }
-                comment
-            })
-            .collect();
+        let lines = sanitize_comment_lines(lines);
 
         if lines.is_empty() {
             DUMMY_SP
@@ -108,6 +106,54 @@ impl CommentStore {
             )
         }
     }
+
+    /// Remove the comment (if any) associated with `sp`, returning its lines.
+    pub fn take_comment_lines(&mut self, sp: Span) -> Vec<String> {
+        self.output_comments.remove(&sp).map_or(vec![], |cmmt| cmmt.lines)
+    }
+
+    /// Add comment lines in front of the comment (if any) associated with `sp`, then return the
+    /// `Span` that should replace `sp`.
+    pub fn prepend_comment_lines(&mut self, sp: Span, mut lines: Vec<String>) -> Span {
+        if lines.is_empty() {
+            return sp
+        }
+        if let Some(cmmt) = self.output_comments.remove(&sp) {
+            lines.extend(cmmt.lines);
+        }
+        self.add_comment_lines(lines)
+    }
+
+    /// Add comment lines after the comment (if any) associated with `sp`, then return the `Span`
+    /// that should replace `sp`.
+    pub fn append_comment_lines(&mut self, sp: Span, lines: Vec<String>) -> Span {
+        if lines.is_empty() {
+            return sp
+        }
+        let mut all_lines = self.output_comments.remove(&sp).map_or(vec![], |cmmt| cmmt.lines);
+        all_lines.extend(lines);
+        self.add_comment_lines(all_lines)
+    }
+}
+
+
+/// Keep C comments from turning into Rust doc comments, which are only valid in some positions.
+pub fn sanitize_comment_lines(lines: Vec<String>) -> Vec<String> {
+    lines
+        .into_iter()
+        .map(|mut comment| {
+            let start = if comment.starts_with(TRAILING_COMMENT) { TRAILING_COMMENT.len_utf8() } else { 0 };
+            let is_doc = {
+                let text = &comment[start..];
+                text.starts_with("//!") || text.starts_with("///") ||
+                    text.starts_with("/**") || text.starts_with("/*!")
+            };
+            if is_doc {
+                comment.insert(start + 2,' ');
+            }
+            comment
+        })
+        .collect()
 }
 
 
@@ -170,6 +216,15 @@ impl traverse::Traversal for CommentTraverser {
 
     fn traverse_item(&mut self, mut i: Item) -> Item {
         i.span = self.reinsert_comment_at(i.span);
+        match i.node {
+            ItemKind::Struct(VariantData::Struct(ref mut fields, _), _) |
+            ItemKind::Union(VariantData::Struct(ref mut fields, _), _) => {
+                for field in fields {
+                    field.span = self.reinsert_comment_at(field.span);
+                }
+            }
+            _ => { }
+        }
         traverse::traverse_item_def(self, i)
     }
 
@@ -624,6 +624,18 @@ impl Builder {
         })
     }
 
+    pub fn labelled_block_expr<B, L>(self, blk: B, label: Option<L>) -> P<Expr>
+        where B: Make<P<Block>>, L: Make<Label> {
+        let blk = blk.make(&self);
+        let label = label.map(|l| l.make(&self));
+        P(Expr {
+            id: DUMMY_NODE_ID,
+            node: ExprKind::Block(blk, label),
+            span: DUMMY_SP,
+            attrs: self.attrs.into(),
+        })
+    }
+
     pub fn assign_expr<E1, E2>(self, lhs: E1, rhs: E2) -> P<Expr>
         where E1: Make<P<Expr>>, E2: Make<P<Expr>> {
         let lhs = lhs.make(&self);
@@ -691,6 +703,18 @@ impl Builder {
         })
     }
 
+    pub fn range_expr<E1, E2>(self, lo: E1, hi: E2, limits: RangeLimits) -> P<Expr>
+        where E1: Make<P<Expr>>, E2: Make<P<Expr>> {
+        let lo = lo.make(&self);
+        let hi = hi.make(&self);
+        P(Expr {
+            id: DUMMY_NODE_ID,
+            node: ExprKind::Range(Some(lo), Some(hi), limits),
+            span: DUMMY_SP,
+            attrs: self.attrs.into(),
+        })
+    }
+
     pub fn paren_expr<E>(self, e: E) -> P<Expr>
         where E: Make<P<Expr>> {
         let e = e.make(&self);
@@ -974,6 +998,17 @@ impl Builder {
         })
     }
 
+    pub fn tuple_struct_pat<Pa, Pt>(self, path: Pa, pats: Vec<Pt>) -> P<Pat>
+        where Pa: Make<Path>, Pt: Make<P<Pat>> {
+        let path = path.make(&self);
+        let pats: Vec<P<Pat>> = pats.into_iter().map(|x| x.make(&self)).collect();
+        P(Pat {
+            id: DUMMY_NODE_ID,
+            node: PatKind::TupleStruct(path, pats, None),
+            span: DUMMY_SP,
+        })
+    }
+
     pub fn wild_pat(self) -> P<Pat> {
         P(Pat {
             id: DUMMY_NODE_ID,
@@ -992,6 +1027,17 @@ impl Builder {
         })
     }
 
+    pub fn range_pat<E1, E2>(self, lo: E1, hi: E2) -> P<Pat>
+        where E1: Make<P<Expr>>, E2: Make<P<Expr>> {
+        let lo = lo.make(&self);
+        let hi = hi.make(&self);
+        P(Pat {
+            id: DUMMY_NODE_ID,
+            node: PatKind::Range(lo, hi, RangeEnd::Included(RangeSyntax::DotDotEq)),
+            span: DUMMY_SP,
+        })
+    }
+
     pub fn ident_ref_pat<I>(self, name: I) -> P<Pat>
         where I: Make<Ident> {
         let name = name.make(&self);
@@ -1234,7 +1280,7 @@ impl Builder {
         let ident = ident.make(&self);
         let ty = ty.make(&self);
         StructField {
-            span: DUMMY_SP,
+            span: self.span,
             ident: Some(ident),
             vis: self.vis,
             id: DUMMY_NODE_ID,
@@ -1316,6 +1362,30 @@ impl Builder {
                                   items))
     }
 
+    pub fn method_impl_item<I, D, B>(self, name: I, decl: D, block: B) -> ImplItem
+        where I: Make<Ident>, D: Make<P<FnDecl>>, B: Make<P<Block>> {
+        let name = name.make(&self);
+        let decl = decl.make(&self);
+        let block = block.make(&self);
+        let sig = MethodSig {
+            unsafety: self.unsafety,
+            constness: dummy_spanned(self.constness),
+            abi: self.abi,
+            decl,
+        };
+        ImplItem {
+            id: DUMMY_NODE_ID,
+            ident: name,
+            vis: self.vis,
+            defaultness: Defaultness::Final,
+            attrs: self.attrs,
+            generics: self.generics,
+            node: ImplItemKind::Method(sig, block),
+            span: self.span,
+            tokens: None,
+        }
+    }
+
     pub fn extern_crate_item<I>(self, name: I, rename: Option<I>) -> P<Item>
         where I: Make<Ident>
     {
@@ -1325,9 +1395,9 @@ impl Builder {
     }
 
     // `use <path>;` item
-    // TODO: for now, we only support simple paths with an optional rename;
-    // if we ever need them, we should add support for globs and nested trees,
-    // e.g., `use foo::*;` and `use foo::{a, b, c};`
+    // TODO: for now, we only support simple paths with an optional rename
+    // and globs (see `use_glob_item`); if we ever need them, we should add
+    // support for nested trees, e.g., `use foo::{a, b, c};`
     pub fn use_item<Pa, I>(self, path: Pa, rename: Option<I>) -> P<Item>
         where Pa: Make<Path>, I: Make<Ident>
     {
@@ -1342,6 +1412,20 @@ impl Builder {
                    ItemKind::Use(P(use_tree)))
     }
 
+    // `use <path>::*;` item
+    pub fn use_glob_item<Pa>(self, path: Pa) -> P<Item>
+        where Pa: Make<Path>
+    {
+        let path = path.make(&self);
+        let use_tree = UseTree {
+            span: DUMMY_SP,
+            prefix: path,
+            kind: UseTreeKind::Glob,
+        };
+        Self::item(keywords::Invalid.ident(), self.attrs, self.vis, self.span,
+                   ItemKind::Use(P(use_tree)))
+    }
+
     pub fn foreign_items(self, items: Vec<ForeignItem>) -> P<Item>
     {
         let fgn_mod = ForeignMod { abi: self.abi, items };
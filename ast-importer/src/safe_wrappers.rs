@@ -0,0 +1,175 @@
+//! Configuration for the safe wrapper API generated alongside the raw translation.
+//!
+//! For every public function declared in a selected header, the translator emits a wrapper in a
+//! separate module which calls the raw `extern "C"` function. By default a wrapper has the same
+//! signature as the function it wraps, but annotations can make it more idiomatic. The annotations
+//! are read from a JSON file of the form
+//!
+//! ```json
+//! {
+//!   "header": "buffer.h",
+//!   "module": "safe",
+//!   "functions": {
+//!     "buffer_new":   { "return": "option" },
+//!     "buffer_write": { "params": { "path": "cstr", "data": "slice(len)" },
+//!                       "return": "zero_is_ok" }
+//!   }
+//! }
+//! ```
+//!
+//! `module` is optional and defaults to `safe`.
+
+use std::collections::HashMap;
+use std::fs::File;
+use serde_json::{self, Value};
+
+/// How a pointer parameter should be exposed by a wrapper
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamAnnotation {
+    /// A NUL-terminated string, taken as `&CStr`
+    CStr,
+    /// A buffer whose length is passed in the named parameter, taken as a slice. Slices sharing a
+    /// length parameter are checked to be as long as each other.
+    Slice(String),
+}
+
+/// How the return value of a wrapped function should be exposed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReturnAnnotation {
+    /// Return the value unchanged
+    Raw,
+    /// A pointer which is null on failure, returned as an `Option`
+    Option,
+    /// An error code which is zero on success, returned as a `Result<(), _>`
+    ZeroIsOk,
+    /// A value which is negative on failure, returned as a `Result`
+    NonNegativeIsOk,
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionAnnotations {
+    pub params: HashMap<String, ParamAnnotation>,
+    pub ret: ReturnAnnotation,
+}
+
+impl Default for FunctionAnnotations {
+    fn default() -> Self {
+        FunctionAnnotations {
+            params: HashMap::new(),
+            ret: ReturnAnnotation::Raw,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SafeWrapperConfig {
+    /// Functions declared in a file whose path ends with this get wrapped
+    pub header: String,
+    /// Name of the module holding the wrappers
+    pub module: String,
+    pub functions: HashMap<String, FunctionAnnotations>,
+}
+
+impl SafeWrapperConfig {
+    pub fn from_file(path: &str) -> Result<SafeWrapperConfig, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open safe wrapper config {}: {}", path, e))?;
+        let value: Value = serde_json::from_reader(file)
+            .map_err(|e| format!("Failed to parse safe wrapper config {}: {}", path, e))?;
+        SafeWrapperConfig::from_json(&value)
+    }
+
+    pub fn from_json(value: &Value) -> Result<SafeWrapperConfig, String> {
+        let header = value.get("header").and_then(Value::as_str)
+            .ok_or_else(|| format!("Safe wrapper config is missing a \"header\" string"))?;
+        let module = match value.get("module") {
+            Some(module) => module.as_str()
+                .ok_or_else(|| format!("Safe wrapper \"module\" should be a string"))?,
+            None => "safe",
+        };
+
+        let mut functions = HashMap::new();
+        if let Some(entries) = value.get("functions") {
+            let entries = entries.as_object()
+                .ok_or_else(|| format!("Safe wrapper \"functions\" should be an object"))?;
+            for (name, entry) in entries {
+                functions.insert(name.clone(), parse_function(name, entry)?);
+            }
+        }
+
+        Ok(SafeWrapperConfig {
+            header: header.to_owned(),
+            module: module.to_owned(),
+            functions,
+        })
+    }
+
+    pub fn annotations(&self, function: &str) -> FunctionAnnotations {
+        self.functions.get(function).cloned().unwrap_or_default()
+    }
+}
+
+fn parse_function(name: &str, entry: &Value) -> Result<FunctionAnnotations, String> {
+    let mut annotations = FunctionAnnotations::default();
+
+    if let Some(params) = entry.get("params") {
+        let params = params.as_object()
+            .ok_or_else(|| format!("Parameters of wrapper for {} should be an object", name))?;
+        for (param, annotation) in params {
+            let annotation = annotation.as_str().and_then(parse_param)
+                .ok_or_else(|| format!("Invalid annotation for parameter {} of {}: {}", param, name, annotation))?;
+            annotations.params.insert(param.clone(), annotation);
+        }
+    }
+
+    if let Some(ret) = entry.get("return") {
+        annotations.ret = match ret.as_str() {
+            Some("raw") => ReturnAnnotation::Raw,
+            Some("option") => ReturnAnnotation::Option,
+            Some("zero_is_ok") => ReturnAnnotation::ZeroIsOk,
+            Some("nonnegative_is_ok") => ReturnAnnotation::NonNegativeIsOk,
+            _ => return Err(format!("Invalid return annotation for {}: {}", name, ret)),
+        };
+    }
+
+    Ok(annotations)
+}
+
+fn parse_param(annotation: &str) -> Option<ParamAnnotation> {
+    if annotation == "cstr" {
+        Some(ParamAnnotation::CStr)
+    } else if annotation.starts_with("slice(") && annotation.ends_with(')') {
+        let len = &annotation["slice(".len()..annotation.len() - 1];
+        if len.is_empty() { None } else { Some(ParamAnnotation::Slice(len.to_owned())) }
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = SafeWrapperConfig::from_json(&serde_json::from_str(r#"{
+            "header": "buffer.h",
+            "functions": {
+                "buffer_write": {
+                    "params": { "path": "cstr", "data": "slice(len)" },
+                    "return": "zero_is_ok"
+                }
+            }
+        }"#).unwrap()).unwrap();
+
+        assert_eq!(config.module, "safe");
+        let write = config.annotations("buffer_write");
+        assert_eq!(write.params["path"], ParamAnnotation::CStr);
+        assert_eq!(write.params["data"], ParamAnnotation::Slice("len".to_owned()));
+        assert_eq!(write.ret, ReturnAnnotation::ZeroIsOk);
+        assert_eq!(config.annotations("buffer_new").ret, ReturnAnnotation::Raw);
+
+        let invalid = r#"{ "header": "buffer.h", "functions": { "buffer_new": { "return": "maybe" } } }"#;
+        assert!(SafeWrapperConfig::from_json(&serde_json::from_str(invalid).unwrap()).is_err());
+    }
+}
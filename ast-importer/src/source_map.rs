@@ -0,0 +1,510 @@
+//! This module handles linking the generated Rust code back to the C source it came from.
+//!
+//! Generated AST nodes have no meaningful `Span`s, so instead we tag every node that has a C source
+//! location with a fresh `NodeId` (these are otherwise unused in the output). While pretty-printing,
+//! a `PpAnn` annotator emits a zero-width marker before and after each tagged node. Once printing
+//! is over, the markers are stripped out of the output and their byte offsets turned into a
+//! `SourceMap`:
+//!
+//! ```norun
+//!   let text = to_string_annotated(&locations, |s| s.print_item(&item));
+//!   let (text, source_map) = extract_source_map(&text, &ast_context.c_files);
+//! ```
+//!
+//! Since the markers carry the C location itself (rather than the `NodeId`), text pretty-printed
+//! separately (for instance on a worker thread) can be spliced into the output before extraction.
+//!
+//! The same markers let `align_lines` lay the output out so that its line numbers follow those of
+//! the C file. Compiling the result with `--remap-path-prefix <output.rs>=<input.c>` then produces
+//! DWARF line tables which refer to the C source, so that debuggers step through the original C.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use serde::ser::{Serialize, Serializer, SerializeStruct};
+use serde_json;
+use syntax::ast::{NodeId, DUMMY_NODE_ID};
+use syntax::codemap::{CodeMap, FilePathMapping};
+use syntax::print::pprust::{AnnNode, PpAnn, PrintState, State};
+
+use c_ast::SrcLoc;
+use rust_ast::comment_store::TRAILING_COMMENT;
+
+/// Starts a marker. It is followed by `kind:fileid:line:column` and `MARKER_END`.
+const MARKER_OPEN: char = '\u{1}';
+
+/// Closes the most recently opened marker. It is followed by `MARKER_END`.
+const MARKER_CLOSE: char = '\u{3}';
+
+/// Terminates the payload of a marker
+const MARKER_END: char = '\u{2}';
+
+/// The C source locations of the AST nodes tagged so far. The index of a location is the `NodeId`
+/// of the node it tags.
+#[derive(Clone, Debug, Default)]
+pub struct SourceLocations {
+    locations: Vec<(&'static str, SrcLoc)>,
+}
+
+impl SourceLocations {
+    pub fn new() -> SourceLocations {
+        SourceLocations { locations: vec![] }
+    }
+
+    /// Get a fresh `NodeId` for a node of the given kind (`item`, `stmt` or `expr`) that came from
+    /// the given location.
+    pub fn node_id(&mut self, kind: &'static str, loc: SrcLoc) -> NodeId {
+        self.locations.push((kind, loc));
+        NodeId::new(self.locations.len() - 1)
+    }
+
+    fn get(&self, id: NodeId) -> Option<&(&'static str, SrcLoc)> {
+        if id == DUMMY_NODE_ID {
+            None
+        } else {
+            self.locations.get(id.as_usize())
+        }
+    }
+}
+
+/// Pretty-printer annotation which surrounds every tagged node with markers
+struct MarkerAnn<'a> {
+    locations: &'a SourceLocations,
+}
+
+impl<'a> MarkerAnn<'a> {
+    fn node_id(node: &AnnNode) -> Option<NodeId> {
+        match *node {
+            AnnNode::NodeItem(item) => Some(item.id),
+            AnnNode::NodeSubItem(id) => Some(id),
+            AnnNode::NodeExpr(expr) => Some(expr.id),
+            AnnNode::NodePat(pat) => Some(pat.id),
+            _ => None,
+        }
+    }
+
+    /// Markers are zero-width tokens, so they don't change where the pretty-printer breaks lines.
+    fn marker(s: &mut State, marker: String) -> io::Result<()> {
+        s.writer().zero_word(&marker)
+    }
+}
+
+impl<'a> PpAnn for MarkerAnn<'a> {
+    fn pre(&self, s: &mut State, node: AnnNode) -> io::Result<()> {
+        match MarkerAnn::node_id(&node).and_then(|id| self.locations.get(id)) {
+            Some(&(kind, loc)) => {
+                let marker = format!("{}{}:{}:{}:{}{}", MARKER_OPEN, kind, loc.fileid, loc.line,
+                                     loc.column, MARKER_END);
+                MarkerAnn::marker(s, marker)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn post(&self, s: &mut State, node: AnnNode) -> io::Result<()> {
+        match MarkerAnn::node_id(&node).and_then(|id| self.locations.get(id)) {
+            Some(_) => MarkerAnn::marker(s, format!("{}{}", MARKER_CLOSE, MARKER_END)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Like `pprust::to_string`, but the output contains markers around every node tagged in
+/// `locations`. These have to be removed with `extract_source_map`.
+pub fn to_string_annotated<F>(locations: &SourceLocations, f: F) -> String
+    where F: FnOnce(&mut State) -> io::Result<()> {
+    let mut wr = Vec::new();
+    {
+        let ann = MarkerAnn { locations };
+        let cm = CodeMap::new(FilePathMapping::empty());
+        let mut printer = State::new(&cm, Box::new(&mut wr), &ann, None, None);
+        f(&mut printer).unwrap();
+        printer.s.eof().unwrap();
+    }
+    String::from_utf8(wr).unwrap()
+}
+
+/// Where one piece of generated Rust came from
+#[derive(Clone, Debug)]
+pub struct SourceMapping {
+    /// `item`, `stmt` or `expr`
+    pub kind: &'static str,
+
+    /// Byte range (half-open) of the Rust code
+    pub rust_bytes: (usize, usize),
+
+    /// Line range (1-based, inclusive) of the Rust code
+    pub rust_lines: (usize, usize),
+
+    /// Originating C file, line, and column
+    pub c_file: Option<String>,
+    pub c_line: u64,
+    pub c_column: u64,
+}
+
+impl Serialize for SourceMapping {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut st = serializer.serialize_struct("SourceMapping", 7)?;
+        st.serialize_field("kind", &self.kind)?;
+        st.serialize_field("rust_bytes", &self.rust_bytes)?;
+        st.serialize_field("rust_lines", &self.rust_lines)?;
+        st.serialize_field("c_file", &self.c_file)?;
+        st.serialize_field("c_line", &self.c_line)?;
+        st.serialize_field("c_column", &self.c_column)?;
+        st.end()
+    }
+}
+
+/// Source map for a whole generated Rust file
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    pub mappings: Vec<SourceMapping>,
+}
+
+impl Serialize for SourceMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut st = serializer.serialize_struct("SourceMap", 2)?;
+        st.serialize_field("version", &1)?;
+        st.serialize_field("mappings", &self.mappings)?;
+        st.end()
+    }
+}
+
+impl SourceMap {
+    pub fn dump_json(&self, file_path: &str) -> io::Result<()> {
+        let file = File::create(file_path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+/// Remove the markers from pretty-printed output, returning the clean output and the source map
+/// recovered from the markers. Mappings are ordered by the start of their Rust byte range.
+pub fn extract_source_map(annotated: &str, c_files: &HashMap<u64, String>) -> (String, SourceMap) {
+    let mut output = String::with_capacity(annotated.len());
+    let mut mappings = vec![];
+
+    // Line number at the current end of `output`
+    let mut line = 1;
+
+    // Markers opened but not yet closed: the index of their mapping in `mappings`
+    let mut open: Vec<usize> = vec![];
+
+    let mut rest = annotated;
+    while let Some(start) = rest.find(|c| c == MARKER_OPEN || c == MARKER_CLOSE) {
+        let (text, marker) = rest.split_at(start);
+        output.push_str(text);
+        line += text.matches('\n').count();
+
+        let end = marker.find(MARKER_END).expect("Unterminated source map marker");
+        let payload = &marker[1..end];
+        rest = &marker[end + MARKER_END.len_utf8()..];
+
+        if marker.starts_with(MARKER_OPEN) {
+            let fields: Vec<&str> = payload.split(':').collect();
+            let kind = match fields[0] {
+                "item" => "item",
+                "stmt" => "stmt",
+                _ => "expr",
+            };
+            let number = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok()).unwrap_or(0);
+
+            open.push(mappings.len());
+            mappings.push(SourceMapping {
+                kind,
+                rust_bytes: (output.len(), output.len()),
+                rust_lines: (line, line),
+                c_file: c_files.get(&number(1)).cloned(),
+                c_line: number(2),
+                c_column: number(3),
+            });
+        } else {
+            let index = open.pop().expect("Unbalanced source map marker");
+            let mapping = &mut mappings[index];
+            mapping.rust_bytes.1 = output.len();
+            mapping.rust_lines.1 = line;
+        }
+    }
+    output.push_str(rest);
+
+    (output, SourceMap { mappings })
+}
+
+/// Lay annotated output out so that statements and items which came from the C file `fileid` sit
+/// on the same line as in that file. When the output has not reached the C line yet, blank lines
+/// go in front of the line holding the node (and in front of any attributes above it). When it has
+/// gone past the C line, the lines since then are joined. Either way, nodes already lined up stay
+/// where they are, so a node is only misaligned when it comes from an earlier C line than a node
+/// before it (like the increment of a `for` loop, which follows the loop body). Returns the laid
+/// out (still annotated) output along with the number of nodes that ended up aligned and
+/// misaligned.
+pub fn align_lines(annotated: &str, fileid: u64) -> (String, usize, usize) {
+    let mut output = String::with_capacity(annotated.len());
+    let mut aligned = 0;
+    let mut misaligned = 0;
+
+    // Line number at the current end of `output`, and the last line a node was aligned on
+    let mut line = 1;
+    let mut anchored_line = 0;
+
+    let mut rest = annotated;
+    while let Some(start) = rest.find(MARKER_OPEN) {
+        let (text, marker) = rest.split_at(start);
+        output.push_str(text);
+        line += text.matches('\n').count();
+
+        let end = marker.find(MARKER_END).expect("Unterminated source map marker");
+        let fields: Vec<&str> = marker[MARKER_OPEN.len_utf8()..end].split(':').collect();
+        let c_line = match (fields.get(0), fields.get(1).and_then(|f| f.parse::<u64>().ok())) {
+            (Some(&"stmt"), Some(f)) | (Some(&"item"), Some(f)) if f == fileid =>
+                fields.get(2).and_then(|f| f.parse::<usize>().ok()),
+            _ => None,
+        };
+
+        match c_line {
+            Some(c_line) if c_line == line => {
+                anchored_line = line;
+                aligned += 1;
+            }
+            Some(c_line) if c_line > line && anchored_line != line => {
+                let padding: String = (line..c_line).map(|_| '\n').collect();
+                let insert_at = attributes_start(&output);
+                output.insert_str(insert_at, &padding);
+                line = c_line;
+                anchored_line = line;
+                aligned += 1;
+            }
+            Some(c_line) if c_line < line && c_line >= anchored_line => {
+                join_last_lines(&mut output, line - c_line);
+                line = c_line;
+                anchored_line = line;
+                aligned += 1;
+            }
+            Some(_) => misaligned += 1,
+            None => { },
+        }
+
+        output.push_str(&marker[..end + MARKER_END.len_utf8()]);
+        rest = &marker[end + MARKER_END.len_utf8()..];
+    }
+    output.push_str(rest);
+
+    (output, aligned, misaligned)
+}
+
+/// Move the comments marked with `TRAILING_COMMENT` from the lines above a node to the end of the
+/// line the node starts on. When that line does not hold the whole node (or is not code), the
+/// comments stay where they are, since they would otherwise end up in the middle of the node.
+pub fn place_trailing_comments(annotated: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+    // Indentation and text of the trailing comments waiting for the next line
+    let mut pending: Vec<(&str, &str)> = vec![];
+
+    for line in annotated.split('\n') {
+        let text = line.trim_left();
+        if text.starts_with(TRAILING_COMMENT) {
+            pending.push((&line[..line.len() - text.len()], &text[TRAILING_COMMENT.len_utf8()..]));
+            continue
+        }
+
+        let code = strip_markers(line);
+        let code = code.trim();
+        let whole_node = (code.ends_with(';') || code.ends_with(',')) &&
+            !code.starts_with("/*") && line_comment_start(code).is_none();
+        if whole_node && !pending.is_empty() {
+            let mut joined = String::from(line);
+            for (_, comment) in pending.drain(..) {
+                joined.push(' ');
+                joined.push_str(comment);
+            }
+            lines.push(joined);
+        } else {
+            lines.extend(pending.drain(..).map(|(indent, comment)| format!("{}{}", indent, comment)));
+            lines.push(String::from(line));
+        }
+    }
+    lines.extend(pending.drain(..).map(|(indent, comment)| format!("{}{}", indent, comment)));
+
+    lines.join("\n")
+}
+
+/// Remove the markers from a piece of annotated output
+fn strip_markers(annotated: &str) -> String {
+    let mut output = String::new();
+    let mut rest = annotated;
+    while let Some(start) = rest.find(|c| c == MARKER_OPEN || c == MARKER_CLOSE) {
+        output.push_str(&rest[..start]);
+        let marker = &rest[start..];
+        let end = marker.find(MARKER_END).expect("Unterminated source map marker");
+        rest = &marker[end + MARKER_END.len_utf8()..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Join the last `count + 1` lines of `output` into one. Line comments on the lines joined become
+/// block comments, so that they do not swallow the code joined after them.
+fn join_last_lines(output: &mut String, count: usize) {
+    let mut start = output.len();
+    for _ in 0..count {
+        start = output[..start].rfind('\n').expect("not enough lines to join");
+    }
+    start = output[..start].rfind('\n').map_or(0, |i| i + 1);
+
+    let mut joined = String::new();
+    for (i, line) in output[start..].split('\n').enumerate() {
+        let line = if i == 0 { line.trim_right() } else { line.trim() };
+        if i > 0 && !joined.is_empty() {
+            joined.push(' ');
+        }
+        match line_comment_start(line) {
+            Some(at) => {
+                let (code, comment) = line.split_at(at);
+                let (opening, text) = if comment.starts_with("///") && !comment.starts_with("////") {
+                    ("/**", &comment[3..])
+                } else if comment.starts_with("//!") {
+                    ("/*!", &comment[3..])
+                } else {
+                    ("/*", &comment[2..])
+                };
+                joined.push_str(code);
+                joined.push_str(opening);
+                joined.push_str(&text.replace("*/", "* /"));
+                joined.push_str(" */");
+            }
+            None => joined.push_str(line),
+        }
+    }
+    output.truncate(start);
+    output.push_str(&joined);
+}
+
+/// Where the line comment on a line of Rust starts, if it has one outside of literals and block
+/// comments
+fn line_comment_start(line: &str) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'/' if bytes.get(i + 1) == Some(&b'/') => return Some(i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = line[i + 2..].find("*/").map_or(bytes.len(), |end| i + 2 + end + 2);
+                continue
+            }
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            // A character literal, as opposed to a lifetime or label
+            b'\'' if bytes.get(i + 1) == Some(&b'\\') => {
+                i += 2;
+                while i < bytes.len() && bytes[i] != b'\'' {
+                    i += 1;
+                }
+            }
+            b'\'' if bytes.get(i + 2) == Some(&b'\'') => i += 2,
+            _ => { }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Start of the last line of `output`, moved up past any lines of attributes directly above it
+fn attributes_start(output: &str) -> usize {
+    let mut start = output.rfind('\n').map_or(0, |i| i + 1);
+    while start > 0 {
+        let prev_start = output[..start - 1].rfind('\n').map_or(0, |i| i + 1);
+        if !output[prev_start..start].trim_left().starts_with("#[") {
+            break;
+        }
+        start = prev_start;
+    }
+    start
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn marker_open(kind: &str, line: u64) -> String {
+        format!("{}{}:0:{}:1{}", MARKER_OPEN, kind, line, MARKER_END)
+    }
+
+    fn marker_close() -> String {
+        format!("{}{}", MARKER_CLOSE, MARKER_END)
+    }
+
+    #[test]
+    fn nested_markers() {
+        let annotated = format!("{}fn f() {{\n    {}1{}\n}}{}\n", marker_open("item", 3),
+                                marker_open("expr", 4), marker_close(), marker_close());
+        let mut files = HashMap::new();
+        files.insert(0, String::from("f.c"));
+
+        let (output, map) = extract_source_map(&annotated, &files);
+        assert_eq!(output, "fn f() {\n    1\n}\n");
+        assert_eq!(map.mappings.len(), 2);
+
+        let item = &map.mappings[0];
+        assert_eq!((item.kind, item.rust_bytes, item.rust_lines), ("item", (0, 16), (1, 3)));
+        assert_eq!((item.c_file.as_ref().map(String::as_str), item.c_line), (Some("f.c"), 3));
+
+        let expr = &map.mappings[1];
+        assert_eq!((expr.kind, expr.rust_bytes, expr.rust_lines), ("expr", (13, 14), (2, 2)));
+        assert_eq!(&output[expr.rust_bytes.0..expr.rust_bytes.1], "1");
+    }
+
+    #[test]
+    fn aligned_lines() {
+        let annotated = format!("#[inline]\n{}fn f() {{\n    let x = {}g(){};\n    {}h();{}\n}}{}\n",
+                                marker_open("item", 3),
+                                marker_open("stmt", 5), marker_close(),
+                                marker_open("stmt", 4), marker_close(),
+                                marker_close());
+
+        let (padded, aligned, misaligned) = align_lines(&annotated, 0);
+        assert_eq!((aligned, misaligned), (2, 1));
+
+        let (output, _) = extract_source_map(&padded, &HashMap::new());
+        assert_eq!(output, "\n#[inline]\nfn f() {\n\n    let x = g();\n    h();\n}\n");
+    }
+
+    #[test]
+    fn joined_lines() {
+        let annotated = format!("#[no_mangle]\n{}fn f() {{\n    {}a(){};\n    {}b(){}; // \"//\" */\n    {}c(){};\n}}{}\n",
+                                marker_open("item", 1),
+                                marker_open("stmt", 2), marker_close(),
+                                marker_open("stmt", 2), marker_close(),
+                                marker_open("stmt", 2), marker_close(),
+                                marker_close());
+
+        let (joined, aligned, misaligned) = align_lines(&annotated, 0);
+        assert_eq!((aligned, misaligned), (4, 0));
+
+        let (output, _) = extract_source_map(&joined, &HashMap::new());
+        assert_eq!(output, "#[no_mangle] fn f() {\n    a(); b(); /* \"//\" * / */ c();\n}\n");
+    }
+
+    #[test]
+    fn trailing_comments() {
+        let annotated = format!("fn f() {{\n    // about x\n    {t}// set x\n    {}x = 1;{}\n    \
+                                 {t}// loop\n    while c {{\n        g();\n    }}\n    {t}/* last */\n}}\n",
+                                marker_open("stmt", 2), marker_close(), t = TRAILING_COMMENT);
+        let placed = place_trailing_comments(&annotated);
+
+        let (output, _) = extract_source_map(&placed, &HashMap::new());
+        assert_eq!(output, "fn f() {\n    // about x\n    x = 1; // set x\n    // loop\n    while c {\n        \
+                            g();\n    }\n    /* last */\n}\n");
+    }
+
+    #[test]
+    fn line_comments() {
+        assert_eq!(line_comment_start("let s = \"http://\"; // url"), Some(19));
+        assert_eq!(line_comment_start("/* a // b */ 'a: loop { x = '/'; }"), None);
+        assert_eq!(line_comment_start("c = '\\''; /// doc"), Some(10));
+    }
+}
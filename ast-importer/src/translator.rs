@@ -1,4 +1,4 @@
-use syntax::{with_globals, ast};
+use syntax::{with_globals, ast, attr};
 use syntax::ast::*;
 use syntax::codemap::{DUMMY_SP, Span};
 use syntax::tokenstream::{TokenStream};
@@ -9,9 +9,19 @@ use convert_type::TypeConverter;
 use loops::*;
 use c_ast;
 use c_ast::*;
+use clang_ast::ConditionalRegion;
 use rust_ast::{mk, Builder};
-use rust_ast::comment_store::CommentStore;
-use c_ast::iterators::{DFExpr, SomeId};
+use rust_ast::comment_store::{CommentStore, sanitize_comment_lines};
+use syntax::parse::lexer::comments;
+use safe_wrappers::{SafeWrapperConfig, FunctionAnnotations, ParamAnnotation, ReturnAnnotation};
+use call_rewrites::{CallRewrites, CallRewrite, CallArg, Callee};
+use naming::{NameMap, to_upper_camel_case, to_snake_case};
+use source_map::{SourceLocations, align_lines, extract_source_map, place_trailing_comments,
+                 to_string_annotated};
+use c_ast::iterators::{DFExpr, SomeId, immediate_children};
+use c_ast::const_eval::{ConstEvaluator, ConstValue, ConstAddress, AddressBase, Projection};
+use c_ast::tagged_unions::{TaggedUnion, TagValue, find_tagged_unions};
+use c_ast::indexed_arrays::find_indexed_arrays;
 use syntax::ptr::*;
 use syntax::print::pprust::*;
 use std::ops::Index;
@@ -22,6 +32,9 @@ use dtoa;
 use with_stmts::WithStmts;
 use rust_ast::traverse::Traversal;
 use std::io;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::panic;
 
 use cfg;
 
@@ -69,13 +82,26 @@ pub enum ReplaceMode {
     Extern,
 }
 
+/// What integer arithmetic and pointer offsets do when they overflow
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowMode {
+    /// Keep C semantics: unsigned arithmetic wraps and signed overflow is left undefined (plain
+    /// Rust operators)
+    Faithful,
+    /// Panic on any overflow, signed or unsigned. Useful for finding latent undefined behavior.
+    Checked,
+    /// Wrap on any overflow, signed or unsigned
+    Wrapping,
+}
+
 /// Configuration settings for the translation process
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TranslationConfig {
     pub reloop_cfgs: bool,
     pub fail_on_multiple: bool,
     pub dump_function_cfgs: bool,
     pub json_function_cfgs: bool,
+    pub html_function_cfgs: bool,
     pub dump_cfg_liveness: bool,
     pub dump_structures: bool,
     pub debug_relooper_labels: bool,
@@ -93,12 +119,76 @@ pub struct TranslationConfig {
     pub replace_unsupported_decls: ReplaceMode,
     pub translate_valist: bool,
     pub reduce_type_annotations: bool,
+    pub jobs: usize,
+    pub source_map: Option<String>,
+    pub debug_line_layout: bool,
+    pub safe_wrappers: Option<SafeWrapperConfig>,
+    pub split_irreducible: Option<usize>,
+    pub overflow_mode: OverflowMode,
+    pub target_stable: bool,
+    pub no_std: bool,
+    pub fixed_width_ints: bool,
+    pub call_rewrites: Option<CallRewrites>,
+    pub cfg_features: Vec<String>,
+    pub normalize_names: bool,
+    pub name_map: Option<String>,
+    pub tagged_unions: bool,
+    pub for_ranges: bool,
+    pub static_interior_refs: bool,
+    pub index_arrays: bool,
+}
+
+impl Default for TranslationConfig {
+    /// The configuration used by the command line translator when given no options
+    fn default() -> Self {
+        TranslationConfig {
+            reloop_cfgs: false,
+            fail_on_multiple: false,
+            dump_function_cfgs: false,
+            json_function_cfgs: false,
+            html_function_cfgs: false,
+            dump_cfg_liveness: false,
+            dump_structures: false,
+            debug_relooper_labels: false,
+            cross_checks: false,
+            cross_check_configs: vec![],
+            prefix_function_names: None,
+            translate_asm: false,
+            translate_entry: false,
+            use_c_loop_info: true,
+            use_c_multiple_info: true,
+            simplify_structures: true,
+            panic_on_translator_failure: false,
+            emit_module: false,
+            fail_on_error: false,
+            replace_unsupported_decls: ReplaceMode::Extern,
+            translate_valist: false,
+            reduce_type_annotations: false,
+            jobs: 1,
+            source_map: None,
+            debug_line_layout: false,
+            safe_wrappers: None,
+            split_irreducible: None,
+            overflow_mode: OverflowMode::Faithful,
+            target_stable: false,
+            no_std: false,
+            fixed_width_ints: false,
+            call_rewrites: None,
+            cfg_features: vec![],
+            normalize_names: false,
+            name_map: None,
+            tagged_unions: false,
+            for_ranges: false,
+            static_interior_refs: false,
+            index_arrays: false,
+        }
+    }
 }
 
 pub struct Translation {
 
     // Translation environment
-    pub ast_context: TypedAstContext,
+    pub ast_context: Arc<TypedAstContext>,
     pub tcfg: TranslationConfig,
 
     // Accumulated outputs
@@ -106,16 +196,43 @@ pub struct Translation {
     pub items: RefCell<Vec<P<Item>>>,
     pub foreign_items: Vec<ForeignItem>,
     sectioned_static_initializers: RefCell<Vec<Stmt>>,
+    diagnostics: RefCell<Vec<String>>,
+    /// Whether failures are only recorded, to be reported by the main thread in order (which fails
+    /// the translation with `fail_on_error`)
+    defer_failures: bool,
+    /// Why the translation failed as a whole, with `fail_on_error` or `fail_on_multiple`
+    failure: RefCell<Option<String>>,
 
     // Translation state and utilities
     type_converter: RefCell<TypeConverter>,
     renamer: RefCell<Renamer<CDeclId>>,
     loops: LoopContext,
     zero_inits: RefCell<HashMap<CDeclId, Result<P<Expr>, String>>>,
+    lowered_stmt_exprs: RefCell<HashMap<CExprId, P<Expr>>>,
+    /// Values of the static initializer subexpressions evaluated so far
+    const_values: RefCell<HashMap<CExprId, Option<ConstValue>>>,
+    /// Whether the nodes of static initializers seen so far have a subexpression whose direct
+    /// translation can't appear in a static initializer
+    uncompilable_static_nodes: RefCell<HashMap<SomeId, bool>>,
+    /// How many of the conditionals on features enclosing the current code already guard the
+    /// declaration or statement it is part of
+    feature_cfg_depth: RefCell<usize>,
+    /// Union fields of structs whose members are read through accessors checking the tag
+    tagged_unions: HashMap<CFieldId, TaggedUnion>,
+    /// Local and static arrays whose pointer never escapes, so they can always be indexed
+    indexed_arrays: HashSet<CDeclId>,
+    /// Names of the functions the translated code defines
+    defined_functions: HashSet<String>,
 
     // Comment support
     pub comment_context: RefCell<CommentContext>, // Incoming comments
     pub comment_store: RefCell<CommentStore>, // Outgoing comments
+
+    // Source map support
+    source_locations: RefCell<SourceLocations>,
+
+    // Relooper statistics
+    structuring_stats: RefCell<cfg::StructuringStats>,
 }
 
 
@@ -140,24 +257,15 @@ fn cast_int(val: P<Expr>, name: &str) -> P<Expr> {
     }
 }
 
-/// Pointer offset that casts its argument to isize
-fn pointer_offset(ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
-    pointer_offset_isize(ptr, cast_int(offset, "isize"))
+/// Pointer offset (using the `offset` or `wrapping_offset` method) that casts its argument to isize
+fn pointer_offset(method: &str, ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
+    pointer_offset_isize(method, ptr, cast_int(offset, "isize"))
 }
 
 
 /// Pointer offset that requires its argument to have type isize
-fn pointer_offset_isize(ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
-    mk().method_call_expr(ptr, "offset", vec![offset])
-}
-
-fn pointer_neg_offset(ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
-    let offset = cast_int(offset, "isize");
-    pointer_neg_offset_isize(ptr, offset)
-}
-
-fn pointer_neg_offset_isize(ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
-    mk().method_call_expr(ptr, "offset", vec![mk().unary_expr(ast::UnOp::Neg, offset)])
+fn pointer_offset_isize(method: &str, ptr: P<Expr>, offset: P<Expr>) -> P<Expr> {
+    mk().method_call_expr(ptr, method, vec![offset])
 }
 
 /// Given an expression with type Option<fn(...)->...>, unwrap
@@ -167,26 +275,6 @@ fn unwrap_function_pointer(ptr: P<Expr>) -> P<Expr> {
     mk().method_call_expr(ptr, "expect", vec![err_msg])
 }
 
-fn neg_expr(arg: P<Expr>) -> P<Expr> {
-    mk().unary_expr(ast::UnOp::Neg, arg)
-}
-
-fn wrapping_neg_expr(arg: P<Expr>) -> P<Expr> {
-    mk().method_call_expr(arg, "wrapping_neg", vec![] as Vec<P<Expr>>)
-}
-
-
-fn transmute_expr(source_ty: P<Ty>, target_ty: P<Ty>, expr: P<Expr>) -> P<Expr> {
-    let type_args = vec![source_ty, target_ty];
-    let path = vec![
-        mk().path_segment(""),
-        mk().path_segment("std"),
-        mk().path_segment("mem"),
-        mk().path_segment_with_params("transmute",
-                                      mk().angle_bracketed_param_types(type_args)),
-    ];
-    mk().call_expr(mk().path_expr(path), vec![expr])
-}
 
 pub fn stmts_block(mut stmts: Vec<Stmt>) -> P<Block> {
     if stmts.len() == 1 {
@@ -213,6 +301,24 @@ pub fn with_stmts_opt<T>(opt: Option<WithStmts<T>>) -> WithStmts<Option<T>> {
     }
 }
 
+/// Add outer attributes to a statement
+pub fn stmt_with_attrs(mut stmt: Stmt, attrs: &[ast::Attribute]) -> Stmt {
+    fn extend(old: ThinVec<ast::Attribute>, attrs: &[ast::Attribute]) -> ThinVec<ast::Attribute> {
+        let mut old: Vec<ast::Attribute> = old.into();
+        old.extend(attrs.iter().cloned());
+        old.into()
+    }
+
+    stmt.node = match stmt.node {
+        StmtKind::Local(local) => StmtKind::Local(local.map(|mut l| { l.attrs = extend(l.attrs, attrs); l })),
+        StmtKind::Item(item) => StmtKind::Item(item.map(|mut i| { i.attrs.extend(attrs.iter().cloned()); i })),
+        StmtKind::Expr(expr) => StmtKind::Expr(expr.map(|mut e| { e.attrs = extend(e.attrs, attrs); e })),
+        StmtKind::Semi(expr) => StmtKind::Semi(expr.map(|mut e| { e.attrs = extend(e.attrs, attrs); e })),
+        StmtKind::Mac(mac) => StmtKind::Mac(mac.map(|(m, style, a)| (m, style, extend(a, attrs)))),
+    };
+    stmt
+}
+
 // Generate link attributes needed to ensure that the generated Rust libraries have the right symbol
 // values.
 fn mk_linkage(in_extern_block: bool, new_name: &str, old_name: &str) -> Builder {
@@ -235,7 +341,7 @@ pub fn signed_int_expr(value: i64) -> P<Expr> {
 
 // This should only be used for tests
 fn prefix_names(translation: &mut Translation, prefix: String) {
-    for (&decl_id, ref mut decl) in &mut translation.ast_context.c_decls {
+    for (&decl_id, ref mut decl) in &mut Arc::make_mut(&mut translation.ast_context).c_decls {
         match decl.kind {
             CDeclKind::Function { ref mut name, ref body, .. } if body.is_some() => {
                 name.insert_str(0, &prefix);
@@ -248,52 +354,154 @@ fn prefix_names(translation: &mut Translation, prefix: String) {
     }
 }
 
-pub fn translate_failure(tcfg: &TranslationConfig, msg: &str) {
-    if tcfg.fail_on_error {
-        panic!("{}", msg)
-    } else {
-        eprintln!("{}", msg)
+/// The text of a translation, along with what it needs to compile and what went wrong
+pub struct Translated {
+    /// The Rust module, pretty-printed
+    pub rust: String,
+    /// The language features the module uses
+    pub features: Vec<&'static str>,
+    /// Declarations that could not be translated and why, along with other problems with the output
+    pub diagnostics: Vec<String>,
+}
+
+/// The `libsyntax` items of a translation, along with what they need to compile and what went
+/// wrong
+pub struct TranslatedItems {
+    /// The items of the Rust module. Foreign items are grouped into a leading `extern "C"` block.
+    pub items: Vec<P<Item>>,
+    /// Comments for the pretty-printer to place among the items
+    pub comments: Vec<comments::Comment>,
+    /// The language features the items use
+    pub features: Vec<&'static str>,
+    /// Declarations that could not be translated, and why
+    pub diagnostics: Vec<String>,
+}
+
+/// Translate, printing the diagnostics to stderr
+pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Result<String, String> {
+    let translated = translate_to_text(ast_context, tcfg)?;
+    for diagnostic in &translated.diagnostics {
+        eprintln!("{}", diagnostic);
     }
+    Ok(translated.rust)
+}
+
+/// Translate, reporting the features the output uses and the diagnostics produced along the way.
+/// A translation that fails as a whole (with `fail_on_error` or `fail_on_multiple`) is an error.
+pub fn translate_to_text(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Result<Translated, String> {
+    translate_with(ast_context, tcfg, print_translation)
+}
+
+/// Translate into `libsyntax` items and pass them to `f`. The items are only valid within `f`,
+/// since their identifiers are interned in state which only lives as long as the translation.
+pub fn translate_to_items<R, F>(ast_context: TypedAstContext, tcfg: TranslationConfig, f: F) -> Result<R, String>
+    where F: FnOnce(TranslatedItems) -> R {
+    // Functions translated on worker threads come back already pretty-printed
+    let tcfg = TranslationConfig { jobs: 1, ..tcfg };
+
+    translate_with(ast_context, tcfg, |t, function_items| {
+        let features = t.features_required();
+        let diagnostics = t.diagnostics.replace(vec![]);
+
+        let mut traverser = t.comment_store.into_inner().into_comment_traverser();
+        let mut items = vec![];
+        if !t.foreign_items.is_empty() {
+            let foreign_items = t.foreign_items
+                .into_iter()
+                .map(|fi| traverser.traverse_foreign_item(fi))
+                .collect();
+            items.push(mk().abi("C").foreign_items(foreign_items));
+        }
+        for item in merge_function_items(t.items.into_inner(), function_items) {
+            match item {
+                OutputItem::Item(p_i) => items.push(p_i.map(|i| traverser.traverse_item(i))),
+                OutputItem::Rendered(_) => unreachable!("function rendered while translating to items"),
+            }
+        }
+        let comments = traverser.into_comment_store().into_comments();
+
+        f(TranslatedItems { items, comments, features, diagnostics })
+    })
 }
 
-pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> String {
+/// Translate a translation unit, then hand the translation and the function definitions set
+/// aside for worker threads to `finish`, unless the translation failed as a whole
+fn translate_with<R, F>(ast_context: TypedAstContext, tcfg: TranslationConfig, finish: F) -> Result<R, String>
+    where F: FnOnce(Translation, Vec<(usize, OutputItem)>) -> R {
 
     let mut t = Translation::new(ast_context, tcfg);
 
     if !t.tcfg.translate_entry {
-        t.ast_context.c_main = None;
+        Arc::make_mut(&mut t.ast_context).c_main = None;
+    }
+
+    // Safe wrappers are generated for the functions a header declares, and those declarations
+    // are usually unused prototypes, so look for them before they get pruned below
+    let wrapped_functions = t.tcfg.safe_wrappers.as_ref()
+        .map(|config| t.functions_declared_in(&config.header));
+
+    // Calls to a function the translated code defines are never rewritten, whichever of its
+    // declarations they refer to
+    if t.tcfg.call_rewrites.is_some() {
+        t.defined_functions = t.ast_context.c_decls.values()
+            .filter_map(|decl| match decl.kind {
+                CDeclKind::Function { ref name, body: Some(_), .. } => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
     }
 
     // Headers often pull in declarations that are unused;
     // we simplify the translator output by omitting those.
-    t.ast_context.prune_unused_decls();
+    Arc::make_mut(&mut t.ast_context).prune_unused_decls();
+
+    if t.tcfg.tagged_unions {
+        t.tagged_unions = find_tagged_unions(&t.ast_context);
+    }
+    if t.tcfg.index_arrays {
+        t.indexed_arrays = find_indexed_arrays(&t.ast_context);
+    }
 
     enum Name<'a> {
         VarName(&'a str),
-        TypeName(&'a str),
+        FunctionName(&'a str),
+        /// The tag keyword of a struct, union or enum (empty for a typedef), and the name
+        TypeName(&'static str, &'a str),
         AnonymousType,
         NoName,
     }
 
-    fn some_type_name(s: Option<&str>) -> Name {
+    fn some_type_name<'a>(tag: &'static str, s: Option<&'a str>) -> Name<'a> {
         match s {
             None => Name::AnonymousType,
-            Some(r) => Name::TypeName(r),
+            Some(r) => Name::TypeName(tag, r),
         }
     }
 
+    /// Tagged types are kept apart from typedefs of the same name in the name map
+    fn type_key(tag: &str, name: &str) -> String {
+        if tag.is_empty() { name.to_owned() } else { format!("{} {}", tag, name) }
+    }
+
     // Used for testing; so that we don't overlap with C function names
     if let Some(prefix) = t.tcfg.prefix_function_names.clone() {
         prefix_names(&mut t, prefix);
     }
 
+    // Names picked by a previous translation take precedence over the names we would pick
+    let mut names = match t.tcfg.name_map {
+        Some(ref path) => NameMap::from_file(path)?,
+        None => NameMap::default(),
+    };
+
     // `with_globals` sets up a thread-local variable required by the syntax crate.
     with_globals(|| {
         // Identify typedefs that name unnamed types and collapse the two declarations
         // into a single name and declaration, eliminating the typedef altogether.
         let mut prenamed_decls: HashSet<CDeclId> = HashSet::new();
+        let mut prenamed_aliases: Vec<(CDeclId, CDeclId)> = vec![];
         for (&decl_id, decl) in &t.ast_context.c_decls {
-            if let CDeclKind::Typedef { ref name, typ, .. } = decl.kind {
+            if let CDeclKind::Typedef { typ, .. } = decl.kind {
                 if let Some(subdecl_id) = t.ast_context.resolve_type(typ.ctype).kind.as_underlying_decl() {
                     let is_unnamed = match t.ast_context[subdecl_id].kind {
                         CDeclKind::Struct { name: None, .. } => true,
@@ -305,34 +513,70 @@ pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Strin
                     if is_unnamed && !prenamed_decls.contains(&subdecl_id) {
                         prenamed_decls.insert(decl_id);
                         prenamed_decls.insert(subdecl_id);
-
-                        t.type_converter.borrow_mut().declare_decl_name(decl_id, name);
-                        t.type_converter.borrow_mut().alias_decl_name(subdecl_id, decl_id);
+                        prenamed_aliases.push((subdecl_id, decl_id));
                     }
                 }
             }
         }
 
         // Populate renamer with top-level names
-        for (&decl_id, decl) in &t.ast_context.c_decls {
-            let decl_name = match decl.kind {
-                _ if prenamed_decls.contains(&decl_id) => Name::NoName,
-                CDeclKind::Struct { ref name, .. } => some_type_name(name.as_ref().map(String::as_str)),
-                CDeclKind::Enum { ref name, .. } => some_type_name(name.as_ref().map(String::as_str)),
-                CDeclKind::Union { ref name, .. } => some_type_name(name.as_ref().map(String::as_str)),
-                CDeclKind::Typedef { ref name, .. } => Name::TypeName(name),
-                CDeclKind::Function { ref name, .. } => Name::VarName(name),
-                CDeclKind::EnumConstant { ref name, .. } => Name::VarName(name),
-                CDeclKind::Variable { ref ident, .. }
-                if t.ast_context.c_decls_top.contains(&decl_id) => Name::VarName(ident),
-                _ => Name::NoName,
-            };
-            match decl_name {
-                Name::NoName => (),
-                Name::AnonymousType => { t.type_converter.borrow_mut().declare_decl_name(decl_id, "unnamed"); }
-                Name::TypeName(name) => { t.type_converter.borrow_mut().declare_decl_name(decl_id, name); }
-                Name::VarName(name) => { t.renamer.borrow_mut().insert(decl_id, &name); }
+        {
+            let mut decl_names = vec![];
+            for (&decl_id, decl) in &t.ast_context.c_decls {
+                let decl_name = match decl.kind {
+                    CDeclKind::Typedef { ref name, .. } => Name::TypeName("", name),
+                    _ if prenamed_decls.contains(&decl_id) => Name::NoName,
+                    CDeclKind::Struct { ref name, .. } => some_type_name("struct", name.as_ref().map(String::as_str)),
+                    CDeclKind::Enum { ref name, .. } => some_type_name("enum", name.as_ref().map(String::as_str)),
+                    CDeclKind::Union { ref name, .. } => some_type_name("union", name.as_ref().map(String::as_str)),
+                    CDeclKind::Function { ref name, .. } => Name::FunctionName(name),
+                    CDeclKind::EnumConstant { ref name, .. } => Name::VarName(name),
+                    CDeclKind::Variable { ref ident, .. }
+                    if t.ast_context.c_decls_top.contains(&decl_id) => Name::VarName(ident),
+                    _ => Name::NoName,
+                };
+                decl_names.push((decl_id, decl_name));
             }
+
+            // Declarations named by the name map go first, so that no other declaration takes their name
+            decl_names.sort_by_key(|&(_, ref decl_name)| match *decl_name {
+                Name::TypeName(tag, name) => !names.types.contains_key(&type_key(tag, name)),
+                Name::VarName(name) | Name::FunctionName(name) => !names.values.contains_key(name),
+                Name::AnonymousType | Name::NoName => true,
+            });
+
+            let normalize = t.tcfg.normalize_names;
+            for (decl_id, decl_name) in decl_names {
+                match decl_name {
+                    Name::NoName => (),
+                    Name::AnonymousType => {
+                        let name = if normalize { "Unnamed" } else { "unnamed" };
+                        t.type_converter.borrow_mut().declare_decl_name(decl_id, name);
+                    }
+                    Name::TypeName(tag, name) => {
+                        let key = type_key(tag, name);
+                        let basename = names.types.get(&key).cloned()
+                            .unwrap_or_else(|| if normalize { to_upper_camel_case(name) } else { name.to_owned() });
+                        let new_name = t.type_converter.borrow_mut().declare_decl_name(decl_id, &basename);
+                        names.types.insert(key, new_name);
+                    }
+                    Name::VarName(name) | Name::FunctionName(name) => {
+                        let is_function = match decl_name { Name::FunctionName(_) => true, _ => false };
+                        let basename = names.values.get(name).cloned()
+                            .unwrap_or_else(|| if normalize && is_function { to_snake_case(name) } else { name.to_owned() });
+                        if let Some(new_name) = t.renamer.borrow_mut().insert(decl_id, &basename) {
+                            names.values.insert(name.to_owned(), new_name);
+                        }
+                    }
+                }
+            }
+            for (subdecl_id, decl_id) in prenamed_aliases {
+                t.type_converter.borrow_mut().alias_decl_name(subdecl_id, decl_id);
+            }
+        }
+
+        if let Some(ref path) = t.tcfg.name_map {
+            names.dump_json(path).map_err(|e| format!("Failed to write name map {}: {}", path, e))?;
         }
 
         // Export all types
@@ -343,7 +587,8 @@ pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Strin
                 CDeclKind::EnumConstant { .. } => true,
                 CDeclKind::Union { .. } => true,
                 CDeclKind::Typedef { .. } =>
-                    !prenamed_decls.contains(&decl_id),
+                    !prenamed_decls.contains(&decl_id) &&
+                        t.type_converter.borrow().fixed_width_typedef(&t.ast_context, decl_id).is_none(),
                 _ => false,
             };
             if needs_export {
@@ -353,43 +598,122 @@ pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Strin
                     Err(e) => {
                         let ref k = t.ast_context.c_decls.get(&decl_id).map(|x| &x.kind);
                         let msg = format!("Skipping declaration due to error: {}, kind: {:?}", e, k);
-                        translate_failure(&t.tcfg, &msg)
+                        t.translate_failure(&msg)
                     },
                 }
             }
         }
 
-        // Export top-level value declarations
+        // Give each tagged union an enum of its members, along with methods of its struct
+        let mut union_fields: Vec<CFieldId> = t.tagged_unions.keys().cloned().collect();
+        union_fields.sort();
+        for union_field in union_fields {
+            let converted = t.convert_tagged_union(&t.tagged_unions[&union_field]);
+            match converted {
+                Ok(items) => t.items.borrow_mut().extend(items),
+                Err(e) => {
+                    // Its members are read directly then
+                    t.tagged_unions.remove(&union_field);
+                    let msg = format!("Skipping tagged union accessors due to error: {}", e);
+                    t.translate_failure(&msg)
+                }
+            }
+        }
+
+        // Export top-level value declarations. When translating with several jobs, function
+        // definitions are set aside (along with the position their item should occupy) and
+        // translated on worker threads once every other top-level declaration has been seen.
+        let mut deferred_functions: Vec<(usize, CDeclId)> = vec![];
+        let rewritten_away = t.rewritten_away_functions();
         for top_id in &t.ast_context.c_decls_top {
             let needs_export = match t.ast_context.c_decls[top_id].kind {
+                CDeclKind::Function { is_implicit, body: None, .. } =>
+                    !is_implicit && !rewritten_away.contains(top_id),
                 CDeclKind::Function { is_implicit, .. } => !is_implicit,
                 CDeclKind::Variable { .. } => true,
                 _ => false,
             };
-            if needs_export {
+            let is_definition = match t.ast_context.c_decls[top_id].kind {
+                CDeclKind::Function { body: Some(_), .. } => true,
+                _ => false,
+            };
+            // Dumps go to stderr as functions are translated, so they would interleave
+            if needs_export && is_definition && t.tcfg.jobs > 1 && !t.tcfg.dump_structures {
+                deferred_functions.push((t.items.borrow().len(), *top_id));
+            } else if needs_export {
                 match t.convert_decl(true, *top_id) {
                     Ok(ConvertedDecl::Item(mut item)) => t.items.borrow_mut().push(item),
                     Ok(ConvertedDecl::ForeignItem(mut item)) => t.foreign_items.push(item),
                     Err(e) => {
                         let ref k = t.ast_context.c_decls.get(top_id).map(|x| &x.kind);
                         let msg = format!("Failed translating declaration due to error: {}, kind: {:?}", e, k);
-                        translate_failure(&t.tcfg, &msg)
+                        t.translate_failure(&msg)
                     },
                 }
             }
         }
 
+        // Translate the deferred function definitions. The results are merged back in the order
+        // the functions were declared, so the output does not depend on thread scheduling.
+        let mut function_items: Vec<(usize, OutputItem)> = vec![];
+        let outputs = translate_functions_in_parallel(&t, &deferred_functions);
+        for (&(position, decl_id), output) in deferred_functions.iter().zip(outputs) {
+            match output {
+                WorkerOutput::Rendered(text, features, stats, diagnostics) => {
+                    t.structuring_stats.borrow_mut().add(stats);
+                    t.features.borrow_mut().extend(features);
+                    for msg in diagnostics {
+                        t.translate_failure(&msg)
+                    }
+                    function_items.push((position, OutputItem::Rendered(text)));
+                }
+                WorkerOutput::Failed(e, diagnostics) => {
+                    for msg in diagnostics {
+                        t.translate_failure(&msg)
+                    }
+                    let ref k = t.ast_context.c_decls.get(&decl_id).map(|x| &x.kind);
+                    let msg = format!("Failed translating declaration due to error: {}, kind: {:?}", e, k);
+                    t.translate_failure(&msg)
+                }
+                WorkerOutput::Fatal(msg) => t.fail_translation(&msg),
+                WorkerOutput::Sequential => match t.convert_decl(true, decl_id) {
+                    Ok(ConvertedDecl::Item(item)) => function_items.push((position, OutputItem::Item(item))),
+                    Ok(ConvertedDecl::ForeignItem(item)) => t.foreign_items.push(item),
+                    Err(e) => {
+                        let ref k = t.ast_context.c_decls.get(&decl_id).map(|x| &x.kind);
+                        let msg = format!("Failed translating declaration due to error: {}, kind: {:?}", e, k);
+                        t.translate_failure(&msg)
+                    },
+                },
+            }
+        }
+
         // Add the main entry point
         if let Some(main_id) = t.ast_context.c_main {
             match t.convert_main(main_id) {
                 Ok(item) => t.items.borrow_mut().push(item),
                 Err(e) => {
                     let msg = format!("Failed translating main declaration due to error: {}", e);
-                    translate_failure(&t.tcfg, &msg)
+                    t.translate_failure(&msg)
                 }
             }
         };
 
+        // Add the safe wrappers around the raw API
+        if let (Some(config), Some(functions)) = (t.tcfg.safe_wrappers.as_ref(), wrapped_functions) {
+            let wrappers = t.convert_safe_wrappers(config, &functions);
+            t.items.borrow_mut().push(wrappers);
+        }
+
+        // Report how much of the control flow came out structured
+        if t.tcfg.split_irreducible.is_some() {
+            let stats = *t.structuring_stats.borrow();
+            t.diagnostics.borrow_mut().push(format!(
+                "Structured {} of {} relooped function bodies without `current_block`",
+                stats.structured, stats.relooped,
+            ));
+        }
+
         // Initialize global statics when necessary
         if !t.sectioned_static_initializers.borrow().is_empty() {
             let (initializer_fn, initializer_static) = t.generate_global_static_init();
@@ -401,56 +725,259 @@ pub fn translate(ast_context: TypedAstContext, tcfg: TranslationConfig) -> Strin
             t.use_feature("used");
         }
 
-        // pass all converted items to the Rust pretty printer
-        to_string(|s| {
+        if let Some(msg) = t.failure.replace(None) {
+            return Err(msg)
+        }
+        Ok(finish(t, function_items))
+    })
+}
 
-            print_header(s,&t)?;
+/// Pretty-print a translation
+fn print_translation(t: Translation, function_items: Vec<(usize, OutputItem)>) -> Translated {
+    let features = t.features_required();
+    let mut diagnostics = t.diagnostics.replace(vec![]);
+
+    // Take what we still need once the printer has consumed the translation
+    let source_locations = t.source_locations.replace(SourceLocations::new());
+    let source_map_file = t.tcfg.source_map.clone();
+    let c_files = t.ast_context.c_files.clone();
+    let main_file = if t.tcfg.debug_line_layout { t.ast_context.main_file_id() } else { None };
+
+    // pass all converted items to the Rust pretty printer
+    let annotated = to_string_annotated(&source_locations, |s| {
+
+        print_header(s,&t)?;
+
+        // Re-order comments
+        let mut traverser = t.comment_store.into_inner().into_comment_traverser();
+
+        let foreign_items: Vec<ForeignItem> = t.foreign_items
+            .into_iter()
+            .map(|fi| traverser.traverse_foreign_item(fi))
+            .collect();
+        let items: Vec<OutputItem> = merge_function_items(t.items.into_inner(), function_items)
+            .into_iter()
+            .map(|item| match item {
+                OutputItem::Item(p_i) => OutputItem::Item(p_i.map(|i| traverser.traverse_item(i))),
+                rendered => rendered,
+            })
+            .collect();
 
-            // Re-order comments
-            let mut traverser = t.comment_store.into_inner().into_comment_traverser();
+        s.comments().get_or_insert(vec![]).extend(traverser.into_comment_store().into_comments());
 
-            let foreign_items: Vec<ForeignItem> = t.foreign_items
-                .into_iter()
-                .map(|fi| traverser.traverse_foreign_item(fi))
-                .collect();
-            let items: Vec<P<Item>> = t.items.into_inner()
-                .into_iter()
-                .map(|p_i| p_i.map(|i| traverser.traverse_item(i)))
-                .collect();
-
-            s.comments().get_or_insert(vec![]).extend(traverser.into_comment_store().into_comments());
+        if !foreign_items.is_empty() {
+            s.print_item(&mk().abi("C").foreign_items(foreign_items))?
+        }
 
-            if !foreign_items.is_empty() {
-                s.print_item(&mk().abi("C").foreign_items(foreign_items))?
+        // Add the items accumulated
+        for x in items {
+            match x {
+                OutputItem::Item(item) => s.print_item(&*item)?,
+                OutputItem::Rendered(text) => {
+                    // Print line by line, so the printer keeps track of the column it is at
+                    s.hardbreak_if_not_bol()?;
+                    for (i, line) in text.lines().enumerate() {
+                        if i > 0 {
+                            s.writer().hardbreak()?;
+                        }
+                        s.writer().word(line)?;
+                    }
+                }
             }
+        }
 
-            // Add the items accumulated
-            for x in items {
-                s.print_item(&*x)?;
+        Ok(())
+    });
+
+    let annotated = place_trailing_comments(&annotated);
+
+    // Line up the output with the main C file, so that debug info can point back into it
+    let annotated = match main_file {
+        Some(fileid) => {
+            let (laid_out, aligned, misaligned) = align_lines(&annotated, fileid);
+            if misaligned > 0 {
+                let c_file = c_files.get(&fileid).map_or("<unknown>", |f| f.as_str());
+                diagnostics.push(format!("Could not line up {} of {} statements and items with the \
+                                          lines of {}, since they come after code from later lines",
+                                         misaligned, aligned + misaligned, c_file));
             }
+            laid_out
+        }
+        None => annotated,
+    };
 
-            Ok(())
+    // Strip the source map markers out of the output
+    let (rust, source_map) = extract_source_map(&annotated, &c_files);
+    if let Some(path) = source_map_file {
+        source_map.dump_json(&path).expect("Failed to write source map");
+    }
+    Translated { rust, features, diagnostics }
+}
+
+/// An item ready to be printed: either still an AST item, or a function definition that has
+/// already been pretty-printed on a worker thread.
+enum OutputItem {
+    Item(P<Item>),
+    Rendered(String),
+}
+
+/// Interleave the function definitions translated off the main thread with the other items. The
+/// `usize` is the number of items which preceded the function when it was set aside.
+fn merge_function_items(items: Vec<P<Item>>, function_items: Vec<(usize, OutputItem)>) -> Vec<OutputItem> {
+    let mut merged = vec![];
+    let mut function_items = function_items.into_iter().peekable();
+
+    for (index, item) in items.into_iter().enumerate() {
+        while function_items.peek().map_or(false, |&(position, _)| position <= index) {
+            merged.push(function_items.next().expect("just peeked").1);
+        }
+        merged.push(OutputItem::Item(item));
+    }
+    merged.extend(function_items.map(|(_, item)| item));
+
+    merged
+}
+
+/// Everything a worker thread needs in order to translate function definitions independently of
+/// the main `Translation`. None of this contains `libsyntax` data, so it can be sent across threads
+/// (`libsyntax` interns identifiers in thread-local storage).
+#[derive(Clone)]
+struct WorkerSeed {
+    ast_context: Arc<TypedAstContext>,
+    tcfg: TranslationConfig,
+    renamer: Renamer<CDeclId>,
+    type_converter: TypeConverter,
+    tagged_unions: HashMap<CFieldId, TaggedUnion>,
+    indexed_arrays: HashSet<CDeclId>,
+    defined_functions: HashSet<String>,
+    comment_context: CommentContext,
+}
+
+/// Result of translating one function definition on a worker thread
+enum WorkerOutput {
+    /// Pretty-printed function item, along with the features it requires, relooper statistics and
+    /// the failures recorded while translating it
+    Rendered(String, Vec<&'static str>, cfg::StructuringStats, Vec<String>),
+
+    /// The function failed to translate, after recording the given failures
+    Failed(String, Vec<String>),
+
+    /// Translating the function failed the translation as a whole
+    Fatal(String),
+
+    /// The function needs to add items or static initializers to the module (e.g. it has a
+    /// function-scoped `static`), so it has to be translated on the main thread instead.
+    Sequential,
+}
+
+/// Translate the given function definitions using a pool of `tcfg.jobs` threads. The outputs are
+/// returned in the same order as `functions`.
+fn translate_functions_in_parallel(t: &Translation, functions: &[(usize, CDeclId)]) -> Vec<WorkerOutput> {
+    if functions.is_empty() {
+        return vec![];
+    }
+
+    // Workers start out from a snapshot of the state after all top-level names have been chosen
+    let seed = Arc::new(WorkerSeed {
+        ast_context: t.ast_context.clone(),
+        tcfg: t.tcfg.clone(),
+        renamer: t.renamer.borrow().clone(),
+        type_converter: t.type_converter.borrow().clone(),
+        tagged_unions: t.tagged_unions.clone(),
+        indexed_arrays: t.indexed_arrays.clone(),
+        defined_functions: t.defined_functions.clone(),
+        comment_context: t.comment_context.borrow().clone(),
+    });
+
+    let jobs: Vec<(usize, CDeclId)> = functions
+        .iter()
+        .enumerate()
+        .map(|(index, &(_, decl_id))| (index, decl_id))
+        .collect();
+    let queue = Arc::new(Mutex::new(jobs.into_iter()));
+    let (sender, receiver) = mpsc::channel();
+
+    let num_workers = t.tcfg.jobs.min(functions.len());
+    let workers: Vec<thread::JoinHandle<()>> = (0..num_workers)
+        .map(|_| {
+            let seed = seed.clone();
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || with_globals(|| {
+                let mut worker = Translation::from_seed(&seed);
+                loop {
+                    let job = queue.lock().expect("worker queue poisoned").next();
+                    let (index, decl_id) = match job {
+                        Some(job) => job,
+                        None => break,
+                    };
+
+                    worker.reset_from_seed(&seed);
+                    if sender.send((index, worker.render_function(decl_id))).is_err() {
+                        break;
+                    }
+                }
+            }))
         })
-    })
+        .collect();
+    drop(sender);
+
+    let mut outputs: Vec<Option<WorkerOutput>> = functions.iter().map(|_| None).collect();
+    for (index, output) in receiver {
+        outputs[index] = Some(output);
+    }
+
+    for worker in workers {
+        if let Err(payload) = worker.join() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    outputs
+        .into_iter()
+        .map(|output| output.expect("every function should have been translated"))
+        .collect()
 }
 
-/// Pretty-print the leading pragmas and extern crate declarations
+/// Pretty-print the leading comments, pragmas and extern crate declarations
 fn print_header(s: &mut State, t: &Translation) -> io::Result<()> {
-    if t.tcfg.emit_module {
-        s.print_item(&mk().use_item(vec!["libc"], None as Option<Ident>))?;
+    // Comments at the top of the main C file go at the top of the Rust file
+    if let Some(main) = t.ast_context.main_file_id() {
+        let lines = t.comment_context.borrow_mut().remove_file_comments(main);
+        for line in sanitize_comment_lines(lines) {
+            s.writer().word(&line)?;
+            s.writer().hardbreak()?;
+        }
+    }
+
+    print_preamble(s, &t.tcfg, t.features_required())
+}
+
+/// Pretty-print the crate attributes and `extern crate` declarations a translation using
+/// `features` needs, or just the `use` declarations when emitting a module
+pub fn print_preamble(s: &mut State, tcfg: &TranslationConfig, features: Vec<&str>) -> io::Result<()> {
+    if tcfg.emit_module {
+        // Without `std`, C types come from `cty` instead of `libc`
+        let c_types = if tcfg.no_std { "cty" } else { "libc" };
+        s.print_item(&mk().use_item(vec![c_types], None as Option<Ident>))?;
     } else {
-        let mut features = vec!["libc"];
-        features.extend(t.features.borrow().iter());
-        features.extend(t.type_converter.borrow().features_used());
+        if tcfg.no_std {
+            let no_std = mk().meta_item(vec!["no_std"], MetaItemKind::Word);
+            for attr in mk().meta_item_attr(AttrStyle::Inner, no_std).as_inner_attrs() {
+                s.print_attribute(&attr)?;
+            }
+        }
+
         let mut pragmas: Vec<(&str, Vec<&str>)> =
             vec![("allow", vec!["non_upper_case_globals", "non_camel_case_types", "non_snake_case",
                                 "dead_code", "mutable_transmutes", "unused_mut"])];
-        if t.tcfg.cross_checks {
-            features.append(&mut vec!["plugin", "custom_attribute"]);
+        if tcfg.cross_checks {
             pragmas.push(("cross_check", vec!["yes"]));
         }
 
-        pragmas.push(("feature", features));
+        if !features.is_empty() {
+            pragmas.push(("feature", features));
+        }
         for (key, mut values) in pragmas {
             values.sort();
             let value_attr_vec = values.into_iter()
@@ -467,9 +994,9 @@ fn print_header(s: &mut State, t: &Translation) -> io::Result<()> {
             }
         }
 
-        if t.tcfg.cross_checks {
+        if tcfg.cross_checks {
             let mut xcheck_plugin_args: Vec<NestedMetaItem> = vec![];
-            for config_file in &t.tcfg.cross_check_configs {
+            for config_file in &tcfg.cross_check_configs {
                 let file_lit = mk().str_lit(config_file);
                 let file_item = mk().meta_item(vec!["config_file"],file_lit.into_inner());
                 xcheck_plugin_args.push(mk().nested_meta_item(file_item));
@@ -488,9 +1015,10 @@ fn print_header(s: &mut State, t: &Translation) -> io::Result<()> {
             }
         }
 
-        // Add `extern crate libc` to the top of the file
-        s.print_item(&mk().extern_crate_item("libc", None))?;
-        if t.tcfg.cross_checks {
+        // Add `extern crate libc` (or `cty`, without `std`) to the top of the file
+        let c_types = if tcfg.no_std { "cty" } else { "libc" };
+        s.print_item(&mk().extern_crate_item(c_types, None))?;
+        if tcfg.cross_checks {
             s.print_item(&mk().single_attr("macro_use")
                 .extern_crate_item("cross_check_derive", None))?;
             s.print_item(&mk().single_attr("macro_use")
@@ -501,11 +1029,6 @@ fn print_header(s: &mut State, t: &Translation) -> io::Result<()> {
 }
 
 
-/// Convert a boolean expression to a c_int
-fn bool_to_int(val: P<Expr>) -> P<Expr> {
-    mk().cast_expr(val, mk().path_ty(vec!["libc","c_int"]))
-}
-
 
 /// This represents all of the ways a C expression can be used in a C program. Making this
 /// distinction is important for:
@@ -548,13 +1071,16 @@ impl Translation {
         let mut type_converter = TypeConverter::new();
 
         if tcfg.translate_valist { type_converter.translate_valist = true }
+        if tcfg.target_stable { type_converter.target_stable = true }
+        if tcfg.no_std { type_converter.no_std = true }
+        if tcfg.fixed_width_ints { type_converter.fixed_width_ints = true }
 
         Translation {
             features: RefCell::new(HashSet::new()),
             items: RefCell::new(vec![]),
             foreign_items: vec![],
             type_converter: RefCell::new(type_converter),
-            ast_context,
+            ast_context: Arc::new(ast_context),
             tcfg,
             renamer: RefCell::new(Renamer::new(&[
                 // Keywords currently in use
@@ -576,10 +1102,106 @@ impl Translation {
             ])),
             loops: LoopContext::new(),
             zero_inits: RefCell::new(HashMap::new()),
+            lowered_stmt_exprs: RefCell::new(HashMap::new()),
+            const_values: RefCell::new(HashMap::new()),
+            uncompilable_static_nodes: RefCell::new(HashMap::new()),
+            feature_cfg_depth: RefCell::new(0),
+            tagged_unions: HashMap::new(),
+            indexed_arrays: HashSet::new(),
+            defined_functions: HashSet::new(),
             comment_context,
             comment_store: RefCell::new(CommentStore::new()),
             sectioned_static_initializers: RefCell::new(Vec::new()),
+            diagnostics: RefCell::new(vec![]),
+            defer_failures: false,
+            failure: RefCell::new(None),
+            source_locations: RefCell::new(SourceLocations::new()),
+            structuring_stats: RefCell::new(Default::default()),
+        }
+    }
+
+    /// Set up a translation on a worker thread
+    fn from_seed(seed: &WorkerSeed) -> Translation {
+        Translation {
+            features: RefCell::new(HashSet::new()),
+            items: RefCell::new(vec![]),
+            foreign_items: vec![],
+            type_converter: RefCell::new(seed.type_converter.clone()),
+            ast_context: seed.ast_context.clone(),
+            tcfg: seed.tcfg.clone(),
+            renamer: RefCell::new(seed.renamer.clone()),
+            loops: LoopContext::new(),
+            zero_inits: RefCell::new(HashMap::new()),
+            lowered_stmt_exprs: RefCell::new(HashMap::new()),
+            const_values: RefCell::new(HashMap::new()),
+            uncompilable_static_nodes: RefCell::new(HashMap::new()),
+            feature_cfg_depth: RefCell::new(0),
+            tagged_unions: seed.tagged_unions.clone(),
+            indexed_arrays: seed.indexed_arrays.clone(),
+            defined_functions: seed.defined_functions.clone(),
+            comment_context: RefCell::new(seed.comment_context.clone()),
+            comment_store: RefCell::new(CommentStore::new()),
+            sectioned_static_initializers: RefCell::new(Vec::new()),
+            diagnostics: RefCell::new(vec![]),
+            defer_failures: true,
+            failure: RefCell::new(None),
+            source_locations: RefCell::new(SourceLocations::new()),
+            structuring_stats: RefCell::new(Default::default()),
+        }
+    }
+
+    /// Restore the per-function state of a worker so that the translation of a function does not
+    /// depend on which other functions the same worker happened to translate before it.
+    fn reset_from_seed(&mut self, seed: &WorkerSeed) {
+        self.features = RefCell::new(HashSet::new());
+        self.items = RefCell::new(vec![]);
+        self.type_converter = RefCell::new(seed.type_converter.clone());
+        self.renamer = RefCell::new(seed.renamer.clone());
+        self.loops = LoopContext::new();
+        self.zero_inits = RefCell::new(HashMap::new());
+        self.lowered_stmt_exprs = RefCell::new(HashMap::new());
+        self.const_values = RefCell::new(HashMap::new());
+        self.uncompilable_static_nodes = RefCell::new(HashMap::new());
+        self.feature_cfg_depth = RefCell::new(0);
+        self.comment_context = RefCell::new(seed.comment_context.clone());
+        self.comment_store = RefCell::new(CommentStore::new());
+        self.sectioned_static_initializers = RefCell::new(Vec::new());
+        self.diagnostics = RefCell::new(vec![]);
+        self.failure = RefCell::new(None);
+        self.source_locations = RefCell::new(SourceLocations::new());
+        self.structuring_stats = RefCell::new(Default::default());
+    }
+
+    /// Translate and pretty-print a single function definition (on a worker thread).
+    fn render_function(&self, decl_id: CDeclId) -> WorkerOutput {
+        let converted = self.convert_decl(true, decl_id);
+        if let Some(msg) = self.failure.replace(None) {
+            return WorkerOutput::Fatal(msg)
+        }
+        let item = match converted {
+            Ok(ConvertedDecl::Item(item)) => item,
+            Ok(ConvertedDecl::ForeignItem(_)) => return WorkerOutput::Sequential,
+            Err(e) => return WorkerOutput::Failed(e, self.diagnostics.replace(vec![])),
+        };
+
+        if !self.items.borrow().is_empty() || !self.sectioned_static_initializers.borrow().is_empty() {
+            return WorkerOutput::Sequential
         }
+
+        let mut traverser = self.comment_store.replace(CommentStore::new()).into_comment_traverser();
+        let item = item.map(|i| traverser.traverse_item(i));
+        let comments = traverser.into_comment_store().into_comments();
+
+        let text = to_string_annotated(&self.source_locations.borrow(), |s| {
+            s.comments().get_or_insert(vec![]).extend(comments);
+            s.print_item(&*item)
+        });
+
+        let mut features: Vec<&'static str> = self.features.borrow().iter().cloned().collect();
+        features.extend(self.type_converter.borrow().features_used());
+
+        let diagnostics = self.diagnostics.replace(vec![]);
+        WorkerOutput::Rendered(text, features, *self.structuring_stats.borrow(), diagnostics)
     }
 
     /// Called when translation makes use of a language feature that will require a feature-gate.
@@ -587,6 +1209,117 @@ impl Translation {
         self.features.borrow_mut().insert(feature);
     }
 
+    /// Called when translation needs a language feature. When targeting stable Rust this fails
+    /// instead, naming the feature.
+    fn require_feature(&self, feature: &'static str) -> Result<(), String> {
+        if self.tcfg.target_stable {
+            Err(format!("Translating this requires the nightly feature `{}`", feature))
+        } else {
+            self.use_feature(feature);
+            Ok(())
+        }
+    }
+
+    /// The crate paths into the standard library start from: `core` when emitting `no_std` code
+    fn std_crate(&self) -> &'static str {
+        self.type_converter.borrow().std_crate()
+    }
+
+    /// A C type like `c_int`, see `TypeConverter::c_type`
+    fn c_type(&self, name: &str) -> P<Ty> {
+        self.type_converter.borrow().c_type(name)
+    }
+
+    /// Fails when emitting `no_std` code, for constructs that can't do without `std`
+    fn require_std(&self, what: &str) -> Result<(), String> {
+        if self.tcfg.no_std {
+            Err(format!("{} needs `std`, which is unavailable with --no-std", what))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn transmute_expr(&self, source_ty: P<Ty>, target_ty: P<Ty>, expr: P<Expr>) -> P<Expr> {
+        let type_args = vec![source_ty, target_ty];
+        let path = vec![
+            mk().path_segment(""),
+            mk().path_segment(self.std_crate()),
+            mk().path_segment("mem"),
+            mk().path_segment_with_params("transmute",
+                                          mk().angle_bracketed_param_types(type_args)),
+        ];
+        mk().call_expr(mk().path_expr(path), vec![expr])
+    }
+
+    /// Convert a boolean expression to a C `int`
+    fn bool_to_int(&self, val: P<Expr>) -> P<Expr> {
+        mk().cast_expr(val, self.type_converter.borrow().int_type(&self.ast_context))
+    }
+
+    /// All of the language features the output needs, sorted. On stable, `libc` comes from
+    /// crates.io instead of the sysroot.
+    fn features_required(&self) -> Vec<&'static str> {
+        let mut features = if self.tcfg.target_stable || self.tcfg.no_std { vec![] } else { vec!["libc"] };
+        features.extend(self.features.borrow().iter());
+        features.extend(self.type_converter.borrow().features_used());
+        if self.tcfg.cross_checks {
+            features.append(&mut vec!["plugin", "custom_attribute"]);
+        }
+        features.sort();
+        features.dedup();
+        features
+    }
+
+    /// Record that part of the translation failed, failing the whole translation with
+    /// `fail_on_error`
+    fn translate_failure(&self, msg: &str) {
+        self.diagnostics.borrow_mut().push(msg.to_string());
+        if self.tcfg.fail_on_error && !self.defer_failures {
+            self.fail_translation(msg)
+        }
+    }
+
+    /// Fail the translation as a whole, which is reported once it is done with the first reason
+    /// given
+    fn fail_translation(&self, msg: &str) {
+        let mut failure = self.failure.borrow_mut();
+        if failure.is_none() {
+            *failure = Some(msg.to_string());
+        }
+    }
+
+    /// When emitting a source map or laying out lines for debugging, get a `NodeId` linking a node
+    /// to the C location it was translated from. Nodes that already link somewhere keep their more
+    /// precise location.
+    fn source_node_id(&self, kind: &'static str, loc: Option<SrcLoc>, id: NodeId) -> NodeId {
+        let tracking = self.tcfg.source_map.is_some() || self.tcfg.debug_line_layout;
+        match loc {
+            Some(loc) if tracking && id == DUMMY_NODE_ID =>
+                self.source_locations.borrow_mut().node_id(kind, loc),
+            _ => id,
+        }
+    }
+
+    fn located_expr(&self, kind: &'static str, loc: Option<SrcLoc>, e: P<Expr>) -> P<Expr> {
+        e.map(|mut e| { e.id = self.source_node_id(kind, loc, e.id); e })
+    }
+
+    /// Statements are linked to C through the expression they contain, since the pretty-printer
+    /// has no annotation hooks for statements themselves.
+    fn located_stmt(&self, loc: Option<SrcLoc>, mut stmt: Stmt) -> Stmt {
+        stmt.node = match stmt.node {
+            StmtKind::Expr(e) => StmtKind::Expr(self.located_expr("stmt", loc, e)),
+            StmtKind::Semi(e) => StmtKind::Semi(self.located_expr("stmt", loc, e)),
+            StmtKind::Local(local) => StmtKind::Local(local.map(|mut l| {
+                // The initializer is usually already tagged as an expression, so tag the pattern
+                l.pat = l.pat.map(|mut p| { p.id = self.source_node_id("stmt", loc, p.id); p });
+                l
+            })),
+            node => node,
+        };
+        stmt
+    }
+
     // This node should _never_ show up in the final generated code. This is an easy way to notice
     // if it does.
     pub fn panic(&self, msg: &str) -> P<Expr> {
@@ -604,63 +1337,204 @@ impl Translation {
     }
 
     fn static_initializer_is_uncompilable(&self, expr_id: Option<CExprId>) -> bool {
-        use c_ast::UnOp::{AddressOf, Negate};
-        use c_ast::CastKind::PointerToIntegral;
-        use c_ast::BinOp::{Add, Subtract, Multiply, Divide, Modulus};
-
         let expr_id = match expr_id {
             Some(expr_id) => expr_id,
             None => return false,
         };
 
-        let iter = DFExpr::new(&self.ast_context, expr_id.into());
+        let mut iter = DFExpr::new(&self.ast_context, expr_id.into());
 
-        for i in iter {
+        while let Some(i) = iter.next() {
             let expr_id = match i {
                 SomeId::Expr(expr_id) => expr_id,
                 _ => unreachable!("Found static initializer type other than expr"),
             };
 
-            match self.ast_context[expr_id].kind {
-                // Technically we're being conservative here, but it's only the most
-                // contrived array indexing initializers that would be accepted
-                CExprKind::ArraySubscript(_, _, _) => return true,
+            // Evaluated subexpressions are emitted as constants, so their contents don't matter
+            if self.evaluated_static_expr(expr_id).is_some() {
+                iter.prune(immediate_children(&self.ast_context, i).len());
+            } else if self.is_uncompilable_static_expr(expr_id) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Whether the direct translation of an expression can't appear in a static initializer
+    fn is_uncompilable_static_expr(&self, expr_id: CExprId) -> bool {
+        use c_ast::UnOp::{AddressOf, Negate};
+        use c_ast::CastKind::PointerToIntegral;
+        use c_ast::BinOp::{Add, Subtract, Multiply, Divide, Modulus};
+
+        match self.ast_context[expr_id].kind {
+            // Technically we're being conservative here, but it's only the most
+            // contrived array indexing initializers that would be accepted
+            CExprKind::ArraySubscript(_, _, _) => true,
+
+            CExprKind::Conditional(_, _, _, _) => true,
+            CExprKind::Unary(typ, Negate, _) =>
+                self.uses_arith_methods(&self.ast_context.resolve_type(typ.ctype).kind),
+            CExprKind::ImplicitCast(_, _, PointerToIntegral, _) => true,
+            CExprKind::Binary(typ, op, _, _, _, _) => {
+                let problematic_op = match op {
+                    Add | Subtract | Multiply | Divide | Modulus => true,
+                    _ => false,
+                };
 
-                CExprKind::Conditional(_, _, _, _) => return true,
-                CExprKind::Unary(typ, Negate, _) => {
-                    if self.ast_context.resolve_type(typ.ctype).kind.is_unsigned_integral_type() {
+                let k = &self.ast_context.resolve_type(typ.ctype).kind;
+                problematic_op && (self.uses_arith_methods(k) || k.is_pointer())
+            },
+            CExprKind::Unary(_, AddressOf, expr_id) => {
+                if let CExprKind::Member(_, expr_id, _, _) = self.ast_context[expr_id].kind {
+                    if let CExprKind::DeclRef(_, _) = self.ast_context[expr_id].kind {
                         return true;
                     }
-                },
-                CExprKind::ImplicitCast(_, _, PointerToIntegral, _) => return true,
-                CExprKind::Binary(typ, op, _, _, _, _) => {
-                    let problematic_op = match op {
-                        Add | Subtract | Multiply | Divide | Modulus => true,
-                        _ => false,
+                }
+                false
+            },
+            _ => false,
+        }
+    }
+
+    /// Whether a static initializer node, or any node below it, can't be translated directly.
+    /// This is remembered for every node, since it is asked of each node of an initializer.
+    fn has_uncompilable_static_expr(&self, id: SomeId) -> bool {
+        if let Some(&uncompilable) = self.uncompilable_static_nodes.borrow().get(&id) {
+            return uncompilable
+        }
+        let uncompilable = match id {
+            SomeId::Expr(expr_id) if self.is_uncompilable_static_expr(expr_id) => true,
+            _ => immediate_children(&self.ast_context, id)
+                .into_iter()
+                .any(|child| self.has_uncompilable_static_expr(child)),
+        };
+        self.uncompilable_static_nodes.borrow_mut().insert(id, uncompilable);
+        uncompilable
+    }
+
+    /// Translates a static initializer subexpression whose direct translation can't appear in a
+    /// static initializer into its value, computed at translation time. Returns `None` if the
+    /// direct translation is fine or if the value can't be computed.
+    fn evaluated_static_expr(&self, expr_id: CExprId) -> Option<P<Expr>> {
+        if !self.has_uncompilable_static_expr(expr_id.into()) {
+            return None
+        }
+
+        let ty = self.ast_context[expr_id].kind.get_type()?;
+        let evaluator = ConstEvaluator::with_values(&self.ast_context, self.const_values.replace(HashMap::new()));
+        let value = evaluator.eval(expr_id);
+        self.const_values.replace(evaluator.into_values());
+        self.const_value_expr(ty, value?)
+    }
+
+    /// Expression for a value of C type `ty` that is usable in a static initializer
+    fn const_value_expr(&self, ty: CTypeId, value: ConstValue) -> Option<P<Expr>> {
+        match value {
+            ConstValue::Int(i) => {
+                if let CTypeKind::Bool = self.ast_context.resolve_type(ty).kind {
+                    return Some(mk().lit_expr(mk().bool_lit(i != 0)))
+                }
+                let lit = mk().lit_expr(mk().int_lit(i.abs() as u128, LitIntType::Unsuffixed));
+                let lit = if i < 0 { mk().unary_expr(ast::UnOp::Neg, lit) } else { lit };
+                Some(mk().cast_expr(lit, self.convert_type(ty).ok()?))
+            }
+            ConstValue::Float(f) => {
+                if !f.is_finite() {
+                    return None
+                }
+                let float_ty = match self.ast_context.resolve_type(ty).kind {
+                    CTypeKind::Float => FloatTy::F32,
+                    _ => FloatTy::F64,
+                };
+                let mut bytes: Vec<u8> = vec![];
+                dtoa::write(&mut bytes, f.abs()).unwrap();
+                let lit = mk().lit_expr(mk().float_lit(String::from_utf8(bytes).unwrap(), float_ty));
+                Some(if f.is_sign_negative() { mk().unary_expr(ast::UnOp::Neg, lit) } else { lit })
+            }
+            ConstValue::Null => self.null_ptr(ty, true).ok(),
+            ConstValue::Address(address) => {
+                if self.ast_context.is_function_pointer(ty) {
+                    return None
+                }
+                // As for `&` in statics, take a shared reference and cast it to the pointer type
+                let (place, place_ty) = self.const_place_expr(&address)?;
+                let pointer = mk().cast_expr(mk().addr_of_expr(place), mk().ptr_ty(place_ty));
+                Some(mk().cast_expr(pointer, self.convert_type(ty).ok()?))
+            }
+        }
+    }
+
+    /// The place expression and Rust type of the object at a constant address
+    fn const_place_expr(&self, address: &ConstAddress) -> Option<(P<Expr>, P<Ty>)> {
+        let mut place = match address.base {
+            AddressBase::Variable(decl_id) => {
+                let name = self.renamer.borrow().get(&decl_id)?;
+
+                // A pointer to the start of an array points to the array itself
+                if address.path.iter().all(|p| match *p { Projection::Index(0, _) => true, _ => false }) {
+                    let ty = match self.ast_context[decl_id].kind {
+                        CDeclKind::Variable { typ, .. } => typ.ctype,
+                        _ => return None,
                     };
+                    return Some((mk().path_expr(vec![name]), self.convert_type(ty).ok()?))
+                }
 
-                    if problematic_op {
-                        let k = &self.ast_context.resolve_type(typ.ctype).kind;
-                        if k.is_unsigned_integral_type() || k.is_pointer() {
-                            return true;
-                        }
+                // Older Rust compilers reject references into other statics (E0494)
+                if !self.tcfg.static_interior_refs {
+                    return None
+                }
+                mk().path_expr(vec![name])
+            }
+            AddressBase::StringLiteral(expr_id) => match self.ast_context[expr_id].kind {
+                CExprKind::Literal(ty, CLiteral::String(ref bytes, 1)) => {
+                    let mut bytes = bytes.to_owned();
+                    match self.ast_context.resolve_type(ty.ctype).kind {
+                        CTypeKind::ConstantArray(_, size) => bytes.resize(size, 0),
+                        _ => bytes.push(0),
                     }
-                },
-                CExprKind::Unary(_, AddressOf, expr_id) => {
-                    if let CExprKind::Member(_, expr_id, _, _) = self.ast_context[expr_id].kind {
-                        if let CExprKind::DeclRef(_, _) = self.ast_context[expr_id].kind {
-                            return true;
+                    let len = bytes.len();
+                    let place = mk().unary_expr(ast::UnOp::Deref, mk().lit_expr(mk().bytestr_lit(bytes)));
+
+                    // The bytes of string literals are `u8`s rather than `c_char`s
+                    if address.path.is_empty() {
+                        let len = mk().lit_expr(mk().int_lit(len as u128, LitIntType::Unsuffixed));
+                        return Some((place, mk().array_ty(mk().path_ty(vec!["u8"]), len)))
+                    }
+                    match (address.path.len(), address.path[0]) {
+                        (1, Projection::Index(index, _)) if index >= 0 && index < len as i128 => {
+                            let index = mk().lit_expr(mk().int_lit(index as u128, LitIntType::Unsuffixed));
+                            return Some((mk().index_expr(place, index), mk().path_ty(vec!["u8"])))
                         }
+                        _ => return None,
                     }
-                },
-                _ => {},
-            }
+                }
+                _ => return None,
+            },
+        };
+
+        for projection in &address.path {
+            place = match *projection {
+                Projection::Field(record_id, field_id) => {
+                    let name = self.type_converter.borrow().resolve_field_name(Some(record_id), field_id)?;
+                    mk().field_expr(place, name)
+                }
+                // Only elements of the array can be named, not the position one past its end
+                Projection::Index(index, Some(len)) if index >= 0 && index < len as i128 => {
+                    let index = mk().lit_expr(mk().int_lit(index as u128, LitIntType::Unsuffixed));
+                    mk().index_expr(place, index)
+                }
+                Projection::Index(..) => return None,
+            };
         }
 
-        false
+        Some((place, self.convert_type(address.ty).ok()?))
     }
 
     fn add_static_initializer_to_section(&self, name: &str, typ: CQualTypeId, init: &mut P<Expr>) -> Result<(), String> {
+        // `run_static_initializers` is kept alive by a `#[used]` static
+        self.require_feature("used")?;
+
         let root_lhs_expr = mk().path_expr(vec![name]);
         let assign_expr = {
             let block = match &init.node {
@@ -710,6 +1584,8 @@ impl Translation {
     }
 
     fn convert_main(&self, main_id: CDeclId) -> Result<P<Item>, String> {
+        self.require_std("A Rust entry point")?;
+
         if let CDeclKind::Function { ref parameters, typ, .. } = self.ast_context.index(main_id).kind {
 
             let ret: CTypeKind = match self.ast_context.resolve_type(typ).kind {
@@ -727,9 +1603,11 @@ impl Translation {
                 .get(&main_id).expect("Could not find main function in renamer");
             let main_fn = mk().path_expr(vec![main_fn_name]);
 
-            let exit_fn = mk().path_expr(vec!["", "std", "process", "exit"]);
-            let args_fn = mk().path_expr(vec!["", "std", "env", "args"]);
-            let vars_fn = mk().path_expr(vec!["", "std", "env", "vars"]);
+            // Exit through libc, like returning from a C `main` does, so `atexit` handlers run
+            let exit_fn = mk().path_expr(vec!["libc", "exit"]);
+            let args_fn = mk().path_expr(vec!["", "std", "env", "args_os"]);
+            let vars_fn = mk().path_expr(vec!["", "std", "env", "vars_os"]);
+            let into_vec_fn = vec!["", "std", "os", "unix", "ffi", "OsStringExt", "into_vec"];
 
             let no_args: Vec<P<Expr>> = vec![];
 
@@ -747,7 +1625,7 @@ impl Translation {
                         "Vec",
                         mk().angle_bracketed_param_types(
                             vec![mk().mutbl().ptr_ty(
-                                mk().path_ty(vec!["libc","c_char"])
+                                self.c_type("c_char")
                             )]
                         ),
                     )])),
@@ -768,7 +1646,10 @@ impl Translation {
                                     mk().method_call_expr(
                                         mk().call_expr(
                                             mk().path_expr(vec!["","std","ffi","CString","new"]),
-                                            vec![mk().path_expr(vec!["arg"])],
+                                            vec![mk().call_expr(
+                                                mk().path_expr(into_vec_fn.clone()),
+                                                vec![mk().path_expr(vec!["arg"])],
+                                            )],
                                         ),
                                         "expect",
                                         vec![mk().lit_expr(
@@ -825,7 +1706,7 @@ impl Translation {
                         "Vec",
                         mk().angle_bracketed_param_types(
                             vec![mk().mutbl().ptr_ty(
-                                mk().path_ty(vec!["libc","c_char"])
+                                self.c_type("c_char")
                             )]
                         ),
                     )])),
@@ -838,20 +1719,31 @@ impl Translation {
                     mk().tuple_pat(vec![mk().ident_pat("var_name"), mk().ident_pat("var_value")]),
                     mk().call_expr(vars_fn, vec![] as Vec<P<Expr>>),
                     mk().block(vec![
+                        // The raw bytes of `NAME=value`
                         mk().local_stmt(P(mk().local(
-                            mk().ident_pat("var"),
-                            Some(mk().path_ty(vec!["String"])),
-                            Some(mk().mac_expr(mk().mac(
-                                vec!["format"],
-                                vec![
-                                    Token::interpolated(Nonterminal::NtExpr(mk().lit_expr(mk().str_lit("{}={}")))),
-                                    Token::Comma,
-                                    Token::from_ast_ident(mk().ident("var_name")),
-                                    Token::Comma,
-                                    Token::from_ast_ident(mk().ident("var_value")),
-                                ].into_iter().collect::<TokenStream>(),
-                            )))
+                            mk().mutbl().ident_pat("var"),
+                            Some(mk().path_ty(vec![mk().path_segment_with_params(
+                                "Vec",
+                                mk().angle_bracketed_param_types(vec![mk().path_ty(vec!["u8"])]),
+                            )])),
+                            Some(mk().call_expr(
+                                mk().path_expr(into_vec_fn.clone()),
+                                vec![mk().path_expr(vec!["var_name"])],
+                            )),
                         ))),
+                        mk().semi_stmt(mk().method_call_expr(
+                            mk().path_expr(vec!["var"]),
+                            "push",
+                            vec![mk().lit_expr(mk().byte_lit(b'='))],
+                        )),
+                        mk().semi_stmt(mk().method_call_expr(
+                            mk().path_expr(vec!["var"]),
+                            "extend",
+                            vec![mk().call_expr(
+                                mk().path_expr(into_vec_fn.clone()),
+                                vec![mk().path_expr(vec!["var_value"])],
+                            )],
+                        )),
                         mk().semi_stmt(mk().method_call_expr(
                             mk().path_expr(vec!["vars"]),
                             "push",
@@ -901,37 +1793,501 @@ impl Translation {
                 Err(format!("Main function should have 0, 2, or 3 parameters, not {}.", n))?;
             };
 
-            if let CTypeKind::Void = ret {
-                let call_main = mk().call_expr(main_fn, main_args);
-                let unsafe_block = mk().unsafe_().block(vec![mk().expr_stmt(call_main)]);
+            if let CTypeKind::Void = ret {
+                let call_main = mk().call_expr(main_fn, main_args);
+                let unsafe_block = mk().unsafe_().block(vec![mk().expr_stmt(call_main)]);
+
+                stmts.push(mk().expr_stmt(mk().block_expr(unsafe_block)));
+
+                let exit_arg = mk().lit_expr(mk().int_lit(0,""));
+                let call_exit = mk().call_expr(exit_fn, vec![exit_arg]);
+                let unsafe_block = mk().unsafe_().block(vec![mk().semi_stmt(call_exit)]);
+
+                stmts.push(mk().expr_stmt(mk().block_expr(unsafe_block)));
+            } else {
+                let call_main = mk().cast_expr(
+                    mk().call_expr(main_fn, main_args),
+                    self.c_type("c_int"),
+                );
+
+                let call_exit = mk().call_expr(exit_fn, vec![call_main]);
+                let unsafe_block = mk().unsafe_().block(vec![mk().expr_stmt(call_exit)]);
+
+                stmts.push(mk().expr_stmt(mk().block_expr(unsafe_block)));
+            };
+
+            let block = mk().block(stmts);
+            let mut main_attributes = self.mk_cross_check(mk(), vec!["none"]);
+            // The raw bytes of the arguments and environment only come out of an `OsString` on unix
+            if n >= 2 {
+                main_attributes = main_attributes.call_attr("cfg", vec!["unix"]);
+                self.diagnostics.borrow_mut().push(format!(
+                    "The Rust entry point passes the arguments to `main` as bytes, so it is only built for unix targets"));
+            }
+            Ok(main_attributes.pub_().fn_item("main", decl, block))
+        } else {
+            Err(format!("Cannot translate non-function main entry point"))
+        }
+    }
+
+    /// Names of the functions with a declaration in a file whose path ends with `header`
+    fn functions_declared_in(&self, header: &str) -> HashSet<String> {
+        let header_files: HashSet<u64> = self.ast_context.c_files
+            .iter()
+            .filter(|&(_, path)| path.ends_with(header))
+            .map(|(&fileid, _)| fileid)
+            .collect();
+
+        self.ast_context.c_decls
+            .values()
+            .filter_map(|decl| match (&decl.kind, decl.loc) {
+                (&CDeclKind::Function { ref name, .. }, Some(loc))
+                    if header_files.contains(&loc.fileid) => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Generate a module of wrappers around the public functions defined among `functions`.
+    /// Functions which cannot be wrapped are reported and left out.
+    fn convert_safe_wrappers(&self, config: &SafeWrapperConfig, functions: &HashSet<String>) -> P<Item> {
+        let mut items = vec![mk().use_glob_item(vec!["super"])];
+        for &decl_id in &self.ast_context.c_decls_top {
+            if let CDeclKind::Function { is_extern: true, body: Some(_), ref name, ref parameters, typ, .. } =
+                self.ast_context.index(decl_id).kind {
+                if !functions.contains(name) || self.ast_context.c_main == Some(decl_id) {
+                    continue
+                }
+
+                match self.convert_safe_wrapper(decl_id, name, parameters, typ, &config.annotations(name)) {
+                    Ok(item) => items.push(item),
+                    Err(e) => {
+                        let msg = format!("Failed generating safe wrapper for {} due to error: {}", name, e);
+                        self.translate_failure(&msg)
+                    }
+                }
+            }
+        }
+
+        mk().pub_().module(config.module.as_str(), items)
+    }
+
+    /// Wrap a single function. Parameters and return values are converted as their annotations
+    /// request; any raw pointer parameter left over makes the wrapper `unsafe`.
+    fn convert_safe_wrapper(
+        &self,
+        decl_id: CDeclId,
+        name: &str,
+        parameters: &[CDeclId],
+        typ: CTypeId,
+        annotations: &FunctionAnnotations,
+    ) -> Result<P<Item>, String> {
+        let ret = match self.ast_context.resolve_type(typ).kind {
+            CTypeKind::Function(_, _, true, _) => return Err(format!("variadic functions cannot be wrapped")),
+            CTypeKind::Function(ret, _, _, _) => ret,
+            ref k => return Err(format!("Type of function {:?} was not a function type, got {:?}", decl_id, k)),
+        };
+
+        let raw_name = self.renamer.borrow().get(&decl_id).expect("Functions should already be renamed");
+        let raw_fn = mk().path_expr(vec!["super".to_owned(), raw_name.clone()]);
+
+        let mut params: Vec<(CDeclId, &str, CQualTypeId)> = vec![];
+        for &param_id in parameters {
+            match self.ast_context.index(param_id).kind {
+                CDeclKind::Variable { ref ident, typ, .. } => params.push((param_id, ident.as_str(), typ)),
+                _ => return Err(format!("Parameter is not variable declaration")),
+            }
+        }
+        for param in annotations.params.keys() {
+            if !params.iter().any(|&(_, ident, _)| ident == param) {
+                return Err(format!("no parameter named {}", param))
+            }
+        }
+
+        // Length parameters are computed from the slices they describe, in parameter order
+        let mut lengths: HashMap<&str, Vec<&str>> = HashMap::new();
+        for &(_, param, _) in &params {
+            if let Some(&ParamAnnotation::Slice(ref len)) = annotations.params.get(param) {
+                if !params.iter().any(|&(_, ident, _)| ident == len) {
+                    return Err(format!("no length parameter named {} for {}", len, param))
+                }
+                lengths.entry(len.as_str()).or_insert(vec![]).push(param);
+            }
+        }
+
+        self.with_scope(|| {
+            let mut names: HashMap<&str, String> = HashMap::new();
+            for (i, &(param_id, ident, _)) in params.iter().enumerate() {
+                let ident = if ident.is_empty() { format!("arg{}", i) } else { ident.to_owned() };
+                let new_name = self.renamer.borrow_mut().insert(param_id, &self.local_name(&ident))
+                    .expect(&format!("Failed to insert argument '{}' while wrapping '{}'", ident, name));
+                names.insert(params[i].1, new_name);
+            }
+
+            let mut is_unsafe = false;
+            let mut args: Vec<Arg> = vec![];
+            let mut call_args: Vec<P<Expr>> = vec![];
+            let mut length_checks: Vec<Stmt> = vec![];
+            for &(_, ident, typ) in &params {
+                let ty = self.convert_type(typ.ctype)?;
+                let var = mk().ident_expr(&names[ident]);
+
+                if let Some(slices) = lengths.get(ident) {
+                    let len = |slice: &str| mk().method_call_expr(mk().ident_expr(&names[slice]), "len", vec![] as Vec<P<Expr>>);
+
+                    // Slices sharing a length parameter have to be as long as each other
+                    for &other in &slices[1..] {
+                        let message = format!("{} and {} should have the same length, since {} is the length of both",
+                                              names[slices[0]], names[other], ident);
+                        let message = vec![
+                            Token::interpolated(Nonterminal::NtExpr(mk().lit_expr(mk().str_lit(&message)))),
+                        ].into_iter().collect::<TokenStream>();
+                        let mismatch = mk().mac_expr(mk().mac(vec!["panic"], message));
+                        length_checks.push(mk().expr_stmt(mk().ifte_expr(
+                            mk().binary_expr(BinOpKind::Ne, len(slices[0]), len(other)),
+                            mk().block(vec![mk().expr_stmt(mismatch)]),
+                            None as Option<P<Expr>>,
+                        )));
+                    }
+
+                    call_args.push(mk().cast_expr(len(slices[0]), ty));
+                    continue
+                }
+
+                let pointee = match self.ast_context.resolve_type(typ.ctype).kind {
+                    CTypeKind::Pointer(pointee) => Some(pointee),
+                    _ => None,
+                };
+                match (annotations.params.get(ident), pointee) {
+                    (None, pointee) => {
+                        is_unsafe |= pointee.is_some();
+                        args.push(mk().arg(ty, mk().ident_pat(&names[ident])));
+                        call_args.push(var);
+                    }
+                    // `core` has no `CStr`, so without `std` strings stay raw pointers
+                    (Some(&ParamAnnotation::CStr), pointee) if self.tcfg.no_std => {
+                        is_unsafe |= pointee.is_some();
+                        args.push(mk().arg(ty, mk().ident_pat(&names[ident])));
+                        call_args.push(var);
+                    }
+                    (Some(&ParamAnnotation::CStr), Some(_)) => {
+                        let cstr_ty = mk().ref_ty(mk().path_ty(vec!["", self.std_crate(), "ffi", "CStr"]));
+                        args.push(mk().arg(cstr_ty, mk().ident_pat(&names[ident])));
+                        let ptr = mk().method_call_expr(var, "as_ptr", vec![] as Vec<P<Expr>>);
+                        call_args.push(mk().cast_expr(ptr, ty));
+                    }
+                    (Some(&ParamAnnotation::Slice(_)), Some(pointee)) => {
+                        // Untyped buffers are exposed as bytes
+                        let elem_ty = match self.ast_context.resolve_type(pointee.ctype).kind {
+                            CTypeKind::Void => mk().path_ty(vec!["u8"]),
+                            _ => self.convert_type(pointee.ctype)?,
+                        };
+                        let (slice_ty, as_ptr) = if pointee.qualifiers.is_const {
+                            (mk().ref_ty(mk().slice_ty(elem_ty)), "as_ptr")
+                        } else {
+                            (mk().mutbl().ref_ty(mk().slice_ty(elem_ty)), "as_mut_ptr")
+                        };
+                        args.push(mk().arg(slice_ty, mk().ident_pat(&names[ident])));
+                        let ptr = mk().method_call_expr(var, as_ptr, vec![] as Vec<P<Expr>>);
+                        call_args.push(mk().cast_expr(ptr, ty));
+                    }
+                    (Some(_), None) => return Err(format!("parameter {} is not a pointer", ident)),
+                }
+            }
+
+            let call = mk().call_expr(raw_fn, call_args);
+            let call = if is_unsafe {
+                call
+            } else {
+                mk().block_expr(mk().unsafe_().block(vec![mk().expr_stmt(call)]))
+            };
+
+            let ret_kind = self.ast_context.resolve_type(ret.ctype).kind.clone();
+            let ret_ty = match ret_kind {
+                CTypeKind::Void => mk().tuple_ty(vec![] as Vec<P<Ty>>),
+                _ => self.convert_type(ret.ctype)?,
+            };
+            let result_ty = |ok_ty: P<Ty>, err_ty: P<Ty>| mk().path_ty(vec![mk().path_segment_with_params(
+                "Result",
+                mk().angle_bracketed_param_types(vec![ok_ty, err_ty]),
+            )]);
+            let ret_var = || mk().ident_expr("ret");
+            let checked = |cond: P<Expr>, then: P<Expr>, otherwise: P<Expr>| vec![
+                mk().local_stmt(P(mk().local(mk().ident_pat("ret"), None as Option<P<Ty>>, Some(call.clone())))),
+                mk().expr_stmt(mk().ifte_expr(
+                    cond,
+                    mk().block(vec![mk().expr_stmt(then)]),
+                    Some(mk().block_expr(mk().block(vec![mk().expr_stmt(otherwise)]))),
+                )),
+            ];
+
+            let (wrapper_ret, stmts) = match annotations.ret {
+                ReturnAnnotation::Raw => (ret_ty, vec![mk().expr_stmt(call.clone())]),
+                ReturnAnnotation::Option if ret_kind.is_pointer() => (
+                    mk().path_ty(vec![mk().path_segment_with_params(
+                        "Option",
+                        mk().angle_bracketed_param_types(vec![ret_ty]),
+                    )]),
+                    checked(
+                        mk().method_call_expr(ret_var(), "is_null", vec![] as Vec<P<Expr>>),
+                        mk().path_expr(vec!["None"]),
+                        mk().call_expr(mk().path_expr(vec!["Some"]), vec![ret_var()]),
+                    ),
+                ),
+                ReturnAnnotation::ZeroIsOk if ret_kind.is_integral_type() => (
+                    result_ty(mk().tuple_ty(vec![] as Vec<P<Ty>>), ret_ty),
+                    checked(
+                        mk().binary_expr(BinOpKind::Eq, ret_var(), mk().lit_expr(mk().int_lit(0, ""))),
+                        mk().call_expr(mk().path_expr(vec!["Ok"]), vec![mk().tuple_expr(vec![] as Vec<P<Expr>>)]),
+                        mk().call_expr(mk().path_expr(vec!["Err"]), vec![ret_var()]),
+                    ),
+                ),
+                ReturnAnnotation::NonNegativeIsOk if ret_kind.is_signed_integral_type() => (
+                    result_ty(ret_ty.clone(), ret_ty),
+                    checked(
+                        mk().binary_expr(BinOpKind::Ge, ret_var(), mk().lit_expr(mk().int_lit(0, ""))),
+                        mk().call_expr(mk().path_expr(vec!["Ok"]), vec![ret_var()]),
+                        mk().call_expr(mk().path_expr(vec!["Err"]), vec![ret_var()]),
+                    ),
+                ),
+                ann => return Err(format!("return annotation {:?} does not fit type {:?}", ann, ret_kind)),
+            };
+
+            length_checks.extend(stmts);
+            let decl = mk().fn_decl(args, FunctionRetTy::Ty(wrapper_ret), false);
+            let builder = if is_unsafe { mk().pub_().unsafe_() } else { mk().pub_() };
+            Ok(builder.fn_item(raw_name.as_str(), decl, mk().block(length_checks)))
+        })
+    }
+
+    fn convert_decl(&self, toplevel: bool, decl_id: CDeclId) -> Result<ConvertedDecl, String> {
+        let loc = self.ast_context.c_decls.get(&decl_id).and_then(|decl| decl.loc);
+        let mut attrs = if toplevel { self.feature_cfg_attrs(loc) } else { vec![] };
+        let converted = if toplevel {
+            self.within_feature_cfgs(loc, || self.convert_decl_kind(toplevel, decl_id))
+        } else {
+            self.convert_decl_kind(toplevel, decl_id)
+        };
+        match converted? {
+            ConvertedDecl::Item(item) =>
+                Ok(ConvertedDecl::Item(item.map(|mut i| {
+                    i.id = self.source_node_id("item", loc, i.id);
+                    attrs.extend(i.attrs);
+                    i.attrs = attrs;
+                    i
+                }))),
+            ConvertedDecl::ForeignItem(mut item) => {
+                item.id = self.source_node_id("item", loc, item.id);
+                attrs.extend(item.attrs);
+                item.attrs = attrs;
+                Ok(ConvertedDecl::ForeignItem(item))
+            }
+        }
+    }
+
+    /// The conditionals of the C source on macros listed in `cfg_features` enclosing `loc`,
+    /// outermost first
+    fn feature_conditionals(&self, loc: Option<SrcLoc>) -> Vec<&ConditionalRegion> {
+        match loc {
+            Some(loc) if !self.tcfg.cfg_features.is_empty() =>
+                self.ast_context.conditionals_at(&loc)
+                    .into_iter()
+                    .filter(|region| self.tcfg.cfg_features.contains(&region.macro_name))
+                    .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Run `f` to translate the code at `loc`, so that the code nested in it is not guarded
+    /// again by the conditionals guarding it
+    fn within_feature_cfgs<R, F: FnOnce() -> R>(&self, loc: Option<SrcLoc>, f: F) -> R {
+        let enclosing = *self.feature_cfg_depth.borrow();
+        let depth = self.feature_conditionals(loc).len().max(enclosing);
+        *self.feature_cfg_depth.borrow_mut() = depth;
+        let result = f();
+        *self.feature_cfg_depth.borrow_mut() = enclosing;
+        result
+    }
 
-                stmts.push(mk().expr_stmt(mk().block_expr(unsafe_block)));
+    /// `#[cfg]` attributes for code at `loc` which the C source only compiles when macros listed
+    /// in `cfg_features` are (or are not) defined, each macro standing for a lowercase feature.
+    /// Conditionals already guarding the enclosing code are left out.
+    pub fn feature_cfg_attrs(&self, loc: Option<SrcLoc>) -> Vec<ast::Attribute> {
+        let depth = *self.feature_cfg_depth.borrow();
+        self.feature_conditionals(loc)
+            .into_iter()
+            .skip(depth)
+            .map(|region| {
+                let name = mk().str_lit(region.macro_name.to_lowercase()).into_inner();
+                let mut predicate = mk().meta_item(vec!["feature"], MetaItemKind::NameValue(name));
+                if !region.when_defined {
+                    let nested = vec![mk().nested_meta_item(NestedMetaItemKind::MetaItem(predicate))];
+                    predicate = mk().meta_item(vec!["not"], MetaItemKind::List(nested));
+                }
+                let nested = vec![mk().nested_meta_item(NestedMetaItemKind::MetaItem(predicate))];
+                let cfg = mk().meta_item(vec!["cfg"], MetaItemKind::List(nested));
+                attr::mk_attr_outer(DUMMY_SP, attr::mk_attr_id(), cfg)
+            })
+            .collect()
+    }
 
-                let exit_arg = mk().lit_expr(mk().int_lit(0,"i32"));
-                let call_exit = mk().call_expr(exit_fn, vec![exit_arg]);
+    /// The enum of the members of a tagged union, and methods of its struct to read a member once
+    /// the tag has been checked, to convert the union to the enum, and to set the union and its
+    /// tag from the enum
+    fn convert_tagged_union(&self, tagged: &TaggedUnion) -> Result<Vec<P<Item>>, String> {
+        let field_name = |field: CFieldId| self.type_converter.borrow().resolve_field_name(None, field)
+            .ok_or_else(|| format!("Field {:?} of a tagged union was not translated", field));
+        let struct_name = self.type_converter.borrow().resolve_decl_name(tagged.record)
+            .ok_or_else(|| format!("Struct {:?} of a tagged union was not translated", tagged.record))?;
+        let union_name = field_name(tagged.union_field)?;
+        let tag_name = field_name(tagged.tag)?;
+
+        let enum_name = format!("{}_{}", struct_name, union_name);
+        let enum_name = if self.tcfg.normalize_names { to_upper_camel_case(&enum_name) } else { enum_name };
+        let enum_name = self.type_converter.borrow_mut().declare_type_name(&enum_name);
+
+        // Tag values are written as the constants of the tag's enum when it has one
+        let tag_enum = match self.ast_context.index(tagged.tag).kind {
+            CDeclKind::Field { typ, .. } => match self.ast_context.resolve_type(typ.ctype).kind {
+                CTypeKind::Enum(enum_id) => Some(enum_id),
+                _ => None,
+            },
+            _ => None,
+        };
+        let tag_value = |value: &TagValue| match value.constant {
+            Some(constant) if tag_enum.is_some() && self.ast_context.parents.get(&constant) == tag_enum.as_ref() => {
+                let name = self.renamer.borrow().get(&constant).expect("Enum constant not named");
+                mk().path_expr(vec![name])
+            }
+            _ => signed_int_expr(value.value as i64),
+        };
+        let self_tag = || mk().field_expr(mk().ident_expr("self"), &tag_name);
+        let tag_is = |values: &[TagValue]| values.iter()
+            .map(|value| mk().binary_expr(BinOpKind::Eq, self_tag(), tag_value(value)))
+            .fold(None, |cond: Option<P<Expr>>, is_value| Some(match cond {
+                Some(cond) => mk().binary_expr(BinOpKind::Or, cond, is_value),
+                None => is_value,
+            }))
+            .expect("Tagged union member without tag values");
+        let self_member = |member_name: &str| mk().field_expr(
+            mk().field_expr(mk().ident_expr("self"), &union_name), member_name);
+        let read_member = |member_name: &str| mk().block_expr(
+            mk().unsafe_().block(vec![mk().expr_stmt(self_member(member_name))]));
+
+        let mut variants = vec![];
+        let mut accessors = vec![];
+        let mut from_union = mk().ident_expr("None");
+        let mut set_arms = vec![];
+        let mut variant_names: Renamer<CFieldId> = Renamer::new(&[]);
+        for &(member, ref values) in tagged.members.iter().rev() {
+            let member_name = field_name(member)?;
+            let ty = match self.ast_context.index(member).kind {
+                CDeclKind::Field { typ, .. } => self.convert_type(typ.ctype)?,
+                _ => return Err(format!("Found non-field in record field list")),
+            };
+            let variant_name = variant_names.pick_name(&to_upper_camel_case(&member_name));
+            let variant_path = vec![enum_name.clone(), variant_name.clone()];
+
+            variants.push(mk().variant(&variant_name, VariantData::Tuple(
+                vec![mk().enum_field(ty.clone())], DUMMY_NODE_ID)));
+
+            // `self.u_i()` panics unless the tag selects `i`
+            let message = format!("{}.{} read while {} does not select it", union_name, member_name, tag_name);
+            let message = vec![
+                Token::interpolated(Nonterminal::NtExpr(mk().lit_expr(mk().str_lit(&message)))),
+            ].into_iter().collect::<TokenStream>();
+            let mismatch = mk().mac_expr(mk().mac(vec!["panic"], message));
+            let body = mk().ifte_expr(tag_is(values), mk().block(vec![mk().expr_stmt(read_member(&member_name))]),
+                                      Some(mk().block_expr(mk().block(vec![mk().expr_stmt(mismatch)]))));
+            let decl = mk().fn_decl(vec![mk().self_arg(SelfKind::Region(None, Mutability::Immutable))],
+                                    FunctionRetTy::Ty(ty), false);
+            accessors.push(mk().pub_().method_impl_item(format!("{}_{}", union_name, member_name), decl,
+                                                        mk().block(vec![mk().expr_stmt(body)])));
+
+            let wrapped = mk().call_expr(mk().path_expr(variant_path.clone()), vec![read_member(&member_name)]);
+            from_union = mk().ifte_expr(tag_is(values),
+                                        mk().block(vec![mk().expr_stmt(mk().call_expr(mk().ident_expr("Some"), vec![wrapped]))]),
+                                        Some(from_union));
+
+            let set = vec![
+                mk().semi_stmt(mk().assign_expr(self_tag(), tag_value(&values[0]))),
+                mk().semi_stmt(mk().assign_expr(self_member(&member_name), mk().ident_expr("value"))),
+            ];
+            set_arms.push(mk().arm(vec![mk().tuple_struct_pat(variant_path, vec![mk().ident_pat("value")])],
+                                   None, mk().block_expr(mk().block(set))));
+        }
+        variants.reverse();
+        accessors.reverse();
+        set_arms.reverse();
+
+        let enum_ty = mk().path_ty(vec![enum_name.clone()]);
+        let decl = mk().fn_decl(vec![mk().self_arg(SelfKind::Region(None, Mutability::Immutable))],
+                                FunctionRetTy::Ty(mk().path_ty(vec![mk().path_segment_with_params("Option",
+                                    mk().angle_bracketed_param_types(vec![enum_ty.clone()]))])), false);
+        accessors.push(mk().pub_().method_impl_item(&union_name, decl, mk().block(vec![mk().expr_stmt(from_union)])));
+
+        let decl = mk().fn_decl(vec![mk().self_arg(SelfKind::Region(None, Mutability::Mutable)),
+                                     mk().arg(enum_ty, mk().ident_pat("data"))],
+                                FunctionRetTy::Default(DUMMY_SP), false);
+        let set = mk().match_expr(mk().ident_expr("data"), set_arms);
+        accessors.push(mk().pub_().method_impl_item(format!("set_{}", union_name), decl,
+                                                    mk().block(vec![mk().expr_stmt(set)])));
+
+        Ok(vec![
+            mk().pub_().call_attr("derive", vec!["Copy", "Clone"]).enum_item(&enum_name, variants),
+            mk().impl_item(mk().path_ty(vec![struct_name]), accessors),
+        ])
+    }
 
-                stmts.push(mk().semi_stmt(call_exit));
-            } else {
-                let call_main = mk().cast_expr(
-                    mk().call_expr(main_fn, main_args),
-                    mk().path_ty(vec!["i32"]),
-                );
+    /// A read of a member of a tagged union: the expression of the struct holding the union, how
+    /// the union field is accessed, and the method reading the member
+    fn tagged_union_read(&self, expr: CExprId) -> Option<(CExprId, MemberKind, String)> {
+        if let CExprKind::Member(_, union_expr, member, MemberKind::Dot) = self.ast_context[expr].kind {
+            if let CExprKind::Member(_, struct_expr, union_field, kind) = self.ast_context[union_expr].kind {
+                let tagged = self.tagged_unions.get(&union_field)?;
+                if tagged.members.iter().any(|&(m, _)| m == member) {
+                    let type_converter = self.type_converter.borrow();
+                    let accessor = format!("{}_{}", type_converter.resolve_field_name(None, union_field)?,
+                                           type_converter.resolve_field_name(None, member)?);
+                    return Some((struct_expr, kind, accessor))
+                }
+            }
+        }
+        None
+    }
 
-                let call_exit = mk().call_expr(exit_fn, vec![call_main]);
-                let unsafe_block = mk().unsafe_().block(vec![mk().expr_stmt(call_exit)]);
+    /// The element a dereferenced pointer points to, when the pointer is `buf + i`, `i + buf` or
+    /// `buf` for an array `buf` that never escapes: the array expression, and the index if any
+    fn indexed_array_element(&self, pointer: CExprId) -> Option<(CExprId, Option<CExprId>)> {
+        let decayed_array = |expr: CExprId| match self.ast_context[expr].kind {
+            CExprKind::ImplicitCast(_, arr, CastKind::ArrayToPointerDecay, _) => Some(arr),
+            _ => None,
+        };
 
-                stmts.push(mk().expr_stmt(mk().block_expr(unsafe_block)));
-            };
+        let (arr, index) = match self.ast_context[pointer].kind {
+            CExprKind::Binary(_, c_ast::BinOp::Add, lhs, rhs, _, _) => match decayed_array(lhs) {
+                Some(arr) => (arr, Some(rhs)),
+                None => (decayed_array(rhs)?, Some(lhs)),
+            },
+            _ => (decayed_array(pointer)?, None),
+        };
 
-            let block = mk().block(stmts);
-            let main_attributes = self.mk_cross_check(mk(), vec!["none"]);
-            Ok(main_attributes.pub_().fn_item("main", decl, block))
-        } else {
-            Err(format!("Cannot translate non-function main entry point"))
+        // Rows of multidimensional arrays are indexed when the whole array is
+        let mut base = arr;
+        loop {
+            match self.ast_context[base].kind {
+                CExprKind::DeclRef(_, decl_id) if self.indexed_arrays.contains(&decl_id) =>
+                    return Some((arr, index)),
+                CExprKind::ArraySubscript(_, lhs, rhs) => {
+                    let lhs_is_array = decayed_array(lhs).is_some();
+                    base = decayed_array(if lhs_is_array { lhs } else { rhs })?;
+                }
+                _ => return None,
+            }
         }
     }
 
-    fn convert_decl(&self, toplevel: bool, decl_id: CDeclId) -> Result<ConvertedDecl, String> {
+    fn convert_decl_kind(&self, toplevel: bool, decl_id: CDeclId) -> Result<ConvertedDecl, String> {
         let mut s = {
             let decl_cmt = self.comment_context.borrow_mut().remove_decl_comment(decl_id);
             self.comment_store.borrow_mut().add_comment_lines(decl_cmt)
@@ -943,10 +2299,20 @@ impl Translation {
             CDeclKind::Struct { fields: None, .. } |
             CDeclKind::Union { fields: None, .. } |
             CDeclKind::Enum { integral_type: None, .. } => {
-                self.use_feature("extern_types");
                 let name = self.type_converter.borrow().resolve_decl_name(decl_id).unwrap();
-                let extern_item = mk().span(s).pub_().foreign_ty(name);
-                Ok(ConvertedDecl::ForeignItem(extern_item))
+                if self.tcfg.target_stable {
+                    // Without extern types, an opaque type is a struct that can't be constructed
+                    let private = mk().struct_field("_private", mk().array_ty(
+                        mk().path_ty(vec!["u8"]), mk().lit_expr(mk().int_lit(0, LitIntType::Unsuffixed))));
+                    let item = mk().span(s).pub_()
+                        .call_attr("repr", vec!["C"])
+                        .struct_item(name, vec![private]);
+                    Ok(ConvertedDecl::Item(item))
+                } else {
+                    self.use_feature("extern_types");
+                    let extern_item = mk().span(s).pub_().foreign_ty(name);
+                    Ok(ConvertedDecl::ForeignItem(extern_item))
+                }
             }
 
             CDeclKind::Struct { fields: Some(ref fields), is_packed, manual_alignment, .. } => {
@@ -959,7 +2325,9 @@ impl Translation {
                         CDeclKind::Field { ref name, typ } => {
                             let name = self.type_converter.borrow_mut().declare_field_name(decl_id, x, name);
                             let typ = self.convert_type(typ.ctype)?;
-                            field_entries.push(mk().span(s).pub_().struct_field(name, typ))
+                            let field_cmt = self.comment_context.borrow_mut().remove_decl_comment(x);
+                            let fs = self.comment_store.borrow_mut().add_comment_lines(field_cmt);
+                            field_entries.push(mk().span(fs).pub_().struct_field(name, typ))
                         }
                         _ => return Err(format!("Found non-field in record field list")),
                     }
@@ -976,8 +2344,11 @@ impl Translation {
                 if is_packed { reprs.push(simple_metaitem("packed")); };
                 // https://github.com/rust-lang/rust/issues/33626
                 if let Some(alignment) = manual_alignment {
-                    self.use_feature("repr_align");
-                    self.use_feature("attr_literals");
+                    // `repr(align)` itself is stable
+                    if !self.tcfg.target_stable {
+                        self.use_feature("repr_align");
+                        self.use_feature("attr_literals");
+                    }
 
                     let lit = mk().int_lit(alignment as u128, LitIntType::Unsuffixed);
                     let inner = mk().meta_item(
@@ -1006,7 +2377,9 @@ impl Translation {
                         CDeclKind::Field { ref name, typ } => {
                             let name = self.type_converter.borrow_mut().declare_field_name(decl_id, x, name);
                             let typ = self.convert_type(typ.ctype)?;
-                            field_syns.push(mk().span(s).struct_field(name, typ))
+                            let field_cmt = self.comment_context.borrow_mut().remove_decl_comment(x);
+                            let fs = self.comment_store.borrow_mut().add_comment_lines(field_cmt);
+                            field_syns.push(mk().span(fs).struct_field(name, typ))
                         }
                         _ => return Err(format!("Found non-field in record field list")),
                     }
@@ -1069,17 +2442,21 @@ impl Translation {
 
                 let is_main = self.ast_context.c_main == Some(decl_id);
 
-                let converted_function =
-                    self.convert_function(s, is_extern, is_inline, is_main, is_var,
-                                          new_name, name, &args, ret, body);
+                let convert = || {
+                    let converted_function =
+                        self.convert_function(s, is_extern, is_inline, is_main, is_var,
+                                              new_name, name, &args, ret, body);
+
+                    converted_function.or_else(|e|
+                        match self.tcfg.replace_unsupported_decls {
+                            ReplaceMode::Extern if body.is_none() =>
+                                self.convert_function(s, is_extern, false, is_main, is_var,
+                                                      new_name, name, &args, ret, None),
+                            _ => Err(e),
+                        })
+                };
 
-                converted_function.or_else(|e|
-                    match self.tcfg.replace_unsupported_decls {
-                        ReplaceMode::Extern if body.is_none() =>
-                            self.convert_function(s, is_extern, false, is_main, is_var,
-                                                  new_name, name, &args, ret, None),
-                        _ => Err(e),
-                    })
+                if body.is_none() { self.with_c_type_aliases(convert) } else { convert() }
             },
 
             CDeclKind::Typedef { ref typ, .. } => {
@@ -1095,7 +2472,7 @@ impl Translation {
                 assert!(initializer.is_none(), "An extern variable that isn't a definition can't have an initializer");
 
                 let new_name = self.renamer.borrow().get(&decl_id).expect("Variables should already be renamed");
-                let (ty, mutbl, _) = self.convert_variable(None, typ, is_static)?;
+                let (ty, mutbl, _) = self.with_c_type_aliases(|| self.convert_variable(None, typ, is_static))?;
 
                 let extern_item = mk_linkage(true, &new_name, ident)
                     .span(s)
@@ -1124,8 +2501,7 @@ impl Translation {
                     let mut init = mk().block_expr(init);
 
                     let comment = String::from("// Initialized in run_static_initializers");
-                    // REVIEW: We might want to add the comment to the original span comments
-                    s = self.comment_store.borrow_mut().add_comment_lines(vec![comment]);
+                    s = self.comment_store.borrow_mut().append_comment_lines(s, vec![comment]);
 
                     self.add_static_initializer_to_section(new_name, typ, &mut init)?;
 
@@ -1169,8 +2545,7 @@ impl Translation {
                 // to initialize for us
                 if self.static_initializer_is_uncompilable(initializer) {
                     let comment = String::from("// Initialized in run_static_initializers");
-                    // REVIEW: We might want to add the comment to the original span comments
-                    s = self.comment_store.borrow_mut().add_comment_lines(vec![comment]);
+                    s = self.comment_store.borrow_mut().append_comment_lines(s, vec![comment]);
 
                     self.add_static_initializer_to_section(new_name, typ, &mut init)?;
                 }
@@ -1222,7 +2597,7 @@ impl Translation {
                     let mutbl = if body.is_none() { Mutability::Immutable } else { mutbl };
 
                     let new_var = self.renamer.borrow_mut()
-                        .insert(decl_id, var.as_str())
+                        .insert(decl_id, &self.local_name(var))
                         .expect(&format!("Failed to insert argument '{}' while converting '{}'", var, name));
 
                     mk().set_mutbl(mutbl).ident_pat(new_var)
@@ -1267,6 +2642,17 @@ impl Translation {
                     _ => panic!("function body expects to be a compound statement"),
                 };
                 body_stmts.append(&mut self.convert_function_body(name, body_ids, ret)?);
+
+                // Comments on trailing statements that produced nothing go with the last statement,
+                // or in front of the function when no statement is left
+                let deferred = self.comment_context.borrow_mut().take_deferred_comments();
+                let span = match body_stmts.last_mut() {
+                    Some(last) => {
+                        last.span = self.comment_store.borrow_mut().append_comment_lines(last.span, deferred);
+                        span
+                    }
+                    None => self.comment_store.borrow_mut().append_comment_lines(span, deferred),
+                };
                 let block = stmts_block(body_stmts);
 
                 // Only add linkage attributes if the function is `extern`
@@ -1323,6 +2709,11 @@ impl Translation {
                     graph.dump_json_graph(&store, format!("{}_{}.json", "cfg", name))
                         .expect("Failed to write CFG .json file");
                 }
+                let mut explorer = if self.tcfg.html_function_cfgs {
+                    Some(cfg::explorer::Explorer::new(name, &self.ast_context, body_ids, &graph, &store))
+                } else {
+                    None
+                };
 
                 let (lifted_stmts, relooped) = cfg::relooper::reloop(
                     graph,
@@ -1338,14 +2729,47 @@ impl Translation {
                         eprintln!("  {:#?}", s);
                     }
                 }
+                if let Some(ref mut explorer) = explorer {
+                    explorer.set_structures(&relooped);
+                }
 
                 let current_block_ident = self.renamer.borrow_mut().pick_name("current_block");
                 let current_block = mk().ident_expr(&current_block_ident);
                 let mut stmts: Vec<Stmt> = lifted_stmts;
-                if cfg::structures::has_multiple(&relooped) {
+
+                let (structured, info) = cfg::structures::structured_cfg(
+                    &relooped,
+                    &mut self.comment_store.borrow_mut(),
+                    current_block,
+                    self.tcfg.debug_relooper_labels,
+                    !self.tcfg.target_stable,
+                )?;
+                if info.uses_labelled_blocks {
+                    self.use_feature("label_break_value");
+                }
+
+                if self.tcfg.dump_structures {
+                    eprintln!(
+                        "Uses of current_block: {} assignments and {} dispatches before propagation, \
+                         {} assignments and {} dispatches after",
+                        info.current_block_before.assignments,
+                        info.current_block_before.dispatches,
+                        info.current_block_after.assignments,
+                        info.current_block_after.dispatches,
+                    );
+                }
+
+                let uses_current_block = info.uses_current_block();
+                self.structuring_stats.borrow_mut().add(cfg::StructuringStats {
+                    relooped: 1,
+                    structured: if uses_current_block { 0 } else { 1 },
+                });
+                if uses_current_block {
 
                     if self.tcfg.fail_on_multiple {
-                        panic!("Uses of `current_block' are illegal with `--fail-on-multiple'.");
+                        let msg = "Uses of `current_block' are illegal with `--fail-on-multiple'.";
+                        self.fail_translation(msg);
+                        return Err(msg.to_string())
                     }
 
                     let current_block_ty = if self.tcfg.debug_relooper_labels {
@@ -1360,38 +2784,67 @@ impl Translation {
                     stmts.push(mk().local_stmt(P(local)))
                 }
 
-                stmts.extend(cfg::structures::structured_cfg(
-                    &relooped,
-                    &mut self.comment_store.borrow_mut(),
-                    current_block,
-                    self.tcfg.debug_relooper_labels
-                )?);
+                stmts.extend(structured);
+                match stmts.last_mut() {
+                    Some(last) => {
+                        let mut store = self.comment_store.borrow_mut();
+                        last.span = store.append_comment_lines(last.span, info.unplaced_comments);
+                    }
+                    None => self.comment_context.borrow_mut().defer_comments(info.unplaced_comments),
+                }
+                let stmts = if self.tcfg.for_ranges {
+                    cfg::structures::reconstruct_for_ranges(stmts)
+                } else {
+                    stmts
+                };
+
+                if let Some(mut explorer) = explorer {
+                    explorer.set_rust(&stmts);
+                    explorer.write(format!("{}_{}.html", "cfg", name))
+                        .expect("Failed to write CFG .html file");
+                }
                 Ok(stmts)
             } else {
                 let mut res = vec![];
                 for &stmt in body_ids {
                     res.append(&mut self.convert_stmt(stmt)?)
                 }
+
+                // Comments on trailing statements that produced nothing go with the last statement
+                if let Some(last) = res.last_mut() {
+                    let deferred = self.comment_context.borrow_mut().take_deferred_comments();
+                    last.span = self.comment_store.borrow_mut().append_comment_lines(last.span, deferred);
+                }
+                if self.tcfg.for_ranges {
+                    res = cfg::structures::reconstruct_for_ranges(res);
+                }
                 Ok(res)
             }
         })
     }
 
     fn convert_stmt(&self, stmt_id: CStmtId) -> Result<Vec<Stmt>, String> {
+        let loc = self.ast_context.index(stmt_id).loc;
+        let cfg_attrs = self.feature_cfg_attrs(loc);
+        let stmts = self.within_feature_cfgs(loc, || self.convert_stmt_kind(stmt_id))?;
+        Ok(stmts.into_iter().map(|stmt| stmt_with_attrs(self.located_stmt(loc, stmt), &cfg_attrs)).collect())
+    }
+
+    fn convert_stmt_kind(&self, stmt_id: CStmtId) -> Result<Vec<Stmt>, String> {
         let s = {
             let stmt_cmt = self.comment_context.borrow_mut().remove_stmt_comment(stmt_id);
             self.comment_store.borrow_mut().add_comment_lines(stmt_cmt)
         };
 
         match self.ast_context.index(stmt_id).kind {
-            CStmtKind::Empty => Ok(vec![]),
+            CStmtKind::Empty => Ok(self.attach_comment(s, vec![])),
 
             CStmtKind::Decls(ref decls) => {
                 let mut res = vec![];
                 for decl in decls {
                     res.append(&mut self.convert_decl_stmt(*decl)?)
                 }
-                Ok(res)
+                Ok(self.attach_comment(s, res))
             },
 
             CStmtKind::Return(expr) =>
@@ -1416,12 +2869,14 @@ impl Translation {
                         res.append(&mut self.convert_stmt(*stmt)?)
                     }
 
-                    Ok(vec![mk().expr_stmt(mk().block_expr(stmts_block(res)))])
+                    Ok(vec![mk().span(s).expr_stmt(mk().block_expr(stmts_block(res)))])
                 })
             },
 
-            CStmtKind::Expr(expr) =>
-                Ok(self.convert_expr(ExprUse::Unused, expr, false, DecayRef::Default)?.stmts),
+            CStmtKind::Expr(expr) => {
+                let stmts = self.convert_expr(ExprUse::Unused, expr, false, DecayRef::Default)?.stmts;
+                Ok(self.attach_comment(s, stmts))
+            }
 
             CStmtKind::Break => {
                 let mut loop_ = self.loops.current_loop_mut();
@@ -1457,6 +2912,18 @@ impl Translation {
         }
     }
 
+    /// Move the comment at `sp` onto the first of `stmts`. When there are no statements, the
+    /// comment goes with the next statement translated instead.
+    fn attach_comment(&self, sp: Span, mut stmts: Vec<Stmt>) -> Vec<Stmt> {
+        let lines = self.comment_store.borrow_mut().take_comment_lines(sp);
+        match stmts.first_mut() {
+            Some(stmt) =>
+                stmt.span = self.comment_store.borrow_mut().prepend_comment_lines(stmt.span, lines),
+            None => self.comment_context.borrow_mut().defer_comments(lines),
+        }
+        stmts
+    }
+
     pub fn convert_asm
         (&self,
          span: Span,
@@ -1471,7 +2938,7 @@ impl Translation {
             return Err(format!("Inline assembly not enabled, to enable use --translate-asm"))
         }
 
-        self.use_feature("asm");
+        self.require_feature("asm")?;
 
         fn push_expr(tokens: &mut Vec<Token>, expr: P<Expr>) {
             tokens.push(Token::interpolated(Nonterminal::NtExpr(expr)));
@@ -1787,7 +3254,7 @@ impl Translation {
                 let mut stmts = self.compute_variable_array_sizes(typ.ctype)?;
 
                 let rust_name = self.renamer.borrow_mut()
-                    .insert(decl_id, &ident)
+                    .insert(decl_id, &self.local_name(ident))
                     .expect(&format!("Failed to insert variable '{}'", ident));
                 let (ty, mutbl, init) = self.convert_variable(initializer, typ, is_static)?;
                 let mut init = init?;
@@ -1989,6 +3456,15 @@ impl Translation {
         self.type_converter.borrow_mut().convert(&self.ast_context, type_id)
     }
 
+    /// Run `f` converting C's arithmetic types to their `libc` aliases even when fixed width
+    /// integers are used elsewhere, so that `extern "C"` declarations keep the C types
+    fn with_c_type_aliases<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let c_type_aliases = mem::replace(&mut self.type_converter.borrow_mut().c_type_aliases, true);
+        let result = f();
+        self.type_converter.borrow_mut().c_type_aliases = c_type_aliases;
+        result
+    }
+
     /// Construct an expression for a NULL at any type, including forward declarations,
     /// function pointers, and normal pointers.
     fn null_ptr(&self, type_id: CTypeId, is_static: bool) -> Result<P<Expr>, String> {
@@ -2035,7 +3511,7 @@ impl Translation {
             },
         };
 
-        Ok(mk().call_expr(mk().path_expr(vec!["", "std", "ptr", "write_volatile"]), vec![addr_lhs, rhs]))
+        Ok(mk().call_expr(mk().path_expr(vec!["", self.std_crate(), "ptr", "write_volatile"]), vec![addr_lhs, rhs]))
     }
 
     /// Read from a `lhs` that is volatile
@@ -2065,7 +3541,7 @@ impl Translation {
         // in order to avoid omitted bit-casts to const from causing the
         // wrong type to be inferred via the result of the pointer.
         let mut path_parts: Vec<PathSegment> = vec![];
-        for elt in vec!["", "std", "ptr"] {
+        for elt in vec!["", self.std_crate(), "ptr"] {
             path_parts.push(mk().path_segment(elt))
         }
         let elt_ty = self.convert_type(lhs_type.ctype)?;
@@ -2196,7 +3672,7 @@ impl Translation {
             let name = "size_of";
             let params = mk().angle_bracketed_param_types(vec![ty]);
             let path = vec![mk().path_segment(""),
-                            mk().path_segment("std"),
+                            mk().path_segment(self.std_crate()),
                             mk().path_segment("mem"),
                             mk().path_segment_with_params(name, params)];
             let call = mk().call_expr(mk().path_expr(path), vec![] as Vec<P<Expr>>);
@@ -2214,7 +3690,7 @@ impl Translation {
         let name = "align_of";
         let tys = vec![ty];
         let path = vec![mk().path_segment(""),
-                        mk().path_segment("std"),
+                        mk().path_segment(self.std_crate()),
                         mk().path_segment("mem"),
                         mk().path_segment_with_params(name,
                                                       mk().angle_bracketed_param_types(tys)),
@@ -2233,13 +3709,24 @@ impl Translation {
     /// `stmts` field of the output and it is expected that the `val` field of the output will be
     /// ignored.
     pub fn convert_expr(&self, use_: ExprUse, expr_id: CExprId, is_static: bool, decay_ref: DecayRef) -> Result<WithStmts<P<Expr>>, String> {
+        let loc = self.ast_context[expr_id].loc;
+        if is_static {
+            if let Some(val) = self.evaluated_static_expr(expr_id) {
+                return Ok(WithStmts::new(self.located_expr("expr", loc, val)))
+            }
+        }
+        let converted = self.convert_expr_kind(use_, expr_id, is_static, decay_ref)?;
+        Ok(converted.map(|val| self.located_expr("expr", loc, val)))
+    }
+
+    fn convert_expr_kind(&self, use_: ExprUse, expr_id: CExprId, is_static: bool, decay_ref: DecayRef) -> Result<WithStmts<P<Expr>>, String> {
         match self.ast_context[expr_id].kind {
             CExprKind::DesignatedInitExpr(..) => Err(format!("Unexpected designated init expr")),
             CExprKind::BadExpr => Err(format!("convert_expr: expression kind not supported")),
             CExprKind::ShuffleVector(..) => Err(format!("shuffle vector not supported")),
             CExprKind::ConvertVector(..) => Err(format!("convert vector not supported")),
 
-            CExprKind::UnaryType(_ty, kind, opt_expr, arg_ty) => {
+            CExprKind::UnaryType(ty, kind, opt_expr, arg_ty) => {
                 let result = match kind {
                     UnTypeOp::SizeOf =>
                         match opt_expr {
@@ -2260,7 +3747,8 @@ impl Translation {
                     UnTypeOp::AlignOf => self.compute_align_of_type(arg_ty.ctype)?,
                 };
 
-                Ok(result.map(|x| mk().cast_expr(x, mk().path_ty(vec!["libc","c_ulong"]))))
+                let size_ty = self.type_converter.borrow().arithmetic_type(&self.ast_context, ty.ctype, "c_ulong");
+                Ok(result.map(|x| mk().cast_expr(x, size_ty)))
             }
 
             CExprKind::DeclRef(qual_ty, decl_id) => {
@@ -2360,12 +3848,25 @@ impl Translation {
                     } else { Mutability::Mutable };
                     let target_ty = mk().set_mutbl(mutbl).ref_ty(self.convert_type(ty.ctype)?);
                     let byte_literal = mk().lit_expr(mk().bytestr_lit(val));
-                    let pointer = transmute_expr(source_ty, target_ty, byte_literal);
+                    let pointer = self.transmute_expr(source_ty, target_ty, byte_literal);
                     let array = mk().unary_expr(ast::UnOp::Deref, pointer);
                     Ok(WithStmts::new(array))
                 }
             }
 
+            CExprKind::ImplicitCast(_, member, CastKind::LValueToRValue, _)
+            if use_ != ExprUse::Unused && self.tagged_union_read(member).is_some() => {
+                let (struct_expr, kind, accessor) = self.tagged_union_read(member).unwrap();
+                let val = self.convert_expr(use_, struct_expr, is_static, decay_ref)?;
+                Ok(val.map(|v| {
+                    let receiver = match kind {
+                        MemberKind::Dot => v,
+                        MemberKind::Arrow => mk().unary_expr(ast::UnOp::Deref, v),
+                    };
+                    mk().method_call_expr(receiver, accessor, vec![] as Vec<P<Expr>>)
+                }))
+            }
+
             CExprKind::ImplicitCast(ty, expr, kind, opt_field_id) =>
                 self.convert_cast(use_, ty, expr, kind, opt_field_id, false, is_static, decay_ref),
 
@@ -2439,7 +3940,7 @@ impl Translation {
                     c_ast::BinOp::And => {
                         let lhs = self.convert_condition(true, lhs, is_static)?;
                         let rhs = self.convert_condition(true, rhs, is_static)?;
-                        let mut out = lhs.map(|x| bool_to_int(mk().binary_expr(BinOpKind::And, x, rhs.to_expr())));
+                        let mut out = lhs.map(|x| self.bool_to_int(mk().binary_expr(BinOpKind::And, x, rhs.to_expr())));
 
                         if use_ == ExprUse::Unused {
                             let out_val = mem::replace(
@@ -2456,7 +3957,7 @@ impl Translation {
                     c_ast::BinOp::Or => {
                         let lhs = self.convert_condition(true, lhs, is_static)?;
                         let rhs = self.convert_condition(true, rhs, is_static)?;
-                        let mut out = lhs.map(|x| bool_to_int(mk().binary_expr(BinOpKind::Or, x, rhs.to_expr())));
+                        let mut out = lhs.map(|x| self.bool_to_int(mk().binary_expr(BinOpKind::Or, x, rhs.to_expr())));
 
                         if use_ == ExprUse::Unused {
                             let out_val = mem::replace(
@@ -2508,7 +4009,7 @@ impl Translation {
                             stmts.extend(lhs_stmts);
                             stmts.extend(rhs_stmts);
                             let expr_ids = Some((lhs, rhs));
-                            let val = self.convert_binary_operator(op, ty, type_id.ctype, lhs_type, rhs_type, lhs_val, rhs_val, expr_ids);
+                            let val = self.convert_binary_operator(op, ty, type_id.ctype, lhs_type, rhs_type, lhs_val, rhs_val, expr_ids)?;
 
                             Ok(WithStmts { stmts, val })
                         }
@@ -2565,8 +4066,11 @@ impl Translation {
                     // Don't dereference the offset if we're still within the variable portion
                     if let Some(elt_type_id) = var_elt_type_id {
                         match self.compute_size_of_expr(elt_type_id) {
-                            None => mk().unary_expr(ast::UnOp::Deref, pointer_offset(lhs.val, rhs.val)),
-                            Some(sz) => pointer_offset(lhs.val, mk().binary_expr(BinOpKind::Mul, sz, cast_int(rhs.val, "usize"))),
+                            None => mk().unary_expr(ast::UnOp::Deref, pointer_offset(self.offset_method(), lhs.val, rhs.val)),
+                            Some(sz) => {
+                                let offset = self.arith_expr(BinOpKind::Mul, false, sz, cast_int(rhs.val, "usize"));
+                                pointer_offset(self.offset_method(), lhs.val, offset)
+                            }
                         }
                     } else {
                         mk().index_expr(lhs.val, cast_int(rhs.val, "usize"))
@@ -2585,18 +4089,22 @@ impl Translation {
                     };
 
                     if let Some(sz) = self.compute_size_of_expr(pointee_type_id.ctype) {
-                        let offset = mk().binary_expr(BinOpKind::Mul, sz, cast_int(rhs.val, "usize"));
-                        pointer_offset(lhs.val, offset)
+                        let offset = self.arith_expr(BinOpKind::Mul, false, sz, cast_int(rhs.val, "usize"));
+                        pointer_offset(self.offset_method(), lhs.val, offset)
                     } else {
                         // Otherwise, use the pointer and make a deref of a pointer offset expression
-                        mk().unary_expr(ast::UnOp::Deref, pointer_offset(lhs.val, rhs.val))
+                        mk().unary_expr(ast::UnOp::Deref, pointer_offset(self.offset_method(), lhs.val, rhs.val))
                     }
                 };
 
                 Ok(WithStmts { stmts, val })
             }
 
-            CExprKind::Call(_, func, ref args) => {
+            CExprKind::Call(ty, func, ref args) => {
+                if let Some((rewrite, rust_args)) = self.call_rewrite(func, args) {
+                    return self.convert_rewritten_call(use_, ty, rewrite, rust_args, args, is_static)
+                }
+
                 let is_variadic = self.fn_expr_is_variadic(func);
                 let WithStmts { mut stmts, val: func } = match self.ast_context.index(func).kind {
                     CExprKind::ImplicitCast(_, fexp, CastKind::FunctionToPointerDecay, _) =>
@@ -2725,12 +4233,17 @@ impl Translation {
             CExprKind::Predefined(_, val_id) =>
                 self.convert_expr(use_, val_id, is_static, decay_ref),
 
-            CExprKind::Statements(_, compound_stmt_id) =>
-                self.convert_statement_expression(use_, compound_stmt_id, is_static),
+            CExprKind::Statements(_, compound_stmt_id) => {
+                if let Some(val) = self.lowered_stmt_exprs.borrow().get(&expr_id) {
+                    return Ok(WithStmts::new(val.clone()))
+                }
+                self.convert_statement_expression(use_, compound_stmt_id, is_static)
+            }
 
             CExprKind::VAArg(ty, val_id) => {
                 if self.tcfg.translate_valist {
                     // https://github.com/rust-lang/rust/pull/49878/files
+                    self.require_feature("c_variadic")?;
                     let val = self.convert_expr(ExprUse::RValue, val_id, is_static, decay_ref)?;
                     let ty = self.convert_type(ty.ctype)?;
 
@@ -2783,17 +4296,17 @@ impl Translation {
 
         match builtin_name {
             "__builtin_huge_valf" =>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f32","INFINITY"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f32", "INFINITY"]))),
             "__builtin_huge_val" | "__builtin_huge_vall"=>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f64","INFINITY"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f64", "INFINITY"]))),
             "__builtin_inff" =>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f32","INFINITY"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f32", "INFINITY"]))),
             "__builtin_inf" | "__builtin_infl" =>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f64","INFINITY"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f64", "INFINITY"]))),
             "__builtin_nanf" =>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f32","NAN"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f32", "NAN"]))),
             "__builtin_nan" =>
-                Ok(WithStmts::new(mk().path_expr(vec!["","std","f64","NAN"]))),
+                Ok(WithStmts::new(mk().path_expr(vec!["", self.std_crate(), "f64", "NAN"]))),
             "__builtin_clz" | "__builtin_clzl" | "__builtin_clzll" => {
                 let val = self.convert_expr(ExprUse::RValue, args[0], is_static, decay_ref)?;
                 Ok(val.map(|x| {
@@ -2816,6 +4329,23 @@ impl Translation {
             }
             "__builtin_fabs" | "__builtin_fabsf" | "__builtin_fabsl" => {
                 let val = self.convert_expr(ExprUse::RValue, args[0], is_static, decay_ref)?;
+                if self.tcfg.no_std {
+                    // `abs` on floats (like `to_bits`) needs `std`, so clear the sign bit of the
+                    // transmuted value instead
+                    let (float_ty, bits_ty, mask) = if builtin_name == "__builtin_fabsf" {
+                        ("f32", "u32", u32::max_value() as u128 >> 1)
+                    } else {
+                        ("f64", "u64", u64::max_value() as u128 >> 1)
+                    };
+                    return Ok(val.map(|x| {
+                        let float_ty = mk().path_ty(vec![float_ty]);
+                        let bits_ty = mk().path_ty(vec![bits_ty]);
+                        let bits = self.transmute_expr(float_ty.clone(), bits_ty.clone(), x);
+                        let mask = mk().lit_expr(mk().int_lit(mask, LitIntType::Unsuffixed));
+                        let abs_bits = mk().binary_expr(BinOpKind::BitAnd, bits, mask);
+                        self.transmute_expr(bits_ty, float_ty, abs_bits)
+                    }))
+                }
                 Ok(val.map(|x|
                     mk().method_call_expr(x, "abs", vec![] as Vec<P<Expr>>)
                 ))
@@ -2833,7 +4363,7 @@ impl Translation {
             "__builtin_bzero" => {
                 let ptr_stmts = self.convert_expr(ExprUse::RValue, args[0], is_static, decay_ref)?;
                 let n_stmts = self.convert_expr(ExprUse::RValue, args[1], is_static, decay_ref)?;
-                let write_bytes = mk().path_expr(vec!["", "std", "ptr", "write_bytes"]);
+                let write_bytes = mk().path_expr(vec!["", self.std_crate(), "ptr", "write_bytes"]);
                 let zero = mk().lit_expr(mk().int_lit(0, "u8"));
                 Ok(ptr_stmts.and_then(|ptr| n_stmts.map(|n| {
                     mk().call_expr(write_bytes, vec![ptr, zero, n])
@@ -2858,6 +4388,178 @@ impl Translation {
         }
     }
 
+    /// The rewrite of a call to the C library function `func` into Rust, along with the arguments
+    /// of the Rust call
+    fn call_rewrite(&self, func: CExprId, args: &[CExprId]) -> Option<(&CallRewrite, Vec<CallArg>)> {
+        let rewrites = self.tcfg.call_rewrites.as_ref()?;
+        match self.ast_context[self.called_function(func)?].kind {
+            // Functions defined in the translated code are left alone
+            CDeclKind::Function { ref name, .. } if !self.defined_functions.contains(name) => {
+                let rewrite = rewrites.get(name)?;
+                if self.tcfg.no_std && rewrite.needs_std() {
+                    return None
+                }
+                rewrite.arguments(args.len()).map(|rust_args| (rewrite, rust_args))
+            }
+            _ => None,
+        }
+    }
+
+    /// The function a call expression calls directly by name
+    fn called_function(&self, func: CExprId) -> Option<CDeclId> {
+        match self.ast_context[func].kind {
+            CExprKind::ImplicitCast(_, fexp, CastKind::FunctionToPointerDecay, _) =>
+                match self.ast_context[fexp].kind {
+                    CExprKind::DeclRef(_, decl_id) => Some(decl_id),
+                    _ => None,
+                },
+            _ => None,
+        }
+    }
+
+    /// The declarations of the functions whose uses are all calls rewritten into Rust, leaving
+    /// nothing that needs their `extern "C"` declaration. Functions that are never used keep it.
+    fn rewritten_away_functions(&self) -> HashSet<CDeclId> {
+        if self.tcfg.call_rewrites.is_none() {
+            return HashSet::new()
+        }
+
+        // The references to functions that are the callees of rewritten calls
+        let mut rewritten_refs = HashSet::new();
+        for expr in self.ast_context.c_exprs.values() {
+            if let CExprKind::Call(_, func, ref args) = expr.kind {
+                if self.call_rewrite(func, args).is_some() {
+                    if let CExprKind::ImplicitCast(_, fexp, _, _) = self.ast_context[func].kind {
+                        rewritten_refs.insert(fexp);
+                    }
+                }
+            }
+        }
+
+        // Uses are counted by name, as they may refer to any of a function's declarations
+        let mut rewritten_names = HashSet::new();
+        let mut used_names = HashSet::new();
+        for (&expr_id, expr) in &self.ast_context.c_exprs {
+            if let CExprKind::DeclRef(_, decl_id) = expr.kind {
+                if let CDeclKind::Function { ref name, .. } = self.ast_context[decl_id].kind {
+                    if rewritten_refs.contains(&expr_id) {
+                        rewritten_names.insert(name.as_str());
+                    } else {
+                        used_names.insert(name.as_str());
+                    }
+                }
+            }
+        }
+
+        self.ast_context.c_decls.iter()
+            .filter(|&(_, decl)| match decl.kind {
+                CDeclKind::Function { ref name, .. } =>
+                    rewritten_names.contains(name.as_str()) && !used_names.contains(name.as_str()),
+                _ => false,
+            })
+            .map(|(&decl_id, _)| decl_id)
+            .collect()
+    }
+
+    /// Translate a call to a C library function into the Rust described by `rewrite`
+    fn convert_rewritten_call(
+        &self,
+        use_: ExprUse,
+        ty: CQualTypeId,
+        rewrite: &CallRewrite,
+        rust_args: Vec<CallArg>,
+        args: &[CExprId],
+        is_static: bool,
+    ) -> Result<WithStmts<P<Expr>>, String> {
+        // C arguments the Rust call does not take are still evaluated for their side effects
+        let mut stmts = vec![];
+        let mut c_args: Vec<Option<P<Expr>>> = vec![];
+        for (index, &arg) in args.iter().enumerate() {
+            if rust_args.iter().any(|a| a.index == index) || rewrite.returns == Some(index) {
+                let WithStmts { stmts: ss, val } = self.convert_expr(ExprUse::RValue, arg, is_static, DecayRef::Default)?;
+                stmts.extend(ss);
+                c_args.push(Some(val));
+            } else {
+                let WithStmts { stmts: ss, .. } = self.convert_expr(ExprUse::Unused, arg, is_static, DecayRef::Default)?;
+                stmts.extend(ss);
+                c_args.push(None);
+            }
+        }
+
+        // The argument the C function returns is bound to a variable, so it is evaluated once
+        let returned = match rewrite.returns {
+            Some(index) if use_ != ExprUse::Unused => {
+                let name = self.renamer.borrow_mut().fresh();
+                let arg = mem::replace(&mut c_args[index], Some(mk().ident_expr(&name)));
+                stmts.push(mk().local_stmt(P(mk().local(mk().ident_pat(&name), None as Option<P<Ty>>, arg))));
+                Some(mk().ident_expr(&name))
+            }
+            _ => None,
+        };
+
+        let mut rust_args: Vec<P<Expr>> = rust_args.into_iter()
+            .map(|arg| {
+                let val = c_args[arg.index].clone().expect("Argument of a rewritten call not converted");
+                match arg.cast {
+                    Some(ty) => mk().cast_expr(val, mk().path_ty(ty.split("::").collect::<Vec<_>>())),
+                    None => val,
+                }
+            })
+            .collect();
+
+        let mut call = match rewrite.callee {
+            Callee::Function(ref path) => {
+                let mut segments = vec![""];
+                segments.extend(path.iter().map(|segment| {
+                    if segment == "std" { self.std_crate() } else { segment.as_str() }
+                }));
+                mk().call_expr(mk().path_expr(segments), rust_args)
+            }
+            Callee::Method(ref method) => {
+                let receiver = rust_args.remove(0);
+                mk().method_call_expr(receiver, method, rust_args)
+            }
+        };
+        for method in &rewrite.then {
+            call = mk().method_call_expr(call, method, vec![] as Vec<P<Expr>>);
+        }
+        if rewrite.cast_result {
+            call = mk().cast_expr(call, self.convert_type(ty.ctype)?);
+        }
+
+        if use_ == ExprUse::Unused {
+            stmts.push(mk().semi_stmt(call));
+            let val = self.panic("Function call expression is not supposed to be used");
+            Ok(WithStmts { stmts, val })
+        } else if let Some(returned) = returned {
+            stmts.push(mk().semi_stmt(call));
+            Ok(WithStmts { stmts, val: returned })
+        } else {
+            Ok(WithStmts { stmts, val: call })
+        }
+    }
+
+    /// Assign the value of a statement expression lowered into the CFG of the enclosing function
+    /// to a fresh variable, which then stands for the statement expression when translating the
+    /// expression around it. Returns the declaration of the variable (to be placed at the top of
+    /// the function) and the assignment.
+    pub fn bind_lowered_stmt_expr(&self, expr_id: CExprId, val: P<Expr>) -> Result<(Stmt, Stmt), String> {
+        let ty = self.ast_context[expr_id].kind.get_type()
+            .ok_or_else(|| format!("Statement expression {:?} has no type", expr_id))?;
+        let name = self.renamer.borrow_mut().fresh();
+        self.lowered_stmt_exprs.borrow_mut().insert(expr_id, mk().ident_expr(&name));
+
+        let init = self.implicit_default_expr(ty, false)?;
+        let local = mk().local(mk().mutbl().ident_pat(&name), Some(self.convert_type(ty)?), Some(init));
+        let assign = mk().semi_stmt(mk().assign_expr(mk().ident_expr(&name), val));
+        Ok((mk().local_stmt(P(local)), assign))
+    }
+
+    /// Like `bind_lowered_stmt_expr`, for statement expressions without a value to bind
+    pub fn set_lowered_stmt_expr(&self, expr_id: CExprId, val: P<Expr>) {
+        self.lowered_stmt_exprs.borrow_mut().insert(expr_id, val);
+    }
+
     fn convert_statement_expression(
         &self,
         use_: ExprUse,
@@ -3019,7 +4721,7 @@ impl Translation {
                        self.ast_context.is_function_pointer(source_ty_id) {
                         let source_ty = self.convert_type(source_ty_id)?;
                         let target_ty = self.convert_type(ty.ctype)?;
-                        Ok(transmute_expr(source_ty, target_ty, x))
+                        Ok(self.transmute_expr(source_ty, target_ty, x))
                     } else {
                         // Normal case
                         let target_ty = self.convert_type(ty.ctype)?;
@@ -3031,9 +4733,13 @@ impl Translation {
             CastKind::IntegralToPointer if self.ast_context.is_function_pointer(ty.ctype) => {
                 let target_ty = self.convert_type(ty.ctype)?;
                 Ok(val.map(|x| {
-                    let intptr_t = mk().path_ty(vec!["libc","intptr_t"]);
+                    let intptr_t = if self.tcfg.no_std || self.tcfg.fixed_width_ints {
+                        mk().path_ty(vec!["isize"])
+                    } else {
+                        mk().path_ty(vec!["libc","intptr_t"])
+                    };
                     let intptr = mk().cast_expr(x, intptr_t.clone());
-                    transmute_expr(intptr_t, target_ty, intptr)
+                    self.transmute_expr(intptr_t, target_ty, intptr)
                 }))
             }
 
@@ -3056,7 +4762,7 @@ impl Translation {
                     // unless the cast is to a function pointer then use `transmute`.
                     Ok(val.map(|x| {
                         if self.ast_context.is_function_pointer(source_ty_ctype_id) {
-                            transmute_expr(source_ty, target_ty, x)
+                            self.transmute_expr(source_ty, target_ty, x)
                         } else  {
                             mk().cast_expr(x, target_ty)
                         }
@@ -3104,6 +4810,16 @@ impl Translation {
                                 "as_mut_ptr"
                             };
 
+                            // On stable, static arrays decay by casting a reference to the array
+                            if is_static && self.tcfg.target_stable {
+                                let array_ty = self.convert_type(source_ty)?;
+                                let target_ty = self.convert_type(ty.ctype)?;
+                                return Ok(val.map(|x| {
+                                    let array_ptr = mk().cast_expr(mk().addr_of_expr(x), mk().ptr_ty(array_ty));
+                                    mk().cast_expr(array_ptr, target_ty)
+                                }))
+                            }
+
                             let mut call = val.map(|x| mk().method_call_expr(x, method, vec![] as Vec<P<Expr>>));
 
                             // Static arrays can now use as_ptr with the const_slice_as_ptr feature
@@ -3371,6 +5087,7 @@ impl Translation {
             // Find base element type of potentially nested arrays
             let inner = self.variable_array_base_type(elt);
 
+            self.require_std("Allocating a variable length array")?;
             let count = self.compute_size_of_expr(ty_id).unwrap();
             let val = self.implicit_default_expr(inner, is_static)?;
             let from_elem = mk().path_expr(vec!["", "std", "vec", "from_elem"]);
@@ -3593,14 +5310,14 @@ impl Translation {
                     one = n
                 }
 
-                let n = if up { one } else { mk().unary_expr(ast::UnOp::Neg, one) };
-                mk().method_call_expr(read.clone(), "offset", vec![n])
+                let n = if up { one } else { self.negate_expr(false, one) };
+                mk().method_call_expr(read.clone(), self.offset_method(), vec![n])
             } else {
-                if self.ast_context.resolve_type(ty.ctype).kind.is_unsigned_integral_type() {
-                    let m = if up { "wrapping_add" } else { "wrapping_sub" };
-                    mk().method_call_expr(read.clone(), m, vec![one])
+                let kind = &self.ast_context.resolve_type(ty.ctype).kind;
+                let k = if up { BinOpKind::Add } else { BinOpKind::Sub };
+                if kind.is_integral_type() {
+                    self.arith_expr(k, kind.is_unsigned_integral_type(), read.clone(), one)
                 } else {
-                    let k = if up { BinOpKind::Add } else { BinOpKind::Sub };
                     mk().binary_expr(k, read.clone(), one)
                 }
             };
@@ -3703,8 +5420,28 @@ impl Translation {
             c_ast::UnOp::PostDecrement => self.convert_post_increment(use_, cqual_type, false, arg),
             c_ast::UnOp::Deref => {
 
+                let indexed_element = if cqual_type.qualifiers.is_volatile {
+                    None
+                } else {
+                    self.indexed_array_element(arg)
+                };
+
                 if let CExprKind::Unary(_, c_ast::UnOp::AddressOf, arg_) = self.ast_context[arg].kind {
                     self.convert_expr(ExprUse::RValue, arg_, is_static, decay_ref)
+                } else if let Some((arr, index)) = indexed_element {
+                    // `*(buf + i)` becomes `buf[i as usize]` when `buf` never escapes
+                    let mut stmts = vec![];
+                    let index = match index {
+                        Some(index) => {
+                            let index = self.convert_expr(ExprUse::RValue, index, is_static, decay_ref)?;
+                            stmts.extend(index.stmts);
+                            cast_int(index.val, "usize")
+                        }
+                        None => mk().lit_expr(mk().int_lit(0, LitIntType::Unsuffixed)),
+                    };
+                    let arr = self.convert_expr(use_, arr, is_static, decay_ref)?;
+                    stmts.extend(arr.stmts);
+                    Ok(WithStmts { stmts, val: mk().index_expr(arr.val, index) })
                 } else {
                     self.convert_expr(ExprUse::RValue, arg, is_static, decay_ref)?.result_map(|val: P<Expr>| {
                         if let CTypeKind::Function(..) = self.ast_context.resolve_type(ctype).kind {
@@ -3728,12 +5465,13 @@ impl Translation {
 
             c_ast::UnOp::Negate => {
                 let val = self.convert_expr(ExprUse::RValue, arg, is_static, decay_ref)?;
-
-                if resolved_ctype.kind.is_unsigned_integral_type() {
-                    Ok(val.map(wrapping_neg_expr))
+                let is_unsigned = resolved_ctype.kind.is_unsigned_integral_type();
+                let is_integral = resolved_ctype.kind.is_integral_type();
+                Ok(val.map(|v| if is_integral {
+                    self.negate_expr(is_unsigned, v)
                 } else {
-                    Ok(val.map(neg_expr))
-                }
+                    mk().unary_expr(ast::UnOp::Neg, v)
+                }))
             }
             c_ast::UnOp::Complement =>
                 Ok(self.convert_expr(ExprUse::RValue, arg, is_static, decay_ref)?
@@ -3741,7 +5479,7 @@ impl Translation {
 
             c_ast::UnOp::Not => {
                 let val = self.convert_condition(false, arg, is_static)?;
-                Ok(val.map(|x| mk().cast_expr(x, mk().path_ty(vec!["libc", "c_int"]))))
+                Ok(val.map(|x| self.bool_to_int(x)))
             },
             c_ast::UnOp::Extension => {
                 let arg = self.convert_expr(use_, arg, is_static, decay_ref)?;
@@ -3773,12 +5511,12 @@ impl Translation {
             let lhs_type = self.convert_type(compute_lhs_ty.ctype)?;
             let lhs = mk().cast_expr(read, lhs_type.clone());
             let ty = self.convert_type(compute_res_ty.ctype)?;
-            let val = self.convert_binary_operator(bin_op, ty, compute_res_ty.ctype, compute_lhs_ty, rhs_ty, lhs, rhs, None);
+            let val = self.convert_binary_operator(bin_op, ty, compute_res_ty.ctype, compute_lhs_ty, rhs_ty, lhs, rhs, None)?;
 
             let is_enum_result = self.ast_context[self.ast_context.resolve_type_id(lhs_ty.ctype)].kind.is_enum();
             let result_type = self.convert_type(lhs_ty.ctype)?;
             let val = if is_enum_result {
-                transmute_expr(lhs_type, result_type, val)
+                self.transmute_expr(lhs_type, result_type, val)
             } else {
                 mk().cast_expr(val, result_type)
             };
@@ -3831,11 +5569,11 @@ impl Translation {
             _ => None,
         };
 
-        let is_unsigned_arith = match op {
+        // Arithmetic which isn't just a plain Rust operator needs to be desugared
+        let is_method_arith = match op {
             c_ast::BinOp::AssignAdd | c_ast::BinOp::AssignSubtract |
             c_ast::BinOp::AssignMultiply | c_ast::BinOp::AssignDivide |
-            c_ast::BinOp::AssignModulus
-             => compute_type_kind.is_unsigned_integral_type(),
+            c_ast::BinOp::AssignModulus => self.uses_arith_methods(compute_type_kind),
             _ => false,
         };
 
@@ -3844,7 +5582,7 @@ impl Translation {
                 use_ == ExprUse::RValue ||
                 pointer_lhs.is_some() ||
                 is_volatile_compound_assign ||
-                is_unsigned_arith {
+                is_method_arith {
             let WithStmts { val: (write, read), stmts: lhs_stmts } = self.name_reference_write_read(lhs)?;
             (write, read, lhs_stmts)
         } else {
@@ -3866,22 +5604,22 @@ impl Translation {
             c_ast::BinOp::Assign => self.volatile_write(&write, initial_lhs_type_id, rhs)?,
 
             // Anything volatile needs to be desugared into explicit reads and writes
-            op if is_volatile || is_unsigned_arith => {
+            op if is_volatile || is_method_arith => {
                 let op = op.underlying_assignment().expect("Cannot convert non-assignment operator");
 
                 let val = if compute_lhs_type_id.ctype == initial_lhs_type_id.ctype {
-                    self.convert_binary_operator(op, ty, qtype.ctype, initial_lhs_type_id, rhs_type_id, read.clone(), rhs, None)
+                    self.convert_binary_operator(op, ty, qtype.ctype, initial_lhs_type_id, rhs_type_id, read.clone(), rhs, None)?
                 } else {
                     let lhs_type = self.convert_type(compute_type.unwrap().ctype)?;
                     let write_type = self.convert_type(qtype.ctype)?;
                     let lhs = mk().cast_expr(read.clone(), lhs_type.clone());
                     let ty = self.convert_type(result_type_id.ctype)?;
-                    let val = self.convert_binary_operator(op, ty, result_type_id.ctype, compute_lhs_type_id, rhs_type_id, lhs, rhs, None);
+                    let val = self.convert_binary_operator(op, ty, result_type_id.ctype, compute_lhs_type_id, rhs_type_id, lhs, rhs, None)?;
 
                     let is_enum_result = self.ast_context[self.ast_context.resolve_type_id(qtype.ctype)].kind.is_enum();
                     let result_type = self.convert_type(qtype.ctype)?;
                     let val = if is_enum_result {
-                        transmute_expr(lhs_type, result_type, val)
+                        self.transmute_expr(lhs_type, result_type, val)
                     } else {
                         mk().cast_expr(val, result_type)
                     };
@@ -3899,22 +5637,13 @@ impl Translation {
             c_ast::BinOp::AssignAdd
             if pointer_lhs.is_some() =>
                 {
-                    let ptr = match self.compute_size_of_expr(pointer_lhs.unwrap().ctype) {
-                        Some(sz) => {
-                            let offset = mk().binary_expr(BinOpKind::Mul, cast_int(rhs,"isize"), cast_int(sz,"isize"));
-                            pointer_offset_isize(write.clone(), offset)
-                        },
-                        None => pointer_offset(write.clone(), rhs),
-                    };
+                    let ptr = self.offset_by_elements(write.clone(), pointer_lhs.unwrap().ctype, rhs, false);
                     mk().assign_expr(&write, ptr)
                 },
             c_ast::BinOp::AssignSubtract
             if pointer_lhs.is_some() => {
                 {
-                    let ptr = match self.compute_size_of_expr(pointer_lhs.unwrap().ctype) {
-                        Some(sz) => pointer_neg_offset_isize(write.clone(), mk().binary_expr(BinOpKind::Mul, cast_int(rhs,"isize"), cast_int(sz,"isize"))),
-                        None => pointer_neg_offset(write.clone(), rhs),
-                    };
+                    let ptr = self.offset_by_elements(write.clone(), pointer_lhs.unwrap().ctype, rhs, true);
                     mk().assign_expr(&write, ptr)
                 }
             },
@@ -3950,23 +5679,24 @@ impl Translation {
         lhs: P<Expr>,
         rhs: P<Expr>,
         lhs_rhs_ids: Option<(CExprId, CExprId)>,
-    ) -> P<Expr> {
+    ) -> Result<P<Expr>, String> {
+        let is_integral_type = self.ast_context.resolve_type(ctype).kind.is_integral_type();
         let is_unsigned_integral_type = self.ast_context.index(ctype).kind.is_unsigned_integral_type();
 
-        match op {
+        Ok(match op {
             c_ast::BinOp::Add => self.convert_addition(lhs_type, rhs_type, lhs, rhs),
-            c_ast::BinOp::Subtract => self.convert_subtraction(ty, lhs_type, rhs_type, lhs, rhs),
+            c_ast::BinOp::Subtract => self.convert_subtraction(ty, lhs_type, rhs_type, lhs, rhs)?,
 
-            c_ast::BinOp::Multiply if is_unsigned_integral_type =>
-                mk().method_call_expr(lhs, mk().path_segment("wrapping_mul"), vec![rhs]),
+            c_ast::BinOp::Multiply if is_integral_type =>
+                self.arith_expr(BinOpKind::Mul, is_unsigned_integral_type, lhs, rhs),
             c_ast::BinOp::Multiply => mk().binary_expr(BinOpKind::Mul, lhs, rhs),
 
-            c_ast::BinOp::Divide if is_unsigned_integral_type =>
-                mk().method_call_expr(lhs, mk().path_segment("wrapping_div"), vec![rhs]),
+            c_ast::BinOp::Divide if is_integral_type =>
+                self.arith_expr(BinOpKind::Div, is_unsigned_integral_type, lhs, rhs),
             c_ast::BinOp::Divide => mk().binary_expr(BinOpKind::Div, lhs, rhs),
 
-            c_ast::BinOp::Modulus if is_unsigned_integral_type =>
-                mk().method_call_expr(lhs, mk().path_segment("wrapping_rem"), vec![rhs]),
+            c_ast::BinOp::Modulus if is_integral_type =>
+                self.arith_expr(BinOpKind::Rem, is_unsigned_integral_type, lhs, rhs),
             c_ast::BinOp::Modulus => mk().binary_expr(BinOpKind::Rem, lhs, rhs),
 
             c_ast::BinOp::BitXor => mk().binary_expr(BinOpKind::BitXor, lhs, rhs),
@@ -3994,7 +5724,7 @@ impl Translation {
                     mk().binary_expr(BinOpKind::Eq, lhs, rhs)
                 };
 
-                bool_to_int(expr)
+                self.bool_to_int(expr)
             },
             c_ast::BinOp::NotEqual => {
                 // Using is_some method for null comparison means we don't have to
@@ -4016,18 +5746,18 @@ impl Translation {
                     mk().binary_expr(BinOpKind::Ne, lhs, rhs)
                 };
 
-                bool_to_int(expr)
+                self.bool_to_int(expr)
             },
-            c_ast::BinOp::Less => bool_to_int(mk().binary_expr(BinOpKind::Lt, lhs, rhs)),
-            c_ast::BinOp::Greater => bool_to_int(mk().binary_expr(BinOpKind::Gt, lhs, rhs)),
-            c_ast::BinOp::GreaterEqual => bool_to_int(mk().binary_expr(BinOpKind::Ge, lhs, rhs)),
-            c_ast::BinOp::LessEqual => bool_to_int(mk().binary_expr(BinOpKind::Le, lhs, rhs)),
+            c_ast::BinOp::Less => self.bool_to_int(mk().binary_expr(BinOpKind::Lt, lhs, rhs)),
+            c_ast::BinOp::Greater => self.bool_to_int(mk().binary_expr(BinOpKind::Gt, lhs, rhs)),
+            c_ast::BinOp::GreaterEqual => self.bool_to_int(mk().binary_expr(BinOpKind::Ge, lhs, rhs)),
+            c_ast::BinOp::LessEqual => self.bool_to_int(mk().binary_expr(BinOpKind::Le, lhs, rhs)),
 
             c_ast::BinOp::BitAnd => mk().binary_expr(BinOpKind::BitAnd, lhs, rhs),
             c_ast::BinOp::BitOr => mk().binary_expr(BinOpKind::BitOr, lhs, rhs),
 
             op => unimplemented!("Translation of binary operator {:?}", op),
-        }
+        })
     }
 
     fn convert_addition(
@@ -4041,23 +5771,11 @@ impl Translation {
         let rhs_type = &self.ast_context.resolve_type(rhs_type_id.ctype).kind;
 
         if let &CTypeKind::Pointer(pointee) = lhs_type {
-            match self.compute_size_of_expr(pointee.ctype) {
-                Some(sz) => {
-                    let rhs = mk().binary_expr(BinOpKind::Mul, cast_int(rhs, "isize"), cast_int(sz, "isize"));
-                    pointer_offset_isize(lhs, rhs)
-                }
-                None => pointer_offset(lhs, rhs)
-            }
+            self.offset_by_elements(lhs, pointee.ctype, rhs, false)
         } else if let &CTypeKind::Pointer(pointee) = rhs_type {
-            match self.compute_size_of_expr(pointee.ctype) {
-                Some(sz) => {
-                    let lhs = mk().binary_expr(BinOpKind::Mul, cast_int(lhs, "isize"), cast_int(sz, "isize"));
-                    pointer_offset_isize(rhs, lhs)
-                }
-                None => pointer_offset(rhs, lhs),
-            }
-        } else if lhs_type.is_unsigned_integral_type() {
-            mk().method_call_expr(lhs, mk().path_segment("wrapping_add"), vec![rhs])
+            self.offset_by_elements(rhs, pointee.ctype, lhs, false)
+        } else if lhs_type.is_integral_type() {
+            self.arith_expr(BinOpKind::Add, lhs_type.is_unsigned_integral_type(), lhs, rhs)
         } else {
             mk().binary_expr(BinOpKind::Add, lhs, rhs)
         }
@@ -4070,33 +5788,106 @@ impl Translation {
         rhs_type_id: CQualTypeId,
         lhs: P<Expr>,
         rhs: P<Expr>,
-    ) -> P<Expr> {
+    ) -> Result<P<Expr>, String> {
         let lhs_type = &self.ast_context.resolve_type(lhs_type_id.ctype).kind;
         let rhs_type = &self.ast_context.resolve_type(rhs_type_id.ctype).kind;
 
         if let &CTypeKind::Pointer(pointee) = rhs_type {
-            // The wrapping_offset_from method is locked behind a feature gate
-            // and replaces the now deprecated offset_to (opposite argument order)
-            // wrapping_offset_from panics when the pointee is a ZST
-            self.use_feature("ptr_wrapping_offset_from");
+            let mut offset = if self.tcfg.target_stable {
+                // Divide the distance in bytes by the size of the (non-VLA) elements, which also
+                // panics when the pointee is a ZST
+                let elt_size = self.compute_size_of_type(self.variable_array_base_type(pointee.ctype))?.val;
+                let bytes = mk().method_call_expr(
+                    cast_int(lhs, "isize"), "wrapping_sub", vec![cast_int(rhs, "isize")]);
+                mk().binary_expr(BinOpKind::Div, bytes, cast_int(elt_size, "isize"))
+            } else {
+                // The wrapping_offset_from method is locked behind a feature gate
+                // and replaces the now deprecated offset_to (opposite argument order)
+                // wrapping_offset_from panics when the pointee is a ZST
+                self.use_feature("ptr_wrapping_offset_from");
 
-            let mut offset = mk().method_call_expr(lhs, "wrapping_offset_from", vec![rhs]);
+                mk().method_call_expr(lhs, "wrapping_offset_from", vec![rhs])
+            };
 
             if let Some(sz) = self.compute_size_of_expr(pointee.ctype) {
                 offset = mk().binary_expr(BinOpKind::Div, offset, cast_int(sz, "isize"))
             }
 
-            mk().cast_expr(offset, ty)
+            Ok(mk().cast_expr(offset, ty))
         } else if let &CTypeKind::Pointer(pointee) = lhs_type {
-            match self.compute_size_of_expr(pointee.ctype) {
-                None => pointer_neg_offset(lhs, rhs),
-                Some(sz) => pointer_neg_offset_isize(lhs, mk().binary_expr(BinOpKind::Mul, cast_int(rhs,"isize"), cast_int(sz, "isize"))),
-            }
-        } else if lhs_type.is_unsigned_integral_type() {
-            mk().method_call_expr(lhs, mk().path_segment("wrapping_sub"), vec![rhs])
+            Ok(self.offset_by_elements(lhs, pointee.ctype, rhs, true))
+        } else if lhs_type.is_integral_type() {
+            Ok(self.arith_expr(BinOpKind::Sub, lhs_type.is_unsigned_integral_type(), lhs, rhs))
         } else {
-            mk().binary_expr(BinOpKind::Sub, lhs, rhs)
+            Ok(mk().binary_expr(BinOpKind::Sub, lhs, rhs))
+        }
+    }
+
+    /// Whether arithmetic on a type is translated to method calls (which can't be used in
+    /// constant expressions) rather than to plain Rust operators
+    fn uses_arith_methods(&self, kind: &CTypeKind) -> bool {
+        kind.is_unsigned_integral_type() ||
+            (kind.is_integral_type() && self.tcfg.overflow_mode != OverflowMode::Faithful)
+    }
+
+    /// Integer `+`, `-`, `*`, `/`, or `%` with the overflow semantics of `tcfg.overflow_mode`
+    fn arith_expr(&self, op: BinOpKind, is_unsigned: bool, lhs: P<Expr>, rhs: P<Expr>) -> P<Expr> {
+        let (name, verb) = match op {
+            BinOpKind::Add => ("add", "add"),
+            BinOpKind::Sub => ("sub", "subtract"),
+            BinOpKind::Mul => ("mul", "multiply"),
+            BinOpKind::Div => ("div", "divide"),
+            BinOpKind::Rem => ("rem", "calculate the remainder"),
+            _ => return mk().binary_expr(op, lhs, rhs),
+        };
+        match self.tcfg.overflow_mode {
+            OverflowMode::Faithful if !is_unsigned => mk().binary_expr(op, lhs, rhs),
+            OverflowMode::Faithful | OverflowMode::Wrapping =>
+                mk().method_call_expr(lhs, &*format!("wrapping_{}", name), vec![rhs]),
+            // Rust's own `/` and `%` already panic, telling division by zero apart from overflow
+            OverflowMode::Checked if op == BinOpKind::Div || op == BinOpKind::Rem =>
+                mk().binary_expr(op, lhs, rhs),
+            OverflowMode::Checked => {
+                let checked = mk().method_call_expr(lhs, &*format!("checked_{}", name), vec![rhs]);
+                let msg = mk().lit_expr(mk().str_lit(&format!("attempt to {} with overflow", verb)));
+                mk().method_call_expr(checked, "expect", vec![msg])
+            }
+        }
+    }
+
+    /// Integer negation with the overflow semantics of `tcfg.overflow_mode`
+    fn negate_expr(&self, is_unsigned: bool, arg: P<Expr>) -> P<Expr> {
+        match self.tcfg.overflow_mode {
+            OverflowMode::Faithful if !is_unsigned => mk().unary_expr(ast::UnOp::Neg, arg),
+            OverflowMode::Faithful | OverflowMode::Wrapping =>
+                mk().method_call_expr(arg, "wrapping_neg", vec![] as Vec<P<Expr>>),
+            OverflowMode::Checked => {
+                let checked = mk().method_call_expr(arg, "checked_neg", vec![] as Vec<P<Expr>>);
+                let msg = mk().lit_expr(mk().str_lit("attempt to negate with overflow"));
+                mk().method_call_expr(checked, "expect", vec![msg])
+            }
+        }
+    }
+
+    /// The pointer method used to offset pointers: `wrapping_offset` when overflow wraps, and
+    /// `offset` otherwise
+    fn offset_method(&self) -> &'static str {
+        match self.tcfg.overflow_mode {
+            OverflowMode::Wrapping => "wrapping_offset",
+            OverflowMode::Faithful | OverflowMode::Checked => "offset",
+        }
+    }
+
+    /// Offset a pointer forwards (or backwards, if `negate`) by `n` elements of type `pointee`
+    fn offset_by_elements(&self, ptr: P<Expr>, pointee: CTypeId, n: P<Expr>, negate: bool) -> P<Expr> {
+        let mut offset = cast_int(n, "isize");
+        if let Some(sz) = self.compute_size_of_expr(pointee) {
+            offset = self.arith_expr(BinOpKind::Mul, false, offset, cast_int(sz, "isize"));
         }
+        if negate {
+            offset = self.negate_expr(false, offset);
+        }
+        pointer_offset_isize(self.offset_method(), ptr, offset)
     }
 
     /// Convert a boolean expression to a boolean for use in && or || or if
@@ -4168,6 +5959,11 @@ impl Translation {
         result
     }
 
+    /// The name to give a parameter or local variable called `ident` in C
+    fn local_name(&self, ident: &str) -> String {
+        if self.tcfg.normalize_names { to_snake_case(ident) } else { ident.to_owned() }
+    }
+
     /// This predicate checks for control-flow statements under a declaration
     /// that will require relooper to be enabled to be handled.
     fn function_requires_relooper(&self, stmt_ids: &[CStmtId]) -> bool {
@@ -4184,16 +5980,17 @@ impl Translation {
     }
 
     fn mk_int_lit(&self, ty: CQualTypeId, val: u64, base: IntBase) -> P<Expr> {
-        // Note that C doesn't have anything smaller than integer literals
-        let (intty,suffix) = match self.ast_context.resolve_type(ty.ctype).kind {
-                CTypeKind::Int => (LitIntType::Signed(IntTy::I32), "i32"),
-                CTypeKind::Long => (LitIntType::Signed(IntTy::I64), "i64"),
-                CTypeKind::LongLong => (LitIntType::Signed(IntTy::I64), "i64"),
-                CTypeKind::UInt => (LitIntType::Unsigned(UintTy::U32), "u32"),
-                CTypeKind::ULong => (LitIntType::Unsigned(UintTy::U64), "u64"),
-                CTypeKind::ULongLong => (LitIntType::Unsigned(UintTy::U64), "u64"),
-                _ => (LitIntType::Unsuffixed, ""),
-            };
+        // Note that C doesn't have anything smaller than integer literals. The widths of the
+        // types differ between targets.
+        let (intty, suffix) = match self.ast_context.int_format(ty.ctype) {
+            Some((16, true)) => (LitIntType::Signed(IntTy::I16), "i16"),
+            Some((32, true)) => (LitIntType::Signed(IntTy::I32), "i32"),
+            Some((64, true)) => (LitIntType::Signed(IntTy::I64), "i64"),
+            Some((16, false)) => (LitIntType::Unsigned(UintTy::U16), "u16"),
+            Some((32, false)) => (LitIntType::Unsigned(UintTy::U32), "u32"),
+            Some((64, false)) => (LitIntType::Unsigned(UintTy::U64), "u64"),
+            _ => (LitIntType::Unsuffixed, ""),
+        };
 
         let lit = match base {
             IntBase::Dec => mk().int_lit(val.into(), intty),
@@ -4204,3 +6001,170 @@ impl Translation {
         mk().lit_expr(lit)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn located<T>(kind: T) -> Located<T> {
+        Located { loc: Some(SrcLoc { fileid: 0, line: 1, column: 1 }), kind }
+    }
+
+    /// `long labs(long x);` followed by `long f(long y) { return labs(y); }`, and a definition of
+    /// `labs` at the end when `defined`
+    fn calling_labs(defined: bool) -> TypedAstContext {
+        let mut context = TypedAstContext::new();
+        context.c_files.insert(0, "t.c".to_string());
+        let ty = |id| CQualTypeId { qualifiers: Qualifiers::default(), ctype: CTypeId(id) };
+        context.c_types.insert(CTypeId(1), located(CTypeKind::Long));
+        context.c_types.insert(CTypeId(2), located(CTypeKind::Function(ty(1), vec![ty(1)], false, false)));
+        context.c_types.insert(CTypeId(3), located(CTypeKind::Pointer(ty(2))));
+
+        let function = |name: &str, parameters, body| CDeclKind::Function {
+            is_extern: true, is_inline: false, is_implicit: false, typ: CTypeId(2),
+            name: name.to_string(), parameters, body,
+        };
+        let parameter = |name: &str| CDeclKind::Variable {
+            is_static: false, is_extern: false, is_defn: true, ident: name.to_string(),
+            initializer: None, typ: ty(1),
+        };
+        let rvalue = |context: &mut TypedAstContext, id, decl| {
+            context.c_exprs.insert(CExprId(id), located(CExprKind::DeclRef(ty(1), decl)));
+            context.c_exprs.insert(CExprId(id + 1), located(CExprKind::ImplicitCast(ty(1), CExprId(id), CastKind::LValueToRValue, None)));
+            CExprId(id + 1)
+        };
+
+        context.c_decls.insert(CDeclId(10), located(function("labs", vec![], None)));
+        context.c_decls.insert(CDeclId(21), located(parameter("y")));
+        context.c_exprs.insert(CExprId(1), located(CExprKind::DeclRef(ty(2), CDeclId(10))));
+        context.c_exprs.insert(CExprId(2), located(CExprKind::ImplicitCast(ty(3), CExprId(1), CastKind::FunctionToPointerDecay, None)));
+        let y = rvalue(&mut context, 3, CDeclId(21));
+        context.c_exprs.insert(CExprId(5), located(CExprKind::Call(ty(1), CExprId(2), vec![y])));
+        context.c_stmts.insert(CStmtId(1), located(CStmtKind::Return(Some(CExprId(5)))));
+        context.c_stmts.insert(CStmtId(2), located(CStmtKind::Compound(vec![CStmtId(1)])));
+        context.c_decls.insert(CDeclId(20), located(function("f", vec![CDeclId(21)], Some(CStmtId(2)))));
+        context.c_decls_top = vec![CDeclId(10), CDeclId(20)];
+
+        if defined {
+            context.c_decls.insert(CDeclId(12), located(parameter("x")));
+            let x = rvalue(&mut context, 6, CDeclId(12));
+            context.c_stmts.insert(CStmtId(3), located(CStmtKind::Return(Some(x))));
+            context.c_stmts.insert(CStmtId(4), located(CStmtKind::Compound(vec![CStmtId(3)])));
+            context.c_decls.insert(CDeclId(11), located(function("labs", vec![CDeclId(12)], Some(CStmtId(4)))));
+            context.c_decls_top.push(CDeclId(11));
+        }
+        context
+    }
+
+    /// Functions `f0` to `f5`, each returning its number under a comment and ending with a
+    /// commented empty statement
+    fn commented_functions() -> TypedAstContext {
+        let mut context = TypedAstContext::new();
+        context.c_files.insert(0, "t.c".to_string());
+        let int = CQualTypeId { qualifiers: Qualifiers::default(), ctype: CTypeId(1) };
+        context.c_types.insert(CTypeId(1), located(CTypeKind::Int));
+        context.c_types.insert(CTypeId(2), located(CTypeKind::Function(int, vec![], false, false)));
+
+        let at = |line, column| Some(SrcLoc { fileid: 0, line, column });
+        for i in 0..6 {
+            let line = 10 * i + 1;
+            let lit = CExprId(i);
+            context.c_exprs.insert(lit, Located { loc: at(line + 2, 12), kind: CExprKind::Literal(int, CLiteral::Integer(i, IntBase::Dec)) });
+            context.c_stmts.insert(CStmtId(3 * i), Located { loc: at(line + 2, 5), kind: CStmtKind::Return(Some(lit)) });
+            context.c_stmts.insert(CStmtId(3 * i + 1), Located { loc: at(line + 4, 5), kind: CStmtKind::Empty });
+            let body = CStmtId(3 * i + 2);
+            context.c_stmts.insert(body, Located { loc: at(line, 10), kind: CStmtKind::Compound(vec![CStmtId(3 * i), CStmtId(3 * i + 1)]) });
+            let function = CDeclId(i);
+            context.c_decls.insert(function, Located { loc: at(line, 1), kind: CDeclKind::Function {
+                is_extern: true, is_inline: false, is_implicit: false, typ: CTypeId(2),
+                name: format!("f{}", i), parameters: vec![], body: Some(body),
+            }});
+            context.c_decls_top.push(function);
+            for &(offset, text) in &[(1, "// returns"), (3, "// after the return")] {
+                context.comments.push(Located { loc: at(line + offset, 5), kind: format!("{} {}", text, i) });
+            }
+        }
+        context
+    }
+
+    #[test]
+    fn parallel_translation_matches_sequential() {
+        let tcfg = |jobs| TranslationConfig { jobs, ..TranslationConfig::default() };
+        let sequential = translate_to_text(commented_functions(), tcfg(1)).unwrap().rust;
+        for i in 0..6 {
+            assert!(sequential.contains(&format!("// after the return {}", i)), "{}", sequential);
+        }
+        for &jobs in &[2, 4] {
+            assert_eq!(translate_to_text(commented_functions(), tcfg(jobs)).unwrap().rust, sequential);
+        }
+    }
+
+    #[test]
+    fn failures_with_fail_on_error_are_returned() {
+        let mut context = commented_functions();
+        context.c_main = Some(CDeclId(0));
+        let tcfg = |fail_on_error| TranslationConfig {
+            translate_entry: true, no_std: true, fail_on_error, jobs: 2, ..TranslationConfig::default()
+        };
+
+        let translated = translate_to_text(context.clone(), tcfg(false)).unwrap();
+        assert!(translated.diagnostics.iter().any(|d| d.contains("needs `std`")), "{:?}", translated.diagnostics);
+
+        let failure = translate_to_text(context, tcfg(true)).err().unwrap();
+        assert!(failure.contains("needs `std`"), "{}", failure);
+    }
+
+    /// `void vprint(va_list args) {}`
+    fn taking_va_list() -> TypedAstContext {
+        let mut context = TypedAstContext::new();
+        context.c_files.insert(0, "t.c".to_string());
+        let ty = |id| CQualTypeId { qualifiers: Qualifiers::default(), ctype: CTypeId(id) };
+        context.c_decls.insert(CDeclId(1), located(CDeclKind::Struct {
+            name: Some("__va_list_tag".to_string()), fields: None, is_packed: false, manual_alignment: None,
+        }));
+        context.c_types.insert(CTypeId(1), located(CTypeKind::Void));
+        context.c_types.insert(CTypeId(2), located(CTypeKind::Struct(CDeclId(1))));
+        context.c_types.insert(CTypeId(3), located(CTypeKind::Pointer(ty(2))));
+        context.c_types.insert(CTypeId(4), located(CTypeKind::Function(ty(1), vec![ty(3)], false, false)));
+        context.c_decls.insert(CDeclId(3), located(CDeclKind::Variable {
+            is_static: false, is_extern: false, is_defn: true, ident: "args".to_string(),
+            initializer: None, typ: ty(3),
+        }));
+        context.c_decls.insert(CDeclId(2), located(CDeclKind::Function {
+            is_extern: true, is_inline: false, is_implicit: false, typ: CTypeId(4),
+            name: "vprint".to_string(), parameters: vec![CDeclId(3)], body: Some(CStmtId(1)),
+        }));
+        context.c_stmts.insert(CStmtId(1), located(CStmtKind::Compound(vec![])));
+        context.c_decls_top = vec![CDeclId(2)];
+        context
+    }
+
+    #[test]
+    fn va_list_needs_nightly() {
+        let tcfg = |target_stable| TranslationConfig {
+            translate_valist: true, target_stable, ..TranslationConfig::default()
+        };
+
+        let nightly = translate_to_text(taking_va_list(), tcfg(false)).unwrap();
+        assert!(nightly.rust.contains("VaList"), "{}", nightly.rust);
+        assert!(nightly.features.contains(&"c_variadic"), "{:?}", nightly.features);
+
+        let stable = translate_to_text(taking_va_list(), tcfg(true)).unwrap();
+        assert!(!stable.rust.contains("fn vprint"), "{}", stable.rust);
+        assert!(!stable.features.contains(&"c_variadic"), "{:?}", stable.features);
+        assert!(stable.diagnostics.iter().any(|d| d.contains("`c_variadic`")), "{:?}", stable.diagnostics);
+    }
+
+    #[test]
+    fn calls_to_defined_functions_are_not_rewritten() {
+        let tcfg = || TranslationConfig { call_rewrites: Some(CallRewrites::libc()), ..TranslationConfig::default() };
+
+        let rewritten = translate_to_text(calling_labs(false), tcfg()).unwrap().rust;
+        assert!(rewritten.contains("wrapping_abs"), "{}", rewritten);
+        assert!(!rewritten.contains("fn labs"), "{}", rewritten);
+
+        let called = translate_to_text(calling_labs(true), tcfg()).unwrap().rust;
+        assert!(called.contains("labs(y)"), "{}", called);
+        assert!(!called.contains("wrapping_abs"), "{}", called);
+    }
+}
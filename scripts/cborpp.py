@@ -149,8 +149,11 @@ def _main():
     except cbor2.CBORDecodeError as de:
         die("CBOR decoding error:" + str(de))
 
+    # the schema version comes first, followed by the nodes
+    print("schema version:", array[0])
+
     # translate tags
-    for e in array:
+    for e in array[1]:
         assert len(e) >= 2
         e[1] = TAGS[e[1]] if e[1] in TAGS else "MissingTag"
 
@@ -53,10 +53,12 @@ class TestOutcome(Enum):
 
 class CborFile:
     def __init__(self, path: str, enable_relooper: bool = False,
-                 disallow_current_block: bool = False) -> None:
+                 disallow_current_block: bool = False,
+                 translator_args: List[str] = None) -> None:
         self.path = path
         self.enable_relooper = enable_relooper
         self.disallow_current_block = disallow_current_block
+        self.translator_args = translator_args or []
 
     def translate(self) -> RustFile:
         c_file_path, _ = os.path.splitext(self.path)
@@ -83,6 +85,7 @@ class CborFile:
             #  args.append("--use-c-multiple-info")
         if self.disallow_current_block:
             args.append("--fail-on-multiple")
+        args.extend(self.translator_args)
 
         with pb.local.env(RUST_BACKTRACE='1', LD_LIBRARY_PATH=ld_lib_path):
             # log the command in a format that's easy to re-run
@@ -97,6 +100,13 @@ class CborFile:
         if retcode != 0:
             raise NonZeroReturn(stderr)
 
+        # output for stable rust has to build without feature gates
+        if "--target-stable" in self.translator_args:
+            with open(rust_src) as rust_file:
+                if any(line.startswith("#![feature") for line in rust_file):
+                    raise NonZeroReturn(
+                        "--target-stable output uses nightly features")
+
         return RustFile(extensionless_file + ".rs")
 
 
@@ -116,6 +126,8 @@ class CFile:
         self.path = path
         self.enable_relooper = "enable_relooper" in flags
         self.disallow_current_block = "disallow_current_block" in flags
+        # flags like `--split-irreducible=100` are passed on to the importer
+        self.translator_args = sorted(f for f in flags if f.startswith("--"))
 
     def export(self) -> CborFile:
         ast_exporter = get_cmd_or_die(c.AST_EXPO)
@@ -139,7 +151,7 @@ class CFile:
             raise NonZeroReturn(stderr)
 
         return CborFile(self.path + ".cbor", self.enable_relooper,
-                        self.disallow_current_block)
+                        self.disallow_current_block, self.translator_args)
 
 
 def build_static_library(c_files: Iterable[CFile],
@@ -0,0 +1,56 @@
+//! --index-arrays
+
+struct point {
+    int x;
+    int coords[2];
+};
+
+static int table[4][3];
+
+static int sum(const int *values, int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++)
+        total += values[i];
+    return total;
+}
+
+static void bump(int *x) {
+    (*x)++;
+}
+
+// `table`, `squares` and `kept` are only indexed, so they become Rust arrays; the
+// pointers into `points` and `rows` escape, so those stay pointer offsets.
+void indexed(unsigned buffer_size, int buffer[]) {
+    int squares[5];
+    struct point kept[2];
+    struct point points[3];
+    int rows[2][2] = { { 1, 2 }, { 3, 4 } };
+
+    if (buffer_size < 7)
+        return;
+
+    for (int i = 0; i < 5; i++)
+        *(squares + i) = i * i;
+    for (int i = 0; i < 4; i++)
+        for (int j = 0; j < 3; j++)
+            table[i][j] = i + j;
+    for (int i = 0; i < 2; i++) {
+        kept[i].x = i;
+        kept[i].coords[0] = 2 * i;
+        kept[i].coords[1] = 3 * i;
+    }
+    for (int i = 0; i < 3; i++) {
+        points[i].x = i;
+        points[i].coords[0] = i;
+        points[i].coords[1] = -i;
+    }
+
+    bump(&points[1].x);
+    buffer[0] = points[1].x;
+    buffer[1] = sum(points[2].coords, 2);
+    buffer[2] = sum(rows[1], 2);
+    buffer[3] = squares[4] + *squares;
+    buffer[4] = table[3][2] + sizeof table / sizeof table[0];
+    buffer[5] = kept[1].x + kept[1].coords[0] + kept[1].coords[1];
+    buffer[6] = sizeof(kept);
+}
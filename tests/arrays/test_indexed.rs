@@ -0,0 +1,26 @@
+extern crate libc;
+
+use indexed::rust_indexed;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn indexed(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 7;
+
+pub fn test_indexed() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        indexed(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_indexed(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [2, 0, 7, 16, 9, 6, 24]);
+}
@@ -0,0 +1,51 @@
+//! enable_relooper
+
+// Loops left from several places through gotos, which the relooper dispatches
+// on with a `current_block` variable unless it knows which exit was taken.
+static int find(const int *values, int n, int wanted) {
+    int i;
+    for (i = 0; i < n; i++) {
+        if (values[i] == wanted)
+            goto found;
+        if (values[i] < 0)
+            goto negative;
+    }
+    return -1;
+found:
+    return i;
+negative:
+    return -2 - i;
+}
+
+static int classify(int x) {
+    int result = 0;
+    if (x > 100)
+        goto big;
+    if (x < 0)
+        goto small;
+    result = 1;
+    goto done;
+big:
+    result = 2;
+    goto done;
+small:
+    result = 3;
+done:
+    return result * 10 + (x & 1);
+}
+
+void early_exits(unsigned buffer_size, int buffer[]) {
+    int values[] = { 4, 8, 15, 16, -23, 42 };
+
+    if (buffer_size < 8)
+        return;
+
+    buffer[0] = find(values, 6, 15);
+    buffer[1] = find(values, 6, 42);
+    buffer[2] = find(values, 4, 42);
+    buffer[3] = find(values, 0, 4);
+    buffer[4] = classify(7);
+    buffer[5] = classify(101);
+    buffer[6] = classify(-4);
+    buffer[7] = classify(50);
+}
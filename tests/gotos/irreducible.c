@@ -0,0 +1,20 @@
+//! enable_relooper, disallow_current_block, --split-irreducible=100
+
+// Jumping into the middle of a loop makes its control flow irreducible. Splitting
+// the loop body should let this translate without `current_block`.
+int irreducible(int n, int skip_first) {
+    int total = 0;
+    int i = 0;
+
+    if (skip_first)
+        goto middle;
+
+    while (i < n) {
+        total += i;
+    middle:
+        total *= 2;
+        i++;
+    }
+
+    return total;
+}
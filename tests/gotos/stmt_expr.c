@@ -19,3 +19,13 @@ lbl: ;
 
   return x + y;
 }
+
+// Both statement expressions jump out to `fail`, so the blocks assigning their values
+// are not in the scope of the addition using them.
+int two_stmt_exprs(int a, int b) {
+  int sum = ({ if (a < 0) goto fail; a * 2; }) + ({ if (b < 0) goto fail; b * 3; });
+  return sum;
+
+fail:
+  return -1;
+}
@@ -0,0 +1,27 @@
+//! enable_relooper, disallow_current_block
+
+// The cases fall through into each other, so the bodies are laid out in labelled
+// blocks after the `match`. The `continue` has to name the loop to leave them.
+int switch_in_loop(int n) {
+    int total = 0;
+    int i;
+
+    for (i = 0; i < n; i++) {
+        switch (i % 4) {
+            case 0:
+                total += 1;
+            case 1:
+                if (i > 4)
+                    continue;
+                total += 10;
+                break;
+            case 2:
+                total += 100;
+            default:
+                total += 1000;
+        }
+        total *= 2;
+    }
+
+    return total;
+}
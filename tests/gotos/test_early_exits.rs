@@ -0,0 +1,26 @@
+extern crate libc;
+
+use early_exits::rust_early_exits;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn early_exits(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 8;
+
+pub fn test_early_exits() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        early_exits(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_early_exits(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [2, -6, -1, -1, 11, 21, 30, 10]);
+}
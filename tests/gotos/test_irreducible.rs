@@ -0,0 +1,21 @@
+extern crate libc;
+
+use irreducible::rust_irreducible;
+
+use self::libc::c_int;
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn irreducible(_: c_int, _: c_int) -> c_int;
+}
+
+pub fn test_irreducible() {
+    for n in 0..6 {
+        for skip_first in 0..2 {
+            let expected = unsafe { irreducible(n, skip_first) };
+            let actual = unsafe { rust_irreducible(n, skip_first) };
+            assert_eq!(expected, actual);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 extern crate libc;
 
-use stmt_expr::rust_stmt_expr_func;
+use stmt_expr::{rust_stmt_expr_func, rust_two_stmt_exprs};
 
 use self::libc::c_int;
 
@@ -12,3 +12,11 @@ pub fn test_stmt_expr_relooper() {
         assert_eq!(rust_stmt_expr_func(3), 29);
     }
 }
+
+pub fn test_two_stmt_exprs() {
+    unsafe {
+        assert_eq!(rust_two_stmt_exprs(1, 2), 8);
+        assert_eq!(rust_two_stmt_exprs(-1, 2), -1);
+        assert_eq!(rust_two_stmt_exprs(1, -2), -1);
+    }
+}
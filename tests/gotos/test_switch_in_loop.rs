@@ -0,0 +1,19 @@
+extern crate libc;
+
+use switch_in_loop::rust_switch_in_loop;
+
+use self::libc::c_int;
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn switch_in_loop(_: c_int) -> c_int;
+}
+
+pub fn test_switch_in_loop() {
+    for n in 0..12 {
+        let expected = unsafe { switch_in_loop(n) };
+        let actual = unsafe { rust_switch_in_loop(n) };
+        assert_eq!(expected, actual);
+    }
+}
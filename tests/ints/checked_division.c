@@ -0,0 +1,21 @@
+//! --overflow=checked
+
+// With checked overflow, division and remainder go through Rust's `/` and `%`,
+// which panic on division by zero and on overflow, and otherwise match C.
+void checked_division(unsigned buffer_size, int buffer[]) {
+    int i = 0;
+    for (int a = -7; a <= 7; a += 2) {
+        for (int b = -3; b <= 3; b++) {
+            if (b == 0 || i + 2 > buffer_size)
+                continue;
+            buffer[i++] = a / b;
+            buffer[i++] = a % b;
+        }
+    }
+
+    unsigned u = 4000000000u;
+    if (i + 2 <= buffer_size) {
+        buffer[i++] = (int)(u / 7u);
+        buffer[i++] = (int)(u % 7u);
+    }
+}
@@ -0,0 +1,39 @@
+//! --fixed-width-ints
+
+#include <stddef.h>
+#include <stdint.h>
+
+// C's integer types become Rust's fixed-width ones, which have to keep the same
+// widths and signedness as the target's C types.
+static uint64_t mix(uint32_t a, int16_t b, unsigned char c) {
+    return ((uint64_t)a << 16) ^ (uint64_t)(int64_t)b ^ c;
+}
+
+void fixed_width(unsigned buffer_size, int buffer[]) {
+    long l = -5;
+    unsigned long ul = (unsigned long)l;
+    short s = 32766;
+    signed char sc = -127;
+    unsigned short us = 65534;
+    size_t n = sizeof(long long);
+    int8_t i8 = (int8_t)200;
+    uint64_t m = mix(0xffffffffu, -2, 250);
+
+    if (buffer_size < 10)
+        return;
+
+    s++;
+    sc--;
+    us++;
+    buffer[0] = (int)(ul >> 60);
+    buffer[1] = s;
+    buffer[2] = sc;
+    buffer[3] = us;
+    buffer[4] = (int)n;
+    buffer[5] = i8;
+    buffer[6] = (int)(m >> 32);
+    buffer[7] = (int)(m & 0xffff);
+    // Comparisons and `sizeof` have C types as well
+    buffer[8] = (l < 0) + (ul > 5) + !us + (s == 32767);
+    buffer[9] = (int)(sizeof s + sizeof(uint64_t) * 2 + sizeof buffer[0]);
+}
@@ -0,0 +1,25 @@
+extern crate libc;
+
+use checked_division::rust_checked_division;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn checked_division(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 100;
+
+pub fn test_checked_division() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        checked_division(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_checked_division(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(&buffer[..], &rust_buffer[..]);
+}
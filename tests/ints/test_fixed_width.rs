@@ -0,0 +1,26 @@
+extern crate libc;
+
+use fixed_width::rust_fixed_width;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn fixed_width(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 10;
+
+pub fn test_fixed_width() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        fixed_width(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_fixed_width(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [15, 32767, -128, 65535, 8, -56, -65536, 65284, 3, 22]);
+}
@@ -0,0 +1,39 @@
+//! --for-ranges
+
+static int limit = 10;
+
+static void shrink(int *n) {
+    *n -= 2;
+}
+
+static void shrink_limit(void) {
+    limit -= 3;
+}
+
+// Only the first loop can become a `for` loop: the others count with a global, or up
+// to bounds that change while looping.
+int for_ranges(int n) {
+    int total = 0;
+    int i;
+    int j;
+    int k;
+
+    for (i = 0; i < n; i++)
+        total += i;
+
+    for (limit = 0; limit < 4; limit++)
+        total += limit;
+    limit = 10;
+
+    for (j = 0; j < n; j++) {
+        total += j;
+        shrink(&n);
+    }
+
+    for (k = 0; k < limit; k += 2) {
+        total += k;
+        shrink_limit();
+    }
+
+    return total;
+}
@@ -0,0 +1,19 @@
+extern crate libc;
+
+use for_ranges::rust_for_ranges;
+
+use self::libc::c_int;
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn for_ranges(_: c_int) -> c_int;
+}
+
+pub fn test_for_ranges() {
+    for n in 0..12 {
+        let expected = unsafe { for_ranges(n) };
+        let actual = unsafe { rust_for_ranges(n) };
+        assert_eq!(expected, actual);
+    }
+}
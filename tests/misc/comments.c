@@ -0,0 +1,60 @@
+// Comments in all the places the translator keeps them. The translation has
+// to compile and behave the same with them in.
+
+#define LIMIT 3 // trailing comment after a macro
+
+/* A struct with commented fields */
+struct counter {
+    int count; // how many so far
+    /* the step between counts */
+    int step;
+};
+
+enum mode {
+    ADD, // add the step
+    SUB  /* subtract the step */
+};
+
+// Runs one step
+static void tick(struct counter *c, enum mode m) {
+    switch (m) { // on the mode
+    // adding
+    case ADD:
+        c->count += c->step; // forward
+        break;
+    case SUB: /* backward */
+        c->count -= c->step;
+        break;
+    }
+}
+
+static void nothing(void) {
+    // only a comment in here
+}
+
+// The comment on its only statement, which translates to nothing, stays with it
+static void ignore(int x) {
+    /* unused */ (void)x;
+}
+
+static int after_ignore(int x) {
+    return x + 1; // not the comment of `ignore`
+}
+
+void comments(unsigned buffer_size, int buffer[]) {
+    struct counter c = { 0, 2 }; // starts at zero
+    int i; /* declared up front */
+
+    if (buffer_size < 2) // too small
+        return;
+
+    for (i = 0; i < LIMIT; i++) {
+        tick(&c, ADD); // three times
+    }
+    nothing();
+    ignore(c.count);
+    buffer[0] = after_ignore(c.count) - 1;
+    tick(&c, SUB);
+    buffer[1] = c.count; // after going back
+    // a comment after the last statement
+}
@@ -0,0 +1,33 @@
+//! --translate-entry
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+// The translated entry point hands the raw bytes of the arguments and the
+// environment to `main`, and exits like returning from `main` does in C, which
+// runs the handlers registered with `atexit`.
+
+static int status;
+
+static void report(void) {
+    printf("status %d\n", status);
+}
+
+static void print_bytes(const char *label, const char *s) {
+    printf("%s", label);
+    for (; *s; s++)
+        printf(" %d", (unsigned char)*s);
+    printf("\n");
+}
+
+int main(int argc, char *argv[], char *envp[]) {
+    atexit(report);
+    for (int i = 2; i < argc; i++)
+        print_bytes("argv", argv[i]);
+    for (char **var = envp; *var; var++)
+        if (strncmp(*var, "ENTRY_BYTES=", 12) == 0)
+            print_bytes("envp", *var + 12);
+    status = argc;
+    return 3;
+}
@@ -0,0 +1,22 @@
+//! --rewrite-libc-calls
+
+#include <stdlib.h>
+#include <string.h>
+
+void rewritten_calls(unsigned buffer_size, int buffer[]) {
+    char src[] = "hello";
+    char dst[8];
+
+    if (buffer_size < 4)
+        return;
+
+    memset(dst, 'x', sizeof(dst));
+    memcpy(dst, src, sizeof(src));
+    buffer[0] = strlen(dst);
+    buffer[1] = dst[7];
+    buffer[2] = abs(-3);
+
+    // Taking its address keeps the declaration of `abs` around
+    int (*f)(int) = abs;
+    buffer[3] = f(-5);
+}
@@ -0,0 +1,50 @@
+//! enable_relooper, --target-stable
+
+// Code whose translation uses nightly features unless targeting stable Rust.
+
+struct opaque;
+
+struct aligned {
+    char c;
+    int x;
+} __attribute__((aligned(16)));
+
+static int numbers[4] = { 1, 2, 3, 4 };
+static int *first = numbers;
+
+static struct opaque *nowhere(void) {
+    return 0;
+}
+
+static int distance(int *from, int *to) {
+    return (int)(to - from);
+}
+
+static int skip_odd(int n) {
+    int total = 0;
+    int i = 0;
+loop:
+    if (i >= n)
+        goto done;
+    if (i % 2)
+        goto next;
+    total += i;
+next:
+    i++;
+    goto loop;
+done:
+    return total;
+}
+
+void target_stable(unsigned buffer_size, int buffer[]) {
+    struct aligned a = { 'a', 5 };
+
+    if (buffer_size < 5)
+        return;
+
+    buffer[0] = distance(&numbers[0], &numbers[3]);
+    buffer[1] = *first + first[2];
+    buffer[2] = skip_odd(7);
+    buffer[3] = a.x + (int)sizeof(struct aligned);
+    buffer[4] = nowhere() == 0;
+}
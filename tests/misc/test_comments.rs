@@ -0,0 +1,26 @@
+extern crate libc;
+
+use comments::rust_comments;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn comments(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 2;
+
+pub fn test_comments() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        comments(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_comments(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [6, 4]);
+}
@@ -0,0 +1,24 @@
+use entry::main;
+
+use std::env;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+pub fn test_entry() {
+    // The translated entry point exits the process, so it runs in a child started by this test
+    if env::var_os("ENTRY_CHILD").is_some() {
+        main();
+    }
+
+    let output = Command::new(env::current_exe().unwrap())
+        .arg("test_entry::test_entry")
+        .arg(OsStr::from_bytes(b"caf\xe9"))
+        .env("ENTRY_CHILD", "1")
+        .env("ENTRY_BYTES", OsStr::from_bytes(b"\xff=x"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "argv 99 97 102 233\nenvp 255 61 120\nstatus 3\n");
+}
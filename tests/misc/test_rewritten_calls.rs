@@ -0,0 +1,26 @@
+extern crate libc;
+
+use rewritten_calls::rust_rewritten_calls;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn rewritten_calls(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 4;
+
+pub fn test_rewritten_calls() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        rewritten_calls(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_rewritten_calls(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [5, 120, 3, 5]);
+}
@@ -0,0 +1,26 @@
+extern crate libc;
+
+use target_stable::rust_target_stable;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn target_stable(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 5;
+
+pub fn test_target_stable() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        target_stable(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_target_stable(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [3, 4, 12, 21, 1]);
+}
@@ -0,0 +1,32 @@
+// Static initializers that are C constant expressions but not Rust ones are
+// evaluated by the translator; ones pointing into another static run at startup.
+static int arr[3] = { 4, 5, 6 };
+static struct { int a; int b[2]; } s = { 7, { 8, 9 } };
+
+static unsigned u = -1u + (unsigned)sizeof(long);
+static int k = 1 ? 2 : -3;
+static const char *str = "hello" + 1;
+static int *p = &arr[1];
+static int *q = arr + 2;
+static int *pa = &s.a;
+static int *pb = &s.b[1];
+
+#define ONES4 1 + 1 + 1 + 1
+#define ONES16 ONES4 + ONES4 + ONES4 + ONES4
+#define ONES64 ONES16 + ONES16 + ONES16 + ONES16
+static unsigned deep = 0u + ONES64 + ONES64 + ONES64 + ONES64;
+
+void folded(unsigned buffer_size, int buffer[]) {
+    if (buffer_size < 9)
+        return;
+
+    buffer[0] = (int)(u - (unsigned)sizeof(long));
+    buffer[1] = k;
+    buffer[2] = str[0];
+    buffer[3] = *p;
+    buffer[4] = *q;
+    buffer[5] = *pa;
+    buffer[6] = *pb;
+    buffer[7] = (int)deep;
+    buffer[8] = p + 1 == q;
+}
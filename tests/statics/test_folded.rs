@@ -0,0 +1,26 @@
+extern crate libc;
+
+use folded::rust_folded;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn folded(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 9;
+
+pub fn test_folded() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        folded(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_folded(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [-1, 2, 101, 5, 6, 7, 9, 256, 1]);
+}
@@ -0,0 +1,32 @@
+//! --normalize-names
+
+// Types and functions named against Rust's conventions, which normalizing renames
+// consistently across definitions and uses.
+
+typedef struct point_2d {
+    int X;
+    int Y;
+} point_t;
+
+typedef enum { RED_COLOR, GREEN_COLOR } color_t;
+
+static int SquaredLength(point_t P) {
+    return P.X * P.X + P.Y * P.Y;
+}
+
+static int Shade(color_t Color) {
+    return Color == GREEN_COLOR ? 2 : 1;
+}
+
+void normalized_names(unsigned buffer_size, int buffer[]) {
+    point_t Origin = { 3, 4 };
+    struct point_2d Other = { 1, 1 };
+    int LocalCount = 2;
+
+    if (buffer_size < 3)
+        return;
+
+    buffer[0] = SquaredLength(Origin);
+    buffer[1] = SquaredLength(Other) * LocalCount;
+    buffer[2] = Shade(GREEN_COLOR) + Shade(RED_COLOR);
+}
@@ -0,0 +1,26 @@
+extern crate libc;
+
+use normalized_names::rust_normalized_names;
+
+use self::libc::{c_int, c_uint};
+
+#[link(name = "test")]
+extern "C" {
+    #[no_mangle]
+    fn normalized_names(_: c_uint, _: *mut c_int);
+}
+
+const BUFFER_SIZE: usize = 3;
+
+pub fn test_normalized_names() {
+    let mut buffer = [0; BUFFER_SIZE];
+    let mut rust_buffer = [0; BUFFER_SIZE];
+
+    unsafe {
+        normalized_names(BUFFER_SIZE as u32, buffer.as_mut_ptr());
+        rust_normalized_names(BUFFER_SIZE as u32, rust_buffer.as_mut_ptr());
+    }
+
+    assert_eq!(buffer, rust_buffer);
+    assert_eq!(buffer, [25, 4, 3]);
+}
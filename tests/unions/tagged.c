@@ -0,0 +1,85 @@
+//! --tagged-unions
+
+enum kind { INT, DOUBLE, CHAR };
+
+struct value {
+    enum kind tag;
+    union {
+        int i;
+        double d;
+        char c;
+    } u;
+};
+
+struct cell {
+    int tag;
+    union {
+        int whole;
+        unsigned bits;
+    } u;
+};
+
+static void retag(struct cell *c) {
+    c->tag = 1;
+}
+
+// `struct value` is only read under checks of its tag, so it gets accessors
+static int sum(struct value *v) {
+    if (v->tag == INT)
+        return v->u.i;
+    switch (v->tag) {
+    case DOUBLE:
+        return (int)(v->u.d * 2);
+    case CHAR:
+        return v->u.c;
+    default:
+        break;
+    }
+    return 0;
+}
+
+// Both of these read `struct cell` past a change of its tag, so it stays a raw union
+static int after_call(struct cell *c) {
+    if (c->tag == 0) {
+        retag(c);
+        return c->u.whole;
+    }
+    return -1;
+}
+
+static int nested_case(struct cell *c, int flag) {
+    int total = 0;
+    switch (c->tag) {
+    case 0:
+        if (flag) {
+    case 1:
+            total += c->u.whole;
+        }
+        break;
+    }
+    return total;
+}
+
+void tagged(unsigned buffer_size, int buffer[]) {
+    struct value values[3];
+    struct cell c;
+
+    if (buffer_size < 6)
+        return;
+
+    values[0].tag = INT;
+    values[0].u.i = 42;
+    values[1].tag = DOUBLE;
+    values[1].u.d = 3.5;
+    values[2].tag = CHAR;
+    values[2].u.c = 'x';
+    for (int i = 0; i < 3; i++)
+        buffer[i] = sum(&values[i]);
+
+    c.tag = 0;
+    c.u.whole = 7;
+    buffer[3] = after_call(&c);
+    buffer[4] = nested_case(&c, 0);
+    c.tag = 0;
+    buffer[5] = nested_case(&c, 1);
+}